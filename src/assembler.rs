@@ -0,0 +1,503 @@
+//! Two-pass assembler for the mnemonics produced by the disassembler.
+//!
+//! ```text
+//! ; comments start with a semicolon
+//! start:
+//!     LD I, sprite
+//!     DRW V0, V1, 5
+//! loop: JP loop
+//! sprite:
+//!     DB 0xF0, 0x90, 0x90, 0x90, 0xF0
+//! ```
+//!
+//! Numbers can be decimal, hex (`0x2A` or `#2A`) or binary (`0b1010`), and
+//! labels can be used anywhere a number is expected.
+
+use crate::Error;
+use crate::parser::Instruction;
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// A ROM along with where its code came from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Assembly {
+    pub rom: Vec<u8>,
+    /// Address and source line of each statement that emits bytes, in
+    /// address order
+    pub lines: Vec<(u16, usize)>,
+    pub labels: HashMap<String, u16>,
+}
+
+impl Assembly {
+    /// The source line of the statement starting at `address`.
+    pub fn line_at(&self, address: u16) -> Option<usize> {
+        self.lines
+            .binary_search_by_key(&address, |&(a, _)| a)
+            .ok()
+            .map(|i| self.lines[i].1)
+    }
+
+    /// The address of the first statement on or after `line`.
+    pub fn address_of_line(&self, line: usize) -> Option<(u16, usize)> {
+        self.lines
+            .iter()
+            .filter(|&&(_, l)| l >= line)
+            .min_by_key(|&&(_, l)| l)
+            .copied()
+    }
+}
+
+/// Assemble `source` into a ROM to be loaded at `base`.
+pub fn assemble(source: &str, base: u16) -> Result<Vec<u8>, Error> {
+    assemble_program(source, base).map(|assembly| assembly.rom)
+}
+
+/// Like [`assemble`], also returning the line of each statement and the
+/// address of each label.
+pub fn assemble_program(source: &str, base: u16) -> Result<Assembly, Error> {
+    let statements = parse(source)?;
+
+    // First pass: addresses of labels
+    let mut labels = HashMap::new();
+    let mut address = base as usize;
+    for statement in &statements {
+        if let Some(label) = &statement.label
+            && labels.insert(label.clone(), address as u16).is_some()
+        {
+            return Err(Error::Assembly(
+                statement.line,
+                format!("Duplicate label '{}'", label),
+            ));
+        }
+        address += statement.size();
+    }
+
+    // Second pass: encode
+    let mut rom = Vec::new();
+    let mut lines = Vec::new();
+    for statement in &statements {
+        let Some(mnemonic) = &statement.mnemonic else {
+            continue;
+        };
+        if statement.size() > 0 {
+            lines.push((base.wrapping_add(rom.len() as u16), statement.line));
+        }
+        let operands = Operands {
+            line: statement.line,
+            items: &statement.operands,
+            labels: &labels,
+        };
+        match mnemonic.as_str() {
+            "DB" => {
+                for i in 0..operands.items.len() {
+                    rom.push(operands.byte(i)?);
+                }
+            }
+            "DW" => {
+                for i in 0..operands.items.len() {
+                    rom.extend(operands.number(i, u16::MAX as u32)?.to_be_bytes());
+                }
+            }
+            _ => {
+                let instruction = encode(mnemonic, &operands)?;
+                rom.extend(instruction.to_opcode().to_be_bytes());
+            }
+        }
+    }
+
+    Ok(Assembly { rom, lines, labels })
+}
+
+struct Statement {
+    line: usize,
+    label: Option<String>,
+    mnemonic: Option<String>,
+    operands: Vec<String>,
+}
+
+impl Statement {
+    fn size(&self) -> usize {
+        match self.mnemonic.as_deref() {
+            None => 0,
+            Some("DB") => self.operands.len(),
+            Some("DW") => self.operands.len() * 2,
+            Some(_) => 2,
+        }
+    }
+}
+
+fn parse(source: &str) -> Result<Vec<Statement>, Error> {
+    let mut statements = Vec::new();
+
+    for (number, line) in source.lines().enumerate() {
+        let number = number + 1;
+        let mut text = line.split(';').next().unwrap_or("").trim();
+        if text.is_empty() {
+            continue;
+        }
+
+        let mut label = None;
+        if let Some((name, rest)) = text.split_once(':') {
+            let name = name.trim();
+            if !is_identifier(name) {
+                return Err(Error::Assembly(number, format!("Invalid label '{}'", name)));
+            }
+            label = Some(name.to_string());
+            text = rest.trim();
+        }
+
+        let (mnemonic, operands) = match text.split_once(char::is_whitespace) {
+            Some((mnemonic, rest)) => (mnemonic, rest.trim()),
+            None => (text, ""),
+        };
+        let operands = if operands.is_empty() {
+            Vec::new()
+        } else {
+            operands.split(',').map(|o| o.trim().to_string()).collect()
+        };
+
+        statements.push(Statement {
+            line: number,
+            label,
+            mnemonic: (!mnemonic.is_empty()).then(|| mnemonic.to_ascii_uppercase()),
+            operands,
+        });
+    }
+
+    Ok(statements)
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parse a number literal: decimal, `0x`/`#` hex or `0b` binary.
+pub fn parse_number(text: &str) -> Option<u32> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix('#')) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b") {
+        u32::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+/// Parse an address range `START..END`, END excluded, e.g. `0x200..0x300`.
+pub fn parse_range(text: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = text.split_once("..")?;
+    let (start, end) = (parse_number(start)?, parse_number(end)?);
+    if start >= end || end > 0x10000 {
+        return None;
+    }
+    Some(start as u16..=(end - 1) as u16)
+}
+
+struct Operands<'a> {
+    line: usize,
+    items: &'a [String],
+    labels: &'a HashMap<String, u16>,
+}
+
+impl Operands<'_> {
+    fn error(&self, message: String) -> Error {
+        Error::Assembly(self.line, message)
+    }
+
+    fn get(&self, index: usize) -> Result<&str, Error> {
+        self.items
+            .get(index)
+            .map(String::as_str)
+            .ok_or_else(|| self.error(format!("Missing operand {}", index + 1)))
+    }
+
+    /// Register index if operand `index` is `V0`-`VF`.
+    fn register(&self, index: usize) -> Option<usize> {
+        let operand = self.items.get(index)?;
+        let digit = operand
+            .strip_prefix('V')
+            .or_else(|| operand.strip_prefix('v'))?;
+        if digit.len() != 1 {
+            return None;
+        }
+        usize::from_str_radix(digit, 16).ok()
+    }
+
+    fn expect_register(&self, index: usize) -> Result<usize, Error> {
+        self.register(index).ok_or_else(|| {
+            let operand = self.items.get(index).map_or("", String::as_str);
+            self.error(format!("Expected a register, found '{}'", operand))
+        })
+    }
+
+    fn number(&self, index: usize, max: u32) -> Result<u16, Error> {
+        let operand = self.get(index)?;
+        let value = match self.labels.get(operand) {
+            Some(&address) => address as u32,
+            None => parse_number(operand)
+                .ok_or_else(|| self.error(format!("Unknown label or number '{}'", operand)))?,
+        };
+        if value > max {
+            return Err(self.error(format!("Value '{}' out of range", operand)));
+        }
+        Ok(value as u16)
+    }
+
+    fn address(&self, index: usize) -> Result<u16, Error> {
+        self.number(index, 0xFFF)
+    }
+
+    fn byte(&self, index: usize) -> Result<u8, Error> {
+        self.number(index, 0xFF).map(|v| v as u8)
+    }
+
+    /// Operand `index` compared case-insensitively to a keyword like `I` or `DT`.
+    fn is(&self, index: usize, keyword: &str) -> bool {
+        self.items
+            .get(index)
+            .is_some_and(|o| o.eq_ignore_ascii_case(keyword))
+    }
+
+    fn count(&self, expected: usize) -> Result<(), Error> {
+        if self.items.len() != expected {
+            return Err(self.error(format!(
+                "Expected {} operands, found {}",
+                expected,
+                self.items.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn encode(mnemonic: &str, ops: &Operands) -> Result<Instruction, Error> {
+    let instruction = match mnemonic {
+        "SYS" => {
+            ops.count(1)?;
+            let address = ops.address(0)?;
+            // 00E0, 00EE and the SUPER-CHIP opcodes share the 0NNN space
+            if Instruction::from_opcode(address) != Ok(Instruction::SysCall(address)) {
+                return Err(ops.error(format!(
+                    "SYS 0x{:03X} would encode another instruction",
+                    address
+                )));
+            }
+            Instruction::SysCall(address)
+        }
+        "CLS" => {
+            ops.count(0)?;
+            Instruction::ClearScreen
+        }
+        "RET" => {
+            ops.count(0)?;
+            Instruction::ReturnFromSubroutine
+        }
+        "JP" if ops.is(0, "V0") => {
+            ops.count(2)?;
+            Instruction::JumpByValue(ops.address(1)?)
+        }
+        "JP" => {
+            ops.count(1)?;
+            Instruction::Jump(ops.address(0)?)
+        }
+        "CALL" => {
+            ops.count(1)?;
+            Instruction::Call(ops.address(0)?)
+        }
+        "SE" | "SNE" => {
+            ops.count(2)?;
+            let x = ops.expect_register(0)?;
+            match (mnemonic, ops.register(1)) {
+                ("SE", Some(y)) => Instruction::SkipIfRegistersEqual(x, y),
+                ("SNE", Some(y)) => Instruction::SkipIfRegistersNotEqual(x, y),
+                ("SE", None) => Instruction::SkipIfEqualByte(x, ops.byte(1)?),
+                _ => Instruction::SkipIfNotEqualByte(x, ops.byte(1)?),
+            }
+        }
+        "ADD" => {
+            ops.count(2)?;
+            if ops.is(0, "I") {
+                Instruction::AddRegisterToIndexRegister(ops.expect_register(1)?)
+            } else {
+                let x = ops.expect_register(0)?;
+                match ops.register(1) {
+                    Some(y) => Instruction::RegistersSumWithOverflow(x, y),
+                    None => Instruction::AddToRegister(x, ops.byte(1)?),
+                }
+            }
+        }
+        "OR" | "AND" | "XOR" | "SUB" | "SUBN" | "SHR" | "SHL" => {
+            let x = ops.expect_register(0)?;
+            // The shifts accept a single register, as commonly written
+            let y = if matches!(mnemonic, "SHR" | "SHL") && ops.items.len() == 1 {
+                x
+            } else {
+                ops.count(2)?;
+                ops.expect_register(1)?
+            };
+            match mnemonic {
+                "OR" => Instruction::RegistersBitwiseOr(x, y),
+                "AND" => Instruction::RegistersBitwiseAnd(x, y),
+                "XOR" => Instruction::RegistersBitwiseXor(x, y),
+                "SUB" => Instruction::SubtractRegisterFromRegisterValue(x, y),
+                "SUBN" => Instruction::SubtractRegisterValueFromRegister(x, y),
+                "SHR" => Instruction::ShiftRegisterBitsRight(x, y),
+                _ => Instruction::ShiftRegisterBitsLeft(x, y),
+            }
+        }
+        "RND" => {
+            ops.count(2)?;
+            Instruction::SetRegisterToRandAndValue(ops.expect_register(0)?, ops.byte(1)?)
+        }
+        "DRW" => {
+            ops.count(3)?;
+            let n = ops.number(2, 0xF)? as u8;
+            Instruction::DrawSprite(ops.expect_register(0)?, ops.expect_register(1)?, n)
+        }
+        "SKP" => {
+            ops.count(1)?;
+            Instruction::SkipIfKeyEqualsRegister(ops.expect_register(0)?)
+        }
+        "SKNP" => {
+            ops.count(1)?;
+            Instruction::SkipIfKeyNotEqualsRegister(ops.expect_register(0)?)
+        }
+        "LD" => {
+            ops.count(2)?;
+            encode_load(ops)?
+        }
+        _ => {
+            return Err(ops.error(format!("Unknown mnemonic '{}'", mnemonic)));
+        }
+    };
+    Ok(instruction)
+}
+
+/// The many forms of `LD`.
+fn encode_load(ops: &Operands) -> Result<Instruction, Error> {
+    let instruction = if ops.is(0, "I") {
+        Instruction::SetIndexRegisterToValue(ops.address(1)?)
+    } else if ops.is(0, "DT") {
+        Instruction::SetDelayTimerToRegisterValue(ops.expect_register(1)?)
+    } else if ops.is(0, "ST") {
+        Instruction::SetSoundTimerToRegisterValue(ops.expect_register(1)?)
+    } else if ops.is(0, "F") {
+        Instruction::SetIndexRegisterToSpriteForRegister(ops.expect_register(1)?)
+    } else if ops.is(0, "B") {
+        Instruction::StoreBinaryCodedDecimalAtIndexRegisterValue(ops.expect_register(1)?)
+    } else if ops.is(0, "[I]") {
+        Instruction::DumpRegistersToMemoryAtIndexRegister(ops.expect_register(1)?)
+    } else {
+        let x = ops.expect_register(0)?;
+        if ops.is(1, "DT") {
+            Instruction::SetRegisterToDelayTimerValue(x)
+        } else if ops.is(1, "K") {
+            Instruction::WaitForKeyPress(x)
+        } else if ops.is(1, "[I]") {
+            Instruction::LoadMemoryToRegistersAtIndexRegister(x)
+        } else if let Some(y) = ops.register(1) {
+            Instruction::SetRegisterToRegisterValue(x, y)
+        } else {
+            Instruction::SetRegisterToValue(x, ops.byte(1)?)
+        }
+    };
+    Ok(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_labels_and_data() {
+        let source = "\
+; draw a box
+start:
+    LD I, sprite
+    drw v0, v1, 5   ; lower case works too
+loop: JP loop
+sprite:
+    DB 0xF0, #90, 0b10010000, 144, 0xF0
+    DW 0x1234
+";
+        let assembly = assemble_program(source, 0x200).unwrap();
+        assert_eq!(
+            assembly.rom,
+            [
+                0xA2, 0x06, 0xD0, 0x15, 0x12, 0x04, 0xF0, 0x90, 0x90, 0x90, 0xF0, 0x12, 0x34
+            ]
+        );
+        assert_eq!(assembly.labels["start"], 0x200);
+        assert_eq!(assembly.labels["sprite"], 0x206);
+        assert_eq!(assembly.line_at(0x204), Some(5));
+        assert_eq!(assembly.address_of_line(6), Some((0x206, 7)));
+    }
+
+    #[test]
+    fn every_mnemonic_assembles_back() {
+        for opcode in 0..=u16::MAX {
+            if let Ok(instruction) = Instruction::from_opcode(opcode) {
+                let source = instruction.to_string();
+                assert_eq!(
+                    assemble(&source, 0x200),
+                    Ok(opcode.to_be_bytes().to_vec()),
+                    "{}",
+                    source
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn shifts_take_one_or_two_registers() {
+        assert_eq!(
+            assemble("SHR V3\nSHL V1, V2", 0),
+            Ok(vec![0x83, 0x36, 0x81, 0x2E])
+        );
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = |source: &str| match assemble(source, 0x200) {
+            Err(Error::Assembly(line, message)) => (line, message),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(
+            error("a: CLS\na: CLS"),
+            (2, "Duplicate label 'a'".to_string())
+        );
+        assert_eq!(error("1a: CLS"), (1, "Invalid label '1a'".to_string()));
+        assert_eq!(
+            error("\nMOV V0, V1"),
+            (2, "Unknown mnemonic 'MOV'".to_string())
+        );
+        assert_eq!(
+            error("LD V0, 256"),
+            (1, "Value '256' out of range".to_string())
+        );
+        assert_eq!(error("JP nowhere").1, "Unknown label or number 'nowhere'");
+        assert_eq!(error("DRW V0, 1, 2").1, "Expected a register, found '1'");
+        assert_eq!(error("CLS V0").1, "Expected 0 operands, found 1");
+        for address in ["0x0E0", "0x0EE", "0x0C4", "0x0DF", "0x0FB", "0x0FF"] {
+            assert_eq!(
+                error(&format!("SYS {}", address)).1,
+                format!("SYS {} would encode another instruction", address)
+            );
+        }
+        assert_eq!(assemble("SYS 0x0FA", 0x200).unwrap(), [0x00, 0xFA]);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("42"), Some(42));
+        assert_eq!(parse_number("0x2A"), Some(42));
+        assert_eq!(parse_number("#2a"), Some(42));
+        assert_eq!(parse_number("0b101010"), Some(42));
+        assert_eq!(parse_number("0x"), None);
+        assert_eq!(parse_number("V0"), None);
+    }
+}
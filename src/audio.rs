@@ -0,0 +1,314 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+pub const DEFAULT_PITCH: f32 = 440.0;
+pub const DEFAULT_VOLUME: f32 = 0.25;
+
+/// Destination for the PCM samples generated from the sound timer.
+///
+/// Samples are mono, signed 16-bit and delivered one frame at a time.
+pub trait AudioSink {
+    fn write_samples(&mut self, samples: &[i16]) -> io::Result<()>;
+
+    /// Called once when the interpreter stops producing audio.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Square wave generator driven by the sound timer.
+pub struct SquareWave {
+    sample_rate: u32,
+    pitch: f32,
+    volume: f32,
+    phase: f32,
+}
+
+impl Default for SquareWave {
+    fn default() -> Self {
+        Self::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+impl SquareWave {
+    pub fn new(sample_rate: u32) -> Self {
+        SquareWave {
+            sample_rate,
+            pitch: DEFAULT_PITCH,
+            volume: DEFAULT_VOLUME,
+            phase: 0.0,
+        }
+    }
+
+    /// Tone frequency in Hz, which must be positive and finite.
+    pub fn with_pitch(mut self, pitch: f32) -> Self {
+        self.pitch = pitch;
+        self
+    }
+
+    /// Amplitude between 0.0 (silent) and 1.0 (full scale).
+    pub fn with_volume(mut self, volume: f32) -> Self {
+        self.volume = volume.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Fill `out` with the tone if `on`, otherwise with silence.
+    pub fn generate(&mut self, on: bool, out: &mut [i16]) {
+        if !on {
+            out.fill(0);
+            // Restart the wave on the next beep so every tone sounds the same
+            self.phase = 0.0;
+            return;
+        }
+
+        let amplitude = (self.volume * i16::MAX as f32) as i16;
+        let step = self.pitch / self.sample_rate as f32;
+
+        for sample in out.iter_mut() {
+            *sample = if self.phase < 0.5 {
+                amplitude
+            } else {
+                -amplitude
+            };
+            self.phase = (self.phase + step).fract();
+        }
+    }
+}
+
+/// Turns sound timer state into sample blocks for an [`AudioSink`].
+pub struct AudioOutput {
+    wave: SquareWave,
+    sink: Box<dyn AudioSink>,
+    frame_rate: u32,
+    remainder: u32,
+    buffer: Vec<i16>,
+}
+
+impl AudioOutput {
+    pub fn new(wave: SquareWave, sink: Box<dyn AudioSink>, frame_rate: u32) -> Self {
+        AudioOutput {
+            wave,
+            sink,
+            frame_rate,
+            remainder: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Produce one frame worth of audio.
+    pub fn frame(&mut self, sound_on: bool) -> io::Result<()> {
+        // Carry the fractional part so rates not divisible by the frame rate
+        // don't drift over time
+        let total = self.wave.sample_rate() + self.remainder;
+        let count = total / self.frame_rate;
+        self.remainder = total % self.frame_rate;
+
+        self.buffer.resize(count as usize, 0);
+        self.wave.generate(sound_on, &mut self.buffer);
+        self.sink.write_samples(&self.buffer)
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.sink.finish()
+    }
+}
+
+/// Writes samples as a 16-bit mono WAV file.
+///
+/// The header sizes are patched in [`AudioSink::finish`] (or on drop).
+pub struct WavWriter<W: Write + Seek> {
+    inner: W,
+    data_len: u32,
+    finished: bool,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut inner: W, sample_rate: u32) -> io::Result<Self> {
+        const CHANNELS: u16 = 1;
+        const BITS_PER_SAMPLE: u16 = 16;
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+        let byte_rate = sample_rate * block_align as u32;
+
+        inner.write_all(b"RIFF")?;
+        inner.write_all(&36u32.to_le_bytes())?;
+        inner.write_all(b"WAVE")?;
+        inner.write_all(b"fmt ")?;
+        inner.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        inner.write_all(&1u16.to_le_bytes())?;
+        inner.write_all(&CHANNELS.to_le_bytes())?;
+        inner.write_all(&sample_rate.to_le_bytes())?;
+        inner.write_all(&byte_rate.to_le_bytes())?;
+        inner.write_all(&block_align.to_le_bytes())?;
+        inner.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        inner.write_all(b"data")?;
+        inner.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            inner,
+            data_len: 0,
+            finished: false,
+        })
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavWriter<W> {
+    fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.inner.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len += (samples.len() * 2) as u32;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.inner.seek(SeekFrom::Start(4))?;
+        self.inner.write_all(&(36 + self.data_len).to_le_bytes())?;
+        self.inner.seek(SeekFrom::Start(40))?;
+        self.inner.write_all(&self.data_len.to_le_bytes())?;
+        self.inner.seek(SeekFrom::End(0))?;
+        self.inner.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// Writes raw signed 16-bit little-endian samples, e.g. to pipe into
+/// `aplay -f S16_LE -r 44100 -c 1`.
+pub struct RawPcmSink<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> RawPcmSink<W> {
+    pub fn new(inner: W) -> Self {
+        RawPcmSink { inner }
+    }
+}
+
+impl RawPcmSink<io::Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+impl<W: Write> AudioSink for RawPcmSink<W> {
+    fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        self.inner.write_all(&bytes)?;
+        self.inner.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn wav_header_is_patched_with_the_data_size() {
+        let mut file = Cursor::new(Vec::new());
+        let mut wav = WavWriter::new(&mut file, 8000).unwrap();
+        wav.write_samples(&[1, -1, 2]).unwrap();
+        wav.finish().unwrap();
+        drop(wav);
+
+        let bytes = file.into_inner();
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), 36 + 6);
+        assert_eq!(&bytes[8..12], b"WAVE");
+        assert_eq!(u32_at(&bytes, 24), 8000);
+        assert_eq!(u32_at(&bytes, 28), 16000);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 6);
+        assert_eq!(&bytes[44..], &[1, 0, 0xFF, 0xFF, 2, 0]);
+    }
+
+    #[test]
+    fn wav_header_is_patched_on_drop() {
+        let mut file = Cursor::new(Vec::new());
+        let mut wav = WavWriter::new(&mut file, DEFAULT_SAMPLE_RATE).unwrap();
+        wav.write_samples(&[0; 10]).unwrap();
+        drop(wav);
+
+        let bytes = file.into_inner();
+        assert_eq!(u32_at(&bytes, 4), 36 + 20);
+        assert_eq!(u32_at(&bytes, 40), 20);
+    }
+
+    #[test]
+    fn square_wave_alternates_and_silence_resets_the_phase() {
+        let mut wave = SquareWave::new(8).with_pitch(2.0).with_volume(1.0);
+        let mut out = [0; 8];
+        wave.generate(true, &mut out);
+        let max = i16::MAX;
+        assert_eq!(out, [max, max, -max, -max, max, max, -max, -max]);
+
+        wave.generate(true, &mut out[..1]);
+        wave.generate(false, &mut out);
+        assert_eq!(out, [0; 8]);
+        wave.generate(true, &mut out[..1]);
+        assert_eq!(out[0], max);
+    }
+
+    #[test]
+    fn volume_is_clamped() {
+        let mut wave = SquareWave::new(8).with_volume(3.0);
+        let mut out = [0; 1];
+        wave.generate(true, &mut out);
+        assert_eq!(out[0], i16::MAX);
+    }
+
+    struct Recorder(Rc<RefCell<Vec<usize>>>);
+
+    impl AudioSink for Recorder {
+        fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+            self.0.borrow_mut().push(samples.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn frames_carry_the_fractional_sample_count() {
+        let blocks = Rc::new(RefCell::new(Vec::new()));
+        let sink = Box::new(Recorder(blocks.clone()));
+        let mut output = AudioOutput::new(SquareWave::new(100), sink, 60);
+        for _ in 0..3 {
+            output.frame(false).unwrap();
+        }
+        assert_eq!(*blocks.borrow(), [1, 2, 2]);
+    }
+
+    #[test]
+    fn raw_pcm_is_little_endian() {
+        let mut out = Vec::new();
+        RawPcmSink::new(&mut out)
+            .write_samples(&[0x0102, -2])
+            .unwrap();
+        assert_eq!(out, [0x02, 0x01, 0xFE, 0xFF]);
+    }
+}
@@ -0,0 +1,298 @@
+//! Code coverage: which bytes of a ROM were executed, accessed as data, or
+//! never touched during a run.
+//!
+//! The annotated listing is disassembly with the coverage of each line in
+//! its comment, so it can still be assembled:
+//!
+//! ```text
+//!     CLS                 ; 0x200: 00E0       exec 1
+//!     DB 0xFF, 0x00       ; 0x22A: FF00       data
+//!     DW 0x1234           ; 0x22C: 1234       ----
+//! ```
+//!
+//! The lcov-like report has a `DA:<address>,<executions>` record for every
+//! instruction, with the address in decimal in place of a line number, so
+//! coverage tools can merge and compare runs.
+
+use crate::disassembler::Line;
+use crate::parser::Instruction;
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+const EXECUTED: u8 = 1;
+const READ: u8 = 2;
+const WRITTEN: u8 = 4;
+
+/// Longest run of data bytes on one listing line.
+const DATA_BYTES_PER_LINE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Use {
+    /// Executed as an instruction this many times
+    Executed(u64),
+    /// Read or written as data, never executed
+    Data,
+    Untouched,
+}
+
+/// Bytes of each kind in a ROM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Summary {
+    pub executed: usize,
+    pub data: usize,
+    pub untouched: usize,
+}
+
+impl Summary {
+    pub fn total(&self) -> usize {
+        self.executed + self.data + self.untouched
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    /// How each RAM byte was used
+    flags: Vec<u8>,
+    /// Executions of the instruction at each address
+    executions: HashMap<u16, u64>,
+}
+
+impl Coverage {
+    pub fn new(ram_size: usize) -> Self {
+        Coverage {
+            flags: vec![0; ram_size],
+            executions: HashMap::new(),
+        }
+    }
+
+    /// Note the instruction at `address` and the RAM it read and wrote.
+    pub fn record(&mut self, address: u16, reads: &[(u16, u8)], writes: &[(u16, u8)]) {
+        *self.executions.entry(address).or_default() += 1;
+        self.mark(address, EXECUTED);
+        self.mark(address.wrapping_add(1), EXECUTED);
+        for &(address, _) in reads {
+            self.mark(address, READ);
+        }
+        for &(address, _) in writes {
+            self.mark(address, WRITTEN);
+        }
+    }
+
+    fn mark(&mut self, address: u16, flag: u8) {
+        if let Some(flags) = self.flags.get_mut(address as usize) {
+            *flags |= flag;
+        }
+    }
+
+    fn flags(&self, address: u16) -> u8 {
+        self.flags.get(address as usize).copied().unwrap_or(0)
+    }
+
+    /// Whether the byte at `address` was executed, used as data, or neither.
+    pub fn byte_use(&self, address: u16) -> Use {
+        let flags = self.flags(address);
+        if flags & EXECUTED != 0 {
+            Use::Executed(self.executions.get(&address).copied().unwrap_or(0))
+        } else if flags & (READ | WRITTEN) != 0 {
+            Use::Data
+        } else {
+            Use::Untouched
+        }
+    }
+
+    /// Count the bytes of each kind in the `len` bytes from `base`.
+    pub fn summary(&self, base: u16, len: usize) -> Summary {
+        let mut summary = Summary::default();
+        for offset in 0..len {
+            match self.byte_use(base.wrapping_add(offset as u16)) {
+                Use::Executed(_) => summary.executed += 1,
+                Use::Data => summary.data += 1,
+                Use::Untouched => summary.untouched += 1,
+            }
+        }
+        summary
+    }
+
+    /// Split `rom`, loaded at `base`, into lines: instructions wherever one
+    /// was executed, runs of data bytes, and untouched words.
+    pub fn lines(&self, rom: &[u8], base: u16) -> Vec<(Line, Use)> {
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while offset < rom.len() {
+            let address = base.wrapping_add(offset as u16);
+            let (len, usage) = match self.byte_use(address) {
+                Use::Data => {
+                    let len = (offset..rom.len())
+                        .take(DATA_BYTES_PER_LINE)
+                        .take_while(|&o| self.byte_use(base.wrapping_add(o as u16)) == Use::Data)
+                        .count();
+                    (len, Use::Data)
+                }
+                Use::Executed(count) if count > 0 => (2, Use::Executed(count)),
+                // The second byte of an instruction that started elsewhere
+                Use::Executed(_) => (1, Use::Executed(0)),
+                Use::Untouched => {
+                    // An untouched byte before a used one stands alone
+                    let next = self.byte_use(address.wrapping_add(1));
+                    (if next == Use::Untouched { 2 } else { 1 }, Use::Untouched)
+                }
+            };
+            let bytes = rom[offset..(offset + len).min(rom.len())].to_vec();
+            let instruction = match (usage, bytes.as_slice()) {
+                (Use::Data, _) => None,
+                (_, &[b1, b2]) => Instruction::from_opcode(u16::from_be_bytes([b1, b2])).ok(),
+                _ => None,
+            };
+            offset += bytes.len();
+            lines.push((
+                Line {
+                    address,
+                    bytes,
+                    instruction,
+                },
+                usage,
+            ));
+        }
+        lines
+    }
+
+    /// Write the disassembly of `rom` with the coverage of each line.
+    pub fn write_annotated(&self, rom: &[u8], base: u16, out: &mut impl Write) -> io::Result<()> {
+        let summary = self.summary(base, rom.len());
+        let percent = |n: usize| n as f64 * 100.0 / summary.total().max(1) as f64;
+        writeln!(
+            out,
+            "; {} of {} bytes executed ({:.1}%), {} used as data ({:.1}%), {} untouched ({:.1}%)",
+            summary.executed,
+            summary.total(),
+            percent(summary.executed),
+            summary.data,
+            percent(summary.data),
+            summary.untouched,
+            percent(summary.untouched)
+        )?;
+        for (line, usage) in self.lines(rom, base) {
+            let tag = match usage {
+                Use::Executed(count) => format!("exec {}", count),
+                Use::Data => "data".to_string(),
+                Use::Untouched => "----".to_string(),
+            };
+            writeln!(out, "{:<42}  {}", line.to_string(), tag)?;
+        }
+        Ok(())
+    }
+
+    /// Write an lcov tracefile for `rom`, loaded at `base`, under the source
+    /// name `name`. Data is left out.
+    pub fn write_lcov(
+        &self,
+        name: &str,
+        rom: &[u8],
+        base: u16,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", name)?;
+        let (mut found, mut hit) = (0, 0);
+        for (line, usage) in self.lines(rom, base) {
+            let count = match usage {
+                Use::Executed(count) if count > 0 => count,
+                Use::Untouched if line.bytes.len() == 2 => 0,
+                _ => continue,
+            };
+            writeln!(out, "DA:{},{}", line.address, count)?;
+            found += 1;
+            if count > 0 {
+                hit += 1;
+            }
+        }
+        writeln!(out, "LF:{}", found)?;
+        writeln!(out, "LH:{}", hit)?;
+        writeln!(out, "end_of_record")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::CLIDisplay;
+    use crate::interpreter::Interpreter;
+
+    const ROM: [u8; 10] = [
+        0xA2, 0x08, // LD I, 0x208
+        0xD0, 0x01, // DRW V0, V0, 1
+        0x12, 0x04, // JP 0x204
+        0x00, 0xE0, // CLS, never run
+        0xFF, 0x00, // a sprite row and a spare byte
+    ];
+
+    fn coverage(steps: usize) -> Coverage {
+        let mut core = Interpreter::new();
+        core.set_display(CLIDisplay::headless());
+        core.load_rom(ROM.to_vec()).unwrap();
+        core.set_coverage(Coverage::new(core.state().ram.len()));
+        for _ in 0..steps {
+            core.step().unwrap();
+        }
+        core.coverage().unwrap().clone()
+    }
+
+    #[test]
+    fn bytes_are_executed_data_or_untouched() {
+        let coverage = coverage(5);
+        assert_eq!(coverage.byte_use(0x204), Use::Executed(3));
+        assert_eq!(coverage.byte_use(0x205), Use::Executed(0));
+        assert_eq!(coverage.byte_use(0x208), Use::Data);
+        assert_eq!(coverage.byte_use(0x206), Use::Untouched);
+        assert_eq!(
+            coverage.summary(0x200, ROM.len()),
+            Summary {
+                executed: 6,
+                data: 1,
+                untouched: 3
+            }
+        );
+    }
+
+    #[test]
+    fn writes_count_as_data() {
+        let mut coverage = Coverage::new(0x1000);
+        coverage.record(0x200, &[], &[(0x300, 1), (0x301, 2)]);
+        coverage.record(0x200, &[], &[]);
+        assert_eq!(coverage.byte_use(0x200), Use::Executed(2));
+        assert_eq!(coverage.byte_use(0x301), Use::Data);
+        // Addresses outside RAM are ignored
+        coverage.record(0xFFFF, &[(0x2000, 0)], &[]);
+        assert_eq!(coverage.byte_use(0x2000), Use::Untouched);
+    }
+
+    #[test]
+    fn annotated_listing_tags_each_line() {
+        let mut out = Vec::new();
+        coverage(5).write_annotated(&ROM, 0x200, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "; 6 of 10 bytes executed (60.0%), 1 used as data (10.0%), 3 untouched (30.0%)
+    LD I, 0x208         ; 0x200: A208       exec 1
+    DRW V0, V0, 1       ; 0x202: D001       exec 1
+    JP 0x204            ; 0x204: 1204       exec 3
+    CLS                 ; 0x206: 00E0       ----
+    DB 0xFF             ; 0x208: FF         data
+    DB 0x00             ; 0x209: 00         ----
+"
+        );
+    }
+
+    #[test]
+    fn lcov_lists_instructions_by_address() {
+        let mut out = Vec::new();
+        coverage(5)
+            .write_lcov("rom.ch8", &ROM, 0x200, &mut out)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "TN:\nSF:rom.ch8\nDA:512,1\nDA:514,1\nDA:516,3\nDA:518,0\nLF:4\nLH:3\nend_of_record\n"
+        );
+    }
+}
@@ -0,0 +1,312 @@
+//! Snapshots of the machine taken when execution fails.
+//!
+//! A dump is JSON holding everything needed to inspect the failure later:
+//! the failing instruction, registers, timers, stack, the last instructions
+//! executed, RAM and the screen. `secrus8 debug --crash-dump <file>` loads
+//! one back into the debugger.
+
+use crate::consts::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::hex;
+use crate::input::Keypad;
+use crate::interpreter::Interpreter;
+use crate::memory::MemoryLayout;
+use crate::parser::Instruction;
+use crate::quirks::Quirks;
+use crate::{Error, Result};
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CrashDump {
+    pub error: String,
+    /// Address of the failing instruction
+    pub pc: u16,
+    pub opcode: u16,
+    /// The failing instruction in assembler syntax, if it decodes
+    pub instruction: Option<String>,
+    pub registers: [u8; 16],
+    pub index_register: u16,
+    pub stack: Vec<u16>,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    /// Keys held down, missing in older dumps
+    #[serde(default)]
+    pub keypad: Keypad,
+    pub cycle: u64,
+    pub frame: u64,
+    /// Names of the quirks that were on
+    pub quirks: Vec<String>,
+    pub program_start: u16,
+    pub font_address: u16,
+    pub big_font_address: u16,
+    /// Address and opcode of the last instructions executed, oldest first
+    pub history: Vec<(u16, u16)>,
+    /// RAM as hex, 64 bytes per line
+    pub ram: Vec<String>,
+    /// Screen rows, `#` for pixels that are on
+    pub screen: Vec<String>,
+}
+
+impl CrashDump {
+    /// Snapshot `interpreter` right after `error` was returned by `step`.
+    pub fn capture(interpreter: &Interpreter, error: &Error) -> Self {
+        let state = interpreter.state();
+        let history: Vec<(u16, u16)> = interpreter.history().collect();
+        // The failing instruction is the last one fetched, unless PC itself
+        // was out of bounds and nothing could be fetched
+        let (pc, opcode) = match *error {
+            Error::PcOutOfBounds(pc) => (pc, word_at(&state.ram, pc)),
+            _ => interpreter.last_instruction(),
+        };
+
+        let screen = (0..SCREEN_HEIGHT)
+            .map(|y| {
+                (0..SCREEN_WIDTH)
+                    .map(|x| match interpreter.display().pixel(x, y) {
+                        true => '#',
+                        false => '.',
+                    })
+                    .collect()
+            })
+            .collect();
+
+        CrashDump {
+            error: error.to_string(),
+            pc,
+            opcode,
+            instruction: Instruction::from_opcode(opcode).ok().map(|i| i.to_string()),
+            registers: state.registers,
+            index_register: state.index_register,
+            stack: state.stack.clone(),
+            delay_timer: state.delay_timer,
+            sound_timer: state.sound_timer,
+            keypad: state.keypad,
+            cycle: interpreter.cycle(),
+            frame: interpreter.frame(),
+            quirks: interpreter
+                .quirks()
+                .enabled()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            program_start: state.layout.program_start,
+            font_address: state.layout.font_address,
+            big_font_address: state.layout.big_font_address,
+            history,
+            ram: state.ram.chunks(64).map(hex::encode).collect(),
+            screen,
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(path, json)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Put `interpreter` back in the state of the dump, with PC on the
+    /// failing instruction and the history up to it.
+    ///
+    /// The input backend, random number generator and undo log are left as
+    /// they are.
+    pub fn restore(&self, interpreter: &mut Interpreter) -> Result<()> {
+        let ram = self
+            .ram
+            .iter()
+            .map(|line| hex::decode(line))
+            .collect::<Option<Vec<Vec<u8>>>>()
+            .ok_or_else(|| Error::InvalidCrashDump("RAM is not hex".to_string()))?
+            .concat();
+
+        let mut quirks = Quirks::default();
+        for name in Quirks::NAMES {
+            quirks.set(name, false)?;
+        }
+        for name in &self.quirks {
+            quirks.set(name, true)?;
+        }
+        interpreter.set_quirks(quirks);
+
+        // Fonts come back with the rest of RAM
+        let layout = MemoryLayout {
+            program_start: self.program_start,
+            font_address: self.font_address,
+            big_font_address: self.big_font_address,
+            ram_size: ram.len(),
+        };
+        interpreter.set_memory(layout, &Default::default())?;
+
+        let state = interpreter.state_mut();
+        state.ram = ram;
+        state.pc = self.pc;
+        state.registers = self.registers;
+        state.index_register = self.index_register;
+        state.stack = self.stack.clone();
+        state.delay_timer = self.delay_timer;
+        state.sound_timer = self.sound_timer;
+        state.keypad = self.keypad;
+
+        interpreter.set_counters(self.cycle, self.frame);
+        // The failing instruction is remembered again when it is retried
+        let history = match self.history.split_last() {
+            Some((&last, rest)) if last == (self.pc, self.opcode) => rest,
+            _ => &self.history[..],
+        };
+        interpreter.set_history(history);
+
+        let display = interpreter.display_mut();
+        display.clear();
+        for (y, row) in self.screen.iter().take(SCREEN_HEIGHT as usize).enumerate() {
+            for (x, pixel) in row.chars().take(SCREEN_WIDTH as usize).enumerate() {
+                display.set_pixel(x as u8, y as u8, pixel == '#');
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Human readable report, without RAM and screen.
+impl fmt::Display for CrashDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Execution error: {}", self.error)?;
+        writeln!(
+            f,
+            "At {:#05x}: {:04X}  {}",
+            self.pc,
+            self.opcode,
+            self.instruction.as_deref().unwrap_or("???")
+        )?;
+        writeln!(
+            f,
+            "I {:#05x}  SP {}  DT {}  ST {}  cycle {}  frame {}",
+            self.index_register,
+            self.stack.len(),
+            self.delay_timer,
+            self.sound_timer,
+            self.cycle,
+            self.frame
+        )?;
+        let registers: Vec<String> = self
+            .registers
+            .iter()
+            .enumerate()
+            .map(|(i, v)| format!("V{:X} {:02X}", i, v))
+            .collect();
+        writeln!(f, "{}", registers[..8].join("  "))?;
+        writeln!(f, "{}", registers[8..].join("  "))?;
+        if !self.stack.is_empty() {
+            let stack: Vec<String> = self.stack.iter().map(|a| format!("{:#05x}", a)).collect();
+            writeln!(f, "Stack: {}", stack.join(" "))?;
+        }
+        writeln!(f, "Last instructions:")?;
+        for &(address, opcode) in &self.history {
+            let source = Instruction::from_opcode(opcode)
+                .map(|i| i.to_string())
+                .unwrap_or_else(|_| "???".to_string());
+            writeln!(f, "  {:#05x}: {:04X}  {}", address, opcode, source)?;
+        }
+        Ok(())
+    }
+}
+
+fn word_at(ram: &[u8], address: u16) -> u16 {
+    let byte = |a: usize| ram.get(a).copied().unwrap_or(0) as u16;
+    byte(address as usize) << 8 | byte(address as usize + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::FRAMES_PER_SECOND;
+    use crate::display::CLIDisplay;
+    use crate::interpreter::DEFAULT_HISTORY_SIZE;
+
+    /// Draws a digit, calls a subroutine and returns twice.
+    const ROM: [u8; 12] = [
+        0x60, 0x07, // LD V0, 7
+        0xF0, 0x29, // LD F, V0
+        0xD0, 0x05, // DRW V0, V0, 5
+        0x22, 0x0A, // CALL 0x20A
+        0x00, 0xEE, // RET
+        0x00, 0xEE, // RET
+    ];
+
+    fn crashed(history_size: usize) -> (Interpreter, CrashDump) {
+        let mut core = Interpreter::new();
+        core.set_display(CLIDisplay::headless());
+        core.set_instructions_per_second(FRAMES_PER_SECOND);
+        core.set_history_size(history_size);
+        core.load_rom(ROM.to_vec()).unwrap();
+        core.state_mut().keypad[0xA] = true;
+        let error = loop {
+            if let Err(e) = core.step() {
+                break e;
+            }
+        };
+        let dump = CrashDump::capture(&core, &error);
+        (core, dump)
+    }
+
+    #[test]
+    fn capture_points_at_the_failing_instruction() {
+        for history_size in [0, 3] {
+            let (_, dump) = crashed(history_size);
+            assert_eq!(dump.error, "Return with an empty stack at 0x208");
+            assert_eq!((dump.pc, dump.opcode), (0x208, 0x00EE));
+            assert_eq!(dump.instruction.as_deref(), Some("RET"));
+            assert_eq!(dump.cycle, 5);
+            assert_eq!(dump.frame, 5);
+            assert!(dump.keypad[0xA]);
+        }
+        let (_, dump) = crashed(3);
+        assert_eq!(
+            dump.history,
+            [(0x206, 0x220A), (0x20A, 0x00EE), (0x208, 0x00EE)]
+        );
+    }
+
+    #[test]
+    fn saved_dumps_load_and_restore_the_machine() {
+        let (core, dump) = crashed(DEFAULT_HISTORY_SIZE);
+        let path =
+            std::env::temp_dir().join(format!("secrus8-crash-test-{}.json", std::process::id()));
+        dump.save(&path).unwrap();
+        let loaded = CrashDump::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, dump);
+
+        let mut restored = Interpreter::new();
+        restored.set_display(CLIDisplay::headless());
+        loaded.restore(&mut restored).unwrap();
+        assert_eq!(restored.state().ram, core.state().ram);
+        assert_eq!(restored.state().pc, 0x208);
+        assert_eq!(restored.state().registers, core.state().registers);
+        assert_eq!(restored.state().index_register, core.state().index_register);
+        assert_eq!(restored.state().keypad, core.state().keypad);
+        assert_eq!((restored.cycle(), restored.frame()), (5, 5));
+        assert_eq!(restored.history().count(), 5);
+        assert_eq!(restored.display().screen(), core.display().screen());
+        assert_eq!(restored.quirks(), core.quirks());
+        assert_eq!(restored.step(), Err(Error::StackUnderflow(0x208)));
+    }
+
+    #[test]
+    fn restore_rejects_ram_that_is_not_hex() {
+        let (_, mut dump) = crashed(0);
+        dump.ram[0].replace_range(0..2, "zz");
+        let mut core = Interpreter::new();
+        assert!(matches!(
+            dump.restore(&mut core),
+            Err(Error::InvalidCrashDump(_))
+        ));
+    }
+}
@@ -0,0 +1,808 @@
+//! Debug Adapter Protocol server, so editors such as VS Code can debug ROMs
+//! and assembler source.
+//!
+//! Messages are JSON with a `Content-Length` header, read from one stream and
+//! written to another (stdin and stdout for `secrus8 dap`). A `launch`
+//! request takes the `program` to debug: a ROM, or assembler source (`.asm`
+//! or `.s`), which is assembled so that breakpoints can be set on its lines.
+//! `stopOnEntry` and `loadAddress` are optional. The `initialized` event is
+//! sent once the program is launched, so the client's breakpoints can be
+//! placed in it.
+//!
+//! There is one thread. Its call stack comes from the return addresses on
+//! the CHIP-8 stack, and its variables are V0-VF, I, PC, the stack depth and
+//! the timers. `readMemory` and `writeMemory` take RAM addresses as memory
+//! references, and `evaluate` accepts the expressions of
+//! [`crate::expression`]. Changing a variable or memory clears the history
+//! `stepBack` runs back through.
+
+use crate::assembler::{Assembly, assemble_program, parse_number};
+use crate::debugger::{Debugger, StopReason};
+use crate::expression::{Expression, Variable};
+use crate::interpreter::Interpreter;
+use crate::octo;
+use crate::parser::Instruction;
+
+use serde_json::{Value, json};
+
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+/// Instructions run between checks for new requests while the program runs.
+const REQUEST_POLL_INTERVAL: u64 = 10_000;
+
+const THREAD_ID: u64 = 1;
+
+const REGISTERS_REFERENCE: u64 = 1;
+const TIMERS_REFERENCE: u64 = 2;
+
+/// Builds an interpreter with `rom` loaded, at `load_address` if given.
+pub type Launcher =
+    Box<dyn FnMut(&[u8], Option<u16>) -> std::result::Result<Interpreter, Box<dyn Error>>>;
+
+/// Program being debugged.
+struct Session {
+    debugger: Debugger,
+    /// Source file and how it was assembled, unless a ROM was launched
+    source: Option<(PathBuf, Assembly)>,
+    /// Addresses of the breakpoints set by the client
+    breakpoints: Vec<u16>,
+    stop_on_entry: bool,
+}
+
+/// What the program is doing between requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Run {
+    Stopped,
+    Continue,
+    /// Run one instruction
+    Step,
+    /// Run until the stack is no deeper than `depth` with PC at `address`
+    StepOver {
+        depth: usize,
+        address: u16,
+    },
+    /// Run until the stack is shallower than `depth`
+    StepOut {
+        depth: usize,
+    },
+    /// Undo one instruction
+    StepBack,
+    /// Run backwards to the previous breakpoint
+    ReverseContinue,
+}
+
+pub struct DapServer<W: Write> {
+    out: W,
+    seq: u64,
+    launcher: Launcher,
+    session: Option<Session>,
+    run: Run,
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(out: W, launcher: Launcher) -> Self {
+        DapServer {
+            out,
+            seq: 1,
+            launcher,
+            session: None,
+            run: Run::Stopped,
+        }
+    }
+
+    /// Handle requests from `input` until the client disconnects.
+    pub fn serve(&mut self, input: impl BufRead + Send + 'static) -> io::Result<()> {
+        let requests = spawn_reader(input);
+        loop {
+            let message = if self.run == Run::Stopped {
+                match requests.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => return Ok(()),
+                }
+            } else {
+                match requests.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            };
+            if let Some(message) = message {
+                if !self.request(&message?)? {
+                    return Ok(());
+                }
+            } else {
+                self.run_for(REQUEST_POLL_INTERVAL)?;
+            }
+        }
+    }
+
+    /// Answer one request, returning false after `disconnect`.
+    fn request(&mut self, request: &Value) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let result = match command {
+            "disconnect" => {
+                self.respond(request, Ok(Value::Null))?;
+                return Ok(false);
+            }
+            "initialize" => {
+                self.respond(
+                    request,
+                    Ok(json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsSetVariable": true,
+                        "supportsReadMemoryRequest": true,
+                        "supportsWriteMemoryRequest": true,
+                        "supportsEvaluateForHovers": true,
+                        "supportsStepBack": true,
+                    })),
+                )?;
+                return Ok(true);
+            }
+            "launch" => self.launch(args),
+            "configurationDone" => self.configuration_done(),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            _ => match self.session.as_mut() {
+                Some(session) => session.request(command, args, &mut self.run),
+                None => Err("No program launched".to_string()),
+            },
+        };
+        let ok = result.is_ok();
+        self.respond(request, result)?;
+        match command {
+            "launch" if ok => self.event("initialized", Value::Null)?,
+            "pause" if ok => self.stopped("pause", None)?,
+            "configurationDone" if ok && self.run == Run::Stopped => self.stopped("entry", None)?,
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or("Missing 'program' in launch arguments")?;
+        let load_address = match &args["loadAddress"] {
+            Value::Null => None,
+            value => Some(json_address(value).ok_or("Invalid 'loadAddress'")?),
+        };
+        let path = Path::new(program);
+        let bytes = fs::read(path).map_err(|e| format!("{}: {}", program, e))?;
+
+        let base = load_address.unwrap_or(crate::consts::INITIAL_PC);
+        let text = String::from_utf8_lossy(&bytes);
+        let compiled = match path.extension().and_then(|e| e.to_str()) {
+            Some("asm" | "s") => Some(assemble_program(&text, base)),
+            Some("8o") => Some(octo::compile(&text, base)),
+            _ => None,
+        };
+        let (rom, source, load_address) = if let Some(compiled) = compiled {
+            let assembly = compiled.map_err(|e| e.to_string())?;
+            (
+                assembly.rom.clone(),
+                Some((path.to_path_buf(), assembly)),
+                Some(base),
+            )
+        } else {
+            (bytes, None, load_address)
+        };
+
+        let interpreter = (self.launcher)(&rom, load_address).map_err(|e| e.to_string())?;
+        self.session = Some(Session {
+            debugger: Debugger::new(interpreter),
+            source,
+            breakpoints: Vec::new(),
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+        });
+        Ok(Value::Null)
+    }
+
+    fn configuration_done(&mut self) -> Result<Value, String> {
+        let session = self.session.as_ref().ok_or("No program launched")?;
+        if !session.stop_on_entry {
+            self.run = Run::Continue;
+        }
+        Ok(Value::Null)
+    }
+
+    /// Run the program for up to `limit` instructions, reporting why it
+    /// stopped.
+    fn run_for(&mut self, limit: u64) -> io::Result<()> {
+        let Some(session) = self.session.as_mut() else {
+            self.run = Run::Stopped;
+            return Ok(());
+        };
+        let debugger = &mut session.debugger;
+        let result = match self.run {
+            Run::Stopped => return Ok(()),
+            Run::Continue => debugger.resume(limit),
+            Run::Step => debugger.step(),
+            Run::StepOver { depth, address } => debugger.resume_until(limit, |i| {
+                i.state().stack.len() <= depth && i.state().pc == address
+            }),
+            Run::StepOut { depth } => {
+                debugger.resume_until(limit, |i| i.state().stack.len() < depth)
+            }
+            Run::StepBack => Ok(debugger.reverse_step()),
+            Run::ReverseContinue => Ok(debugger.reverse_resume(limit)),
+        };
+        let (reason, description) = match result {
+            Ok(StopReason::Limit) => return Ok(()),
+            Ok(StopReason::Step) => ("step", None),
+            Ok(StopReason::Breakpoint(_) | StopReason::Condition(_)) => ("breakpoint", None),
+            Ok(StopReason::Watchpoint(_) | StopReason::Changed { .. }) => ("data breakpoint", None),
+            Ok(StopReason::Trap(address)) => (
+                "exception",
+                Some(format!("Skipped unknown opcode at {:#05x}", address)),
+            ),
+            Ok(StopReason::HistoryStart) => (
+                "step",
+                Some("Reached the oldest recorded instruction".to_string()),
+            ),
+            Ok(StopReason::Halt) => {
+                self.run = Run::Stopped;
+                self.event("exited", json!({ "exitCode": 0 }))?;
+                return self.event("terminated", Value::Null);
+            }
+            Err(e) => ("exception", Some(e.to_string())),
+        };
+        self.stopped(reason, description)
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) -> io::Result<()> {
+        self.run = Run::Stopped;
+        if let Some(text) = &description {
+            self.event(
+                "output",
+                json!({ "category": "stderr", "output": format!("{}\n", text) }),
+            )?;
+        }
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = description {
+            body["description"] = text.into();
+        }
+        self.event("stopped", body)
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = message.into(),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        let mut message = json!({ "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        self.send(message)
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = self.seq.into();
+        self.seq += 1;
+        let text = message.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", text.len(), text)?;
+        self.out.flush()
+    }
+}
+
+impl Session {
+    /// Answer a request about the launched program, updating `run` for
+    /// requests that resume it.
+    fn request(&mut self, command: &str, args: &Value, run: &mut Run) -> Result<Value, String> {
+        let state = self.debugger.interpreter().state();
+        let depth = state.stack.len();
+        match command {
+            "continue" => {
+                *run = Run::Continue;
+                return Ok(json!({ "allThreadsContinued": true }));
+            }
+            "next" => {
+                *run = match self.current_instruction() {
+                    Some(Instruction::Call(_)) => Run::StepOver {
+                        depth,
+                        address: state.pc.wrapping_add(2),
+                    },
+                    _ => Run::Step,
+                };
+            }
+            "stepIn" => *run = Run::Step,
+            "stepOut" if depth > 0 => *run = Run::StepOut { depth },
+            "stepOut" => *run = Run::Continue,
+            "stepBack" => *run = Run::StepBack,
+            "reverseContinue" => *run = Run::ReverseContinue,
+            "pause" => *run = Run::Stopped,
+            "setBreakpoints" => return self.set_breakpoints(args),
+            "stackTrace" => return Ok(self.stack_trace()),
+            "scopes" => {
+                return Ok(json!({ "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Timers", "variablesReference": TIMERS_REFERENCE, "expensive": false },
+                ]}));
+            }
+            "variables" => return Ok(self.variables(args["variablesReference"].as_u64())),
+            "setVariable" => return self.set_variable(args),
+            "evaluate" => {
+                let expression = Expression::parse(args["expression"].as_str().unwrap_or(""))
+                    .map_err(|e| e.to_string())?;
+                let value = expression.evaluate(self.debugger.interpreter());
+                return Ok(
+                    json!({ "result": format!("{:#x} ({})", value, value), "variablesReference": 0 }),
+                );
+            }
+            "readMemory" => return self.read_memory(args),
+            "writeMemory" => return self.write_memory(args),
+            _ => return Err(format!("Unsupported request '{}'", command)),
+        }
+        Ok(Value::Null)
+    }
+
+    fn current_instruction(&self) -> Option<Instruction> {
+        let state = self.debugger.interpreter().state();
+        let pc = state.pc as usize;
+        let bytes = state.ram.get(pc..pc + 2)?;
+        Instruction::from_opcode(u16::from_be_bytes([bytes[0], bytes[1]])).ok()
+    }
+
+    /// Replace the breakpoints in the program's source.
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        for address in self.breakpoints.drain(..) {
+            self.debugger.remove_breakpoint(address);
+        }
+        let path = args["source"]["path"].as_str().map(Path::new);
+        let assembly = match &self.source {
+            Some((source, assembly)) if path.is_some_and(|p| same_file(p, source)) => {
+                Some(assembly)
+            }
+            _ => None,
+        };
+
+        let mut results = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            match assembly.and_then(|a| a.address_of_line(line)) {
+                Some((address, line)) => {
+                    self.debugger.add_breakpoint(address);
+                    self.breakpoints.push(address);
+                    results.push(json!({ "verified": true, "line": line }));
+                }
+                None => results.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "No code on or after this line",
+                })),
+            }
+        }
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    fn stack_trace(&self) -> Value {
+        let state = self.debugger.interpreter().state();
+        // The instruction before each return address is the call
+        let addresses =
+            std::iter::once(state.pc).chain(state.stack.iter().rev().map(|r| r.wrapping_sub(2)));
+        let frames: Vec<Value> = addresses
+            .enumerate()
+            .map(|(id, address)| {
+                let mut frame = json!({
+                    "id": id,
+                    "name": self.name_of(address),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("{:#05x}", address),
+                });
+                if let Some((path, assembly)) = &self.source
+                    && let Some(line) = assembly.line_at(address)
+                {
+                    frame["source"] = json!({
+                        "name": path.file_name().map(|n| n.to_string_lossy()),
+                        "path": path,
+                    });
+                    frame["line"] = line.into();
+                    frame["column"] = 1.into();
+                }
+                frame
+            })
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    /// `label+offset` for the closest label at or before `address`, or the
+    /// address itself.
+    fn name_of(&self, address: u16) -> String {
+        let label = self.source.as_ref().and_then(|(_, assembly)| {
+            assembly
+                .labels
+                .iter()
+                .filter(|&(_, &a)| a <= address)
+                .max_by_key(|&(_, &a)| a)
+        });
+        match label {
+            Some((name, &a)) if a == address => name.clone(),
+            Some((name, &a)) => format!("{}+{}", name, address - a),
+            None => format!("{:#05x}", address),
+        }
+    }
+
+    fn variables(&self, reference: Option<u64>) -> Value {
+        let interpreter = self.debugger.interpreter();
+        let variables: Vec<Variable> = match reference {
+            Some(REGISTERS_REFERENCE) => (0..16)
+                .map(Variable::Register)
+                .chain([Variable::I, Variable::Pc, Variable::Sp])
+                .collect(),
+            Some(TIMERS_REFERENCE) => vec![Variable::Dt, Variable::St],
+            _ => Vec::new(),
+        };
+        let variables: Vec<Value> = variables
+            .into_iter()
+            .map(|variable| {
+                let value = variable.value(interpreter);
+                let mut json = json!({
+                    "name": variable.to_string().to_ascii_uppercase(),
+                    "value": format!("{:#04x} ({})", value, value),
+                    "variablesReference": 0,
+                });
+                if matches!(variable, Variable::I | Variable::Pc) {
+                    json["memoryReference"] = format!("{:#05x}", value).into();
+                }
+                json
+            })
+            .collect();
+        json!({ "variables": variables })
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        let variable: Variable = args["name"]
+            .as_str()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|e: crate::Error| e.to_string())?;
+        let value = args["value"]
+            .as_str()
+            .and_then(|v| parse_number(v.split_whitespace().next().unwrap_or(v)))
+            .ok_or("Invalid value")?;
+        let state = self.debugger.interpreter_mut().state_mut();
+        match variable {
+            Variable::Register(x) => state.registers[x] = value as u8,
+            Variable::I => state.index_register = value as u16,
+            Variable::Pc => state.pc = value as u16,
+            Variable::Dt => state.delay_timer = value as u8,
+            Variable::St => state.sound_timer = value as u8,
+            _ => return Err(format!("{} can't be changed", variable)),
+        }
+        self.debugger.interpreter_mut().clear_undo();
+        let value = variable.value(self.debugger.interpreter());
+        Ok(json!({ "value": format!("{:#04x} ({})", value, value) }))
+    }
+
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let address = memory_address(args)?;
+        let ram = &self.debugger.interpreter().state().ram;
+        let start = address.min(ram.len());
+        let count = args["count"].as_u64().unwrap_or(0) as usize;
+        let end = start.saturating_add(count).min(ram.len());
+        Ok(json!({
+            "address": format!("{:#05x}", address),
+            "data": base64_encode(&ram[start..end]),
+            "unreadableBytes": count - (end - start),
+        }))
+    }
+
+    fn write_memory(&mut self, args: &Value) -> Result<Value, String> {
+        let address = memory_address(args)?;
+        let data =
+            base64_decode(args["data"].as_str().unwrap_or("")).ok_or("Invalid base64 data")?;
+        let interpreter = self.debugger.interpreter_mut();
+        let target = address
+            .checked_add(data.len())
+            .and_then(|end| interpreter.state_mut().ram.get_mut(address..end))
+            .ok_or("Write past the end of RAM")?;
+        target.copy_from_slice(&data);
+        interpreter.clear_undo();
+        Ok(json!({ "bytesWritten": data.len() }))
+    }
+}
+
+/// Read requests on another thread, so they can arrive while the program
+/// runs.
+fn spawn_reader(mut input: impl BufRead + Send + 'static) -> Receiver<io::Result<Value>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        loop {
+            let message = match read_message(&mut input) {
+                Ok(Some(message)) => Ok(message),
+                Ok(None) => return,
+                Err(e) => Err(e),
+            };
+            let failed = message.is_err();
+            if sender.send(message).is_err() || failed {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// Read one message, or None at end of input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// An address given as a number or a string such as `"0x300"`.
+fn json_address(value: &Value) -> Option<u16> {
+    let number = match value {
+        Value::Number(n) => u32::try_from(n.as_u64()?).ok()?,
+        Value::String(s) => parse_number(s)?,
+        _ => return None,
+    };
+    u16::try_from(number).ok()
+}
+
+/// The RAM address of a `memoryReference` plus `offset`.
+fn memory_address(args: &Value) -> Result<usize, String> {
+    let base = json_address(&args["memoryReference"]).ok_or("Invalid memory reference")?;
+    let offset = args["offset"].as_i64().unwrap_or(0);
+    usize::try_from(base as i64 + offset).map_err(|_| "Address before the start of RAM".to_string())
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let word = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |w, (i, &b)| w | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64[(word >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut word = 0u32;
+    let mut bits = 0;
+    for c in text.bytes().filter(|&c| c != b'=') {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        word = word << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((word >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::CLIDisplay;
+
+    use std::io::Cursor;
+
+    const SOURCE: &str = "\
+start:
+    LD V0, 5
+loop:
+    ADD V0, 1
+    CALL sub
+    JP loop
+sub:
+    RET
+";
+
+    /// A server driven request by request, without the reader thread.
+    struct Client {
+        server: DapServer<Vec<u8>>,
+        seq: u64,
+    }
+
+    impl Client {
+        fn new() -> Self {
+            let launcher: Launcher = Box::new(|rom, load_address| {
+                let mut interpreter = Interpreter::new();
+                interpreter.set_display(CLIDisplay::headless());
+                interpreter.load_rom_at(rom.to_vec(), load_address.unwrap_or(0x200))?;
+                Ok(interpreter)
+            });
+            Client {
+                server: DapServer::new(Vec::new(), launcher),
+                seq: 0,
+            }
+        }
+
+        /// Send a request and run the program until it stops, returning the
+        /// messages written in the meantime.
+        fn request(&mut self, command: &str, arguments: Value) -> Vec<Value> {
+            self.seq += 1;
+            let request = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            self.server.request(&request).unwrap();
+            for _ in 0..100 {
+                if self.server.run == Run::Stopped {
+                    break;
+                }
+                self.server.run_for(100).unwrap();
+            }
+            let mut out = Cursor::new(std::mem::take(&mut self.server.out));
+            std::iter::from_fn(|| read_message(&mut out).unwrap()).collect()
+        }
+
+        /// The body of the response to a request that succeeds.
+        fn body(&mut self, command: &str, arguments: Value) -> Value {
+            let response = self.request(command, arguments).remove(0);
+            assert_eq!(response["success"], true, "{}", response);
+            response["body"].clone()
+        }
+    }
+
+    fn stopped(messages: &[Value]) -> &Value {
+        let event = messages.last().unwrap();
+        assert_eq!(event["event"], "stopped", "{:?}", messages);
+        &event["body"]
+    }
+
+    #[test]
+    fn a_launched_source_stops_at_breakpoints() {
+        let path = std::env::temp_dir().join(format!("secrus8-dap-{}.asm", std::process::id()));
+        fs::write(&path, SOURCE).unwrap();
+
+        let mut client = Client::new();
+        let messages = client.request("initialize", json!({}));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["body"]["supportsStepBack"], true);
+        let messages = client.request("launch", json!({ "program": path, "stopOnEntry": true }));
+        assert_eq!(messages[0]["success"], true);
+        assert_eq!(messages[1]["event"], "initialized");
+        let breakpoints = client.body(
+            "setBreakpoints",
+            json!({ "source": { "path": path }, "breakpoints": [{ "line": 4 }, { "line": 100 }] }),
+        );
+        assert_eq!(
+            breakpoints["breakpoints"][0],
+            json!({ "verified": true, "line": 4 })
+        );
+        assert_eq!(breakpoints["breakpoints"][1]["verified"], false);
+        assert_eq!(
+            stopped(&client.request("configurationDone", json!({})))["reason"],
+            "entry"
+        );
+
+        assert_eq!(
+            stopped(&client.request("continue", json!({})))["reason"],
+            "breakpoint"
+        );
+        let frames = client.body("stackTrace", json!({ "threadId": THREAD_ID }));
+        assert_eq!(frames["stackFrames"][0]["name"], "loop");
+        assert_eq!(frames["stackFrames"][0]["line"], 4);
+        let registers = client.body(
+            "variables",
+            json!({ "variablesReference": REGISTERS_REFERENCE }),
+        );
+        assert_eq!(registers["variables"][0]["value"], "0x05 (5)");
+        assert_eq!(registers["variables"][17]["memoryReference"], "0x202");
+
+        client.request("stepIn", json!({}));
+        let frames = client.body("stackTrace", json!({ "threadId": THREAD_ID }));
+        assert_eq!(frames["stackFrames"][0]["name"], "loop+2");
+        client.request("stepBack", json!({}));
+        let frames = client.body("stackTrace", json!({ "threadId": THREAD_ID }));
+        assert_eq!(frames["stackFrames"][0]["name"], "loop");
+
+        let value = client.body("setVariable", json!({ "name": "V0", "value": "0x10" }));
+        assert_eq!(value["value"], "0x10 (16)");
+        let back = client.request("stepBack", json!({}));
+        assert_eq!(
+            stopped(&back)["description"],
+            "Reached the oldest recorded instruction"
+        );
+        let result = client.body("evaluate", json!({ "expression": "v0 + 1" }));
+        assert_eq!(result["result"], "0x11 (17)");
+
+        let data = base64_encode(&[1, 2, 3]);
+        let written = client.body(
+            "writeMemory",
+            json!({ "memoryReference": "0x300", "data": data }),
+        );
+        assert_eq!(written["bytesWritten"], 3);
+        let read = client.body(
+            "readMemory",
+            json!({ "memoryReference": "0x300", "offset": 1, "count": 4 }),
+        );
+        assert_eq!(read["data"], base64_encode(&[2, 3, 0, 0]));
+        let read = client.body(
+            "readMemory",
+            json!({ "memoryReference": "0xFFE", "count": 4 }),
+        );
+        assert_eq!(read["unreadableBytes"], 2);
+
+        assert!(
+            !client
+                .server
+                .request(&json!({ "command": "disconnect" }))
+                .unwrap()
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn requests_need_a_launched_program() {
+        let mut client = Client::new();
+        let response = client.request("stackTrace", json!({})).remove(0);
+        assert_eq!(response["success"], false);
+        assert_eq!(response["message"], "No program launched");
+        let response = client.request("launch", json!({})).remove(0);
+        assert_eq!(response["message"], "Missing 'program' in launch arguments");
+    }
+
+    #[test]
+    fn base64_round_trips() {
+        assert_eq!(base64_encode(b"Man"), "TWFu");
+        assert_eq!(base64_encode(&[0xFF]), "/w==");
+        for length in 0..8 {
+            let bytes: Vec<u8> = (0..length).map(|b| b * 37).collect();
+            assert_eq!(base64_decode(&base64_encode(&bytes)), Some(bytes));
+        }
+        assert_eq!(base64_decode("T#Fu"), None);
+    }
+}
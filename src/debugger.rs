@@ -0,0 +1,946 @@
+//! Interactive line-oriented debugger.
+
+use crate::Result;
+use crate::assembler::{parse_number, parse_range};
+use crate::disassembler::disassemble;
+use crate::expression::{Comparison, Expression, Variable};
+use crate::interpreter::{Interpreter, StepResult};
+use crate::profile::{DEFAULT_REPORT_ROWS, Profiler};
+use crate::symbols::Symbols;
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::ops::RangeInclusive;
+
+/// Instructions `continue` runs before giving control back, so a program
+/// stuck waiting for a key doesn't hang the debugger.
+pub const CONTINUE_LIMIT: u64 = 10_000_000;
+
+/// Steps the debugger can run backwards over.
+pub const DEFAULT_UNDO_LIMIT: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    /// Single step finished
+    Step,
+    /// Reached a breakpoint at the given address
+    Breakpoint(u16),
+    /// The program halted
+    Halt,
+    /// Stopped after skipping an unknown opcode at the given address
+    Trap(u16),
+    /// A watchpoint matched a RAM access
+    Watchpoint(WatchHit),
+    /// The condition with the given index became true
+    Condition(usize),
+    /// A tracked variable changed value
+    Changed {
+        variable: Variable,
+        old: u64,
+        new: u64,
+    },
+    /// Ran for the maximum number of instructions
+    Limit,
+    /// Ran backwards to the oldest step that can be undone
+    HistoryStart,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Accesses a watchpoint stops on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    fn matches(self, access: Access) -> bool {
+        match self {
+            WatchKind::Read => access == Access::Read,
+            WatchKind::Write => access == Access::Write,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+/// Stops execution when an instruction accesses RAM in `range`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+    /// Only stop when the value read or written compares true with this
+    pub condition: Option<(Comparison, u8)>,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: Access, address: u16, value: u8) -> bool {
+        self.kind.matches(access)
+            && self.range.contains(&address)
+            && self
+                .condition
+                .is_none_or(|(comparison, operand)| comparison.holds(value, operand))
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::ReadWrite => "access",
+        };
+        write!(f, "{} {:#05x}", kind, self.range.start())?;
+        if self.range.end() != self.range.start() {
+            write!(f, "..{:#05x}", *self.range.end() as u32 + 1)?;
+        }
+        if let Some((comparison, operand)) = self.condition {
+            write!(f, " if value {} {:#04x}", comparison.symbol(), operand)?;
+        }
+        Ok(())
+    }
+}
+
+/// The access that triggered a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Index of the watchpoint
+    pub index: usize,
+    pub access: Access,
+    pub address: u16,
+    pub value: u8,
+    /// Address of the instruction that made the access
+    pub pc: u16,
+}
+
+const HELP: &str = "\
+Commands:
+  s, step [n]          execute n instructions (default 1)
+  c, continue          run until a breakpoint or the program halts
+  rs, reverse-step [n] undo n instructions (default 1)
+  rc, reverse-continue run backwards to the previous breakpoint or watchpoint
+                       hit
+  b, break [addr]      set a breakpoint, or list them without an address
+  b, break [addr] if <expr>
+                       stop when the expression is true after an instruction
+                       (and PC is at addr), e.g. 'break if pc == 0x2A4 &&
+                       v3 > 10' or 'break if i in 0x300..0x320'
+  d, delete <addr>     remove a breakpoint
+  delete if <n>        remove condition n
+  change [var]         stop when v0-vf, i, pc, sp, dt or st changes, or list
+                       the tracked variables without one
+  unchange <var>       stop tracking a variable
+  w, watch [range [read|write|access] [op value]]
+                       stop when RAM in range is accessed (default: written),
+                       optionally only when the value compares true, e.g.
+                       'watch 0x300..0x310 write == 0'; list without a range
+  unwatch <n>          remove watchpoint n
+  profile [start|stop|reset]
+                       count executed instructions by address, kind and
+                       subroutine; without an argument, show the report
+  r, regs              show registers
+  x, mem <addr> [len]  dump memory (default 64 bytes)
+  l, list [addr] [n]   disassemble n instructions (default 10 from PC)
+  screen               show the display
+  key <hex> up|down    release or press a key
+  h, help              show this help
+  q, quit              exit the debugger
+
+Addresses can be numbers, labels from the symbol file or labels plus an
+offset, e.g. 'break main_loop+4'.";
+
+pub struct Debugger {
+    interpreter: Interpreter,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+    conditions: Vec<Expression>,
+    changes: Vec<Variable>,
+    symbols: Symbols,
+}
+
+impl Debugger {
+    /// Debug `interpreter`, recording the last [`DEFAULT_UNDO_LIMIT`] steps
+    /// for reverse execution.
+    pub fn new(mut interpreter: Interpreter) -> Self {
+        interpreter.set_undo_limit(DEFAULT_UNDO_LIMIT);
+        Debugger {
+            interpreter,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            conditions: Vec::new(),
+            changes: Vec::new(),
+            symbols: Symbols::new(),
+        }
+    }
+
+    pub fn interpreter(&self) -> &Interpreter {
+        &self.interpreter
+    }
+
+    pub fn interpreter_mut(&mut self) -> &mut Interpreter {
+        &mut self.interpreter
+    }
+
+    pub fn into_interpreter(self) -> Interpreter {
+        self.interpreter
+    }
+
+    /// Name addresses with `symbols`, and accept their labels as addresses.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        (index < self.watchpoints.len()).then(|| self.watchpoints.remove(index))
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Stop after any instruction that leaves `condition` true.
+    pub fn add_condition(&mut self, condition: Expression) {
+        self.conditions.push(condition);
+    }
+
+    pub fn remove_condition(&mut self, index: usize) -> Option<Expression> {
+        (index < self.conditions.len()).then(|| self.conditions.remove(index))
+    }
+
+    pub fn conditions(&self) -> &[Expression] {
+        &self.conditions
+    }
+
+    /// Stop after any instruction that changes `variable`.
+    pub fn track_change(&mut self, variable: Variable) {
+        if !self.changes.contains(&variable) {
+            self.changes.push(variable);
+        }
+    }
+
+    pub fn untrack_change(&mut self, variable: Variable) -> bool {
+        let len = self.changes.len();
+        self.changes.retain(|&v| v != variable);
+        self.changes.len() != len
+    }
+
+    pub fn tracked_changes(&self) -> &[Variable] {
+        &self.changes
+    }
+
+    /// Execute a single instruction.
+    pub fn step(&mut self) -> Result<StopReason> {
+        let before = self.tracked_values();
+        let result = self.interpreter.step()?;
+        if let Some(reason) = self.stop_after_step(before) {
+            return Ok(reason);
+        }
+        match result {
+            StepResult::Continue => Ok(StopReason::Step),
+            StepResult::Halt => Ok(StopReason::Halt),
+            StepResult::Trap(address) => Ok(StopReason::Trap(address)),
+        }
+    }
+
+    /// Undo the last instruction. Watchpoints match the accesses it made,
+    /// and conditions and tracked variables are checked on the state before
+    /// it.
+    pub fn reverse_step(&mut self) -> StopReason {
+        let before = self.tracked_values();
+        if !self.interpreter.undo() {
+            return StopReason::HistoryStart;
+        }
+        self.stop_after_step(before).unwrap_or(StopReason::Step)
+    }
+
+    /// Run backwards until PC is at a breakpoint, a stop condition matches,
+    /// or there is nothing left to undo.
+    pub fn reverse_resume(&mut self, limit: u64) -> StopReason {
+        for _ in 0..limit {
+            let reason = self.reverse_step();
+            if reason != StopReason::Step {
+                return reason;
+            }
+            let pc = self.interpreter.state().pc;
+            if self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint(pc);
+            }
+        }
+        StopReason::Limit
+    }
+
+    fn tracked_values(&self) -> Vec<u64> {
+        self.changes
+            .iter()
+            .map(|v| v.value(&self.interpreter))
+            .collect()
+    }
+
+    /// Why to stop after running or undoing an instruction, given the
+    /// tracked values from before it.
+    fn stop_after_step(&self, before: Vec<u64>) -> Option<StopReason> {
+        if let Some(hit) = self.watch_hit() {
+            return Some(StopReason::Watchpoint(hit));
+        }
+        for (&variable, old) in self.changes.iter().zip(before) {
+            let new = variable.value(&self.interpreter);
+            if new != old {
+                return Some(StopReason::Changed { variable, old, new });
+            }
+        }
+        self.conditions
+            .iter()
+            .position(|c| c.is_true(&self.interpreter))
+            .map(StopReason::Condition)
+    }
+
+    /// Run until a breakpoint is reached, the program halts or traps, or `limit`
+    /// instructions were executed.
+    pub fn resume(&mut self, limit: u64) -> Result<StopReason> {
+        self.resume_until(limit, |_| false)
+    }
+
+    /// Like [`Debugger::resume`], also stopping with [`StopReason::Step`]
+    /// once `done` holds after an instruction, e.g. to step over a call.
+    pub fn resume_until(
+        &mut self,
+        limit: u64,
+        mut done: impl FnMut(&Interpreter) -> bool,
+    ) -> Result<StopReason> {
+        for _ in 0..limit {
+            let reason = self.step()?;
+            if reason != StopReason::Step {
+                return Ok(reason);
+            }
+            let pc = self.interpreter.state().pc;
+            if self.breakpoints.contains(&pc) {
+                return Ok(StopReason::Breakpoint(pc));
+            }
+            if done(&self.interpreter) {
+                return Ok(StopReason::Step);
+            }
+        }
+        Ok(StopReason::Limit)
+    }
+
+    /// The first access of the last instruction matching a watchpoint.
+    fn watch_hit(&self) -> Option<WatchHit> {
+        let reads = self
+            .interpreter
+            .last_reads()
+            .iter()
+            .map(|&a| (Access::Read, a));
+        let writes = self
+            .interpreter
+            .last_writes()
+            .iter()
+            .map(|&a| (Access::Write, a));
+        reads.chain(writes).find_map(|(access, (address, value))| {
+            let index = self
+                .watchpoints
+                .iter()
+                .position(|w| w.matches(access, address, value))?;
+            Some(WatchHit {
+                index,
+                access,
+                address,
+                value,
+                pc: self.interpreter.last_instruction().0,
+            })
+        })
+    }
+
+    /// Read commands from `input` until `quit` or end of input.
+    pub fn repl(&mut self, input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{}", self.current_instruction())?;
+        write!(out, "(secrus8) ")?;
+        out.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            if !self.command(line.trim(), out)? {
+                break;
+            }
+            write!(out, "(secrus8) ")?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Execute one command, returning false when the session should end.
+    pub fn command(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            return Ok(true);
+        };
+        let number = |index: usize| args.get(index).and_then(|a| parse_number(a));
+        let symbols = &self.symbols;
+        let address = |index: usize| args.get(index).and_then(|a| symbols.resolve(a));
+
+        match command {
+            "s" | "step" => {
+                let count = number(0).unwrap_or(1);
+                let mut result = Ok(StopReason::Step);
+                for _ in 0..count {
+                    result = self.step();
+                    if result != Ok(StopReason::Step) {
+                        break;
+                    }
+                }
+                self.report(result, out)?;
+            }
+            "c" | "continue" => {
+                let result = self.resume(CONTINUE_LIMIT);
+                self.report(result, out)?;
+            }
+            "rs" | "reverse-step" => {
+                let count = number(0).unwrap_or(1);
+                let mut reason = StopReason::Step;
+                for _ in 0..count {
+                    reason = self.reverse_step();
+                    if reason != StopReason::Step {
+                        break;
+                    }
+                }
+                self.report(Ok(reason), out)?;
+            }
+            "rc" | "reverse-continue" => {
+                let reason = self.reverse_resume(CONTINUE_LIMIT);
+                self.report(Ok(reason), out)?;
+            }
+            "b" | "break" if args.contains(&"if") => match parse_condition(args, symbols) {
+                Ok(condition) => {
+                    writeln!(out, "Condition {}: {}", self.conditions.len(), condition)?;
+                    self.add_condition(condition);
+                }
+                Err(e) => writeln!(out, "{}", e)?,
+            },
+            "b" | "break" if args.is_empty() => {
+                for address in self.breakpoints() {
+                    writeln!(out, "{}", self.symbols.format(address))?;
+                }
+                for (i, condition) in self.conditions.iter().enumerate() {
+                    writeln!(out, "if {}: {}", i, condition)?;
+                }
+            }
+            "b" | "break" => match address(0) {
+                Some(address) => {
+                    self.add_breakpoint(address);
+                    writeln!(out, "Breakpoint at {}", self.symbols.format(address))?;
+                }
+                None => writeln!(out, "Usage: break [addr] [if <expr>]")?,
+            },
+            "d" | "delete" if args.first() == Some(&"if") => {
+                match number(1).and_then(|i| self.remove_condition(i as usize)) {
+                    Some(condition) => writeln!(out, "Deleted condition {}", condition)?,
+                    None => writeln!(out, "No such condition")?,
+                }
+            }
+            "d" | "delete" => match address(0) {
+                Some(address) if self.remove_breakpoint(address) => {
+                    writeln!(
+                        out,
+                        "Deleted breakpoint at {}",
+                        self.symbols.format(address)
+                    )?;
+                }
+                _ => writeln!(out, "No such breakpoint")?,
+            },
+            "w" | "watch" if args.is_empty() => {
+                for (i, watchpoint) in self.watchpoints.iter().enumerate() {
+                    writeln!(out, "{}: {}", i, watchpoint)?;
+                }
+            }
+            "w" | "watch" => match parse_watchpoint(args, symbols) {
+                Some(watchpoint) => {
+                    writeln!(out, "Watchpoint {}: {}", self.watchpoints.len(), watchpoint)?;
+                    self.add_watchpoint(watchpoint);
+                }
+                None => writeln!(
+                    out,
+                    "Usage: watch <addr>[..end] [read|write|access] [op value]"
+                )?,
+            },
+            "unwatch" => match number(0).and_then(|i| self.remove_watchpoint(i as usize)) {
+                Some(watchpoint) => writeln!(out, "Deleted watchpoint {}", watchpoint)?,
+                None => writeln!(out, "No such watchpoint")?,
+            },
+            "change" if args.is_empty() => {
+                for variable in &self.changes {
+                    writeln!(out, "{}", variable)?;
+                }
+            }
+            "change" => match args[0].parse::<Variable>() {
+                Ok(variable) => {
+                    self.track_change(variable);
+                    writeln!(out, "Stopping when {} changes", variable)?;
+                }
+                Err(e) => writeln!(out, "{}", e)?,
+            },
+            "unchange" => match args.first().and_then(|a| a.parse::<Variable>().ok()) {
+                Some(variable) if self.untrack_change(variable) => {
+                    writeln!(out, "No longer tracking {}", variable)?
+                }
+                _ => writeln!(out, "Not tracking that variable")?,
+            },
+            "profile" => match args.first().copied() {
+                Some("start" | "reset") => {
+                    self.interpreter.set_profiler(Profiler::new());
+                    writeln!(out, "Profiling")?;
+                }
+                Some("stop") => match self.interpreter.take_profiler() {
+                    Some(profiler) => profiler.report(out, DEFAULT_REPORT_ROWS, &self.symbols)?,
+                    None => writeln!(out, "Not profiling")?,
+                },
+                Some(_) => writeln!(out, "Usage: profile [start|stop|reset]")?,
+                None => match self.interpreter.profiler() {
+                    Some(profiler) => profiler.report(out, DEFAULT_REPORT_ROWS, &self.symbols)?,
+                    None => writeln!(out, "Not profiling, start with 'profile start'")?,
+                },
+            },
+            "r" | "regs" => self.print_registers(out)?,
+            "x" | "mem" => match address(0) {
+                Some(address) => {
+                    self.print_memory(address as usize, number(1).unwrap_or(64) as usize, out)?
+                }
+                None => writeln!(out, "Usage: mem <addr> [len]")?,
+            },
+            "l" | "list" => {
+                let address = address(0).unwrap_or(self.interpreter.state().pc) as usize;
+                self.print_listing(address, number(1).unwrap_or(10) as usize, out)?;
+            }
+            "screen" => write!(out, "{}", self.interpreter.display().render())?,
+            "key" => match (number(0), args.get(1)) {
+                (Some(key), Some(&state @ ("up" | "down"))) if key < 16 => {
+                    self.interpreter.state_mut().keypad[key as usize] = state == "down";
+                }
+                _ => writeln!(out, "Usage: key <hex> up|down")?,
+            },
+            "h" | "help" => writeln!(out, "{}", HELP)?,
+            "q" | "quit" => return Ok(false),
+            _ => writeln!(out, "Unknown command '{}', try 'help'", command)?,
+        }
+        Ok(true)
+    }
+
+    fn report(&self, result: Result<StopReason>, out: &mut impl Write) -> io::Result<()> {
+        match result {
+            Ok(StopReason::Step) => {}
+            Ok(StopReason::Breakpoint(address)) => {
+                writeln!(out, "Breakpoint at {}", self.symbols.format(address))?
+            }
+            Ok(StopReason::Halt) => writeln!(out, "Program halted")?,
+            Ok(StopReason::Watchpoint(hit)) => {
+                let access = match hit.access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                };
+                writeln!(
+                    out,
+                    "Watchpoint {}: {} {} = {:#04x} by the instruction at {}",
+                    hit.index,
+                    access,
+                    self.symbols.format(hit.address),
+                    hit.value,
+                    self.symbols.format(hit.pc)
+                )?
+            }
+            Ok(StopReason::Condition(index)) => {
+                writeln!(out, "Condition {}: {}", index, self.conditions[index])?
+            }
+            Ok(StopReason::Changed { variable, old, new }) => writeln!(
+                out,
+                "{} changed from {:#x} to {:#x} by the instruction at {}",
+                variable,
+                old,
+                new,
+                self.symbols.format(self.interpreter.last_instruction().0)
+            )?,
+            Ok(StopReason::Trap(address)) => writeln!(
+                out,
+                "Skipped unknown opcode at {}",
+                self.symbols.format(address)
+            )?,
+            Ok(StopReason::HistoryStart) => writeln!(
+                out,
+                "Reached the oldest recorded instruction, cycle {}",
+                self.interpreter.cycle()
+            )?,
+            Ok(StopReason::Limit) => {
+                writeln!(out, "Stopped after {} instructions", CONTINUE_LIMIT)?
+            }
+            Err(e) => writeln!(out, "Execution error: {}", e)?,
+        }
+        writeln!(out, "{}", self.current_instruction())
+    }
+
+    /// The instruction at PC, e.g. `0x200: 00E0  CLS`, followed by its
+    /// label and source line if known.
+    pub fn current_instruction(&self) -> String {
+        let pc = self.interpreter.state().pc as usize;
+        let ram = &self.interpreter.state().ram;
+        let bytes = ram.get(pc..(pc + 2).min(ram.len())).unwrap_or_default();
+        let text = match disassemble(bytes, pc as u16).first() {
+            Some(line) => format!(
+                "{:#05x}: {:04X}  {}",
+                pc,
+                line.bytes.iter().fold(0u16, |w, &b| w << 8 | b as u16),
+                line.source()
+            ),
+            None => format!("{:#05x}: <end of memory>", pc),
+        };
+        match self.symbols.describe(pc as u16) {
+            Some(description) => format!("{:<32}<{}>", text, description),
+            None => text,
+        }
+    }
+
+    fn print_registers(&self, out: &mut impl Write) -> io::Result<()> {
+        let state = self.interpreter.state();
+        writeln!(
+            out,
+            "PC {:#05x}  I {:#05x}  SP {}  DT {}  ST {}",
+            state.pc,
+            state.index_register,
+            state.stack.len(),
+            state.delay_timer,
+            state.sound_timer
+        )?;
+        let registers: Vec<String> = state
+            .registers
+            .iter()
+            .enumerate()
+            .map(|(i, v)| format!("V{:X} {:02X}", i, v))
+            .collect();
+        writeln!(out, "{}", registers[..8].join("  "))?;
+        writeln!(out, "{}", registers[8..].join("  "))?;
+        if !state.stack.is_empty() {
+            let stack: Vec<String> = state
+                .stack
+                .iter()
+                .map(|&a| self.symbols.format(a))
+                .collect();
+            writeln!(out, "Stack: {}", stack.join(" "))?;
+        }
+        Ok(())
+    }
+
+    fn print_memory(&self, address: usize, len: usize, out: &mut impl Write) -> io::Result<()> {
+        let ram = &self.interpreter.state().ram;
+        let end = (address + len).min(ram.len());
+        for (i, row) in ram[address.min(end)..end].chunks(16).enumerate() {
+            let bytes: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+            writeln!(out, "{:#05x}: {}", address + i * 16, bytes.join(" "))?;
+        }
+        Ok(())
+    }
+
+    fn print_listing(&self, address: usize, count: usize, out: &mut impl Write) -> io::Result<()> {
+        let ram = &self.interpreter.state().ram;
+        let end = (address + count * 2).min(ram.len());
+        let pc = self.interpreter.state().pc;
+        for line in disassemble(&ram[address.min(end)..end], address as u16) {
+            if let Some(label) = self.symbols.label_at(line.address) {
+                writeln!(out, "{}:", label)?;
+            }
+            let marker = if line.address == pc { "=>" } else { "  " };
+            match self.symbols.source_line(line.address) {
+                Some(source_line) => writeln!(out, "{}{}  {}", marker, line, source_line)?,
+                None => writeln!(out, "{}{}", marker, line)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse the arguments of `break` with a condition, e.g. `0x2A4 if v3 > 10`.
+/// An address before `if` narrows the condition to that PC.
+fn parse_condition(args: &[&str], symbols: &Symbols) -> Result<Expression> {
+    let split = args.iter().position(|&a| a == "if").unwrap_or(0);
+    let source = args[split + 1..].join(" ");
+    match args[..split] {
+        [] => Expression::parse(&source),
+        [address] => {
+            let address = symbols.resolve(address).ok_or_else(|| {
+                crate::Error::InvalidExpression(format!("unknown address '{}'", address))
+            })?;
+            Expression::parse(&format!("pc == {:#x} && ({})", address, source))
+        }
+        _ => Err(crate::Error::InvalidExpression(
+            "expected 'break [addr] if <expr>'".to_string(),
+        )),
+    }
+}
+
+/// Parse the arguments of `watch`, e.g. `0x300..0x310 write == 0`.
+fn parse_watchpoint(args: &[&str], symbols: &Symbols) -> Option<Watchpoint> {
+    let (&range, mut rest) = args.split_first()?;
+    let range = match parse_range(range) {
+        Some(range) => range,
+        None => {
+            let address = symbols.resolve(range)?;
+            address..=address
+        }
+    };
+
+    let mut kind = WatchKind::Write;
+    if let Some((&word, tail)) = rest.split_first() {
+        let parsed = match word {
+            "r" | "read" => Some(WatchKind::Read),
+            "w" | "write" => Some(WatchKind::Write),
+            "rw" | "access" => Some(WatchKind::ReadWrite),
+            _ => None,
+        };
+        if let Some(parsed) = parsed {
+            kind = parsed;
+            rest = tail;
+        }
+    }
+
+    let condition = match rest {
+        [] => None,
+        [comparison, value] => Some((
+            Comparison::parse(comparison)?,
+            parse_number(value).filter(|&v| v <= 0xFF)? as u8,
+        )),
+        _ => return None,
+    };
+    Some(Watchpoint {
+        range,
+        kind,
+        condition,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use crate::assembler::assemble;
+    use crate::display::CLIDisplay;
+
+    pub(crate) fn debugger(source: &str) -> Debugger {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_display(CLIDisplay::headless());
+        interpreter
+            .load_rom(assemble(source, 0x200).unwrap())
+            .unwrap();
+        Debugger::new(interpreter)
+    }
+
+    /// The output of running `commands`, one per line.
+    pub(crate) fn session(debugger: &mut Debugger, commands: &str) -> String {
+        let mut out = Vec::new();
+        for line in commands.lines() {
+            debugger.command(line, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    const COUNT: &str = "\
+        LD V0, 0
+    loop:
+        ADD V0, 1
+        SE V0, 3
+        JP loop
+    end:
+        JP end
+    ";
+
+    #[test]
+    fn runs_backwards_to_breakpoints() {
+        let mut debugger = debugger(COUNT);
+        debugger.add_breakpoint(0x202);
+        for _ in 0..3 {
+            assert_eq!(debugger.resume(100).unwrap(), StopReason::Breakpoint(0x202));
+        }
+        let v0 = |debugger: &Debugger| debugger.interpreter().state().registers[0];
+        assert_eq!(v0(&debugger), 2);
+
+        assert_eq!(debugger.reverse_resume(100), StopReason::Breakpoint(0x202));
+        assert_eq!(v0(&debugger), 1);
+        assert_eq!(debugger.reverse_step(), StopReason::Step);
+        assert_eq!(debugger.interpreter().state().pc, 0x206);
+        assert_eq!(debugger.reverse_resume(100), StopReason::Breakpoint(0x202));
+        assert_eq!(v0(&debugger), 0);
+        assert_eq!(debugger.reverse_resume(100), StopReason::HistoryStart);
+        assert_eq!(debugger.interpreter().state().pc, 0x200);
+    }
+
+    #[test]
+    fn steps_and_stops_at_breakpoints() {
+        let mut debugger = debugger(COUNT);
+        assert_eq!(
+            session(&mut debugger, "step 2\nbreak 0x206\ncontinue"),
+            "0x204: 3003  SE V0, 0x03\n\
+             Breakpoint at 0x206\n\
+             Breakpoint at 0x206\n\
+             0x206: 1202  JP 0x202\n"
+        );
+        assert_eq!(debugger.interpreter().state().registers[0], 1);
+
+        assert_eq!(
+            session(&mut debugger, "delete 0x206\nc"),
+            "Deleted breakpoint at 0x206\nProgram halted\n0x208: 1208  JP 0x208\n"
+        );
+        assert_eq!(debugger.interpreter().state().registers[0], 3);
+    }
+
+    #[test]
+    fn shows_registers_memory_and_listing() {
+        let mut debugger = debugger(COUNT);
+        let out = session(&mut debugger, "s\nregs\nmem 0x200 4\nlist 0x200 2");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "0x202: 7001  ADD V0, 0x01",
+                "PC 0x202  I 0x000  SP 0  DT 0  ST 0",
+                "V0 00  V1 00  V2 00  V3 00  V4 00  V5 00  V6 00  V7 00",
+                "V8 00  V9 00  VA 00  VB 00  VC 00  VD 00  VE 00  VF 00",
+                "0x200: 60 00 70 01",
+                "      LD V0, 0x00         ; 0x200: 6000",
+                "=>    ADD V0, 0x01        ; 0x202: 7001",
+            ]
+        );
+    }
+
+    #[test]
+    fn commands_report_bad_input() {
+        let mut debugger = debugger(COUNT);
+        let out = session(
+            &mut debugger,
+            "frobnicate\nbreak nowhere\nkey 1F down\nquit",
+        );
+        assert_eq!(
+            out,
+            "Unknown command 'frobnicate', try 'help'\n\
+             Usage: break [addr] [if <expr>]\n\
+             Usage: key <hex> up|down\n"
+        );
+        assert!(!debugger.command("quit", &mut Vec::new()).unwrap());
+    }
+
+    const STORE: &str = "\
+        LD I, 0x300
+        LD V0, 5
+        LD [I], V0
+        LD V1, [I]
+        ADD V0, 1
+        LD [I], V0
+    end:
+        JP end
+    ";
+
+    #[test]
+    fn watchpoints_stop_on_matching_accesses() {
+        let mut debugger = debugger(STORE);
+        debugger.add_watchpoint(Watchpoint {
+            range: 0x300..=0x300,
+            kind: WatchKind::Write,
+            condition: None,
+        });
+        let hit = |address, value, pc| {
+            Ok(StopReason::Watchpoint(WatchHit {
+                index: 0,
+                access: Access::Write,
+                address,
+                value,
+                pc,
+            }))
+        };
+        assert_eq!(debugger.resume(100), hit(0x300, 5, 0x204));
+        assert_eq!(debugger.resume(100), hit(0x300, 6, 0x20A));
+        assert_eq!(debugger.resume(100), Ok(StopReason::Halt));
+    }
+
+    #[test]
+    fn watch_commands_parse_kinds_and_conditions() {
+        let mut debugger = debugger(STORE);
+        let out = session(
+            &mut debugger,
+            "watch 0x300..0x302 read\nwatch 0x300 write == 6\nwatch\nwatch 0x300 write ~ 6\nunwatch 0\nunwatch 5",
+        );
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "Watchpoint 0: read 0x300..0x302",
+                "Watchpoint 1: write 0x300 if value == 0x06",
+                "0: read 0x300..0x302",
+                "1: write 0x300 if value == 0x06",
+                "Usage: watch <addr>[..end] [read|write|access] [op value]",
+                "Deleted watchpoint read 0x300..0x302",
+                "No such watchpoint",
+            ]
+        );
+
+        // The write of 5 doesn't match the condition
+        let reason = debugger.resume(100).unwrap();
+        assert!(matches!(
+            reason,
+            StopReason::Watchpoint(WatchHit {
+                value: 6,
+                pc: 0x20A,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn current_instruction_past_the_end_of_memory() {
+        let mut debugger = debugger(COUNT);
+        debugger.interpreter_mut().state_mut().pc = 0xFFF;
+        assert_eq!(debugger.current_instruction(), "0xfff: 0000  DB 0x00");
+        debugger.interpreter_mut().state_mut().pc = 0x2000;
+        assert_eq!(debugger.current_instruction(), "0x2000: <end of memory>");
+    }
+
+    #[test]
+    fn conditions_and_changes_stop_execution() {
+        let mut breaks = debugger(COUNT);
+        assert_eq!(
+            session(&mut breaks, "break 0x204 if v0 == 2\nc"),
+            "Condition 0: pc == 0x204 && (v0 == 2)\n\
+             Condition 0: pc == 0x204 && (v0 == 2)\n\
+             0x204: 3003  SE V0, 0x03\n"
+        );
+        assert_eq!(breaks.interpreter().state().registers[0], 2);
+
+        let mut changes = debugger(COUNT);
+        assert_eq!(
+            session(&mut changes, "change v0\nc\nc"),
+            "Stopping when v0 changes\n\
+             v0 changed from 0x0 to 0x1 by the instruction at 0x202\n\
+             0x204: 3003  SE V0, 0x03\n\
+             v0 changed from 0x1 to 0x2 by the instruction at 0x202\n\
+             0x204: 3003  SE V0, 0x03\n"
+        );
+    }
+}
@@ -0,0 +1,683 @@
+//! Decompiler from ROMs to Octo source.
+//!
+//! The code found by [`ControlFlow`] is written as Octo statements and the
+//! rest as bytes, so [`crate::octo::compile`] turns the source back into
+//! the identical ROM. On top of that:
+//!
+//! - backward jumps become `loop ... again`, and skips over a jump out of
+//!   the loop become `while`
+//! - skips over a forward jump become `if ... begin ... else ... end`
+//! - subroutines, jump targets and data get labels such as `sub_2a4`
+//! - sprites drawn from a known I are written one binary row per line
+//!
+//! A structure is only recovered where it nests properly with the others;
+//! elsewhere plain `if ... then jump` is written.
+
+use crate::flow::{AccessKind, ControlFlow};
+use crate::parser::Instruction;
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write;
+
+/// Data bytes per line, other than sprites.
+const DATA_BYTES_PER_LINE: usize = 8;
+
+/// A word decoded as an instruction, or a byte written as a number.
+#[derive(Debug, Clone, Copy)]
+enum Unit {
+    Code(Instruction),
+    Byte(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Loop {
+    start: u16,
+    /// Address of the backward jump, written as `again`
+    again: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct If {
+    skip: u16,
+    /// Address of the jump over the else branch, written as `else`
+    else_jump: Option<u16>,
+    end: u16,
+}
+
+/// A recovered structure: the addresses it spans, and the parts other
+/// structures may nest in.
+#[derive(Debug, Clone)]
+struct Span {
+    start: u16,
+    end: u16,
+    parts: Vec<(u16, u16)>,
+}
+
+impl Span {
+    fn of_loop(l: &Loop) -> Self {
+        Span {
+            start: l.start,
+            end: l.again + 2,
+            parts: vec![(l.start, l.again)],
+        }
+    }
+
+    fn of_if(i: &If) -> Self {
+        let parts = match i.else_jump {
+            Some(jump) => vec![(i.skip + 4, jump), (jump + 2, i.end)],
+            None => vec![(i.skip + 4, i.end)],
+        };
+        Span {
+            start: i.skip,
+            end: i.end,
+            parts,
+        }
+    }
+
+    fn within(&self, other: &Span) -> bool {
+        other
+            .parts
+            .iter()
+            .any(|&(start, end)| start <= self.start && self.end <= end)
+    }
+
+    /// Whether the two spans are apart, or one lies in a part of the other.
+    fn nests_with(&self, other: &Span) -> bool {
+        self.end <= other.start
+            || other.end <= self.start
+            || self.within(other)
+            || other.within(self)
+    }
+}
+
+/// What the statement at an address is written as, when it's part of a
+/// structure.
+#[derive(Debug, Clone, Copy)]
+enum Role {
+    Again,
+    IfBegin,
+    Else,
+    While,
+    /// The jump written along with the skip before it
+    Consumed,
+}
+
+/// Decompile `rom`, loaded at `base`, into Octo source.
+pub fn decompile(rom: &[u8], base: u16) -> String {
+    let flow = ControlFlow::analyze(rom, base);
+    let units = units(rom, base, &flow);
+    let end = base.wrapping_add(rom.len() as u16);
+
+    let references: BTreeSet<u16> = units
+        .values()
+        .filter_map(|unit| match unit {
+            Unit::Code(instruction) => target(instruction),
+            Unit::Byte(_) => None,
+        })
+        .collect();
+    let jump_at = |address: u16| match units.get(&address) {
+        Some(Unit::Code(Instruction::Jump(target))) => Some(*target),
+        _ => None,
+    };
+    let skip_at = |address: u16| matches!(units.get(&address), Some(Unit::Code(instruction)) if is_skip(instruction));
+    let boundary = |address: u16| address == end || units.contains_key(&address);
+
+    let mut spans: Vec<Span> = Vec::new();
+    let mut roles: BTreeMap<u16, Role> = BTreeMap::new();
+    let fits = |span: &Span, spans: &[Span]| spans.iter().all(|s| span.nests_with(s));
+
+    // Loops, outermost first
+    let mut loops: Vec<Loop> = units
+        .keys()
+        .filter_map(|&again| {
+            let start = jump_at(again)?;
+            (start <= again
+                && start >= base
+                && units.contains_key(&start)
+                && (start == again || !references.contains(&again)))
+            .then_some(Loop { start, again })
+        })
+        .collect();
+    loops.sort_by_key(|l| (l.start, std::cmp::Reverse(l.again)));
+    let mut accepted_loops = Vec::new();
+    for l in loops {
+        let span = Span::of_loop(&l);
+        if fits(&span, &spans) {
+            spans.push(span);
+            roles.insert(l.again, Role::Again);
+            accepted_loops.push(l);
+        }
+    }
+
+    // Skips over a jump to just after the innermost loop around them
+    for &skip in units.keys() {
+        let Some(exit) = jump_at(skip.wrapping_add(2)) else {
+            continue;
+        };
+        let innermost = accepted_loops
+            .iter()
+            .filter(|l| l.start <= skip && skip.wrapping_add(2) < l.again)
+            .min_by_key(|l| l.again);
+        if skip_at(skip)
+            && !roles.contains_key(&skip)
+            && !roles.contains_key(&(skip + 2))
+            && !references.contains(&(skip + 2))
+            && innermost.is_some_and(|l| exit == l.again + 2)
+        {
+            roles.insert(skip, Role::While);
+            roles.insert(skip + 2, Role::Consumed);
+        }
+    }
+
+    // Skips over a forward jump, with an else branch where the jump lands
+    // just after another forward jump
+    for &skip in units.keys() {
+        let Some(target) = jump_at(skip.wrapping_add(2)) else {
+            continue;
+        };
+        if !skip_at(skip)
+            || roles.contains_key(&skip)
+            || roles.contains_key(&(skip + 2))
+            || references.contains(&(skip + 2))
+            || target <= skip + 4
+            || target > end
+            || !boundary(target)
+        {
+            continue;
+        }
+        let else_jump = target - 2;
+        let with_else = jump_at(else_jump)
+            .filter(|&after| {
+                else_jump > skip + 2
+                    && after > target
+                    && after <= end
+                    && boundary(after)
+                    && !roles.contains_key(&else_jump)
+                    && !references.contains(&else_jump)
+            })
+            .map(|after| If {
+                skip,
+                else_jump: Some(else_jump),
+                end: after,
+            });
+        let without_else = If {
+            skip,
+            else_jump: None,
+            end: target,
+        };
+        for candidate in with_else.into_iter().chain([without_else]) {
+            let span = Span::of_if(&candidate);
+            if fits(&span, &spans) {
+                spans.push(span);
+                roles.insert(skip, Role::IfBegin);
+                roles.insert(skip + 2, Role::Consumed);
+                if let Some(jump) = candidate.else_jump {
+                    roles.insert(jump, Role::Else);
+                }
+                break;
+            }
+        }
+    }
+
+    let labels = labels(&flow, &units, &roles, base);
+    let sprites = sprite_bytes(&flow, &units);
+    let mut writer = Writer {
+        out: String::new(),
+        depth: 0,
+        data: Vec::new(),
+        then: None,
+    };
+
+    // Ends of the open ifs, innermost last
+    let mut open_ifs: Vec<u16> = Vec::new();
+    let loop_starts: BTreeMap<u16, usize> =
+        accepted_loops
+            .iter()
+            .fold(BTreeMap::new(), |mut starts, l| {
+                *starts.entry(l.start).or_default() += 1;
+                starts
+            });
+    let if_ends: BTreeMap<u16, u16> = spans
+        .iter()
+        .filter(|s| {
+            roles
+                .get(&s.start)
+                .is_some_and(|r| matches!(r, Role::IfBegin))
+        })
+        .map(|s| (s.start, s.end))
+        .collect();
+
+    for (&address, unit) in &units {
+        let ends_if = open_ifs.last() == Some(&address);
+        let starts_loop = loop_starts.contains_key(&address);
+        let label = labels.get(&address);
+        let sprite = sprites.contains(&address);
+
+        if ends_if || starts_loop || label.is_some() || !matches!(unit, Unit::Byte(_)) {
+            writer.flush_data();
+        }
+        if ends_if || starts_loop || label.is_some() {
+            writer.flush_then();
+        }
+        while open_ifs.last() == Some(&address) {
+            open_ifs.pop();
+            writer.depth -= 1;
+            writer.line("end");
+        }
+        if let Some(name) = label {
+            writer.label(name);
+        }
+        for _ in 0..loop_starts.get(&address).copied().unwrap_or(0) {
+            writer.line("loop");
+            writer.depth += 1;
+        }
+
+        match (unit, roles.get(&address)) {
+            (Unit::Byte(byte), _) => {
+                let sprite_changed = writer.data.first().is_some_and(|&(_, s)| s != sprite);
+                if sprite_changed
+                    || writer.data.len() == DATA_BYTES_PER_LINE
+                    || (sprite && !writer.data.is_empty())
+                {
+                    writer.flush_data();
+                }
+                writer.flush_then();
+                writer.data.push((*byte, sprite));
+            }
+            (Unit::Code(_), Some(Role::Consumed)) => {}
+            (Unit::Code(_), Some(Role::Again)) => {
+                writer.depth -= 1;
+                writer.statement("again");
+            }
+            (Unit::Code(instruction), Some(Role::IfBegin)) => {
+                writer.flush_then();
+                let condition = condition(instruction, false).unwrap_or_default();
+                writer.line(&format!("if {} begin", condition));
+                writer.depth += 1;
+                open_ifs.push(if_ends[&address]);
+            }
+            (Unit::Code(_), Some(Role::Else)) => {
+                writer.flush_then();
+                writer.depth -= 1;
+                writer.line("else");
+                writer.depth += 1;
+            }
+            (Unit::Code(instruction), Some(Role::While)) => {
+                writer.flush_then();
+                let condition = condition(instruction, false).unwrap_or_default();
+                writer.line(&format!("while {}", condition));
+            }
+            (Unit::Code(instruction), None) => {
+                if let Some(condition) = condition(instruction, true) {
+                    writer.flush_then();
+                    writer.then = Some(format!("if {} then", condition));
+                } else {
+                    let statement = statement(instruction, &labels);
+                    writer.statement(&statement);
+                }
+            }
+        }
+    }
+    writer.flush_data();
+    writer.flush_then();
+    while open_ifs.pop().is_some() {
+        writer.depth -= 1;
+        writer.line("end");
+    }
+    writer.out
+}
+
+/// Instructions where `flow` found code, bytes everywhere else.
+fn units(rom: &[u8], base: u16, flow: &ControlFlow) -> BTreeMap<u16, Unit> {
+    let mut units = BTreeMap::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = base.wrapping_add(offset as u16);
+        let instruction = match rom.get(offset..offset + 2) {
+            Some(&[b1, b2]) if flow.is_instruction(address) => {
+                Instruction::from_opcode(u16::from_be_bytes([b1, b2]))
+                    .ok()
+                    .filter(|i| !matches!(i, Instruction::SysCall(_)))
+            }
+            _ => None,
+        };
+        match instruction {
+            Some(instruction) => {
+                units.insert(address, Unit::Code(instruction));
+                offset += 2;
+            }
+            None => {
+                units.insert(address, Unit::Byte(rom[offset]));
+                offset += 1;
+            }
+        }
+    }
+    units
+}
+
+/// The address `instruction` refers to, if any.
+fn target(instruction: &Instruction) -> Option<u16> {
+    match *instruction {
+        Instruction::Jump(address)
+        | Instruction::Call(address)
+        | Instruction::JumpByValue(address)
+        | Instruction::SetIndexRegisterToValue(address) => Some(address),
+        _ => None,
+    }
+}
+
+/// Names for the addresses referred to by instructions that aren't part of
+/// a structure, wherever a label can go.
+fn labels(
+    flow: &ControlFlow,
+    units: &BTreeMap<u16, Unit>,
+    roles: &BTreeMap<u16, Role>,
+    base: u16,
+) -> BTreeMap<u16, String> {
+    let sprites: HashSet<u16> = flow
+        .accesses()
+        .iter()
+        .filter(|a| a.kind == AccessKind::Sprite)
+        .map(|a| a.start as u16)
+        .collect();
+
+    let mut labels = BTreeMap::from([(base, "main".to_string())]);
+    // In order of preference when an address has several uses
+    let mut named: Vec<(u16, u8)> = Vec::new();
+    for (&address, unit) in units {
+        let Unit::Code(instruction) = unit else {
+            continue;
+        };
+        if matches!(
+            roles.get(&address),
+            Some(Role::Again | Role::Else | Role::Consumed)
+        ) {
+            continue;
+        }
+        let rank = match instruction {
+            Instruction::Call(_) => 0,
+            Instruction::SetIndexRegisterToValue(t) if sprites.contains(t) => 1,
+            Instruction::SetIndexRegisterToValue(_) => 2,
+            Instruction::JumpByValue(_) => 3,
+            Instruction::Jump(_) => 4,
+            _ => continue,
+        };
+        if let Some(target) = target(instruction) {
+            named.push((target, rank));
+        }
+    }
+    named.sort_by_key(|&(_, rank)| rank);
+    for (target, rank) in named {
+        if !units.contains_key(&target) {
+            continue;
+        }
+        let prefix = ["sub", "sprite", "data", "table", "label"][rank as usize];
+        labels
+            .entry(target)
+            .or_insert_with(|| format!("{}_{:03x}", prefix, target));
+    }
+    labels
+}
+
+/// Data bytes DXYN draws from an I set in the same block.
+fn sprite_bytes(flow: &ControlFlow, units: &BTreeMap<u16, Unit>) -> HashSet<u16> {
+    flow.accesses()
+        .iter()
+        .filter(|a| a.kind == AccessKind::Sprite)
+        .flat_map(|a| a.start..a.end.min(0x10000))
+        .map(|address| address as u16)
+        .filter(|address| matches!(units.get(address), Some(Unit::Byte(_))))
+        .collect()
+}
+
+fn is_skip(instruction: &Instruction) -> bool {
+    condition(instruction, false).is_some()
+}
+
+/// The condition under which the skip `instruction` skips, or the opposite
+/// with `negate`.
+fn condition(instruction: &Instruction, negate: bool) -> Option<String> {
+    let (x, equal, operand) = match *instruction {
+        Instruction::SkipIfEqualByte(x, nn) => (x, true, nn.to_string()),
+        Instruction::SkipIfNotEqualByte(x, nn) => (x, false, nn.to_string()),
+        Instruction::SkipIfRegistersEqual(x, y) => (x, true, format!("v{:x}", y)),
+        Instruction::SkipIfRegistersNotEqual(x, y) => (x, false, format!("v{:x}", y)),
+        Instruction::SkipIfKeyEqualsRegister(x) => {
+            return Some(format!("v{:x} {}", x, if negate { "-key" } else { "key" }));
+        }
+        Instruction::SkipIfKeyNotEqualsRegister(x) => {
+            return Some(format!("v{:x} {}", x, if negate { "key" } else { "-key" }));
+        }
+        _ => return None,
+    };
+    let operator = if equal != negate { "==" } else { "!=" };
+    Some(format!("v{:x} {} {}", x, operator, operand))
+}
+
+/// The Octo statement for `instruction`, other than skips.
+fn statement(instruction: &Instruction, labels: &BTreeMap<u16, String>) -> String {
+    let name = |address: u16| {
+        labels
+            .get(&address)
+            .cloned()
+            .unwrap_or_else(|| format!("0x{:03X}", address))
+    };
+    match *instruction {
+        Instruction::ClearScreen => "clear".to_string(),
+        Instruction::ReturnFromSubroutine => "return".to_string(),
+        Instruction::Jump(address) => format!("jump {}", name(address)),
+        Instruction::Call(address) => match labels.get(&address) {
+            Some(label) => label.clone(),
+            None => format!(":call 0x{:03X}", address),
+        },
+        Instruction::SetRegisterToValue(x, nn) => format!("v{:x} := {}", x, nn),
+        Instruction::AddToRegister(x, nn) => format!("v{:x} += {}", x, nn),
+        Instruction::SetRegisterToRegisterValue(x, y) => format!("v{:x} := v{:x}", x, y),
+        Instruction::RegistersBitwiseOr(x, y) => format!("v{:x} |= v{:x}", x, y),
+        Instruction::RegistersBitwiseAnd(x, y) => format!("v{:x} &= v{:x}", x, y),
+        Instruction::RegistersBitwiseXor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+        Instruction::RegistersSumWithOverflow(x, y) => format!("v{:x} += v{:x}", x, y),
+        Instruction::SubtractRegisterFromRegisterValue(x, y) => format!("v{:x} -= v{:x}", x, y),
+        Instruction::ShiftRegisterBitsRight(x, y) => format!("v{:x} >>= v{:x}", x, y),
+        Instruction::SubtractRegisterValueFromRegister(x, y) => format!("v{:x} =- v{:x}", x, y),
+        Instruction::ShiftRegisterBitsLeft(x, y) => format!("v{:x} <<= v{:x}", x, y),
+        Instruction::SetIndexRegisterToValue(address) => format!("i := {}", name(address)),
+        Instruction::JumpByValue(address) => format!("jump0 {}", name(address)),
+        Instruction::SetRegisterToRandAndValue(x, nn) => {
+            format!("v{:x} := random 0x{:02X}", x, nn)
+        }
+        Instruction::DrawSprite(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+        Instruction::SetRegisterToDelayTimerValue(x) => format!("v{:x} := delay", x),
+        Instruction::WaitForKeyPress(x) => format!("v{:x} := key", x),
+        Instruction::SetDelayTimerToRegisterValue(x) => format!("delay := v{:x}", x),
+        Instruction::SetSoundTimerToRegisterValue(x) => format!("buzzer := v{:x}", x),
+        Instruction::SetIndexRegisterToSpriteForRegister(x) => format!("i := hex v{:x}", x),
+        Instruction::StoreBinaryCodedDecimalAtIndexRegisterValue(x) => format!("bcd v{:x}", x),
+        Instruction::AddRegisterToIndexRegister(x) => format!("i += v{:x}", x),
+        Instruction::DumpRegistersToMemoryAtIndexRegister(x) => format!("save v{:x}", x),
+        Instruction::LoadMemoryToRegistersAtIndexRegister(x) => format!("load v{:x}", x),
+        // Skips are written as conditions, and machine code calls as bytes
+        Instruction::SysCall(address) => {
+            format!("0x{:02X} 0x{:02X}", address >> 8, address & 0xFF)
+        }
+        Instruction::SkipIfEqualByte(..)
+        | Instruction::SkipIfNotEqualByte(..)
+        | Instruction::SkipIfRegistersEqual(..)
+        | Instruction::SkipIfRegistersNotEqual(..)
+        | Instruction::SkipIfKeyEqualsRegister(_)
+        | Instruction::SkipIfKeyNotEqualsRegister(_) => unreachable!("skips are conditions"),
+    }
+}
+
+struct Writer {
+    out: String,
+    depth: usize,
+    /// Bytes not written yet, and whether each belongs to a sprite
+    data: Vec<(u8, bool)>,
+    /// An `if ... then` waiting for the statement it guards
+    then: Option<String>,
+}
+
+impl Writer {
+    fn line(&mut self, text: &str) {
+        let _ = writeln!(
+            self.out,
+            "{:indent$}{}",
+            "",
+            text,
+            indent = 2 + self.depth * 2
+        );
+    }
+
+    fn label(&mut self, name: &str) {
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        let _ = writeln!(self.out, ": {}", name);
+    }
+
+    fn statement(&mut self, text: &str) {
+        match self.then.take() {
+            Some(then) => self.line(&format!("{} {}", then, text)),
+            None => self.line(text),
+        }
+    }
+
+    fn flush_then(&mut self) {
+        if let Some(then) = self.then.take() {
+            self.line(&then);
+        }
+    }
+
+    fn flush_data(&mut self) {
+        if self.data.is_empty() {
+            return;
+        }
+        let data = std::mem::take(&mut self.data);
+        let text: Vec<String> = data
+            .iter()
+            .map(|&(byte, sprite)| {
+                if sprite {
+                    format!("0b{:08b}", byte)
+                } else {
+                    format!("0x{:02X}", byte)
+                }
+            })
+            .collect();
+        self.statement(&text.join(" "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::octo::compile;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn round_trip(rom: &[u8], base: u16) {
+        let source = decompile(rom, base);
+        let compiled = compile(&source, base).map(|assembly| assembly.rom);
+        assert_eq!(compiled.as_deref(), Ok(rom), "{}", source);
+    }
+
+    #[test]
+    fn recovers_loops_ifs_subroutines_and_sprites() {
+        let rom = assemble(
+            "
+                LD V0, 0
+            loop:
+                ADD V0, 1
+                SNE V0, 10
+                JP done
+                SE V1, 2
+                JP else
+                LD V2, 1
+                JP endif
+            else:
+                LD V2, 2
+            endif:
+                CALL draw
+                JP loop
+            done:
+                JP done
+            draw:
+                LD I, sprite
+                DRW V0, V1, 3
+                RET
+            sprite:
+                DB 0x42, 0x00, 0x3C
+            ",
+            0x200,
+        )
+        .unwrap();
+        assert_eq!(
+            decompile(&rom, 0x200),
+            "\
+: main
+  v0 := 0
+  loop
+    v0 += 1
+    while v0 != 10
+    if v1 == 2 begin
+      v2 := 1
+    else
+      v2 := 2
+    end
+    sub_218
+  again
+  loop
+  again
+
+: sub_218
+  i := sprite_21e
+  sprite v0 v1 3
+  return
+
+: sprite_21e
+  0b01000010
+  0b00000000
+  0b00111100
+"
+        );
+        round_trip(&rom, 0x200);
+    }
+
+    #[test]
+    fn ibm_logo_round_trips() {
+        round_trip(include_bytes!("../ibm-logo.ch8"), 0x200);
+    }
+
+    /// ROMs full of jumps, calls and skips into each other, with some
+    /// random words mixed in, decompile to source compiling back to them.
+    #[test]
+    fn random_programs_round_trip() {
+        let mut rng = StdRng::seed_from_u64(48);
+        for base in [0x200, 0xF80] {
+            for _ in 0..500 {
+                let words = rng.random_range(1..40u16);
+                let mut rom = Vec::new();
+                for _ in 0..words {
+                    let target = base + 2 * rng.random_range(0..words);
+                    let x = rng.random_range(0..16u16) << 8;
+                    let word: u16 = match rng.random_range(0..10) {
+                        0 => 0x1000 | target,
+                        1 => 0x2000 | target,
+                        2 => 0x3000 | x | rng.random_range(0..4u16),
+                        3 => 0x4000 | x | rng.random_range(0..4u16),
+                        4 => 0x00EE,
+                        5 => 0x6000 | x | rng.random_range(0..256u16),
+                        6 => 0xA000 | target,
+                        7 => 0xD000 | x | rng.random_range(0..16u16),
+                        8 => 0x5000 | x,
+                        _ => rng.random(),
+                    };
+                    rom.extend(word.to_be_bytes());
+                }
+                round_trip(&rom, base);
+            }
+        }
+    }
+}
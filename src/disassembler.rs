@@ -0,0 +1,188 @@
+use crate::flow::ControlFlow;
+use crate::parser::Instruction;
+use crate::symbols::Symbols;
+
+use std::collections::HashSet;
+use std::fmt;
+
+/// One decoded word (or trailing byte) of a ROM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// `None` for words that are not valid instructions
+    pub instruction: Option<Instruction>,
+}
+
+impl Line {
+    /// Source text for the line, `DW`/`DB` for anything that doesn't decode.
+    pub fn source(&self) -> String {
+        match (&self.instruction, self.bytes.as_slice()) {
+            (Some(instruction), _) => instruction.to_string(),
+            (None, [b1, b2]) => format!("DW 0x{:02X}{:02X}", b1, b2),
+            (None, bytes) => {
+                let bytes: Vec<String> = bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+                format!("DB {}", bytes.join(", "))
+            }
+        }
+    }
+}
+
+/// Assembler source with the address and raw bytes as a comment, so the
+/// output can be fed back to the assembler.
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: String = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(
+            f,
+            "    {:<20}; {:#05X}: {}",
+            self.source(),
+            self.address,
+            bytes
+        )
+    }
+}
+
+/// Longest run of data bytes on one line of [`disassemble_flow`].
+const DATA_BYTES_PER_LINE: usize = 8;
+
+/// Decode `rom` word by word, as loaded at `base`.
+pub fn disassemble(rom: &[u8], base: u16) -> Vec<Line> {
+    rom.chunks(2)
+        .enumerate()
+        .map(|(i, bytes)| {
+            let instruction = match *bytes {
+                [b1, b2] => Instruction::from_opcode((b1 as u16) << 8 | b2 as u16).ok(),
+                _ => None,
+            };
+            Line {
+                address: base.wrapping_add((i * 2) as u16),
+                bytes: bytes.to_vec(),
+                instruction,
+            }
+        })
+        .collect()
+}
+
+/// Decode the instructions `flow` reached in `rom`, as loaded at `base`,
+/// and list everything else as `DB` data.
+pub fn disassemble_flow(rom: &[u8], base: u16, flow: &ControlFlow) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = base.wrapping_add(offset as u16);
+        let at = |offset: usize| base.wrapping_add(offset as u16);
+        let len = if flow.is_instruction(address) {
+            2
+        } else if flow.is_code(address) {
+            // The operand of a four byte instruction, or an instruction
+            // overlapping another one
+            if flow.is_instruction(address.wrapping_add(1)) {
+                1
+            } else {
+                2
+            }
+        } else {
+            (offset..rom.len())
+                .take(DATA_BYTES_PER_LINE)
+                .take_while(|&o| !flow.is_code(at(o)))
+                .count()
+        };
+        let bytes = rom[offset..(offset + len).min(rom.len())].to_vec();
+        let instruction = match *bytes.as_slice() {
+            [b1, b2] if flow.is_instruction(address) => {
+                Instruction::from_opcode((b1 as u16) << 8 | b2 as u16).ok()
+            }
+            _ => None,
+        };
+        offset += bytes.len();
+        lines.push(Line {
+            address,
+            bytes,
+            instruction,
+        });
+    }
+    lines
+}
+
+/// The listing of `lines` with the labels in `symbols`: a `name:` line
+/// before each labelled line, the address operands pointing at one of them
+/// written as the label, and the source line of each address in its
+/// comment. It can be fed back to the assembler like the plain listing.
+pub fn listing(lines: &[Line], symbols: &Symbols) -> Vec<String> {
+    let starts: HashSet<u16> = lines.iter().map(|line| line.address).collect();
+    let label = |address: u16| {
+        symbols
+            .label_at(address)
+            .filter(|_| starts.contains(&address))
+    };
+
+    let mut text = Vec::new();
+    for line in lines {
+        if let Some(name) = label(line.address) {
+            text.push(format!("{}:", name));
+        }
+        let source = match line.instruction {
+            Some(Instruction::Jump(address)) if let Some(name) = label(address) => {
+                format!("JP {}", name)
+            }
+            Some(Instruction::Call(address)) if let Some(name) = label(address) => {
+                format!("CALL {}", name)
+            }
+            Some(Instruction::SetIndexRegisterToValue(address))
+                if let Some(name) = label(address) =>
+            {
+                format!("LD I, {}", name)
+            }
+            Some(Instruction::JumpByValue(address)) if let Some(name) = label(address) => {
+                format!("JP V0, {}", name)
+            }
+            _ => line.source(),
+        };
+        let bytes: String = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let mut row = format!("    {:<20}; {:#05X}: {}", source, line.address, bytes);
+        if let Some(source_line) = symbols.source_line(line.address) {
+            row = format!("{:<42}  {}", row, source_line);
+        }
+        text.push(row);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_words_and_falls_back_to_data() {
+        let lines = disassemble(&[0x00, 0xE0, 0x50, 0x01, 0xAB], 0x200);
+        let text: Vec<String> = lines.iter().map(Line::to_string).collect();
+        assert_eq!(
+            text,
+            [
+                "    CLS                 ; 0x200: 00E0",
+                "    DW 0x5001           ; 0x202: 5001",
+                "    DB 0xAB             ; 0x204: AB",
+            ]
+        );
+        assert_eq!(lines[1].instruction, None);
+    }
+
+    #[test]
+    fn flow_disassembly_lists_unreached_bytes_as_data() {
+        // JP 0x204; a sprite; CLS
+        let rom = [0x12, 0x04, 0xFF, 0x81, 0x00, 0xE0];
+        let lines = disassemble_flow(&rom, 0x200, &ControlFlow::analyze(&rom, 0x200));
+        let mut symbols = Symbols::new();
+        symbols.add_label(0x204, "start");
+        assert_eq!(
+            listing(&lines, &symbols),
+            [
+                "    JP start            ; 0x200: 1204",
+                "    DW 0xFF81           ; 0x202: FF81",
+                "start:",
+                "    CLS                 ; 0x204: 00E0",
+            ]
+        );
+    }
+}
@@ -2,8 +2,26 @@ use crate::consts::{SCREEN_HEIGHT, SCREEN_WIDTH};
 
 use std::io::{self, Write};
 
+/// Pixels of the screen, one byte per pixel, row by row.
+pub type Screen = [[u8; SCREEN_WIDTH as usize]; SCREEN_HEIGHT as usize];
+
+/// How the screen is drawn in the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Renderer {
+    /// One character per pixel, `░` and `█`
+    #[default]
+    Blocks,
+    /// Two pixel rows per character using half block characters
+    HalfBlocks,
+    /// One character per pixel, `.` and `#`
+    Ascii,
+}
+
 pub struct CLIDisplay {
-    screen: [[u8; SCREEN_WIDTH as usize]; SCREEN_HEIGHT as usize],
+    screen: Screen,
+    out: Box<dyn Write>,
+    renderer: Renderer,
+    scale: usize,
 }
 
 impl Default for CLIDisplay {
@@ -14,69 +32,228 @@ impl Default for CLIDisplay {
 
 impl CLIDisplay {
     pub fn new() -> Self {
+        Self::with_output(Box::new(io::stdout()))
+    }
+
+    /// Display drawing to `out` instead of stdout, e.g. when stdout carries audio.
+    pub fn with_output(out: Box<dyn Write>) -> Self {
         CLIDisplay {
             screen: [[0; _]; _],
+            out,
+            renderer: Renderer::default(),
+            scale: 1,
         }
     }
 
+    /// Display that keeps the framebuffer but never draws it.
+    pub fn headless() -> Self {
+        Self::with_output(Box::new(io::sink()))
+    }
+
+    pub fn with_renderer(mut self, renderer: Renderer) -> Self {
+        self.renderer = renderer;
+        self
+    }
+
+    /// Draw every pixel as a `scale` x `scale` square.
+    pub fn with_scale(mut self, scale: usize) -> Self {
+        self.scale = scale.max(1);
+        self
+    }
+
+    pub fn pixel(&self, x: u8, y: u8) -> bool {
+        self.screen[y as usize][x as usize] != 0
+    }
+
+    /// Turn a pixel on or off without drawing, e.g. to restore a saved screen.
+    pub fn set_pixel(&mut self, x: u8, y: u8, on: bool) {
+        self.screen[y as usize][x as usize] = on as u8;
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    /// Replace every pixel without drawing.
+    pub fn set_screen(&mut self, screen: &Screen) {
+        self.screen = *screen;
+    }
+
     pub fn clear(&mut self) {
         self.screen = [[0; _]; _];
     }
 
-    pub fn show(&self) {
-        // Improve the screen display code for more interactive terminal
+    /// Clear the terminal and draw the screen, for `run --plain`; the
+    /// full-screen view in [`crate::tui`] draws it along with its panels
+    /// instead.
+    pub fn show(&mut self) {
+        write!(self.out, "\x1B[2J\x1B[H").unwrap();
+
+        self.out.flush().unwrap();
 
-        print!("\x1B[2J\x1B[H");
+        let res = self.render();
 
-        io::stdout().flush().unwrap();
+        write!(self.out, "{}", res).unwrap();
+
+        self.out.flush().unwrap();
+    }
+
+    /// The screen as text, one line per row.
+    pub fn render(&self) -> String {
+        let rows: Vec<Vec<bool>> = self
+            .screen
+            .iter()
+            .flat_map(|row| {
+                let scaled: Vec<bool> = row
+                    .iter()
+                    .flat_map(|&pixel| std::iter::repeat_n(pixel != 0, self.scale))
+                    .collect();
+                std::iter::repeat_n(scaled, self.scale)
+            })
+            .collect();
 
         let mut res = String::new();
 
-        for row in &self.screen {
-            for &pixel in row {
-                res.push(if pixel == 0 { '░' } else { '█' });
+        match self.renderer {
+            Renderer::Blocks | Renderer::Ascii => {
+                let (off, on) = if self.renderer == Renderer::Blocks {
+                    ('░', '█')
+                } else {
+                    ('.', '#')
+                };
+                for row in &rows {
+                    res.extend(row.iter().map(|&pixel| if pixel { on } else { off }));
+                    res.push('\n');
+                }
+            }
+            Renderer::HalfBlocks => {
+                for pair in rows.chunks(2) {
+                    let bottom = pair.get(1);
+                    for (x, &top) in pair[0].iter().enumerate() {
+                        let bottom = bottom.is_some_and(|row| row[x]);
+                        res.push(match (top, bottom) {
+                            (false, false) => ' ',
+                            (true, false) => '▀',
+                            (false, true) => '▄',
+                            (true, true) => '█',
+                        });
+                    }
+                    res.push('\n');
+                }
             }
-
-            res.push('\n');
         }
 
-        print!("{}", res);
-
-        io::stdout().flush().unwrap();
+        res
     }
 
-    pub fn draw(&mut self, reg_x: u8, reg_y: u8, sprite: &[u8]) -> bool {
-        let x = reg_x % SCREEN_WIDTH;
-        let y = reg_y % SCREEN_HEIGHT;
+    /// XOR `sprite` onto the screen, returning whether any pixel was turned
+    /// off. Parts past the screen edges wrap around if `wrap`, otherwise
+    /// they are clipped.
+    pub fn draw(&mut self, reg_x: u8, reg_y: u8, sprite: &[u8], wrap: bool) -> bool {
         let mut did_switch: bool = false;
+        for (x, y) in sprite_pixels(reg_x, reg_y, sprite, wrap) {
+            let pixel = &mut self.screen[y as usize][x as usize];
+            did_switch |= *pixel == 1;
+            *pixel ^= 1;
+        }
+        did_switch
+    }
 
-        for (yo, data) in sprite.iter().enumerate() {
-            let row = y as usize + yo;
-            if row >= SCREEN_HEIGHT as usize {
+    /// Pixels that are on, which [`CLIDisplay::clear`] turns off.
+    pub fn lit_pixels(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        self.screen.iter().enumerate().flat_map(|(y, row)| {
+            row.iter()
+                .enumerate()
+                .filter(|&(_, &pixel)| pixel != 0)
+                .map(move |(x, _)| (x as u8, y as u8))
+        })
+    }
+}
+
+/// Pixels covered by the set bits of `sprite` drawn at (`reg_x`, `reg_y`),
+/// which [`CLIDisplay::draw`] flips.
+pub fn sprite_pixels(reg_x: u8, reg_y: u8, sprite: &[u8], wrap: bool) -> Vec<(u8, u8)> {
+    let x = reg_x % SCREEN_WIDTH;
+    let y = reg_y % SCREEN_HEIGHT;
+    let mut pixels = Vec::new();
+
+    for (yo, data) in sprite.iter().enumerate() {
+        let mut row = y as usize + yo;
+        if row >= SCREEN_HEIGHT as usize {
+            if !wrap {
                 break;
             }
+            row %= SCREEN_HEIGHT as usize;
+        }
 
-            for (xo, bit) in byte_to_bits(*data).iter().enumerate() {
-                let col = x as usize + xo;
-                if col >= SCREEN_WIDTH as usize {
+        for (xo, bit) in byte_to_bits(*data).iter().enumerate() {
+            let mut col = x as usize + xo;
+            if col >= SCREEN_WIDTH as usize {
+                if !wrap {
                     break;
                 }
+                col %= SCREEN_WIDTH as usize;
+            }
 
-                if *bit == 1 {
-                    if self.screen[row][col] == 1 {
-                        self.screen[row][col] = 0;
-                        did_switch = true;
-                    } else {
-                        self.screen[row][col] = 1;
-                    }
-                }
+            if *bit == 1 {
+                pixels.push((col as u8, row as u8));
             }
         }
-        did_switch
     }
+    pixels
 }
 
 /// Bits as 0 or 1 u8 from the most to least significant
 fn byte_to_bits(b: u8) -> [u8; 8] {
     std::array::from_fn(|i| (b >> (7 - i)) & 1)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drawing_xors_and_reports_collisions() {
+        let mut display = CLIDisplay::headless();
+        assert!(!display.draw(1, 2, &[0b1000_0001], false));
+        assert!(display.pixel(1, 2) && display.pixel(8, 2));
+        assert!(display.draw(1, 2, &[0b1000_0000], false));
+        assert!(!display.pixel(1, 2) && display.pixel(8, 2));
+    }
+
+    #[test]
+    fn sprites_are_clipped_or_wrapped_at_the_edges() {
+        let (right, bottom) = (SCREEN_WIDTH - 4, SCREEN_HEIGHT - 1);
+        let mut display = CLIDisplay::headless();
+        display.draw(right, bottom, &[0xFF, 0xFF], false);
+        assert!(display.pixel(SCREEN_WIDTH - 1, bottom));
+        assert!(!display.pixel(0, bottom) && !display.pixel(right, 0));
+
+        let mut display = CLIDisplay::headless();
+        display.draw(right, bottom, &[0xFF, 0xFF], true);
+        assert!(display.pixel(3, bottom) && display.pixel(3, 0) && display.pixel(right, 0));
+        // Coordinates past the edge always wrap
+        display.draw(SCREEN_WIDTH + 1, 5, &[0x80], false);
+        assert!(display.pixel(1, 5));
+    }
+
+    #[test]
+    fn renderers() {
+        let mut display = CLIDisplay::headless().with_renderer(Renderer::Ascii);
+        display.set_pixel(0, 0, true);
+        display.set_pixel(0, 1, true);
+        display.set_pixel(1, 1, true);
+        let text = display.render();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), SCREEN_HEIGHT as usize);
+        assert!(lines[0].starts_with("#..") && lines[1].starts_with("##."));
+
+        let display = display.with_renderer(Renderer::HalfBlocks).with_scale(2);
+        let text = display.render();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), SCREEN_HEIGHT as usize);
+        assert_eq!(lines[0].chars().count(), SCREEN_WIDTH as usize * 2);
+        assert!(lines[0].starts_with("██  "));
+        assert!(lines[1].starts_with("████  "));
+    }
+}
@@ -0,0 +1,472 @@
+//! Expressions over the machine state, used for debugger conditions.
+//!
+//! ```text
+//! pc == 0x2A4 && v3 > 10
+//! i in 0x300..0x320 || !(dt == 0)
+//! mem[i + 1] != 0
+//! ```
+//!
+//! Values are unsigned integers; comparisons and logical operators give 1 or
+//! 0, and any non-zero value is true. `in` ranges exclude their end.
+
+use crate::Error;
+use crate::assembler::parse_number;
+use crate::interpreter::Interpreter;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Deepest nesting of parentheses, `mem[..]` and `!` an expression may have.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "==" => Some(Comparison::Equal),
+            "!=" => Some(Comparison::NotEqual),
+            "<" => Some(Comparison::Less),
+            "<=" => Some(Comparison::LessOrEqual),
+            ">" => Some(Comparison::Greater),
+            ">=" => Some(Comparison::GreaterOrEqual),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        }
+    }
+
+    pub fn holds<T: PartialOrd>(self, a: T, b: T) -> bool {
+        match self {
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => a != b,
+            Comparison::Less => a < b,
+            Comparison::LessOrEqual => a <= b,
+            Comparison::Greater => a > b,
+            Comparison::GreaterOrEqual => a >= b,
+        }
+    }
+}
+
+/// A named part of the machine state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variable {
+    Register(usize),
+    Pc,
+    I,
+    /// Stack depth
+    Sp,
+    Dt,
+    St,
+    Cycle,
+    Frame,
+}
+
+impl Variable {
+    pub fn value(self, interpreter: &Interpreter) -> u64 {
+        let state = interpreter.state();
+        match self {
+            Variable::Register(x) => state.registers[x] as u64,
+            Variable::Pc => state.pc as u64,
+            Variable::I => state.index_register as u64,
+            Variable::Sp => state.stack.len() as u64,
+            Variable::Dt => state.delay_timer as u64,
+            Variable::St => state.sound_timer as u64,
+            Variable::Cycle => interpreter.cycle(),
+            Variable::Frame => interpreter.frame(),
+        }
+    }
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Variable::Register(x) => write!(f, "v{:x}", x),
+            Variable::Pc => f.write_str("pc"),
+            Variable::I => f.write_str("i"),
+            Variable::Sp => f.write_str("sp"),
+            Variable::Dt => f.write_str("dt"),
+            Variable::St => f.write_str("st"),
+            Variable::Cycle => f.write_str("cycle"),
+            Variable::Frame => f.write_str("frame"),
+        }
+    }
+}
+
+impl FromStr for Variable {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_ascii_lowercase();
+        let variable = match lower.as_str() {
+            "pc" => Variable::Pc,
+            "i" => Variable::I,
+            "sp" => Variable::Sp,
+            "dt" => Variable::Dt,
+            "st" => Variable::St,
+            "cycle" => Variable::Cycle,
+            "frame" => Variable::Frame,
+            _ => match lower.strip_prefix('v') {
+                Some(x) if x.len() == 1 => {
+                    Variable::Register(usize::from_str_radix(x, 16).map_err(|_| unknown(s))?)
+                }
+                _ => return Err(unknown(s)),
+            },
+        };
+        Ok(variable)
+    }
+}
+
+fn unknown(name: &str) -> Error {
+    Error::InvalidExpression(format!("unknown variable '{}'", name))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(u64),
+    Variable(Variable),
+    Memory(Box<Node>),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Compare(Comparison, Box<Node>, Box<Node>),
+    In(Box<Node>, Box<Node>, Box<Node>),
+    Add(Box<Node>, Box<Node>),
+    Subtract(Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn evaluate(&self, interpreter: &Interpreter) -> u64 {
+        let truth = |b: bool| b as u64;
+        match self {
+            Node::Number(n) => *n,
+            Node::Variable(variable) => variable.value(interpreter),
+            Node::Memory(address) => {
+                let address = address.evaluate(interpreter);
+                let ram = &interpreter.state().ram;
+                usize::try_from(address)
+                    .ok()
+                    .and_then(|a| ram.get(a))
+                    .map_or(0, |&b| b as u64)
+            }
+            Node::Not(a) => truth(a.evaluate(interpreter) == 0),
+            Node::And(a, b) => truth(a.evaluate(interpreter) != 0 && b.evaluate(interpreter) != 0),
+            Node::Or(a, b) => truth(a.evaluate(interpreter) != 0 || b.evaluate(interpreter) != 0),
+            Node::Compare(comparison, a, b) => {
+                truth(comparison.holds(a.evaluate(interpreter), b.evaluate(interpreter)))
+            }
+            Node::In(value, start, end) => {
+                let value = value.evaluate(interpreter);
+                truth((start.evaluate(interpreter)..end.evaluate(interpreter)).contains(&value))
+            }
+            Node::Add(a, b) => a
+                .evaluate(interpreter)
+                .wrapping_add(b.evaluate(interpreter)),
+            Node::Subtract(a, b) => a
+                .evaluate(interpreter)
+                .wrapping_sub(b.evaluate(interpreter)),
+        }
+    }
+}
+
+/// A parsed expression, keeping its source text for display.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            next: 0,
+            depth: 0,
+        };
+        let root = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(Error::InvalidExpression(format!("unexpected '{}'", token)));
+        }
+        Ok(Expression {
+            source: source.trim().to_string(),
+            root,
+        })
+    }
+
+    pub fn evaluate(&self, interpreter: &Interpreter) -> u64 {
+        self.root.evaluate(interpreter)
+    }
+
+    pub fn is_true(&self, interpreter: &Interpreter) -> bool {
+        self.evaluate(interpreter) != 0
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<String>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphanumeric() || c == '#' || c == '_' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek()
+                && (c.is_ascii_alphanumeric() || c == '#' || c == '_')
+            {
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(word);
+        } else {
+            chars.next();
+            let pair = chars.peek().map(|&next| format!("{}{}", c, next));
+            match pair.as_deref() {
+                Some("==" | "!=" | "<=" | ">=" | "&&" | "||" | "..") => {
+                    chars.next();
+                    tokens.push(pair.unwrap());
+                }
+                _ if "()[]<>!+-".contains(c) => tokens.push(c.to_string()),
+                _ => {
+                    return Err(Error::InvalidExpression(format!("unexpected '{}'", c)));
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    next: usize,
+    /// Nesting depth of the node being parsed
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.next).map(String::as_str)
+    }
+
+    fn advance(&mut self) -> Option<String> {
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        token
+    }
+
+    fn accept(&mut self, token: &str) -> bool {
+        let found = self.peek() == Some(token);
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), Error> {
+        if self.accept(token) {
+            Ok(())
+        } else {
+            Err(Error::InvalidExpression(format!("expected '{}'", token)))
+        }
+    }
+
+    /// Parse a nested part with `parse`, failing when nested too deeply.
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<Node, Error>,
+    ) -> Result<Node, Error> {
+        if self.depth == MAX_DEPTH {
+            return Err(Error::InvalidExpression(format!(
+                "nested deeper than {} levels",
+                MAX_DEPTH
+            )));
+        }
+        self.depth += 1;
+        let node = parse(self);
+        self.depth -= 1;
+        node
+    }
+
+    fn or(&mut self) -> Result<Node, Error> {
+        let mut node = self.and()?;
+        while self.accept("||") {
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, Error> {
+        let mut node = self.not()?;
+        while self.accept("&&") {
+            node = Node::And(Box::new(node), Box::new(self.not()?));
+        }
+        Ok(node)
+    }
+
+    fn not(&mut self) -> Result<Node, Error> {
+        if self.accept("!") {
+            let node = self.nested(Self::not)?;
+            return Ok(Node::Not(Box::new(node)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Node, Error> {
+        let node = self.sum()?;
+        if self.accept("in") {
+            let start = self.sum()?;
+            self.expect("..")?;
+            let end = self.sum()?;
+            return Ok(Node::In(Box::new(node), Box::new(start), Box::new(end)));
+        }
+        match self.peek().and_then(Comparison::parse) {
+            Some(comparison) => {
+                self.next += 1;
+                Ok(Node::Compare(
+                    comparison,
+                    Box::new(node),
+                    Box::new(self.sum()?),
+                ))
+            }
+            None => Ok(node),
+        }
+    }
+
+    fn sum(&mut self) -> Result<Node, Error> {
+        let mut node = self.primary()?;
+        loop {
+            if self.accept("+") {
+                node = Node::Add(Box::new(node), Box::new(self.primary()?));
+            } else if self.accept("-") {
+                node = Node::Subtract(Box::new(node), Box::new(self.primary()?));
+            } else {
+                return Ok(node);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, Error> {
+        let token = self
+            .advance()
+            .ok_or_else(|| Error::InvalidExpression("unexpected end".to_string()))?;
+        match token.as_str() {
+            "(" => {
+                let node = self.nested(Self::or)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            "mem" => {
+                self.expect("[")?;
+                let address = self.nested(Self::or)?;
+                self.expect("]")?;
+                Ok(Node::Memory(Box::new(address)))
+            }
+            _ if token.starts_with(|c: char| c.is_ascii_digit() || c == '#') => {
+                parse_number(&token)
+                    .map(|n| Node::Number(n as u64))
+                    .ok_or_else(|| Error::InvalidExpression(format!("invalid number '{}'", token)))
+            }
+            _ if token.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                Ok(Node::Variable(token.parse()?))
+            }
+            _ => Err(Error::InvalidExpression(format!("unexpected '{}'", token))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::CLIDisplay;
+
+    fn machine() -> Interpreter {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_display(CLIDisplay::headless());
+        let state = interpreter.state_mut();
+        state.registers[3] = 5;
+        state.index_register = 0x300;
+        state.ram[0x301] = 0x2A;
+        interpreter
+    }
+
+    fn evaluate(source: &str) -> u64 {
+        Expression::parse(source).unwrap().evaluate(&machine())
+    }
+
+    #[test]
+    fn operators_bind_in_order() {
+        assert_eq!(evaluate("pc == 0x200 && v3 > 4"), 1);
+        assert_eq!(evaluate("0 && 0 || 1"), 1);
+        assert_eq!(evaluate("0 && (0 || 1)"), 0);
+        assert_eq!(evaluate("!v3 == 5"), 0);
+        assert_eq!(evaluate("!(v3 == 4)"), 1);
+        assert_eq!(evaluate("v3 + 1 - 2 == 4"), 1);
+        assert_eq!(evaluate("0 - 1"), u64::MAX);
+    }
+
+    #[test]
+    fn variables_memory_and_ranges() {
+        assert_eq!(evaluate("mem[i + 1]"), 0x2A);
+        assert_eq!(evaluate("mem[0x10000]"), 0);
+        assert_eq!(evaluate("i in 0x300..0x301"), 1);
+        assert_eq!(evaluate("i in 0x2FF..0x300"), 0);
+        assert_eq!(evaluate("V3 + SP + DT + ST + cycle + frame"), 5);
+        assert_eq!(evaluate("#10"), 16);
+        assert_eq!(
+            Expression::parse("  v3 > 1 ").unwrap().to_string(),
+            "v3 > 1"
+        );
+    }
+
+    #[test]
+    fn errors_say_what_is_wrong() {
+        let error = |source: &str| match Expression::parse(source) {
+            Err(Error::InvalidExpression(message)) => message,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(error("v3 >"), "unexpected end");
+        assert_eq!(error("vg == 1"), "unknown variable 'vg'");
+        assert_eq!(error("(v3 == 1"), "expected ')'");
+        assert_eq!(error("v3 = 1"), "unexpected '='");
+        assert_eq!(error("v3 1"), "unexpected '1'");
+        assert_eq!(error("i in 1 2"), "expected '..'");
+        assert_eq!(error("0xZ"), "invalid number '0xZ'");
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Expression::parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            Expression::parse(&nested(MAX_DEPTH + 1)),
+            Err(Error::InvalidExpression(
+                "nested deeper than 32 levels".to_string()
+            ))
+        );
+        assert!(Expression::parse(&"!".repeat(100_000)).is_err());
+        assert!(Expression::parse(&"mem[".repeat(100_000)).is_err());
+    }
+}
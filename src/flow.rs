@@ -0,0 +1,550 @@
+//! Static control-flow analysis: which bytes of a ROM are code and which
+//! are data.
+//!
+//! Linear disassembly decodes sprites and tables as instructions. Here the
+//! program is followed from its entry point instead, by recursive descent:
+//! jumps continue at their target, calls at the subroutine and after the
+//! call, skips at both the next instruction and the one after it, and
+//! returns end the path. A BNNN jump goes to an address only known at run
+//! time, so it is flagged rather than followed. Bytes never reached are data.
+
+use crate::parser::Instruction;
+use crate::rominfo::{schip_opcode, xochip_opcode};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+/// How control gets from one block to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    /// Falling through to the next instruction, or a skip not taken
+    Next,
+    /// A skip taken
+    Skip,
+    Jump,
+    Call,
+}
+
+/// A straight run of instructions, entered only at the top and left only
+/// at the bottom.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub start: u16,
+    /// Address after the last instruction
+    pub end: u16,
+    /// Address of the last instruction
+    pub last: u16,
+    pub successors: Vec<(u16, Edge)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Code,
+    Data,
+}
+
+/// Consecutive bytes of the same kind, from `start` up to `end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u16,
+    pub end: u16,
+    pub kind: RegionKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    /// Read by DXYN
+    Sprite,
+    Read,
+    Write,
+}
+
+/// Memory an instruction accesses through an I known from the analysis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    /// Address of the instruction
+    pub at: u16,
+    pub opcode: u16,
+    pub kind: AccessKind,
+    pub start: u32,
+    /// End of the accessed bytes, which may lie past the end of RAM
+    pub end: u32,
+}
+
+impl Access {
+    pub fn contains(&self, address: u16) -> bool {
+        (self.start..self.end).contains(&(address as u32))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ControlFlow {
+    base: u16,
+    /// Whether each byte of the ROM belongs to a reached instruction
+    code: Vec<bool>,
+    /// Address and opcode of each reached instruction
+    instructions: BTreeMap<u16, u16>,
+    blocks: BTreeMap<u16, Block>,
+    subroutines: BTreeSet<u16>,
+    computed_jumps: BTreeSet<u16>,
+}
+
+impl ControlFlow {
+    /// Follow `rom`, loaded at `base`, from its first instruction.
+    ///
+    /// Extension opcodes and machine code calls are assumed to fall
+    /// through, except for SUPER-CHIP exit. Words that don't decode end the
+    /// path they are on.
+    pub fn analyze(rom: &[u8], base: u16) -> Self {
+        let mut flow = ControlFlow {
+            base,
+            code: vec![false; rom.len()],
+            instructions: BTreeMap::new(),
+            blocks: BTreeMap::new(),
+            subroutines: BTreeSet::new(),
+            computed_jumps: BTreeSet::new(),
+        };
+        let mut leaders = BTreeSet::from([base]);
+        let mut pending = vec![base];
+
+        while let Some(address) = pending.pop() {
+            let Some(word) = word_at(rom, base, address) else {
+                continue;
+            };
+            if flow.instructions.insert(address, word).is_some() {
+                continue;
+            }
+            for offset in 0..length(word) {
+                let index = address.wrapping_add(offset).wrapping_sub(base) as usize;
+                if let Some(byte) = flow.code.get_mut(index) {
+                    *byte = true;
+                }
+            }
+
+            match Instruction::from_opcode(word) {
+                Ok(Instruction::Call(target)) => {
+                    flow.subroutines.insert(target);
+                }
+                Ok(Instruction::JumpByValue(_)) => {
+                    flow.computed_jumps.insert(address);
+                }
+                _ => {}
+            }
+            let successors = successors(address, word);
+            if ends_block(&successors) {
+                leaders.extend(successors.iter().map(|&(target, _)| target));
+            }
+            pending.extend(successors.iter().rev().map(|&(target, _)| target));
+        }
+
+        for &leader in &leaders {
+            if let Some(block) = flow.block_from(leader, &leaders) {
+                flow.blocks.insert(leader, block);
+            }
+        }
+        flow
+    }
+
+    fn block_from(&self, start: u16, leaders: &BTreeSet<u16>) -> Option<Block> {
+        let mut address = start;
+        loop {
+            let word = *self.instructions.get(&address)?;
+            let successors = successors(address, word);
+            let next = address.wrapping_add(length(word));
+            if ends_block(&successors)
+                || leaders.contains(&next)
+                || !self.instructions.contains_key(&next)
+            {
+                return Some(Block {
+                    start,
+                    end: next,
+                    last: address,
+                    successors,
+                });
+            }
+            address = next;
+        }
+    }
+
+    /// Address the analysis started from.
+    pub fn entry(&self) -> u16 {
+        self.base
+    }
+
+    /// Whether `address` is inside the ROM.
+    pub fn in_rom(&self, address: u16) -> bool {
+        (address.wrapping_sub(self.base) as usize) < self.code.len()
+    }
+
+    /// Whether an instruction reached by the analysis starts at `address`.
+    pub fn is_instruction(&self, address: u16) -> bool {
+        self.instructions.contains_key(&address)
+    }
+
+    /// Whether the byte at `address` is part of a reached instruction.
+    pub fn is_code(&self, address: u16) -> bool {
+        let index = address.wrapping_sub(self.base) as usize;
+        self.code.get(index).copied().unwrap_or(false)
+    }
+
+    /// Reached instructions with their opcodes, by address.
+    pub fn instructions(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.instructions
+            .iter()
+            .map(|(&address, &word)| (address, word))
+    }
+
+    /// Opcode of the reached instruction at `address`.
+    pub fn opcode_at(&self, address: u16) -> Option<u16> {
+        self.instructions.get(&address).copied()
+    }
+
+    /// The block starting at `address`.
+    pub fn block(&self, address: u16) -> Option<&Block> {
+        self.blocks.get(&address)
+    }
+
+    /// Basic blocks by start address.
+    pub fn blocks(&self) -> impl Iterator<Item = &Block> {
+        self.blocks.values()
+    }
+
+    /// Address and opcode of each instruction in `block`, in order.
+    pub fn block_instructions(&self, block: &Block) -> Vec<(u16, u16)> {
+        let mut instructions = Vec::new();
+        let mut address = block.start;
+        while let Some(&word) = self.instructions.get(&address) {
+            instructions.push((address, word));
+            if address == block.last {
+                break;
+            }
+            address = address.wrapping_add(length(word));
+        }
+        instructions
+    }
+
+    /// Targets of 2NNN calls.
+    pub fn subroutines(&self) -> &BTreeSet<u16> {
+        &self.subroutines
+    }
+
+    /// Addresses of BNNN jumps, whose targets the analysis can't know.
+    pub fn computed_jumps(&self) -> &BTreeSet<u16> {
+        &self.computed_jumps
+    }
+
+    /// Memory the reached instructions access, following I within each
+    /// block from the last `LD I, NNN`.
+    pub fn accesses(&self) -> Vec<Access> {
+        let mut accesses = Vec::new();
+        for block in self.blocks() {
+            let mut i: Option<u32> = None;
+            for (address, word) in self.block_instructions(block) {
+                let Ok(instruction) = Instruction::from_opcode(word) else {
+                    continue;
+                };
+                let access = |kind, len: u32| Access {
+                    at: address,
+                    opcode: word,
+                    kind,
+                    start: i.unwrap_or(0),
+                    end: i.unwrap_or(0) + len,
+                };
+                let found = match instruction {
+                    // DXY0 draws a 16x16 sprite on SUPER-CHIP
+                    Instruction::DrawSprite(_, _, n) => Some(access(
+                        AccessKind::Sprite,
+                        if n == 0 { 32 } else { n as u32 },
+                    )),
+                    Instruction::StoreBinaryCodedDecimalAtIndexRegisterValue(_) => {
+                        Some(access(AccessKind::Write, 3))
+                    }
+                    Instruction::DumpRegistersToMemoryAtIndexRegister(x) => {
+                        Some(access(AccessKind::Write, x as u32 + 1))
+                    }
+                    Instruction::LoadMemoryToRegistersAtIndexRegister(x) => {
+                        Some(access(AccessKind::Read, x as u32 + 1))
+                    }
+                    _ => None,
+                };
+                if i.is_some()
+                    && let Some(found) = found
+                {
+                    accesses.push(found);
+                }
+                i = match instruction {
+                    Instruction::SetIndexRegisterToValue(address) => Some(address as u32),
+                    _ if sets_i(&instruction) => None,
+                    _ => i,
+                };
+            }
+        }
+        accesses
+    }
+
+    /// Split the ROM into runs of code and data.
+    pub fn regions(&self) -> Vec<Region> {
+        let mut regions: Vec<Region> = Vec::new();
+        for (index, &code) in self.code.iter().enumerate() {
+            let address = self.base.wrapping_add(index as u16);
+            let kind = if code {
+                RegionKind::Code
+            } else {
+                RegionKind::Data
+            };
+            match regions.last_mut() {
+                Some(region) if region.kind == kind => region.end = address.wrapping_add(1),
+                _ => regions.push(Region {
+                    start: address,
+                    end: address.wrapping_add(1),
+                    kind,
+                }),
+            }
+        }
+        regions
+    }
+
+    /// Write the control-flow graph in Graphviz DOT, one node per block
+    /// listing its instructions. Calls are dashed, blocks ending in a
+    /// computed jump are red.
+    pub fn write_dot(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "digraph cfg {{")?;
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];")?;
+
+        let mut outside = BTreeSet::new();
+        for block in self.blocks() {
+            let mut label = String::new();
+            for (address, word) in self.block_instructions(block) {
+                let text = Instruction::from_opcode(word)
+                    .map_or_else(|_| format!("DW 0x{:04X}", word), |i| i.to_string());
+                label.push_str(&format!("{:#05x}  {}\\l", address, text));
+            }
+            let mut attributes = format!("label=\"{}\"", label);
+            if self.subroutines.contains(&block.start) {
+                attributes.push_str(", peripheries=2");
+            }
+            if self.computed_jumps.contains(&block.last) {
+                attributes.push_str(", color=red");
+            }
+            writeln!(out, "    \"{:#05x}\" [{}];", block.start, attributes)?;
+
+            for &(target, edge) in &block.successors {
+                if !self.blocks.contains_key(&target) {
+                    outside.insert(target);
+                }
+                let style = match edge {
+                    Edge::Next | Edge::Jump => "",
+                    Edge::Skip => " [label=\"skip\"]",
+                    Edge::Call => " [style=dashed, label=\"call\"]",
+                };
+                writeln!(
+                    out,
+                    "    \"{:#05x}\" -> \"{:#05x}\"{};",
+                    block.start, target, style
+                )?;
+            }
+            if let Some(&word) = self.instructions.get(&block.last)
+                && self.computed_jumps.contains(&block.last)
+            {
+                writeln!(
+                    out,
+                    "    \"{:#05x}?\" [shape=plaintext, label=\"V0 + {:#05x}\"];",
+                    block.last,
+                    word & 0x0FFF
+                )?;
+                writeln!(
+                    out,
+                    "    \"{:#05x}\" -> \"{:#05x}?\" [style=dotted, color=red];",
+                    block.start, block.last
+                )?;
+            }
+        }
+        for target in outside {
+            writeln!(out, "    \"{:#05x}\" [shape=plaintext];", target)?;
+        }
+        writeln!(out, "}}")
+    }
+}
+
+/// Where control can go after the instruction `word` at `address`.
+fn successors(address: u16, word: u16) -> Vec<(u16, Edge)> {
+    let next = address.wrapping_add(length(word));
+    match Instruction::from_opcode(word) {
+        // SUPER-CHIP exit
+        _ if word == 0x00FD => vec![],
+        Ok(Instruction::Jump(target)) => vec![(target, Edge::Jump)],
+        Ok(Instruction::Call(target)) => vec![(target, Edge::Call), (next, Edge::Next)],
+        Ok(Instruction::ReturnFromSubroutine | Instruction::JumpByValue(_)) => vec![],
+        Ok(
+            Instruction::SkipIfEqualByte(..)
+            | Instruction::SkipIfNotEqualByte(..)
+            | Instruction::SkipIfRegistersEqual(..)
+            | Instruction::SkipIfRegistersNotEqual(..)
+            | Instruction::SkipIfKeyEqualsRegister(_)
+            | Instruction::SkipIfKeyNotEqualsRegister(_),
+        ) => vec![(next, Edge::Next), (next.wrapping_add(2), Edge::Skip)],
+        Ok(_) => vec![(next, Edge::Next)],
+        Err(_) if xochip_opcode(word).is_some() || schip_opcode(word).is_some() => {
+            vec![(next, Edge::Next)]
+        }
+        Err(_) => vec![],
+    }
+}
+
+/// Whether `instruction` changes I, or may depending on the quirks.
+pub(crate) fn sets_i(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::SetIndexRegisterToValue(_)
+            | Instruction::SetIndexRegisterToSpriteForRegister(_)
+            | Instruction::AddRegisterToIndexRegister(_)
+            | Instruction::DumpRegistersToMemoryAtIndexRegister(_)
+            | Instruction::LoadMemoryToRegistersAtIndexRegister(_)
+    )
+}
+
+/// Anything but falling through to the next instruction ends a block.
+fn ends_block(successors: &[(u16, Edge)]) -> bool {
+    !matches!(successors, [(_, Edge::Next)])
+}
+
+/// Bytes taken by the instruction `word`: XO-CHIP's F000 NNNN takes four.
+fn length(word: u16) -> u16 {
+    if word == 0xF000 { 4 } else { 2 }
+}
+
+fn word_at(rom: &[u8], base: u16, address: u16) -> Option<u16> {
+    let offset = address.checked_sub(base)? as usize;
+    let bytes = rom.get(offset..offset + 2)?;
+    Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    const PROGRAM: &str = "
+        CALL draw
+    loop:
+        SE V0, 1
+        JP computed
+        JP loop
+    draw:
+        LD I, sprite
+        DRW V0, V1, 2
+        RET
+    computed:
+        JP V0, sprite
+    sprite:
+        DB 0xFF, 0x81
+        DW 0x1200
+    ";
+
+    fn flow() -> ControlFlow {
+        ControlFlow::analyze(&assemble(PROGRAM, 0x200).unwrap(), 0x200)
+    }
+
+    #[test]
+    fn follows_jumps_calls_and_skips() {
+        let flow = flow();
+        let successors: Vec<(u16, &[(u16, Edge)])> = flow
+            .blocks()
+            .map(|block| (block.start, block.successors.as_slice()))
+            .collect();
+        assert_eq!(
+            successors,
+            [
+                (0x200, &[(0x208, Edge::Call), (0x202, Edge::Next)][..]),
+                (0x202, &[(0x204, Edge::Next), (0x206, Edge::Skip)]),
+                (0x204, &[(0x20E, Edge::Jump)]),
+                (0x206, &[(0x202, Edge::Jump)]),
+                (0x208, &[]),
+                (0x20E, &[]),
+            ]
+        );
+        let draw = flow.block(0x208).unwrap();
+        assert_eq!((draw.end, draw.last), (0x20E, 0x20C));
+        assert_eq!(
+            flow.block_instructions(draw),
+            [(0x208, 0xA210), (0x20A, 0xD012), (0x20C, 0x00EE)]
+        );
+        assert!(flow.subroutines().iter().eq(&[0x208]));
+        assert!(flow.computed_jumps().iter().eq(&[0x20E]));
+    }
+
+    #[test]
+    fn unreached_bytes_are_data() {
+        let flow = flow();
+        assert_eq!(
+            flow.regions(),
+            [
+                Region {
+                    start: 0x200,
+                    end: 0x210,
+                    kind: RegionKind::Code
+                },
+                Region {
+                    start: 0x210,
+                    end: 0x214,
+                    kind: RegionKind::Data
+                },
+            ]
+        );
+        assert!(flow.is_code(0x20F) && !flow.is_code(0x210));
+        assert!(flow.is_instruction(0x20E) && !flow.is_instruction(0x212));
+        assert!(flow.in_rom(0x213) && !flow.in_rom(0x214));
+        assert_eq!(
+            flow.accesses(),
+            [Access {
+                at: 0x20A,
+                opcode: 0xD012,
+                kind: AccessKind::Sprite,
+                start: 0x210,
+                end: 0x212
+            }]
+        );
+    }
+
+    #[test]
+    fn long_and_invalid_words_end_where_they_should() {
+        // LD I, long 0x300; exit; then a word that doesn't decode
+        let rom = [0xF0, 0x00, 0x03, 0x00, 0x00, 0xFD, 0xFF, 0xFF];
+        let flow = ControlFlow::analyze(&rom, 0x200);
+        assert!(flow.instructions().eq([(0x200, 0xF000), (0x204, 0x00FD)]));
+        assert!(flow.is_code(0x203) && !flow.is_code(0x206));
+
+        // JP 0x208, outside the ROM; a path over an unknown word stops there
+        let rom = [0x22, 0x04, 0x12, 0x08, 0x00, 0xFF];
+        let flow = ControlFlow::analyze(&rom, 0x200);
+        assert!(
+            flow.instructions()
+                .map(|(a, _)| a)
+                .eq([0x200, 0x202, 0x204])
+        );
+        let mut out = Vec::new();
+        flow.write_dot(&mut out).unwrap();
+        assert!(
+            String::from_utf8(out)
+                .unwrap()
+                .contains("    \"0x208\" [shape=plaintext];\n")
+        );
+    }
+
+    #[test]
+    fn dot_output_marks_calls_and_computed_jumps() {
+        let mut out = Vec::new();
+        flow().write_dot(&mut out).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+        for line in [
+            "    \"0x200\" -> \"0x208\" [style=dashed, label=\"call\"];",
+            "    \"0x202\" -> \"0x206\" [label=\"skip\"];",
+            "    \"0x208\" [label=\"0x208  LD I, 0x210\\l0x20a  DRW V0, V1, 2\\l0x20c  RET\\l\", peripheries=2];",
+            "    \"0x20e\" [label=\"0x20e  JP V0, 0x210\\l\", color=red];",
+            "    \"0x20e?\" [shape=plaintext, label=\"V0 + 0x210\"];",
+        ] {
+            assert!(dot.contains(line), "{} not in {}", line, dot);
+        }
+        assert!(dot.starts_with("digraph cfg {\n") && dot.ends_with("}\n"));
+    }
+}
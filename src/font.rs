@@ -0,0 +1,225 @@
+//! Hex digit fonts of historical interpreters.
+//!
+//! The small font holds 4x5 glyphs for the digits 0-F, 5 bytes each, used by
+//! FX29. The big font holds SUPER-CHIP 8x10 glyphs, 10 bytes each, for 0-9 or
+//! 0-F.
+
+use crate::Error;
+use crate::consts::{BIG_FONT_DATA, FONT_DATA};
+
+/// Size of a small font.
+pub const SMALL_FONT_SIZE: usize = 80;
+/// Bytes per big font glyph.
+pub const BIG_GLYPH_SIZE: usize = 10;
+
+/// COSMAC VIP
+const VIP: [u8; SMALL_FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x60, 0x20, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0xA0, 0xA0, 0xF0, 0x20, 0x20, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x10, 0x10, 0x10, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xF0, 0x50, 0x70, 0x50, 0xF0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xF0, 0x50, 0x50, 0x50, 0xF0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+/// DREAM 6800
+const DREAM_6800: [u8; SMALL_FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x40, 0x40, 0x40, 0x40, 0x40, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0x80, 0xA0, 0xA0, 0xE0, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0xC0, 0xA0, 0xE0, 0xA0, 0xC0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+/// ETI-660
+const ETI_660: [u8; SMALL_FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x20, 0x20, 0x20, 0x20, 0x20, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0xA0, 0xA0, 0xE0, 0x20, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0x80, 0x80, 0xE0, 0xA0, 0xE0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0x20, 0x20, 0xE0, 0xA0, 0xE0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+/// Octo, with the letters SUPER-CHIP lacks
+const OCTO_BIG: [u8; 160] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+/// Small fonts by name. SUPER-CHIP's font is the one most interpreters use,
+/// and Octo kept it.
+pub const SMALL_FONTS: [(&str, &[u8]); 5] = [
+    ("vip", &VIP),
+    ("dream6800", &DREAM_6800),
+    ("eti660", &ETI_660),
+    ("schip", &FONT_DATA),
+    ("octo", &FONT_DATA),
+];
+
+/// Big fonts by name.
+pub const BIG_FONTS: [(&str, &[u8]); 2] = [("schip", &BIG_FONT_DATA), ("octo", &OCTO_BIG)];
+
+pub fn small_font(name: &str) -> Option<&'static [u8]> {
+    SMALL_FONTS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, data)| *data)
+}
+
+pub fn big_font(name: &str) -> Option<&'static [u8]> {
+    BIG_FONTS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, data)| *data)
+}
+
+/// The fonts `State::new` installs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fonts {
+    small: Vec<u8>,
+    big: Vec<u8>,
+}
+
+impl Default for Fonts {
+    fn default() -> Self {
+        Fonts {
+            small: FONT_DATA.to_vec(),
+            big: BIG_FONT_DATA.to_vec(),
+        }
+    }
+}
+
+impl Fonts {
+    pub fn small(&self) -> &[u8] {
+        &self.small
+    }
+
+    pub fn big(&self) -> &[u8] {
+        &self.big
+    }
+
+    /// Replace the small font, which must have all 16 glyphs.
+    pub fn set_small(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        if data.len() != SMALL_FONT_SIZE {
+            return Err(Error::InvalidFont(format!(
+                "small font is {} bytes, expected {}",
+                data.len(),
+                SMALL_FONT_SIZE
+            )));
+        }
+        self.small = data;
+        Ok(())
+    }
+
+    /// Replace the big font, which may stop after any glyph.
+    pub fn set_big(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        if data.is_empty()
+            || !data.len().is_multiple_of(BIG_GLYPH_SIZE)
+            || data.len() > 16 * BIG_GLYPH_SIZE
+        {
+            return Err(Error::InvalidFont(format!(
+                "big font is {} bytes, expected up to 16 glyphs of {}",
+                data.len(),
+                BIG_GLYPH_SIZE
+            )));
+        }
+        self.big = data;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryLayout;
+    use crate::state::State;
+
+    #[test]
+    fn fonts_are_found_by_name() {
+        for (name, data) in SMALL_FONTS {
+            assert_eq!(small_font(name), Some(data));
+            assert_eq!(data.len(), SMALL_FONT_SIZE);
+        }
+        assert_eq!(big_font("octo").map(<[u8]>::len), Some(16 * BIG_GLYPH_SIZE));
+        assert_eq!(small_font("chip48"), None);
+        assert_eq!(big_font("vip"), None);
+    }
+
+    #[test]
+    fn set_checks_the_font_size() {
+        let mut fonts = Fonts::default();
+        assert!(matches!(
+            fonts.set_small(vec![0; 79]),
+            Err(Error::InvalidFont(_))
+        ));
+        for size in [0, 15, 17 * BIG_GLYPH_SIZE] {
+            assert!(matches!(
+                fonts.set_big(vec![0; size]),
+                Err(Error::InvalidFont(_))
+            ));
+        }
+        fonts.set_big(vec![0xFF; 10 * BIG_GLYPH_SIZE]).unwrap();
+        assert_eq!(fonts.big().len(), 100);
+    }
+
+    #[test]
+    fn fonts_are_installed_at_the_layout_addresses() {
+        let mut fonts = Fonts::default();
+        fonts.set_small(VIP.to_vec()).unwrap();
+        fonts.set_big(OCTO_BIG.to_vec()).unwrap();
+        let layout = MemoryLayout {
+            font_address: 0x100,
+            big_font_address: 0x000,
+            ..MemoryLayout::default()
+        };
+        let state = State::new(layout, &fonts);
+        assert_eq!(&state.ram[0x100..0x150], &VIP);
+        assert_eq!(&state.ram[..160], &OCTO_BIG);
+    }
+}
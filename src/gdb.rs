@@ -0,0 +1,480 @@
+//! Stub for the GDB remote serial protocol, so GDB or another client that
+//! speaks it can drive the debugger over TCP.
+//!
+//! ```text
+//! secrus8 debug --gdb 1234 game.ch8
+//! (gdb) target remote :1234
+//! ```
+//!
+//! The registers are V0-VF (8 bits each), I and PC (16 bits, little-endian),
+//! then the stack depth and the delay and sound timers (8 bits each), in
+//! that order. Memory addresses are RAM addresses. Supported packets are
+//! register and memory reads and writes, software breakpoints (`Z0`/`z0`),
+//! single step, continue, reverse step and continue, and interrupting a
+//! running program with Ctrl-C. Register and memory writes clear the history
+//! reverse execution runs back through.
+
+use crate::debugger::{Debugger, StopReason};
+use crate::hex;
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+/// Instructions run between checks for an interrupt from the client.
+const INTERRUPT_POLL_INTERVAL: u64 = 10_000;
+
+/// Number of registers, in the order described in the module docs.
+const REGISTER_COUNT: usize = 21;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.secrus8.chip8">
+    <reg name="v0" bitsize="8"/><reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/><reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/><reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/><reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/><reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/><reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/><reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/><reg name="vf" bitsize="8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="dt" bitsize="8"/>
+    <reg name="st" bitsize="8"/>
+  </feature>
+</target>"#;
+
+/// Signal numbers reported in stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// Stop reply when running backwards reaches the oldest undoable step.
+const HISTORY_START_REPLY: &str = "T05replaylog:begin;";
+
+pub struct GdbStub {
+    debugger: Debugger,
+    no_ack: bool,
+}
+
+impl GdbStub {
+    pub fn new(debugger: Debugger) -> Self {
+        GdbStub {
+            debugger,
+            no_ack: false,
+        }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    /// Answer packets from `stream` until the client detaches, kills the
+    /// program or disconnects.
+    pub fn serve(&mut self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        while let Some(packet) = self.read_packet(&mut reader, &mut writer)? {
+            let reply = match packet.as_str() {
+                "c" => Some(self.resume(&mut reader, false)?),
+                "bc" => Some(self.resume(&mut reader, true)?),
+                "D" | "k" => {
+                    self.send(&mut writer, "OK")?;
+                    return Ok(());
+                }
+                _ => self.handle(&packet),
+            };
+            self.send(&mut writer, reply.as_deref().unwrap_or(""))?;
+        }
+        Ok(())
+    }
+
+    /// The reply to a packet that doesn't run the program for long.
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let (command, args) = packet.split_at_checked(1)?;
+        let reply = match command {
+            "?" => stop_reply(SIGTRAP),
+            "g" => (0..REGISTER_COUNT)
+                .map(|n| self.register(n).unwrap_or_default())
+                .collect(),
+            "G" => {
+                let mut rest = args;
+                for n in 0..REGISTER_COUNT {
+                    let width = register_width(n) * 2;
+                    let (value, tail) = rest.split_at_checked(width)?;
+                    self.set_register(n, value)?;
+                    rest = tail;
+                }
+                self.debugger.interpreter_mut().clear_undo();
+                "OK".to_string()
+            }
+            "p" => self.register(usize::from_str_radix(args, 16).ok()?)?,
+            "P" => {
+                let (n, value) = args.split_once('=')?;
+                self.set_register(usize::from_str_radix(n, 16).ok()?, value)?;
+                self.debugger.interpreter_mut().clear_undo();
+                "OK".to_string()
+            }
+            "m" => {
+                let (address, len) = parse_address_length(args)?;
+                let ram = &self.debugger.interpreter().state().ram;
+                match ram.get(address..address.checked_add(len)?) {
+                    Some(bytes) => hex::encode(bytes),
+                    None => "E01".to_string(),
+                }
+            }
+            "M" => {
+                let (range, data) = args.split_once(':')?;
+                let (address, len) = parse_address_length(range)?;
+                let bytes = hex::decode(data).filter(|b| b.len() == len)?;
+                let interpreter = self.debugger.interpreter_mut();
+                match interpreter
+                    .state_mut()
+                    .ram
+                    .get_mut(address..address.checked_add(len)?)
+                {
+                    Some(target) => {
+                        target.copy_from_slice(&bytes);
+                        interpreter.clear_undo();
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "b" if args == "s" => match self.debugger.reverse_step() {
+                StopReason::HistoryStart => HISTORY_START_REPLY.to_string(),
+                _ => stop_reply(SIGTRAP),
+            },
+            "s" => match self.debugger.step() {
+                Ok(StopReason::Halt) => "W00".to_string(),
+                Ok(StopReason::Trap(_)) | Err(_) => stop_reply(SIGILL),
+                Ok(_) => stop_reply(SIGTRAP),
+            },
+            "Z" | "z" => {
+                let address = args
+                    .strip_prefix("0,")?
+                    .split(',')
+                    .next()
+                    .and_then(|a| u16::from_str_radix(a, 16).ok())?;
+                if command == "Z" {
+                    self.debugger.add_breakpoint(address);
+                } else {
+                    self.debugger.remove_breakpoint(address);
+                }
+                "OK".to_string()
+            }
+            "H" | "T" => "OK".to_string(),
+            _ => return self.query(packet),
+        };
+        Some(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> Option<String> {
+        let reply = match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ if packet.starts_with("qSupported") => {
+                "PacketSize=1000;QStartNoAckMode+;qXfer:features:read+;ReverseStep+;ReverseContinue+"
+                    .to_string()
+            }
+            _ => {
+                let args = packet.strip_prefix("qXfer:features:read:target.xml:")?;
+                let (offset, length) = parse_address_length(args)?;
+                let chunk = TARGET_XML.get(offset..).unwrap_or("");
+                if chunk.len() > length {
+                    format!("m{}", &chunk[..length])
+                } else {
+                    format!("l{}", chunk)
+                }
+            }
+        };
+        Some(reply)
+    }
+
+    /// Run until a breakpoint, the program stops, or the client interrupts,
+    /// backwards if `reverse`.
+    fn resume(&mut self, reader: &mut BufReader<TcpStream>, reverse: bool) -> io::Result<String> {
+        loop {
+            let result = if reverse {
+                Ok(self.debugger.reverse_resume(INTERRUPT_POLL_INTERVAL))
+            } else {
+                self.debugger.resume(INTERRUPT_POLL_INTERVAL)
+            };
+            match result {
+                Ok(StopReason::Limit) => {}
+                Ok(StopReason::HistoryStart) => return Ok(HISTORY_START_REPLY.to_string()),
+                Ok(StopReason::Halt) => return Ok("W00".to_string()),
+                Ok(StopReason::Trap(_)) | Err(_) => return Ok(stop_reply(SIGILL)),
+                Ok(_) => return Ok(stop_reply(SIGTRAP)),
+            }
+            if interrupted(reader)? {
+                return Ok(stop_reply(SIGINT));
+            }
+        }
+    }
+
+    fn register(&self, n: usize) -> Option<String> {
+        let state = self.debugger.interpreter().state();
+        let value = match n {
+            0..16 => state.registers[n] as u16,
+            16 => state.index_register,
+            17 => state.pc,
+            18 => state.stack.len() as u16,
+            19 => state.delay_timer as u16,
+            20 => state.sound_timer as u16,
+            _ => return None,
+        };
+        Some(hex::encode(&value.to_le_bytes()[..register_width(n)]))
+    }
+
+    /// Set register `n` from little-endian hex. The stack depth is read-only.
+    fn set_register(&mut self, n: usize, value: &str) -> Option<()> {
+        let bytes = hex::decode(value).filter(|b| b.len() == register_width(n))?;
+        let value = bytes.iter().rev().fold(0u16, |v, &b| v << 8 | b as u16);
+        let state = self.debugger.interpreter_mut().state_mut();
+        match n {
+            0..16 => state.registers[n] = value as u8,
+            16 => state.index_register = value,
+            17 => state.pc = value,
+            18 => {}
+            19 => state.delay_timer = value as u8,
+            20 => state.sound_timer = value as u8,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Read the next packet, acknowledging it, or None at end of stream.
+    fn read_packet(
+        &self,
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+    ) -> io::Result<Option<String>> {
+        loop {
+            let Some(byte) = read_byte(reader)? else {
+                return Ok(None);
+            };
+            if byte != b'$' {
+                // Acks, and interrupts arriving while already stopped
+                continue;
+            }
+            let mut data = Vec::new();
+            loop {
+                match read_byte(reader)? {
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            reader.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|c| u8::from_str_radix(c, 16).ok());
+            if !self.no_ack {
+                if expected != Some(checksum_of(&data)) {
+                    writer.write_all(b"-")?;
+                    continue;
+                }
+                writer.write_all(b"+")?;
+            }
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn send(&self, writer: &mut impl Write, data: &str) -> io::Result<()> {
+        write!(writer, "${}#{:02x}", data, checksum_of(data.as_bytes()))?;
+        writer.flush()
+    }
+}
+
+fn read_byte(reader: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match reader.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+/// Whether the client sent Ctrl-C, without waiting for input. Anything else
+/// the client sent is left to be read as a packet.
+fn interrupted(reader: &mut BufReader<TcpStream>) -> io::Result<bool> {
+    reader.get_ref().set_nonblocking(true)?;
+    let result = reader.fill_buf().map(|buffer| buffer.first().copied());
+    reader.get_ref().set_nonblocking(false)?;
+    match result {
+        Ok(Some(0x03)) => {
+            reader.consume(1);
+            Ok(true)
+        }
+        Ok(Some(_)) => Ok(false),
+        Ok(None) => Err(ErrorKind::UnexpectedEof.into()),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn register_width(n: usize) -> usize {
+    match n {
+        16 | 17 => 2,
+        _ => 1,
+    }
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn parse_address_length(text: &str) -> Option<(usize, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((
+        usize::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::tests::debugger;
+
+    use std::io::Cursor;
+
+    const PROGRAM: &str = "\
+        LD V0, 0x12
+        LD I, 0x345
+        ADD V0, 1
+    end:
+        JP end
+    ";
+
+    fn stub() -> GdbStub {
+        GdbStub::new(debugger(PROGRAM))
+    }
+
+    fn reply(stub: &mut GdbStub, packet: &str) -> String {
+        stub.handle(packet).unwrap_or_default()
+    }
+
+    #[test]
+    fn registers_are_little_endian_hex() {
+        let mut stub = stub();
+        assert_eq!(reply(&mut stub, "s"), "S05");
+        assert_eq!(reply(&mut stub, "s"), "S05");
+        let registers = reply(&mut stub, "g");
+        assert_eq!(registers.len(), 2 * (16 + 2 + 2 + 3));
+        assert!(registers.starts_with("12000000"));
+        assert_eq!(&registers[32..40], "45030402");
+        assert_eq!(reply(&mut stub, "p11"), "0402");
+        assert_eq!(reply(&mut stub, "p15"), "");
+
+        assert_eq!(reply(&mut stub, "P0=7f"), "OK");
+        assert_eq!(reply(&mut stub, "P10=0003"), "OK");
+        assert_eq!(reply(&mut stub, "P10=03"), "");
+        let state = stub.debugger().interpreter().state();
+        assert_eq!((state.registers[0], state.index_register), (0x7F, 0x300));
+    }
+
+    #[test]
+    fn memory_reads_and_writes() {
+        let mut stub = stub();
+        assert_eq!(reply(&mut stub, "m200,4"), "6012a345");
+        assert_eq!(reply(&mut stub, "mfff,2"), "E01");
+        assert_eq!(reply(&mut stub, "M300,2:beef"), "OK");
+        assert_eq!(reply(&mut stub, "m300,2"), "beef");
+        assert_eq!(reply(&mut stub, "M300,2:be"), "");
+        assert_eq!(reply(&mut stub, "Mfff,2:0000"), "E01");
+    }
+
+    #[test]
+    fn breakpoints_steps_and_halts() {
+        let mut stub = stub();
+        assert_eq!(reply(&mut stub, "Z0,204,2"), "OK");
+        assert!(stub.debugger().breakpoints().eq([0x204]));
+        assert_eq!(reply(&mut stub, "z0,204,2"), "OK");
+        assert_eq!(stub.debugger().breakpoints().count(), 0);
+        for _ in 0..3 {
+            reply(&mut stub, "s");
+        }
+        assert_eq!(reply(&mut stub, "s"), "W00");
+        assert_eq!(reply(&mut stub, "bs"), "S05");
+        assert_eq!(reply(&mut stub, "x"), "");
+    }
+
+    #[test]
+    fn writes_clear_the_reverse_history() {
+        let mut stub = stub();
+        reply(&mut stub, "s");
+        reply(&mut stub, "s");
+        assert_eq!(stub.debugger().interpreter().undo_depth(), 2);
+        reply(&mut stub, "P11=0002");
+        assert_eq!(stub.debugger().interpreter().undo_depth(), 0);
+        assert_eq!(reply(&mut stub, "bs"), HISTORY_START_REPLY);
+
+        reply(&mut stub, "s");
+        reply(&mut stub, "M300,1:01");
+        assert_eq!(stub.debugger().interpreter().undo_depth(), 0);
+    }
+
+    #[test]
+    fn queries_describe_the_target() {
+        let mut stub = stub();
+        assert!(reply(&mut stub, "qSupported:xmlRegisters=i386").contains("ReverseStep+"));
+        let start = reply(&mut stub, "qXfer:features:read:target.xml:0,10");
+        assert_eq!(start, format!("m{}", &TARGET_XML[..0x10]));
+        let end = reply(
+            &mut stub,
+            &format!("qXfer:features:read:target.xml:{:x},1000", 0x10),
+        );
+        assert_eq!(end, format!("l{}", &TARGET_XML[0x10..]));
+        assert_eq!(reply(&mut stub, "qUnknown"), "");
+    }
+
+    #[test]
+    fn interrupt_checks_leave_packets_unread() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut reader = BufReader::new(listener.accept().unwrap().0);
+        assert!(!interrupted(&mut reader).unwrap());
+
+        client.write_all(b"\x03$g#67").unwrap();
+        drop(client);
+        reader.fill_buf().unwrap();
+        assert!(interrupted(&mut reader).unwrap());
+        assert!(!interrupted(&mut reader).unwrap());
+        let mut packet = String::new();
+        reader.read_to_string(&mut packet).unwrap();
+        assert_eq!(packet, "$g#67");
+    }
+
+    #[test]
+    fn packets_are_checked_and_acknowledged() {
+        let stub = stub();
+        let mut input = Cursor::new(b"+$g#00$g#67".to_vec());
+        let mut output = Vec::new();
+        assert_eq!(
+            stub.read_packet(&mut input, &mut output)
+                .unwrap()
+                .as_deref(),
+            Some("g")
+        );
+        assert_eq!(output, b"-+");
+        assert_eq!(stub.read_packet(&mut input, &mut output).unwrap(), None);
+
+        let mut sent = Vec::new();
+        stub.send(&mut sent, "OK").unwrap();
+        assert_eq!(sent, b"$OK#9a");
+    }
+}
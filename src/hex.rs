@@ -0,0 +1,33 @@
+//! Bytes as lowercase hex text, as used by crash dumps, the GDB protocol and
+//! ROM hashes.
+
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The bytes of `text`, or `None` if it isn't an even number of hex digits.
+pub(crate) fn decode(text: &str) -> Option<Vec<u8>> {
+    // from_str_radix alone would accept a sign, e.g. "+1"
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        assert_eq!(encode(&[0x00, 0xAB, 0x7F]), "00ab7f");
+        assert_eq!(decode("00ab7F"), Some(vec![0x00, 0xAB, 0x7F]));
+        assert_eq!(decode(""), Some(vec![]));
+        for invalid in ["abc", "zz", "+1", "é1"] {
+            assert_eq!(decode(invalid), None, "{}", invalid);
+        }
+    }
+}
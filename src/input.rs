@@ -0,0 +1,387 @@
+use crate::{Error, user_config_dir};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// Number of keys on the CHIP-8 hex keypad.
+pub const KEY_COUNT: usize = 16;
+
+pub type Keypad = [bool; KEY_COUNT];
+
+/// Source of keypad state, polled once per frame by the interpreter.
+pub trait InputBackend {
+    fn update(&mut self, frame: u64, keypad: &mut Keypad);
+
+    /// How far the input has been read, for rewinding it when steps are
+    /// undone, or `None` for live input that can't be replayed.
+    fn position(&self) -> Option<usize> {
+        None
+    }
+
+    /// Go back to a `position` returned earlier.
+    fn rewind(&mut self, _position: usize) {}
+}
+
+/// Mapping from keyboard characters to CHIP-8 keys.
+///
+/// The default is the usual layout, with the left side of a QWERTY keyboard
+/// standing in for the hex keypad:
+///
+/// ```text
+/// 1 2 3 4      1 2 3 C
+/// q w e r  ->  4 5 6 D
+/// a s d f      7 8 9 E
+/// z x c v      A 0 B F
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMap {
+    bindings: HashMap<char, u8>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        const LAYOUT: [(char, u8); KEY_COUNT] = [
+            ('1', 0x1),
+            ('2', 0x2),
+            ('3', 0x3),
+            ('4', 0xC),
+            ('q', 0x4),
+            ('w', 0x5),
+            ('e', 0x6),
+            ('r', 0xD),
+            ('a', 0x7),
+            ('s', 0x8),
+            ('d', 0x9),
+            ('f', 0xE),
+            ('z', 0xA),
+            ('x', 0x0),
+            ('c', 0xB),
+            ('v', 0xF),
+        ];
+        KeyMap {
+            bindings: LAYOUT.into_iter().collect(),
+        }
+    }
+}
+
+impl KeyMap {
+    /// Map without any bindings.
+    pub fn empty() -> Self {
+        KeyMap {
+            bindings: HashMap::new(),
+        }
+    }
+
+    /// Bind `key` to the CHIP-8 key `chip8_key` (0x0-0xF).
+    pub fn bind(&mut self, key: char, chip8_key: u8) {
+        self.bindings
+            .insert(key.to_ascii_lowercase(), chip8_key & 0xF);
+    }
+
+    pub fn get(&self, key: char) -> Option<u8> {
+        self.bindings.get(&key.to_ascii_lowercase()).copied()
+    }
+
+    /// Apply the bindings of `other` on top of this map.
+    pub fn merge(&mut self, other: &KeyMap) {
+        self.bindings
+            .extend(other.bindings.iter().map(|(&k, &v)| (k, v)));
+    }
+
+    /// Parse a single `key=hex` binding, as given on the command line.
+    pub fn parse_binding(text: &str) -> Result<(char, u8), Error> {
+        let invalid = || Error::InvalidKeyBinding(text.to_string());
+
+        let (key, value) = text.split_once('=').ok_or_else(invalid)?;
+        let key = match key.trim() {
+            "space" => ' ',
+            name => {
+                let mut chars = name.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => c,
+                    _ => return Err(invalid()),
+                }
+            }
+        };
+        let value = value.trim();
+        let value = value.strip_prefix("0x").unwrap_or(value);
+        let chip8_key = u8::from_str_radix(value, 16).map_err(|_| invalid())?;
+        if chip8_key as usize >= KEY_COUNT {
+            return Err(invalid());
+        }
+        Ok((key, chip8_key))
+    }
+
+    /// Parse a key map file: one `key = hex` binding per line, `#` comments.
+    ///
+    /// Only the listed keys are bound, so the result is usually merged over
+    /// the default layout.
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut map = KeyMap::empty();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, chip8_key) = Self::parse_binding(line)?;
+            map.bind(key, chip8_key);
+        }
+        Ok(map)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Per-user key map, `$XDG_CONFIG_HOME/secrus8/keymap.conf` (falling back
+    /// to `~/.config`).
+    pub fn user_config_path() -> Option<PathBuf> {
+        user_config_dir().map(|dir| dir.join("keymap.conf"))
+    }
+
+    /// Per-ROM key map, stored next to the ROM as `<name>.keymap`.
+    pub fn rom_config_path(rom: impl AsRef<Path>) -> PathBuf {
+        rom.as_ref().with_extension("keymap")
+    }
+
+    /// Apply the per-user and then the per-ROM key map, if they exist.
+    pub fn merge_config_files(&mut self, rom: impl AsRef<Path>) -> io::Result<()> {
+        let layers = Self::user_config_path()
+            .into_iter()
+            .chain([Self::rom_config_path(rom)]);
+        for path in layers {
+            if path.exists() {
+                self.merge(&Self::load(&path)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Characters from a stream of UTF-8 bytes, so keys typed as several
+/// bytes, like `é` on an AZERTY keyboard, can be looked up in a [`KeyMap`].
+#[derive(Debug, Default)]
+pub(crate) struct Utf8Decoder {
+    bytes: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    /// Add a byte, returning the character it completes. Invalid sequences
+    /// are dropped.
+    pub(crate) fn push(&mut self, byte: u8) -> Option<char> {
+        self.bytes.push(byte);
+        match std::str::from_utf8(&self.bytes) {
+            Ok(text) => {
+                let c = text.chars().next();
+                self.bytes.clear();
+                c
+            }
+            // The rest of the sequence is still to come
+            Err(e) if e.error_len().is_none() => None,
+            Err(_) => {
+                // The byte may start a sequence of its own
+                let retry = self.bytes.len() > 1;
+                self.bytes.clear();
+                if retry { self.push(byte) } else { None }
+            }
+        }
+    }
+}
+
+/// Keypad keys held down by terminal key presses.
+///
+/// Terminals report key presses (and auto-repeat) but no releases, so a key
+/// is considered held for a few frames after its last press.
+#[derive(Debug, Clone, Default)]
+pub struct HeldKeys {
+    frames: [u8; KEY_COUNT],
+}
+
+impl HeldKeys {
+    /// Frames a key stays down after a press, long enough to bridge the
+    /// terminal's auto-repeat interval.
+    const HOLD_FRAMES: u8 = 8;
+
+    pub fn press(&mut self, key: u8) {
+        self.frames[key as usize & 0xF] = Self::HOLD_FRAMES;
+    }
+
+    /// Write the held keys to `keypad`, then count down a frame.
+    pub fn update(&mut self, keypad: &mut Keypad) {
+        for (pressed, held) in keypad.iter_mut().zip(self.frames.iter_mut()) {
+            *pressed = *held > 0;
+            *held = held.saturating_sub(1);
+        }
+    }
+}
+
+/// The terminal in unbuffered, no-echo mode, restored when dropped.
+pub(crate) struct RawMode {
+    saved_mode: Option<String>,
+}
+
+impl RawMode {
+    pub(crate) fn enter() -> Self {
+        let saved_mode = stty(&["-g"]).map(|mode| mode.trim().to_string());
+        if saved_mode.is_some() {
+            stty(&["-icanon", "-echo", "min", "1"]);
+        }
+        RawMode { saved_mode }
+    }
+
+    /// Leave the terminal as it is, for tests.
+    #[cfg(test)]
+    pub(crate) fn unchanged() -> Self {
+        RawMode { saved_mode: None }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(mode) = &self.saved_mode {
+            stty(&[mode]);
+        }
+    }
+}
+
+/// Bytes typed on stdin, read on a background thread.
+pub(crate) fn read_stdin() -> Receiver<u8> {
+    let (sender, keys) = mpsc::channel();
+    thread::spawn(move || {
+        for byte in io::stdin().lock().bytes() {
+            match byte {
+                Ok(byte) if sender.send(byte).is_ok() => {}
+                _ => break,
+            }
+        }
+    });
+    keys
+}
+
+/// Reads key presses from the terminal.
+pub struct TerminalInput {
+    keymap: KeyMap,
+    keys: Receiver<u8>,
+    decoder: Utf8Decoder,
+    held: HeldKeys,
+    _mode: RawMode,
+}
+
+impl TerminalInput {
+    /// Put the terminal in unbuffered, no-echo mode and start reading keys.
+    pub fn new(keymap: KeyMap) -> Self {
+        let mode = RawMode::enter();
+        TerminalInput {
+            keymap,
+            keys: read_stdin(),
+            decoder: Utf8Decoder::default(),
+            held: HeldKeys::default(),
+            _mode: mode,
+        }
+    }
+}
+
+impl InputBackend for TerminalInput {
+    fn update(&mut self, _frame: u64, keypad: &mut Keypad) {
+        while let Ok(byte) = self.keys.try_recv() {
+            if let Some(c) = self.decoder.push(byte)
+                && let Some(key) = self.keymap.get(c)
+            {
+                self.held.press(key);
+            }
+        }
+        self.held.update(keypad);
+    }
+}
+
+/// Run `stty` on the controlling terminal, returning its output on success.
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_layout_covers_the_keypad() {
+        let keymap = KeyMap::default();
+        assert_eq!(keymap.get('1'), Some(0x1));
+        assert_eq!(keymap.get('4'), Some(0xC));
+        assert_eq!(keymap.get('x'), Some(0x0));
+        assert_eq!(keymap.get('V'), Some(0xF));
+        assert_eq!(keymap.get('p'), None);
+    }
+
+    #[test]
+    fn bindings_parse_with_names_and_prefixes() {
+        assert_eq!(KeyMap::parse_binding("k=a"), Ok(('k', 0xA)));
+        assert_eq!(KeyMap::parse_binding(" space = 0x5 "), Ok((' ', 0x5)));
+        for text in ["k", "kk=1", "=1", "k=10", "k=g"] {
+            assert_eq!(
+                KeyMap::parse_binding(text),
+                Err(Error::InvalidKeyBinding(text.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn key_map_files_merge_over_the_default() {
+        let azerty = KeyMap::parse("# AZERTY\na = 4\n\nz = 5 # top row\n").unwrap();
+        assert_eq!(azerty.get('a'), Some(0x4));
+        assert_eq!(azerty.get('q'), None);
+
+        let mut keymap = KeyMap::default();
+        keymap.merge(&azerty);
+        assert_eq!(keymap.get('a'), Some(0x4));
+        assert_eq!(keymap.get('z'), Some(0x5));
+        assert_eq!(keymap.get('q'), Some(0x4));
+        assert!(KeyMap::parse("a = 4\nb\n").is_err());
+    }
+
+    #[test]
+    fn held_keys_are_released_after_a_few_frames() {
+        let mut held = HeldKeys::default();
+        let mut keypad = [false; KEY_COUNT];
+        held.press(0x5);
+        for _ in 0..HeldKeys::HOLD_FRAMES {
+            held.update(&mut keypad);
+            assert!(keypad[0x5]);
+        }
+        held.update(&mut keypad);
+        assert!(!keypad[0x5]);
+    }
+
+    #[test]
+    fn multibyte_keys_are_decoded() {
+        let keymap = KeyMap::parse("é = 2\n").unwrap();
+        let mut decoder = Utf8Decoder::default();
+        let typed: Vec<char> = "aé€"
+            .bytes()
+            .filter_map(|byte| decoder.push(byte))
+            .collect();
+        assert_eq!(typed, ['a', 'é', '€']);
+        assert_eq!(keymap.get(typed[1]), Some(0x2));
+
+        // A broken sequence is dropped without losing the next key
+        assert_eq!(decoder.push(0xC3), None);
+        assert_eq!(decoder.push(b'q'), Some('q'));
+        assert_eq!(decoder.push(0xA9), None);
+        assert_eq!(decoder.push(b'w'), Some('w'));
+    }
+}
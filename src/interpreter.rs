@@ -1,20 +1,163 @@
-use crate::Result;
-use crate::consts::INITIAL_PC;
-use crate::display::CLIDisplay;
+use crate::audio::AudioOutput;
+use crate::consts::FRAMES_PER_SECOND;
+use crate::coverage::Coverage;
+use crate::display::{CLIDisplay, sprite_pixels};
+use crate::font::Fonts;
+use crate::input::{InputBackend, Keypad};
+use crate::memory::MemoryLayout;
 use crate::parser::Instruction;
+use crate::profile::Profiler;
+use crate::quirks::Quirks;
 use crate::state::State;
-use rand::Rng;
+use crate::syscall::{SysCallHandler, SysCallPolicy};
+use crate::trace::{TraceRecord, TraceSink};
+use crate::{Error, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
 use std::thread::sleep;
 
-enum StepResult {
+pub const DEFAULT_IPS: u32 = 700;
+
+/// Number of executed instructions remembered for crash reports.
+pub const DEFAULT_HISTORY_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepResult {
     Continue,
+    /// The program jumped to itself, the usual way CHIP-8 programs end
     Halt,
+    /// An unknown opcode at the given address was skipped by the trap policy
+    Trap(u16),
+}
+
+/// What executing a word that isn't a valid instruction does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownOpcodePolicy {
+    /// Fail with `Error::UnknownOpcode`
+    #[default]
+    Strict,
+    /// Skip it and carry on
+    Lenient,
+    /// Skip it and stop, returning `StepResult::Trap`
+    Trap,
+}
+
+impl UnknownOpcodePolicy {
+    pub const ALL: [UnknownOpcodePolicy; 3] = [
+        UnknownOpcodePolicy::Strict,
+        UnknownOpcodePolicy::Lenient,
+        UnknownOpcodePolicy::Trap,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            UnknownOpcodePolicy::Strict => "strict",
+            UnknownOpcodePolicy::Lenient => "lenient",
+            UnknownOpcodePolicy::Trap => "trap",
+        }
+    }
+}
+
+impl fmt::Display for UnknownOpcodePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for UnknownOpcodePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        UnknownOpcodePolicy::ALL
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| Error::UnknownOpcodePolicy(s.to_string()))
+    }
+}
+
+/// Something that happened during execution that the front end may want to
+/// tell the user about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The word at `address` is not a valid instruction
+    UnknownOpcode { address: u16, opcode: u16 },
+}
+
+pub type EventHandler = Box<dyn FnMut(&Event)>;
+
+/// Everything a step may change, saved before it runs so it can be undone.
+struct Undo {
+    pc: u16,
+    stack: Vec<u16>,
+    registers: [u8; 16],
+    index_register: u16,
+    delay_timer: u8,
+    sound_timer: u8,
+    keypad: Keypad,
+    cycle: u64,
+    frame: u64,
+    frame_cycles: u32,
+    wait_for_vblank: bool,
+    pending_key: Option<u8>,
+    /// Address and previous value of each RAM write, in order
+    ram: Vec<(u16, u8)>,
+    /// Pixels the step flipped
+    pixels: Vec<(u8, u8)>,
+    /// Position of the input backend
+    input: Option<usize>,
+    /// The instruction and its accesses, for `last_*` after undoing it
+    current: (u16, u16),
+    reads: Vec<(u16, u8)>,
+    writes: Vec<(u16, u8)>,
 }
 
 pub struct Interpreter {
     state: State,
     display: CLIDisplay,
+    audio: Option<AudioOutput>,
+    input: Option<Box<dyn InputBackend>>,
+    quirks: Quirks,
+    sys_call_policy: SysCallPolicy,
+    sys_call_handlers: HashMap<u16, SysCallHandler>,
+    unknown_opcode_policy: UnknownOpcodePolicy,
+    event_handler: Option<EventHandler>,
+    rng: StdRng,
+    instructions_per_frame: u32,
+    /// Instructions executed so far
+    cycle: u64,
+    frame: u64,
+    /// Instructions executed in the current frame
+    frame_cycles: u32,
+    /// Set by DXYN with the vblank quirk to end the frame early
+    wait_for_vblank: bool,
+    /// Key seen pressed by FX0A, stored once it is released
+    pending_key: Option<u8>,
+    /// Address and opcode of the last instructions executed, oldest first
+    history: VecDeque<(u16, u16)>,
+    history_size: usize,
+    trace: Option<Box<dyn TraceSink>>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    /// Only instructions at these addresses are traced
+    trace_range: RangeInclusive<u16>,
+    /// Address and opcode of the instruction being executed
+    current: (u16, u16),
+    /// RAM reads and writes made by the instruction being executed
+    reads: Vec<(u16, u8)>,
+    writes: Vec<(u16, u8)>,
+    /// Previous values of the RAM written by the instruction being executed,
+    /// kept while undo is on
+    overwritten: Vec<(u16, u8)>,
+    /// Pixels flipped by the instruction being executed, kept while undo is on
+    flipped: Vec<(u8, u8)>,
+    /// Steps that can be undone, oldest first
+    undo_log: VecDeque<Undo>,
+    undo_limit: usize,
 }
 
 impl Default for Interpreter {
@@ -26,76 +169,541 @@ impl Default for Interpreter {
 impl Interpreter {
     pub fn new() -> Self {
         Interpreter {
-            state: State::new(),
+            state: State::default(),
             display: CLIDisplay::new(),
+            audio: None,
+            input: None,
+            quirks: Quirks::default(),
+            sys_call_policy: SysCallPolicy::default(),
+            sys_call_handlers: HashMap::new(),
+            unknown_opcode_policy: UnknownOpcodePolicy::default(),
+            event_handler: None,
+            rng: StdRng::from_os_rng(),
+            instructions_per_frame: DEFAULT_IPS / FRAMES_PER_SECOND,
+            cycle: 0,
+            frame: 0,
+            frame_cycles: 0,
+            wait_for_vblank: false,
+            pending_key: None,
+            history: VecDeque::new(),
+            history_size: DEFAULT_HISTORY_SIZE,
+            trace: None,
+            profiler: None,
+            coverage: None,
+            trace_range: 0..=u16::MAX,
+            current: (0, 0),
+            reads: Vec::new(),
+            writes: Vec::new(),
+            overwritten: Vec::new(),
+            flipped: Vec::new(),
+            undo_log: VecDeque::new(),
+            undo_limit: 0,
         }
     }
 
-    pub fn load_rom(&mut self, rom: Vec<u8>) {
-        let start = INITIAL_PC as usize;
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// How 0NNN machine code calls are handled.
+    pub fn set_sys_call_policy(&mut self, policy: SysCallPolicy) {
+        self.sys_call_policy = policy;
+    }
+
+    /// Run `handler` for 0NNN calls to `address` with the dispatch policy.
+    pub fn register_sys_call(&mut self, address: u16, handler: SysCallHandler) {
+        self.sys_call_handlers.insert(address, handler);
+    }
+
+    pub fn set_unknown_opcode_policy(&mut self, policy: UnknownOpcodePolicy) {
+        self.unknown_opcode_policy = policy;
+    }
+
+    /// Call `handler` for every `Event`, whatever the policies say.
+    pub fn set_event_handler(&mut self, handler: EventHandler) {
+        self.event_handler = Some(handler);
+    }
+
+    /// Remember the last `size` executed instructions.
+    pub fn set_history_size(&mut self, size: usize) {
+        self.history_size = size;
+        while self.history.len() > size {
+            self.history.pop_front();
+        }
+    }
+
+    /// Address and opcode of the most recently executed instructions, oldest
+    /// first. The last one is the instruction that failed, if any.
+    pub fn history(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.history.iter().copied()
+    }
+
+    /// Replace the remembered instructions, keeping the newest that fit.
+    pub(crate) fn set_history(&mut self, history: &[(u16, u16)]) {
+        let skip = history.len().saturating_sub(self.history_size);
+        self.history = history[skip..].iter().copied().collect();
+    }
+
+    /// Address and opcode of the last instruction executed.
+    pub fn last_instruction(&self) -> (u16, u16) {
+        self.current
+    }
+
+    /// Address and value of the RAM bytes read by the last instruction as
+    /// data, in order. Instruction fetches are not included.
+    pub fn last_reads(&self) -> &[(u16, u8)] {
+        &self.reads
+    }
+
+    /// Address and value of the RAM bytes written by the last instruction,
+    /// in order.
+    pub fn last_writes(&self) -> &[(u16, u8)] {
+        &self.writes
+    }
+
+    /// Keep enough of the last `steps` steps to undo them, or none with 0.
+    pub fn set_undo_limit(&mut self, steps: usize) {
+        self.undo_limit = steps;
+        while self.undo_log.len() > steps {
+            self.undo_log.pop_front();
+        }
+    }
+
+    /// Forget the steps that could be undone, e.g. after the state was edited
+    /// from outside, which undoing them would partly revert.
+    pub fn clear_undo(&mut self) {
+        self.undo_log.clear();
+    }
+
+    /// Number of steps that can currently be undone.
+    pub fn undo_depth(&self) -> usize {
+        self.undo_log.len()
+    }
+
+    /// Put the machine back as it was before the last step, returning false
+    /// if there is nothing to undo. Afterwards `last_instruction`,
+    /// `last_reads` and `last_writes` describe the undone instruction.
+    ///
+    /// Scripted input is rewound along with the machine; random numbers,
+    /// audio, traces, live input and RAM written by machine code call
+    /// handlers are not.
+    pub fn undo(&mut self) -> bool {
+        let Some(undo) = self.undo_log.pop_back() else {
+            return false;
+        };
+        for &(address, value) in undo.ram.iter().rev() {
+            self.state.ram[address as usize] = value;
+        }
+        for &(x, y) in &undo.pixels {
+            let on = self.display.pixel(x, y);
+            self.display.set_pixel(x, y, !on);
+        }
+        if let (Some(position), Some(input)) = (undo.input, self.input.as_mut()) {
+            input.rewind(position);
+        }
+        self.state.pc = undo.pc;
+        self.state.stack = undo.stack;
+        self.state.registers = undo.registers;
+        self.state.index_register = undo.index_register;
+        self.state.delay_timer = undo.delay_timer;
+        self.state.sound_timer = undo.sound_timer;
+        self.state.keypad = undo.keypad;
+        self.cycle = undo.cycle;
+        self.frame = undo.frame;
+        self.frame_cycles = undo.frame_cycles;
+        self.wait_for_vblank = undo.wait_for_vblank;
+        self.pending_key = undo.pending_key;
+        self.current = undo.current;
+        self.reads = undo.reads;
+        self.writes = undo.writes;
+        if self.history.back() == Some(&self.current) {
+            self.history.pop_back();
+        }
+        true
+    }
+
+    /// Record every executed instruction to `sink`.
+    pub fn set_trace(&mut self, sink: Box<dyn TraceSink>) {
+        self.trace = Some(sink);
+    }
+
+    /// Only trace instructions at addresses in `range`.
+    pub fn set_trace_range(&mut self, range: RangeInclusive<u16>) {
+        self.trace_range = range;
+    }
+
+    /// Count every executed instruction in `profiler`.
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Stop profiling, returning what was counted.
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    /// Record which bytes are executed and used as data in `coverage`.
+    pub fn set_coverage(&mut self, coverage: Coverage) {
+        self.coverage = Some(coverage);
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Execution speed, rounded down to a whole number of instructions per frame.
+    pub fn set_instructions_per_second(&mut self, ips: u32) {
+        self.instructions_per_frame = (ips / FRAMES_PER_SECOND).max(1);
+    }
+
+    pub fn instructions_per_second(&self) -> u32 {
+        self.instructions_per_frame * FRAMES_PER_SECOND
+    }
+
+    /// Make CXNN deterministic.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Poll `input` every frame to update the keypad state.
+    pub fn set_input(&mut self, input: Box<dyn InputBackend>) {
+        self.input = Some(input);
+    }
+
+    /// Replace the default stdout display, e.g. when stdout is used for audio.
+    pub fn set_display(&mut self, display: CLIDisplay) {
+        self.display = display;
+    }
+
+    /// Route the sound timer to `audio` instead of the terminal bell.
+    pub fn set_audio_output(&mut self, audio: AudioOutput) {
+        self.audio = Some(audio);
+    }
+
+    /// Reset the machine to a fresh state with `layout` and `fonts`, so
+    /// this must be called before loading a ROM.
+    pub fn set_memory(&mut self, layout: MemoryLayout, fonts: &Fonts) -> Result<()> {
+        layout.validate(fonts)?;
+        self.state = State::new(layout, fonts);
+        Ok(())
+    }
+
+    pub fn memory_layout(&self) -> MemoryLayout {
+        self.state.layout
+    }
+
+    /// Copy `rom` to the program start of the memory layout.
+    pub fn load_rom(&mut self, rom: Vec<u8>) -> Result<()> {
+        self.load_rom_at(rom, self.state.layout.program_start)
+    }
+
+    /// Copy `rom` to `address` and start executing from there.
+    pub fn load_rom_at(&mut self, rom: Vec<u8>, address: u16) -> Result<()> {
+        let start = address as usize;
         let end = start + rom.len();
+        if end > self.state.ram.len() {
+            return Err(Error::RomTooLarge(rom.len(), address));
+        }
         self.state.ram[start..end].copy_from_slice(&rom);
+        self.state.pc = address;
+        Ok(())
     }
 
-    pub fn run(&mut self) {
-        // --- Timing Configuration ---
-        const TARGET_FPS: u32 = 60;
-        const TARGET_IPS: u32 = 700;
-        const INSTRUCTIONS_PER_FRAME: u32 = TARGET_IPS / TARGET_FPS;
-        let frame_duration = std::time::Duration::from_secs_f32(1.0 / TARGET_FPS as f32);
+    /// Run in real time until the program halts, traps or fails.
+    pub fn run(&mut self) -> Result<StepResult> {
+        let frame_duration = std::time::Duration::from_secs_f32(1.0 / FRAMES_PER_SECOND as f32);
 
         // --- Main Emulator Loop ---
-        'main_loop: loop {
+        let result = loop {
             let frame_start = std::time::Instant::now();
 
-            for _ in 0..INSTRUCTIONS_PER_FRAME {
-                match self.step() {
-                    Ok(StepResult::Continue) => {}
-                    Ok(StepResult::Halt) => {
-                        println!("\nProgram finished. Exiting.");
-                        break 'main_loop;
-                    }
-                    Err(_) => {
-                        eprintln!("\nExecution error. Exiting.");
-                        break 'main_loop;
-                    }
-                }
+            match self.run_frame() {
+                Ok(StepResult::Continue) => {}
+                Ok(result) => break Ok(result),
+                Err(e) => break Err(e),
             }
 
-            self.update_timers();
-
             let elapsed = frame_start.elapsed();
             if let Some(sleep_time) = frame_duration.checked_sub(elapsed) {
                 sleep(sleep_time);
             }
+        };
+
+        self.finish_outputs();
+        result
+    }
+
+    /// Run up to `frames` frames as fast as possible, stopping early if the
+    /// program halts, traps or fails.
+    pub fn run_headless(&mut self, frames: u64) -> Result<StepResult> {
+        let mut result = Ok(StepResult::Continue);
+        for _ in 0..frames {
+            result = self.run_frame();
+            if !matches!(result, Ok(StepResult::Continue)) {
+                break;
+            }
+        }
+
+        self.finish_outputs();
+        result
+    }
+
+    /// Execute the rest of the current frame.
+    pub fn run_frame(&mut self) -> Result<StepResult> {
+        loop {
+            let result = self.step()?;
+            if result != StepResult::Continue {
+                return Ok(result);
+            }
+            if self.frame_cycles == 0 {
+                return Ok(StepResult::Continue);
+            }
+        }
+    }
+
+    /// Execute a single instruction.
+    ///
+    /// The input is polled before the first instruction of a frame, and the
+    /// timers tick after the last one.
+    pub fn step(&mut self) -> Result<StepResult> {
+        let undo = (self.undo_limit > 0).then(|| self.save_undo());
+
+        if self.frame_cycles == 0
+            && let Some(input) = self.input.as_mut()
+        {
+            input.update(self.frame, &mut self.state.keypad);
+        }
+
+        let result = self.execute();
+        if let Some(undo) = undo {
+            self.push_undo(undo);
+        }
+        let result = result?;
+        self.trace_instruction();
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(self.current.0, self.current.1);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(self.current.0, &self.reads, &self.writes);
+        }
+        self.cycle += 1;
+        self.frame_cycles += 1;
+
+        if self.frame_cycles >= self.instructions_per_frame || self.wait_for_vblank {
+            self.update_timers();
+            self.frame += 1;
+            self.frame_cycles = 0;
+            self.wait_for_vblank = false;
+        }
+
+        Ok(result)
+    }
+
+    /// Number of frames run so far.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Number of instructions executed so far.
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    /// Continue counting from `cycle` and `frame`, at the start of a frame.
+    pub(crate) fn set_counters(&mut self, cycle: u64, frame: u64) {
+        self.cycle = cycle;
+        self.frame = frame;
+        self.frame_cycles = 0;
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    pub fn display(&self) -> &CLIDisplay {
+        &self.display
+    }
+
+    pub fn display_mut(&mut self) -> &mut CLIDisplay {
+        &mut self.display
+    }
+
+    /// The state a step may change, before it runs.
+    fn save_undo(&mut self) -> Undo {
+        self.overwritten.clear();
+        self.flipped.clear();
+        Undo {
+            pc: self.state.pc,
+            stack: self.state.stack.clone(),
+            registers: self.state.registers,
+            index_register: self.state.index_register,
+            delay_timer: self.state.delay_timer,
+            sound_timer: self.state.sound_timer,
+            keypad: self.state.keypad,
+            cycle: self.cycle,
+            frame: self.frame,
+            frame_cycles: self.frame_cycles,
+            wait_for_vblank: self.wait_for_vblank,
+            pending_key: self.pending_key,
+            ram: Vec::new(),
+            pixels: Vec::new(),
+            input: self.input.as_ref().and_then(|input| input.position()),
+            current: (0, 0),
+            reads: Vec::new(),
+            writes: Vec::new(),
+        }
+    }
+
+    /// Log `undo` once the step has run.
+    fn push_undo(&mut self, mut undo: Undo) {
+        undo.ram = std::mem::take(&mut self.overwritten);
+        undo.pixels = std::mem::take(&mut self.flipped);
+        undo.current = self.current;
+        undo.reads = self.reads.clone();
+        undo.writes = self.writes.clone();
+        if self.undo_log.len() == self.undo_limit {
+            self.undo_log.pop_front();
+        }
+        self.undo_log.push_back(undo);
+    }
+
+    /// Flush the trace and audio outputs. [`Interpreter::run`] and
+    /// [`Interpreter::run_headless`] do this when they return.
+    pub fn finish_outputs(&mut self) {
+        if let Some(audio) = self.audio.as_mut()
+            && let Err(e) = audio.finish()
+        {
+            eprintln!("Audio output error: {}", e);
+        }
+        if let Some(trace) = self.trace.as_mut()
+            && let Err(e) = trace.finish()
+        {
+            eprintln!("Trace output error: {}", e);
+        }
+    }
+
+    fn trace_instruction(&mut self) {
+        let (pc, opcode) = self.current;
+        if let Some(trace) = self.trace.as_mut()
+            && self.trace_range.contains(&pc)
+        {
+            let record = TraceRecord::new(self.cycle, pc, opcode, &self.state, &self.writes);
+            if let Err(e) = trace.record(&record) {
+                eprintln!("Trace output error: {}", e);
+                self.trace = None;
+            }
         }
     }
 
-    fn step(&mut self) -> Result<StepResult> {
+    /// Load a data byte from RAM, remembering the read for watchpoints.
+    fn read_ram(&mut self, address: usize) -> Result<u8> {
+        let value = *self
+            .state
+            .ram
+            .get(address)
+            .ok_or(Error::MemoryOutOfBounds(address))?;
+        self.reads.push((address as u16, value));
+        Ok(value)
+    }
+
+    /// Store `value` in RAM, remembering the write for traces and watchpoints.
+    fn write_ram(&mut self, address: usize, value: u8) -> Result<()> {
+        let byte = self
+            .state
+            .ram
+            .get_mut(address)
+            .ok_or(Error::MemoryOutOfBounds(address))?;
+        if self.undo_limit > 0 {
+            self.overwritten.push((address as u16, *byte));
+        }
+        *byte = value;
+        self.writes.push((address as u16, value));
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Result<StepResult> {
         let instruction_address = self.state.pc;
 
-        let b1 = self.state.ram[self.state.pc as usize];
-        let b2 = self.state.ram[(self.state.pc + 1) as usize];
+        let fetch = |address: u16| self.state.ram.get(address as usize).copied();
+        let (Some(b1), Some(b2)) = (fetch(self.state.pc), fetch(self.state.pc.wrapping_add(1)))
+        else {
+            return Err(Error::PcOutOfBounds(instruction_address));
+        };
 
-        // Increment program counter by 2
+        // Increment program counter by 2, wrapping at the end of a 64 KiB
+        // address space like the index register does
 
-        self.state.pc += 2;
+        self.state.pc = self.state.pc.wrapping_add(2);
 
         let opcode = (b1 as u16) << 8 | (b2 as u16);
+        self.current = (instruction_address, opcode);
+        self.reads.clear();
+        self.writes.clear();
 
-        let instruction = Instruction::from_opcode(opcode)?;
+        if self.history_size > 0 {
+            if self.history.len() == self.history_size {
+                self.history.pop_front();
+            }
+            self.history.push_back((instruction_address, opcode));
+        }
+
+        let instruction = match Instruction::from_opcode(opcode) {
+            Ok(instruction) => instruction,
+            Err(e) => {
+                if let Some(handler) = self.event_handler.as_mut() {
+                    handler(&Event::UnknownOpcode {
+                        address: instruction_address,
+                        opcode,
+                    });
+                }
+                return match self.unknown_opcode_policy {
+                    UnknownOpcodePolicy::Strict => Err(e),
+                    UnknownOpcodePolicy::Lenient => Ok(StepResult::Continue),
+                    UnknownOpcodePolicy::Trap => Ok(StepResult::Trap(instruction_address)),
+                };
+            }
+        };
 
         match instruction {
+            Instruction::SysCall(address) => match self.sys_call_policy {
+                SysCallPolicy::Ignore => {}
+                SysCallPolicy::Error => return Err(Error::UnsupportedSysCall(address)),
+                SysCallPolicy::Dispatch => match self.sys_call_handlers.get_mut(&address) {
+                    Some(handler) => handler(&mut self.state)?,
+                    None => return Err(Error::UnsupportedSysCall(address)),
+                },
+            },
             Instruction::ClearScreen => {
+                if self.undo_limit > 0 {
+                    self.flipped.extend(self.display.lit_pixels());
+                }
                 self.display.clear();
             }
             Instruction::ReturnFromSubroutine => {
-                self.state.pc = self.state.stack.pop().expect("empty stack");
+                self.state.pc = self
+                    .state
+                    .stack
+                    .pop()
+                    .ok_or(Error::StackUnderflow(instruction_address))?;
             }
             Instruction::Jump(address) => {
                 if instruction_address == address {
+                    self.state.pc = address;
                     return Ok(StepResult::Halt);
                 }
+                self.state.pc = address;
             }
             Instruction::Call(address) => {
                 self.state.stack.push(self.state.pc);
@@ -103,17 +711,17 @@ impl Interpreter {
             }
             Instruction::SkipIfEqualByte(register, value) => {
                 if self.state.registers[register] == value {
-                    self.state.pc += 2
+                    self.state.pc = self.state.pc.wrapping_add(2);
                 }
             }
             Instruction::SkipIfNotEqualByte(register, value) => {
                 if self.state.registers[register] != value {
-                    self.state.pc += 2
+                    self.state.pc = self.state.pc.wrapping_add(2);
                 }
             }
             Instruction::SkipIfRegistersEqual(register_x, register_y) => {
                 if self.state.registers[register_x] == self.state.registers[register_y] {
-                    self.state.pc += 2
+                    self.state.pc = self.state.pc.wrapping_add(2);
                 }
             }
             Instruction::SetRegisterToValue(register, value) => {
@@ -128,12 +736,15 @@ impl Interpreter {
             }
             Instruction::RegistersBitwiseOr(register_x, register_y) => {
                 self.state.registers[register_x] |= self.state.registers[register_y];
+                self.reset_flag_after_logic();
             }
             Instruction::RegistersBitwiseAnd(register_x, register_y) => {
                 self.state.registers[register_x] &= self.state.registers[register_y];
+                self.reset_flag_after_logic();
             }
             Instruction::RegistersBitwiseXor(register_x, register_y) => {
                 self.state.registers[register_x] ^= self.state.registers[register_y];
+                self.reset_flag_after_logic();
             }
             Instruction::RegistersSumWithOverflow(register_x, register_y) => {
                 let vx = self.state.registers[register_x];
@@ -151,9 +762,10 @@ impl Interpreter {
                 self.state.registers[register_x] = diff;
                 self.state.registers[0xF] = if overflow { 0 } else { 1 };
             }
-            Instruction::ShiftRegisterBitsRight(register) => {
-                self.state.registers[0xF] = self.state.registers[register] & 1;
-                self.state.registers[register] >>= 1;
+            Instruction::ShiftRegisterBitsRight(register_x, register_y) => {
+                let value = self.shift_source(register_x, register_y);
+                self.state.registers[register_x] = value >> 1;
+                self.state.registers[0xF] = value & 1;
             }
             Instruction::SubtractRegisterValueFromRegister(register_x, register_y) => {
                 let vx = self.state.registers[register_x];
@@ -164,48 +776,83 @@ impl Interpreter {
                 // Set VF to 1 if there was NO borrow
                 self.state.registers[0xF] = if overflow { 0 } else { 1 };
             }
-            Instruction::ShiftRegisterBitsLeft(register) => {
-                self.state.registers[0xF] = self.state.registers[register] >> 7;
-                self.state.registers[register] <<= 1;
+            Instruction::ShiftRegisterBitsLeft(register_x, register_y) => {
+                let value = self.shift_source(register_x, register_y);
+                self.state.registers[register_x] = value << 1;
+                self.state.registers[0xF] = value >> 7;
             }
             Instruction::SkipIfRegistersNotEqual(register_x, register_y) => {
                 if self.state.registers[register_x] != self.state.registers[register_y] {
-                    self.state.pc += 2;
+                    self.state.pc = self.state.pc.wrapping_add(2);
                 }
             }
             Instruction::SetIndexRegisterToValue(value) => {
                 self.state.index_register = value;
             }
             Instruction::JumpByValue(value) => {
-                self.state.pc = self.state.registers[0] as u16 + value;
+                let register = if self.quirks.jump {
+                    ((value >> 8) & 0xF) as usize
+                } else {
+                    0
+                };
+                self.state.pc = self.state.registers[register] as u16 + value;
             }
             Instruction::SetRegisterToRandAndValue(register, value) => {
-                let mut rng = rand::rng();
-                let n: u8 = rng.random_range(0..=255);
+                let n: u8 = self.rng.random();
                 self.state.registers[register] = n & value;
             }
             Instruction::DrawSprite(register_x, register_y, sprite) => {
                 let x: u8 = self.state.registers[register_x];
                 let y: u8 = self.state.registers[register_y];
                 let start = self.state.index_register as usize;
-                let end = (self.state.index_register + sprite as u16) as usize;
+                let sprite = (start..start + sprite as usize)
+                    .map(|a| self.read_ram(a))
+                    .collect::<Result<Vec<u8>>>()?;
+                if self.undo_limit > 0 {
+                    self.flipped
+                        .extend(sprite_pixels(x, y, &sprite, self.quirks.wrap));
+                }
                 self.state.registers[0xF] = {
-                    if self.display.draw(x, y, &self.state.ram[start..end]) {
+                    if self.display.draw(x, y, &sprite, self.quirks.wrap) {
                         1
                     } else {
                         0
                     }
                 };
                 self.display.show();
+                self.wait_for_vblank = self.quirks.vblank;
             }
             Instruction::SkipIfKeyEqualsRegister(register) => {
-                unimplemented!();
+                let key = self.state.registers[register] & 0xF;
+                if self.state.keypad[key as usize] {
+                    self.state.pc = self.state.pc.wrapping_add(2);
+                }
             }
             Instruction::SkipIfKeyNotEqualsRegister(register) => {
-                unimplemented!();
+                let key = self.state.registers[register] & 0xF;
+                if !self.state.keypad[key as usize] {
+                    self.state.pc = self.state.pc.wrapping_add(2);
+                }
             }
+            Instruction::WaitForKeyPress(register) => match self.pending_key {
+                Some(key) if !self.state.keypad[key as usize] => {
+                    self.state.registers[register] = key;
+                    self.pending_key = None;
+                }
+                _ => {
+                    if self.pending_key.is_none() {
+                        self.pending_key = self
+                            .state
+                            .keypad
+                            .iter()
+                            .position(|&pressed| pressed)
+                            .map(|key| key as u8);
+                    }
+                    // Execute this instruction again until the key is released
+                    self.state.pc = self.state.pc.wrapping_sub(2);
+                }
+            },
             Instruction::SetRegisterToDelayTimerValue(register) => {
-                self.update_timers();
                 self.state.registers[register] = self.state.delay_timer;
             }
             Instruction::SetDelayTimerToRegisterValue(register) => {
@@ -216,50 +863,432 @@ impl Interpreter {
             }
             Instruction::SetIndexRegisterToSpriteForRegister(register) => {
                 let character = self.state.registers[register];
-                self.state.index_register = 0x50 + (character as u16 * 5);
+                self.state.index_register = self
+                    .state
+                    .layout
+                    .font_address
+                    .wrapping_add(character as u16 * 5);
             }
             Instruction::StoreBinaryCodedDecimalAtIndexRegisterValue(register) => {
                 let num = self.state.registers[register];
                 let i = self.state.index_register as usize;
                 // Hundreds digit
-                self.state.ram[i] = num / 100;
+                self.write_ram(i, num / 100)?;
                 // Tens digit
-                self.state.ram[i + 1] = (num / 10) % 10;
+                self.write_ram(i + 1, (num / 10) % 10)?;
                 // Ones digit
-                self.state.ram[i + 2] = num % 10;
+                self.write_ram(i + 2, num % 10)?;
             }
             Instruction::AddRegisterToIndexRegister(register) => {
-                self.state.index_register += self.state.registers[register] as u16;
+                self.state.index_register = self
+                    .state
+                    .index_register
+                    .wrapping_add(self.state.registers[register] as u16);
             }
             Instruction::DumpRegistersToMemoryAtIndexRegister(register) => {
                 for ri in 0..=register {
-                    self.state.ram[(self.state.index_register + ri as u16) as usize] =
-                        self.state.registers[ri];
+                    let address = self.state.index_register as usize + ri;
+                    self.write_ram(address, self.state.registers[ri])?;
                 }
+                self.advance_index_after_memory(register);
             }
             Instruction::LoadMemoryToRegistersAtIndexRegister(register) => {
                 for ri in 0..=register {
-                    self.state.registers[ri] =
-                        self.state.ram[(self.state.index_register + ri as u16) as usize];
+                    let address = self.state.index_register as usize + ri;
+                    self.state.registers[ri] = self.read_ram(address)?;
                 }
+                self.advance_index_after_memory(register);
             }
         }
 
         Ok(StepResult::Continue)
     }
 
+    /// Value shifted by 8XY6/8XYE: VX with the shift quirk, VY otherwise
+    fn shift_source(&self, register_x: usize, register_y: usize) -> u8 {
+        if self.quirks.shift {
+            self.state.registers[register_x]
+        } else {
+            self.state.registers[register_y]
+        }
+    }
+
+    fn reset_flag_after_logic(&mut self) {
+        if self.quirks.logic {
+            self.state.registers[0xF] = 0;
+        }
+    }
+
+    fn advance_index_after_memory(&mut self, register: usize) {
+        if self.quirks.memory_leave_i_unchanged {
+            return;
+        }
+        let step = if self.quirks.memory_increment_by_x {
+            register
+        } else {
+            register + 1
+        };
+        self.state.index_register = self.state.index_register.wrapping_add(step as u16);
+    }
+
     fn update_timers(&mut self) {
         if self.state.delay_timer > 0 {
             self.state.delay_timer -= 1;
         }
 
-        if self.state.sound_timer > 0 {
+        if let Some(audio) = self.audio.as_mut() {
+            if let Err(e) = audio.frame(self.state.sound_timer > 0) {
+                eprintln!("Audio output error: {}", e);
+                self.audio = None;
+            }
+        } else if self.state.sound_timer > 0 {
             // A simple terminal beep for sound feedback
             if self.state.sound_timer == 1 {
                 print!("\x07");
                 io::stdout().flush().unwrap();
             }
+        }
+
+        if self.state.sound_timer > 0 {
             self.state.sound_timer -= 1;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::Screen;
+    use crate::timeline::Timeline;
+
+    /// A headless machine running `rom` from the program start, one
+    /// instruction per frame.
+    fn machine(rom: &[u8]) -> Interpreter {
+        let mut core = Interpreter::new();
+        core.set_display(CLIDisplay::headless());
+        core.set_instructions_per_second(FRAMES_PER_SECOND);
+        core.load_rom(rom.to_vec()).unwrap();
+        core
+    }
+
+    fn run(core: &mut Interpreter, steps: usize) {
+        for _ in 0..steps {
+            core.step().unwrap();
+        }
+    }
+
+    /// Keypad states for consecutive frames, the last one held.
+    struct Keys(Vec<Keypad>);
+
+    impl InputBackend for Keys {
+        fn update(&mut self, frame: u64, keypad: &mut Keypad) {
+            let frame = (frame as usize).min(self.0.len() - 1);
+            *keypad = self.0[frame];
+        }
+    }
+
+    fn pressed(key: usize) -> Keypad {
+        let mut keypad = Keypad::default();
+        keypad[key] = true;
+        keypad
+    }
+
+    /// Everything a step may change that the tests can see.
+    #[derive(Debug, PartialEq)]
+    struct Snapshot {
+        ram: Vec<u8>,
+        stack: Vec<u16>,
+        pc: u16,
+        registers: [u8; 16],
+        index_register: u16,
+        timers: (u8, u8),
+        keypad: Keypad,
+        screen: Screen,
+        counters: (u64, u64),
+    }
+
+    fn snapshot(core: &Interpreter) -> Snapshot {
+        let state = core.state();
+        Snapshot {
+            ram: state.ram.clone(),
+            stack: state.stack.clone(),
+            pc: state.pc,
+            registers: state.registers,
+            index_register: state.index_register,
+            timers: (state.delay_timer, state.sound_timer),
+            keypad: state.keypad,
+            screen: *core.display().screen(),
+            counters: (core.cycle(), core.frame()),
+        }
+    }
+
+    #[test]
+    fn undoing_steps_restores_the_machine_they_ran_on() {
+        let rom = [
+            0x6A, 0x05, // LD VA, 5
+            0xFA, 0x29, // LD F, VA
+            0xD0, 0x05, // DRW V0, V0, 5
+            0xF1, 0x0A, // LD V1, K
+            0xA3, 0x00, // LD I, 0x300
+            0xF1, 0x33, // LD B, V1
+            0x22, 0x14, // CALL 0x214
+            0x00, 0xE0, // CLS
+            0xFA, 0x15, // LD DT, VA
+            0x12, 0x12, // JP 0x212
+            0xD0, 0x05, // DRW V0, V0, 5
+            0x00, 0xEE, // RET
+        ];
+        let mut core = machine(&rom);
+        core.set_input(Box::new(Timeline::new().tap(4, 0x7, 3)));
+        core.set_undo_limit(100);
+
+        let mut snapshots = Vec::new();
+        for _ in 0..20 {
+            snapshots.push(snapshot(&core));
+            core.step().unwrap();
+        }
+        let end = snapshot(&core);
+        assert_eq!(core.state().ram[0x300..0x303], [0, 0, 7]);
+        assert_eq!(core.state().pc, 0x212);
+        assert_eq!(core.undo_depth(), 20);
+
+        for expected in snapshots.iter().rev() {
+            assert!(core.undo());
+            assert_eq!(&snapshot(&core), expected);
+        }
+        assert!(!core.undo());
+
+        // Running again replays the same steps, key presses included
+        for expected in snapshots.iter().skip(1) {
+            core.step().unwrap();
+            assert_eq!(&snapshot(&core), expected);
+        }
+        core.step().unwrap();
+        assert_eq!(snapshot(&core), end);
+    }
+
+    #[test]
+    fn undo_keeps_the_last_steps_up_to_the_limit() {
+        // ADD V0, 1; JP 0x200
+        let mut core = machine(&[0x70, 0x01, 0x12, 0x00]);
+        core.set_undo_limit(3);
+        run(&mut core, 10);
+        assert_eq!(core.undo_depth(), 3);
+        while core.undo() {}
+        assert_eq!(core.state().registers[0], 4);
+        assert_eq!(core.cycle(), 7);
+
+        run(&mut core, 2);
+        core.clear_undo();
+        assert!(!core.undo());
+        assert_eq!(core.state().registers[0], 5);
+    }
+
+    #[test]
+    fn skip_if_key_tests_the_keypad() {
+        // LD V0, 5; SKP V0; LD V1, 1; LD V1, 2
+        let rom = [0x60, 0x05, 0xE0, 0x9E, 0x61, 0x01, 0x61, 0x02];
+        let mut core = machine(&rom);
+        core.set_input(Box::new(Keys(vec![pressed(5)])));
+        run(&mut core, 3);
+        assert_eq!(core.state().registers[1], 2);
+
+        let mut core = machine(&rom);
+        core.set_input(Box::new(Keys(vec![Keypad::default()])));
+        run(&mut core, 3);
+        assert_eq!(core.state().registers[1], 1);
+    }
+
+    #[test]
+    fn wait_for_key_stores_the_key_once_released() {
+        // LD V1, K; JP 0x202
+        let mut core = machine(&[0xF1, 0x0A, 0x12, 0x02]);
+        let none = Keypad::default();
+        core.set_input(Box::new(Keys(vec![none, pressed(5), pressed(5), none])));
+        run(&mut core, 3);
+        assert_eq!(core.state().pc, 0x200);
+        run(&mut core, 1);
+        assert_eq!(core.state().pc, 0x202);
+        assert_eq!(core.state().registers[1], 5);
+    }
+
+    fn with_quirks(rom: &[u8], list: &str) -> Interpreter {
+        let mut core = machine(rom);
+        let mut quirks = Quirks::default();
+        quirks.apply(list).unwrap();
+        core.set_quirks(quirks);
+        core
+    }
+
+    #[test]
+    fn shift_uses_vx_unless_the_quirk_is_off() {
+        // LD V1, 0x81; LD V2, 0x06; SHR V1, V2
+        let rom = [0x61, 0x81, 0x62, 0x06, 0x81, 0x26];
+        let mut core = machine(&rom);
+        run(&mut core, 3);
+        assert_eq!(core.state().registers[1], 0x40);
+        assert_eq!(core.state().registers[0xF], 1);
+
+        let mut core = with_quirks(&rom, "-shift");
+        run(&mut core, 3);
+        assert_eq!(core.state().registers[1], 0x03);
+        assert_eq!(core.state().registers[0xF], 0);
+    }
+
+    #[test]
+    fn store_leaves_i_unchanged_unless_the_quirk_is_off() {
+        // LD I, 0x300; LD [I], V2
+        let rom = [0xA3, 0x00, 0xF2, 0x55];
+        for (list, index) in [
+            ("", 0x300),
+            ("-memoryLeaveIUnchanged", 0x303),
+            ("-memoryLeaveIUnchanged,memoryIncrementByX", 0x302),
+        ] {
+            let mut core = with_quirks(&rom, list);
+            run(&mut core, 2);
+            assert_eq!(core.state().index_register, index, "{}", list);
+        }
+    }
+
+    #[test]
+    fn logic_and_jump_quirks() {
+        // LD VF, 1; OR V0, V1
+        let rom = [0x6F, 0x01, 0x80, 0x11];
+        let mut core = machine(&rom);
+        run(&mut core, 2);
+        assert_eq!(core.state().registers[0xF], 1);
+        let mut core = with_quirks(&rom, "logic");
+        run(&mut core, 2);
+        assert_eq!(core.state().registers[0xF], 0);
+
+        // LD V0, 2; LD V3, 4; JP V0, 0x300
+        let rom = [0x60, 0x02, 0x63, 0x04, 0xB3, 0x00];
+        let mut core = machine(&rom);
+        run(&mut core, 3);
+        assert_eq!(core.state().pc, 0x302);
+        let mut core = with_quirks(&rom, "jump");
+        run(&mut core, 3);
+        assert_eq!(core.state().pc, 0x304);
+    }
+
+    #[test]
+    fn vblank_ends_the_frame_after_drawing() {
+        // DRW V0, V0, 1; JP 0x200
+        let rom = [0xD0, 0x01, 0x12, 0x00];
+        let mut core = with_quirks(&rom, "vblank");
+        core.set_instructions_per_second(DEFAULT_IPS);
+        core.step().unwrap();
+        assert_eq!(core.frame(), 1);
+        let mut core = machine(&rom);
+        core.set_instructions_per_second(DEFAULT_IPS);
+        core.step().unwrap();
+        assert_eq!(core.frame(), 0);
+    }
+
+    #[test]
+    fn program_counter_wraps_at_the_end_of_64k() {
+        let mut core = machine(&[]);
+        let layout: MemoryLayout = "xochip".parse().unwrap();
+        core.set_memory(layout, &Fonts::default()).unwrap();
+        assert!(core.load_rom_at(vec![0; 6], 0xFFFC).is_err());
+        // LD V0, 1; SE V0, 1 at the end of memory, then LD V1, 2 at 0x0002
+        core.load_rom_at(vec![0x00, 0x00, 0x61, 0x02], 0).unwrap();
+        core.load_rom_at(vec![0x60, 0x01, 0x30, 0x01], 0xFFFC)
+            .unwrap();
+        run(&mut core, 2);
+        assert_eq!(core.state().pc, 0x0002);
+        run(&mut core, 1);
+        assert_eq!(core.state().registers[1], 2);
+    }
+
+    #[test]
+    fn sys_calls_follow_the_policy() {
+        // SYS 0x300; LD V1, 1
+        let rom = [0x03, 0x00, 0x61, 0x01];
+        let mut core = machine(&rom);
+        assert_eq!(core.step(), Err(Error::UnsupportedSysCall(0x300)));
+
+        let mut core = machine(&rom);
+        core.set_sys_call_policy(SysCallPolicy::Ignore);
+        run(&mut core, 2);
+        assert_eq!(core.state().registers[1], 1);
+
+        let mut core = machine(&rom);
+        core.set_sys_call_policy(SysCallPolicy::Dispatch);
+        core.register_sys_call(0x301, Box::new(|_| Ok(())));
+        assert_eq!(core.step(), Err(Error::UnsupportedSysCall(0x300)));
+
+        let mut core = machine(&rom);
+        core.set_sys_call_policy(SysCallPolicy::Dispatch);
+        core.register_sys_call(
+            0x300,
+            Box::new(|state| {
+                state.registers[0] = 0x42;
+                Ok(())
+            }),
+        );
+        run(&mut core, 2);
+        assert_eq!(core.state().registers[..2], [0x42, 1]);
+    }
+
+    #[test]
+    fn unknown_opcodes_follow_the_policy() {
+        // LD V1, 1; DW 0x5001; LD V1, 2
+        let rom = [0x61, 0x01, 0x50, 0x01, 0x61, 0x02];
+        let mut core = machine(&rom);
+        run(&mut core, 1);
+        assert_eq!(core.step(), Err(Error::UnknownOpcode(0x5001)));
+
+        let events = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut core = machine(&rom);
+        core.set_unknown_opcode_policy(UnknownOpcodePolicy::Lenient);
+        let log = events.clone();
+        core.set_event_handler(Box::new(move |event| log.borrow_mut().push(event.clone())));
+        run(&mut core, 3);
+        assert_eq!(core.state().registers[1], 2);
+        assert_eq!(
+            *events.borrow(),
+            [Event::UnknownOpcode {
+                address: 0x202,
+                opcode: 0x5001
+            }]
+        );
+
+        let mut core = machine(&rom);
+        core.set_unknown_opcode_policy(UnknownOpcodePolicy::Trap);
+        run(&mut core, 1);
+        assert_eq!(core.step(), Ok(StepResult::Trap(0x202)));
+        assert_eq!(core.state().pc, 0x204);
+    }
+
+    #[test]
+    fn unknown_opcode_policies_parse_from_their_names() {
+        for policy in UnknownOpcodePolicy::ALL {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+        assert!("loose".parse::<UnknownOpcodePolicy>().is_err());
+    }
+
+    #[test]
+    fn buggy_programs_fail_with_an_error() {
+        let error = |rom: &[u8], steps: usize| {
+            let mut core = machine(rom);
+            core.set_sys_call_policy(SysCallPolicy::Ignore);
+            run(&mut core, steps - 1);
+            core.step().unwrap_err()
+        };
+        assert_eq!(error(&[0x00, 0xEE], 1), Error::StackUnderflow(0x200));
+        // LD I, 0xFFF; LD B, V0
+        assert_eq!(
+            error(&[0xAF, 0xFF, 0xF0, 0x33], 2),
+            Error::MemoryOutOfBounds(0x1000)
+        );
+        // LD I, 0xFFF; DRW V0, V0, 15
+        assert_eq!(
+            error(&[0xAF, 0xFF, 0xD0, 0x0F], 2),
+            Error::MemoryOutOfBounds(0x1000)
+        );
+        // JP 0xFFE, then SYS 0 runs off the end of RAM
+        assert_eq!(error(&[0x1F, 0xFE], 3), Error::PcOutOfBounds(0x1000));
+    }
+}
@@ -1,18 +1,112 @@
+pub mod assembler;
+pub mod audio;
 pub mod consts;
+pub mod coverage;
+pub mod crash;
+pub mod dap;
+pub mod debugger;
+pub mod decompiler;
+pub mod disassembler;
 pub mod display;
+pub mod expression;
+pub mod flow;
+pub mod font;
+pub mod gdb;
+mod hex;
+pub mod input;
 pub mod interpreter;
-mod parser;
+pub mod lint;
+pub mod memory;
+pub mod octo;
+pub mod parser;
+pub mod profile;
+pub mod quirks;
+pub mod romdb;
+pub mod rominfo;
 pub mod state;
+pub mod symbols;
+pub mod syscall;
+pub mod timeline;
+pub mod trace;
+pub mod tui;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
     UnknownOpcode(u16),
+    InvalidKeyBinding(String),
+    InvalidTimeline(usize, String),
+    RomTooLarge(usize, u16),
+    UnknownPlatform(String),
+    UnknownQuirk(String),
+    Assembly(usize, String),
+    InvalidDatabase(String),
+    UnknownMemoryLayout(String),
+    InvalidMemoryLayout(String),
+    UnknownFont(String),
+    InvalidFont(String),
+    UnknownSysCallPolicy(String),
+    UnsupportedSysCall(u16),
+    StackUnderflow(u16),
+    MemoryOutOfBounds(usize),
+    PcOutOfBounds(u16),
+    UnknownOpcodePolicy(String),
+    InvalidCrashDump(String),
+    InvalidTrace(String),
+    InvalidExpression(String),
+    UnknownLint(String),
+    InvalidSymbols(usize, String),
 }
 
 impl core::fmt::Display for Error {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
-        match *self {
+        match self {
             Self::UnknownOpcode(code) => write!(f, "Unknown opcode: {:x}", code),
+            Self::InvalidKeyBinding(text) => {
+                write!(f, "Invalid key binding '{}', expected <key>=<hex>", text)
+            }
+            Self::InvalidTimeline(line, text) => write!(
+                f,
+                "Invalid timeline entry on line {}: '{}', expected 'frame <n> press|release <hex>'",
+                line, text
+            ),
+            Self::RomTooLarge(size, address) => write!(
+                f,
+                "ROM of {} bytes does not fit in memory at {:#05x}",
+                size, address
+            ),
+            Self::UnknownPlatform(name) => write!(f, "Unknown platform '{}'", name),
+            Self::UnknownQuirk(name) => write!(f, "Unknown quirk '{}'", name),
+            Self::Assembly(line, message) => write!(f, "Line {}: {}", line, message),
+            Self::InvalidDatabase(message) => write!(f, "Invalid ROM database: {}", message),
+            Self::UnknownMemoryLayout(name) => write!(f, "Unknown memory layout '{}'", name),
+            Self::InvalidMemoryLayout(message) => write!(f, "Invalid memory layout: {}", message),
+            Self::UnknownFont(name) => write!(f, "Unknown font '{}'", name),
+            Self::InvalidFont(message) => write!(f, "Invalid font: {}", message),
+            Self::UnknownSysCallPolicy(name) => {
+                write!(f, "Unknown machine code call policy '{}'", name)
+            }
+            Self::UnsupportedSysCall(address) => {
+                write!(f, "Unsupported machine code call to {:#05x}", address)
+            }
+            Self::StackUnderflow(address) => {
+                write!(f, "Return with an empty stack at {:#05x}", address)
+            }
+            Self::MemoryOutOfBounds(address) => {
+                write!(f, "Memory access outside RAM at {:#05x}", address)
+            }
+            Self::PcOutOfBounds(address) => {
+                write!(f, "Program counter {:#05x} is outside RAM", address)
+            }
+            Self::UnknownOpcodePolicy(name) => write!(f, "Unknown opcode policy '{}'", name),
+            Self::InvalidCrashDump(message) => write!(f, "Invalid crash dump: {}", message),
+            Self::InvalidTrace(message) => write!(f, "Invalid trace: {}", message),
+            Self::InvalidExpression(message) => write!(f, "Invalid expression: {}", message),
+            Self::UnknownLint(name) => write!(f, "Unknown lint '{}'", name),
+            Self::InvalidSymbols(line, text) => write!(
+                f,
+                "Invalid symbol on line {}: '{}', expected '<address> <name>' or '<address> <file>:<line>'",
+                line, text
+            ),
         }
     }
 }
@@ -20,3 +114,14 @@ impl core::fmt::Display for Error {
 impl core::error::Error for Error {}
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// Directory for per-user configuration, `$XDG_CONFIG_HOME/secrus8` (falling
+/// back to `~/.config/secrus8`).
+pub fn user_config_dir() -> Option<std::path::PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".config"))
+        })?;
+    Some(config_dir.join("secrus8"))
+}
@@ -0,0 +1,683 @@
+//! Static checks for common bugs in ROMs, built on [`ControlFlow`].
+//!
+//! Only what can be seen without running the program is checked. I is
+//! followed within a block from the last `LD I, NNN`, so memory accessed
+//! through an I computed elsewhere isn't checked, and code reached only
+//! through a BNNN jump may be reported as unreachable.
+
+use crate::Error;
+use crate::flow::{Access, AccessKind, Block, ControlFlow, Edge, RegionKind, sets_i};
+use crate::parser::Instruction;
+use crate::quirks::Platform;
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+/// Addresses listed in one warning before the rest are only counted.
+const LISTED_ADDRESSES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Kind {
+    /// Instructions no path from the entry point reaches
+    UnreachableCode,
+    /// A subroutine with no path to a return
+    NoReturn,
+    /// A return reached from the entry point without a call
+    ReturnWithoutCall,
+    /// Subroutines calling themselves, directly or through others
+    Recursion,
+    /// Control going into data, into the middle of an instruction, or
+    /// outside the ROM
+    BadJump,
+    /// DXYN reading a sprite past the end of RAM
+    SpritePastRam,
+    /// FX33 and FX55 writing over code, or FX65 loading it
+    DataOverlapsCode,
+    /// Opcodes whose result depends on a quirk
+    Quirk,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 8] = [
+        Kind::UnreachableCode,
+        Kind::NoReturn,
+        Kind::ReturnWithoutCall,
+        Kind::Recursion,
+        Kind::BadJump,
+        Kind::SpritePastRam,
+        Kind::DataOverlapsCode,
+        Kind::Quirk,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::UnreachableCode => "unreachable-code",
+            Kind::NoReturn => "no-return",
+            Kind::ReturnWithoutCall => "return-without-call",
+            Kind::Recursion => "recursion",
+            Kind::BadJump => "bad-jump",
+            Kind::SpritePastRam => "sprite-past-ram",
+            Kind::DataOverlapsCode => "data-overlaps-code",
+            Kind::Quirk => "quirk",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Kind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Kind::ALL
+            .into_iter()
+            .find(|k| k.name() == s)
+            .ok_or_else(|| Error::UnknownLint(s.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    /// Address of the instruction or region the warning is about
+    pub address: u16,
+    pub kind: Kind,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#05x}: {} [{}]", self.address, self.message, self.kind)
+    }
+}
+
+/// Check `rom`, loaded at `base` in `ram_size` bytes of RAM, and return the
+/// warnings by address.
+pub fn lint(rom: &[u8], base: u16, ram_size: usize) -> Vec<Warning> {
+    let flow = ControlFlow::analyze(rom, base);
+    let mut linter = Linter {
+        rom,
+        accesses: flow.accesses(),
+        flow,
+        ram_size: ram_size as u32,
+        warnings: Vec::new(),
+    };
+    linter.unreachable_code();
+    linter.subroutines();
+    linter.jumps();
+    linter.memory();
+    linter.quirks();
+
+    let mut warnings = linter.warnings;
+    warnings.sort_by_key(|w| (w.address, w.kind));
+    warnings
+}
+
+struct Linter<'a> {
+    rom: &'a [u8],
+    flow: ControlFlow,
+    accesses: Vec<Access>,
+    ram_size: u32,
+    warnings: Vec<Warning>,
+}
+
+impl Linter<'_> {
+    fn warn(&mut self, address: u16, kind: Kind, message: String) {
+        self.warnings.push(Warning {
+            address,
+            kind,
+            message,
+        });
+    }
+
+    /// Data regions starting with a run of instructions that ends in a
+    /// jump or return, where nothing points I.
+    fn unreachable_code(&mut self) {
+        let pointers: BTreeSet<u16> = self
+            .flow
+            .instructions()
+            .filter_map(|(_, word)| match Instruction::from_opcode(word) {
+                Ok(Instruction::SetIndexRegisterToValue(address)) => Some(address),
+                _ => None,
+            })
+            .collect();
+        let computed: Vec<u16> = self
+            .flow
+            .computed_jumps()
+            .iter()
+            .filter_map(|&address| self.flow.opcode_at(address))
+            .map(|word| word & 0x0FFF)
+            .collect();
+
+        for region in self.flow.regions() {
+            if region.kind != RegionKind::Data {
+                continue;
+            }
+
+            let mut address = region.start;
+            let mut count = 0;
+            let mut last = None;
+            while address.wrapping_add(1) < region.end {
+                let offset = address.wrapping_sub(self.flow.entry()) as usize;
+                let word = (self.rom[offset] as u16) << 8 | self.rom[offset + 1] as u16;
+                match Instruction::from_opcode(word) {
+                    Ok(Instruction::SysCall(_)) | Err(_) => break,
+                    Ok(instruction) => {
+                        count += 1;
+                        if matches!(
+                            instruction,
+                            Instruction::Jump(_) | Instruction::ReturnFromSubroutine
+                        ) {
+                            last = Some(instruction);
+                            break;
+                        }
+                    }
+                }
+                address = address.wrapping_add(2);
+            }
+            let span = region.start..address.wrapping_add(2);
+            if let Some(instruction) = last
+                && count >= 2
+                && pointers.range(span.clone()).next().is_none()
+                && !span
+                    .clone()
+                    .any(|a| self.accesses.iter().any(|x| x.contains(a)))
+                && !computed
+                    .iter()
+                    .any(|&target| (target..=target.saturating_add(0xFF)).contains(&region.start))
+            {
+                self.warn(
+                    region.start,
+                    Kind::UnreachableCode,
+                    format!(
+                        "{} instructions ending in {} at {:#05x} are never reached",
+                        count, instruction, address
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Blocks reachable from `entry` without following calls.
+    fn body(&self, entry: u16) -> Vec<&Block> {
+        let mut seen = BTreeSet::from([entry]);
+        let mut pending = VecDeque::from([entry]);
+        let mut blocks = Vec::new();
+        while let Some(address) = pending.pop_front() {
+            let Some(block) = self.flow.block(address) else {
+                continue;
+            };
+            blocks.push(block);
+            for &(target, edge) in &block.successors {
+                if edge != Edge::Call && seen.insert(target) {
+                    pending.push_back(target);
+                }
+            }
+        }
+        blocks
+    }
+
+    fn returns(&self, block: &Block) -> bool {
+        self.flow.opcode_at(block.last) == Some(0x00EE)
+    }
+
+    /// Subroutines that never return, returns without calls, and recursion.
+    fn subroutines(&mut self) {
+        let mut callers: BTreeMap<u16, Vec<u16>> = BTreeMap::new();
+        for block in self.flow.blocks() {
+            for &(target, edge) in &block.successors {
+                if edge == Edge::Call {
+                    callers.entry(target).or_default().push(block.last);
+                }
+            }
+        }
+
+        let mut calls: BTreeMap<u16, BTreeSet<u16>> = BTreeMap::new();
+        let mut warnings = Vec::new();
+        for (&entry, sites) in &callers {
+            if self.flow.block(entry).is_none() {
+                continue;
+            }
+            let body = self.body(entry);
+            let returns = body.iter().any(|block| self.returns(block));
+            let computed = body
+                .iter()
+                .any(|block| self.flow.computed_jumps().contains(&block.last));
+            if !returns && !computed {
+                warnings.push((
+                    entry,
+                    Kind::NoReturn,
+                    format!(
+                        "the subroutine called from {} never returns",
+                        list_addresses(sites)
+                    ),
+                ));
+            }
+            calls.insert(
+                entry,
+                body.iter()
+                    .flat_map(|block| &block.successors)
+                    .filter(|&&(_, edge)| edge == Edge::Call)
+                    .map(|&(target, _)| target)
+                    .collect(),
+            );
+        }
+
+        if !callers.contains_key(&self.flow.entry()) {
+            for block in self.body(self.flow.entry()) {
+                if self.returns(block) {
+                    warnings.push((
+                        block.last,
+                        Kind::ReturnWithoutCall,
+                        "RET is reached from the entry point without a call, so the stack \
+                         is empty"
+                            .to_string(),
+                    ));
+                }
+            }
+        }
+
+        let reach = |from: u16| {
+            let mut seen = BTreeSet::new();
+            let mut pending = vec![from];
+            while let Some(address) = pending.pop() {
+                for &callee in calls.get(&address).into_iter().flatten() {
+                    if seen.insert(callee) {
+                        pending.push(callee);
+                    }
+                }
+            }
+            seen
+        };
+        let mut reported = BTreeSet::new();
+        for &entry in calls.keys() {
+            let reachable = reach(entry);
+            if !reachable.contains(&entry) || reported.contains(&entry) {
+                continue;
+            }
+            let cycle: BTreeSet<u16> = reachable
+                .iter()
+                .copied()
+                .filter(|&other| reach(other).contains(&entry))
+                .collect();
+            reported.extend(cycle.iter().copied());
+            let names: Vec<String> = cycle.iter().map(|a| format!("{:#05x}", a)).collect();
+            let message = if self.always_recurses(entry, &cycle) {
+                format!(
+                    "the subroutine calls {} before it can return, so the stack overflows",
+                    names.join(" and ")
+                )
+            } else {
+                format!(
+                    "recursion through {} may overflow the stack, which holds 12 to 16 \
+                     return addresses on the original interpreters",
+                    names.join(" and ")
+                )
+            };
+            warnings.push((entry, Kind::Recursion, message));
+        }
+
+        for (address, kind, message) in warnings {
+            self.warn(address, kind, message);
+        }
+    }
+
+    /// Whether the path from `entry` without skips calls into `cycle`.
+    fn always_recurses(&self, entry: u16, cycle: &BTreeSet<u16>) -> bool {
+        let mut seen = BTreeSet::new();
+        let mut address = entry;
+        while seen.insert(address) {
+            let Some(block) = self.flow.block(address) else {
+                return false;
+            };
+            address = match *block.successors.as_slice() {
+                [(target, Edge::Call), _] if cycle.contains(&target) => return true,
+                [(_, Edge::Call), (next, Edge::Next)] => next,
+                [(target, Edge::Jump)] | [(target, Edge::Next)] => target,
+                _ => return false,
+            };
+        }
+        false
+    }
+
+    /// Control going outside the ROM or RAM, into the middle of an
+    /// instruction, or into memory used as data.
+    fn jumps(&mut self) {
+        let mut warnings = Vec::new();
+        for block in self.flow.blocks() {
+            let text = describe(self.flow.opcode_at(block.last).unwrap_or(0));
+            for &(target, edge) in &block.successors {
+                let verb = match edge {
+                    Edge::Next => "runs on into",
+                    Edge::Skip => "skips to",
+                    Edge::Jump => "jumps to",
+                    Edge::Call => "calls",
+                };
+                let problem = if target as u32 >= self.ram_size {
+                    "outside RAM".to_string()
+                } else if !self.flow.in_rom(target) {
+                    "outside the ROM".to_string()
+                } else if self.flow.is_instruction(target.wrapping_sub(1)) {
+                    format!(
+                        "the middle of the instruction at {:#05x}",
+                        target.wrapping_sub(1)
+                    )
+                } else if let Some(access) = self.accesses.iter().find(|a| a.contains(target)) {
+                    format!(
+                        "data used by {} at {:#05x}",
+                        describe(access.opcode),
+                        access.at
+                    )
+                } else {
+                    continue;
+                };
+                warnings.push((
+                    block.last,
+                    format!("{} {} {:#05x}, {}", text, verb, target, problem),
+                ));
+            }
+        }
+        for (address, message) in warnings {
+            self.warn(address, Kind::BadJump, message);
+        }
+    }
+
+    /// Sprites read past the end of RAM, and code written or loaded as data.
+    fn memory(&mut self) {
+        let mut warnings = Vec::new();
+        for access in &self.accesses {
+            let text = describe(access.opcode);
+            let range = format!("{:#05x}..{:#05x}", access.start, access.end);
+            if access.kind == AccessKind::Sprite && access.end > self.ram_size {
+                warnings.push((
+                    access.at,
+                    Kind::SpritePastRam,
+                    format!(
+                        "{} reads the sprite at {}, past the end of RAM at {:#05x}",
+                        text, range, self.ram_size
+                    ),
+                ));
+            }
+            let code = (access.start..access.end.min(0x10000))
+                .map(|address| address as u16)
+                .find(|&address| self.flow.is_code(address));
+            match (access.kind, code) {
+                (AccessKind::Write, Some(code)) => warnings.push((
+                    access.at,
+                    Kind::DataOverlapsCode,
+                    format!("{} writes {} over the code at {:#05x}", text, range, code),
+                )),
+                (AccessKind::Read, Some(code)) => warnings.push((
+                    access.at,
+                    Kind::DataOverlapsCode,
+                    format!("{} loads registers from the code at {:#05x}", text, code),
+                )),
+                _ => {}
+            }
+        }
+        for (address, kind, message) in warnings {
+            self.warn(address, kind, message);
+        }
+    }
+
+    /// Opcodes whose result differs between platforms. Each quirk gets one
+    /// warning listing where it matters.
+    fn quirks(&mut self) {
+        let mut uses: BTreeMap<&[&str], (BTreeSet<&str>, Vec<u16>)> = BTreeMap::new();
+        for block in self.flow.blocks() {
+            let instructions: Vec<(u16, Instruction)> = self
+                .flow
+                .block_instructions(block)
+                .into_iter()
+                .filter_map(|(address, word)| {
+                    Instruction::from_opcode(word).ok().map(|i| (address, i))
+                })
+                .collect();
+            for (index, &(address, instruction)) in instructions.iter().enumerate() {
+                let rest = || instructions[index + 1..].iter().map(|&(_, i)| i);
+                let quirks: &[&str] = match instruction {
+                    Instruction::ShiftRegisterBitsRight(x, y)
+                    | Instruction::ShiftRegisterBitsLeft(x, y)
+                        if x != y =>
+                    {
+                        &["shift"]
+                    }
+                    Instruction::DumpRegistersToMemoryAtIndexRegister(_)
+                    | Instruction::LoadMemoryToRegistersAtIndexRegister(_)
+                        if reads_i_before_setting(rest()) =>
+                    {
+                        &["memoryIncrementByX", "memoryLeaveIUnchanged"]
+                    }
+                    // With the quirk, BXNN adds VX, which is V0 when X is 0
+                    Instruction::JumpByValue(nnn) if nnn & 0xF00 != 0 => &["jump"],
+                    Instruction::RegistersBitwiseOr(..)
+                    | Instruction::RegistersBitwiseAnd(..)
+                    | Instruction::RegistersBitwiseXor(..)
+                        if reads_vf_before_setting(rest()) =>
+                    {
+                        &["logic"]
+                    }
+                    _ => continue,
+                };
+                let entry = uses.entry(quirks).or_default();
+                entry.0.insert(instruction.pattern());
+                entry.1.push(address);
+            }
+        }
+
+        for (quirks, (patterns, addresses)) in uses {
+            let patterns: Vec<&str> = patterns.into_iter().collect();
+            let quirks: Vec<String> = quirks
+                .iter()
+                .map(|quirk| format!("the {} quirk ({})", quirk, platforms_with(quirk)))
+                .collect();
+            self.warn(
+                addresses[0],
+                Kind::Quirk,
+                format!(
+                    "{} at {} depend{} on {}",
+                    patterns.join("/"),
+                    list_addresses(&addresses),
+                    if addresses.len() == 1 { "s" } else { "" },
+                    quirks.join(" and ")
+                ),
+            );
+        }
+    }
+}
+
+fn reads_i(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::DrawSprite(..)
+            | Instruction::AddRegisterToIndexRegister(_)
+            | Instruction::StoreBinaryCodedDecimalAtIndexRegisterValue(_)
+            | Instruction::DumpRegistersToMemoryAtIndexRegister(_)
+            | Instruction::LoadMemoryToRegistersAtIndexRegister(_)
+    )
+}
+
+fn reads_i_before_setting(mut rest: impl Iterator<Item = Instruction>) -> bool {
+    rest.find(|i| reads_i(i) || sets_i(i))
+        .is_some_and(|i| reads_i(&i))
+}
+
+fn reads_vf_before_setting(rest: impl Iterator<Item = Instruction>) -> bool {
+    const VF: u16 = 1 << 0xF;
+    for instruction in rest {
+        let (reads, writes) = registers(&instruction);
+        if reads & VF != 0 {
+            return true;
+        }
+        if writes & VF != 0 {
+            return false;
+        }
+    }
+    false
+}
+
+/// Registers `instruction` reads and writes, as bit masks. VF is only
+/// counted as written where every platform writes it.
+fn registers(instruction: &Instruction) -> (u16, u16) {
+    let v = |x: usize| 1u16 << x;
+    let up_to = |x: usize| ((2u32 << x) - 1) as u16;
+    let flag = v(0xF);
+    match *instruction {
+        Instruction::SkipIfEqualByte(x, _)
+        | Instruction::SkipIfNotEqualByte(x, _)
+        | Instruction::SkipIfKeyEqualsRegister(x)
+        | Instruction::SkipIfKeyNotEqualsRegister(x)
+        | Instruction::SetDelayTimerToRegisterValue(x)
+        | Instruction::SetSoundTimerToRegisterValue(x)
+        | Instruction::SetIndexRegisterToSpriteForRegister(x)
+        | Instruction::StoreBinaryCodedDecimalAtIndexRegisterValue(x)
+        | Instruction::AddRegisterToIndexRegister(x) => (v(x), 0),
+        Instruction::SkipIfRegistersEqual(x, y) | Instruction::SkipIfRegistersNotEqual(x, y) => {
+            (v(x) | v(y), 0)
+        }
+        Instruction::SetRegisterToValue(x, _)
+        | Instruction::SetRegisterToRandAndValue(x, _)
+        | Instruction::SetRegisterToDelayTimerValue(x)
+        | Instruction::WaitForKeyPress(x) => (0, v(x)),
+        Instruction::AddToRegister(x, _) => (v(x), v(x)),
+        Instruction::SetRegisterToRegisterValue(x, y) => (v(y), v(x)),
+        Instruction::RegistersBitwiseOr(x, y)
+        | Instruction::RegistersBitwiseAnd(x, y)
+        | Instruction::RegistersBitwiseXor(x, y) => (v(x) | v(y), v(x)),
+        Instruction::RegistersSumWithOverflow(x, y)
+        | Instruction::SubtractRegisterFromRegisterValue(x, y)
+        | Instruction::ShiftRegisterBitsRight(x, y)
+        | Instruction::SubtractRegisterValueFromRegister(x, y)
+        | Instruction::ShiftRegisterBitsLeft(x, y) => (v(x) | v(y), v(x) | flag),
+        Instruction::JumpByValue(_) => (v(0), 0),
+        Instruction::DrawSprite(x, y, _) => (v(x) | v(y), flag),
+        Instruction::DumpRegistersToMemoryAtIndexRegister(x) => (up_to(x), 0),
+        Instruction::LoadMemoryToRegistersAtIndexRegister(x) => (0, up_to(x)),
+        Instruction::SysCall(_)
+        | Instruction::ClearScreen
+        | Instruction::ReturnFromSubroutine
+        | Instruction::Jump(_)
+        | Instruction::Call(_)
+        | Instruction::SetIndexRegisterToValue(_) => (0, 0),
+    }
+}
+
+/// The platforms that turn `quirk` on, e.g. "on for schip".
+fn platforms_with(quirk: &str) -> String {
+    let on: Vec<&str> = Platform::ALL
+        .into_iter()
+        .filter(|p| p.quirks().enabled().contains(&quirk))
+        .map(Platform::name)
+        .collect();
+    if on.is_empty() {
+        "off on every platform".to_string()
+    } else {
+        format!("on for {}", on.join(", "))
+    }
+}
+
+fn describe(word: u16) -> String {
+    Instruction::from_opcode(word).map_or_else(|_| format!("{:04X}", word), |i| i.to_string())
+}
+
+/// The first few of `addresses`, then how many more there are.
+fn list_addresses(addresses: &[u16]) -> String {
+    let listed: Vec<String> = addresses
+        .iter()
+        .take(LISTED_ADDRESSES)
+        .map(|a| format!("{:#05x}", a))
+        .collect();
+    match addresses.len().saturating_sub(LISTED_ADDRESSES) {
+        0 => listed.join(", "),
+        more => format!("{} and {} more", listed.join(", "), more),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn warnings(source: &str) -> Vec<Warning> {
+        lint(&assemble(source, 0x200).unwrap(), 0x200, 0x1000)
+    }
+
+    fn kinds(source: &str) -> Vec<(u16, Kind)> {
+        warnings(source)
+            .into_iter()
+            .map(|warning| (warning.address, warning.kind))
+            .collect()
+    }
+
+    #[test]
+    fn finds_each_kind_of_bug() {
+        for (source, expected) in [
+            ("loop: JP loop", vec![]),
+            (
+                "JP end\n CLS\n JP end\nend: JP end",
+                vec![(0x202, Kind::UnreachableCode)],
+            ),
+            (
+                "CALL sub\nend: JP end\nsub: JP sub",
+                vec![(0x204, Kind::NoReturn)],
+            ),
+            ("RET", vec![(0x200, Kind::ReturnWithoutCall)]),
+            (
+                "CALL sub\nend: JP end\nsub: CALL sub\n RET",
+                vec![(0x204, Kind::Recursion)],
+            ),
+            ("JP 0x201", vec![(0x200, Kind::BadJump)]),
+            ("JP 0x300", vec![(0x200, Kind::BadJump)]),
+            (
+                "LD I, 0xFFF\n DRW V0, V0, 5\nend: JP end",
+                vec![(0x202, Kind::SpritePastRam)],
+            ),
+            (
+                "LD I, 0x200\n LD [I], V3\nend: JP end",
+                vec![(0x202, Kind::DataOverlapsCode)],
+            ),
+            (
+                "LD I, 0x300\n LD [I], V3\n LD [I], V3\nend: JP end",
+                vec![(0x202, Kind::Quirk)],
+            ),
+            ("SHR V0, V1\nend: JP end", vec![(0x200, Kind::Quirk)]),
+            (
+                "LD V0, 1\n JP V0, 0x204\nend: JP end",
+                vec![(0x202, Kind::Quirk)],
+            ),
+        ] {
+            assert_eq!(kinds(source), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn warnings_explain_themselves() {
+        let text: Vec<String> = warnings("LD I, 0xFFF\n DRW V0, V0, 5\nend: JP end\n CLS\n RET")
+            .iter()
+            .map(Warning::to_string)
+            .collect();
+        assert_eq!(
+            text,
+            [
+                "0x202: DRW V0, V0, 5 reads the sprite at 0xfff..0x1004, past the end of RAM \
+                 at 0x1000 [sprite-past-ram]",
+                "0x206: 2 instructions ending in RET at 0x208 are never reached [unreachable-code]",
+            ]
+        );
+    }
+
+    #[test]
+    fn kinds_parse_from_their_names() {
+        for kind in Kind::ALL {
+            assert_eq!(kind.name().parse::<Kind>().unwrap(), kind);
+        }
+        assert!(matches!(
+            "unreachable".parse::<Kind>(),
+            Err(Error::UnknownLint(_))
+        ));
+    }
+}
@@ -1,42 +1,944 @@
-use std::env;
-use std::fs::File;
-use std::io::{self, Read};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-use secrus8::interpreter::Interpreter;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+use secrus8::assembler::{assemble_program, parse_number, parse_range};
+use secrus8::audio::{self, AudioOutput, AudioSink, RawPcmSink, SquareWave, WavWriter};
+use secrus8::consts::FRAMES_PER_SECOND;
+use secrus8::coverage::Coverage;
+use secrus8::crash::CrashDump;
+use secrus8::dap::{DapServer, Launcher};
+use secrus8::debugger::Debugger;
+use secrus8::decompiler;
+use secrus8::disassembler::{disassemble, disassemble_flow, listing};
+use secrus8::display::{CLIDisplay, Renderer};
+use secrus8::flow::ControlFlow;
+use secrus8::font::{Fonts, big_font, small_font};
+use secrus8::gdb::GdbStub;
+use secrus8::input::{KeyMap, TerminalInput};
+use secrus8::interpreter::{DEFAULT_IPS, Event, Interpreter, StepResult, UnknownOpcodePolicy};
+use secrus8::lint::Kind as LintKind;
+use secrus8::memory::{MAX_RAM_SIZE, MemoryLayout};
+use secrus8::octo;
+use secrus8::profile::{DEFAULT_REPORT_ROWS, Profiler};
+use secrus8::quirks::Platform;
+use secrus8::romdb::{RomDatabase, RomInfo};
+use secrus8::rominfo::{analyze, guess_platform};
+use secrus8::symbols::Symbols;
+use secrus8::syscall::SysCallPolicy;
+use secrus8::timeline::Timeline;
+use secrus8::trace::{self, BinaryTrace, TextTrace, TraceSink};
+use secrus8::tui::Tui;
 
-    // Check that a filename was provided
-    if args.len() < 2 {
-        eprintln!("Usage: {} <filename>", args[0]);
-        std::process::exit(1);
+/// Exit code when the program halted normally.
+const EXIT_HALT: u8 = 0;
+/// Exit code when the program failed at runtime, e.g. on an unknown opcode.
+const EXIT_RUNTIME_ERROR: u8 = 1;
+/// Exit code when trace-diff finds a difference.
+const EXIT_DIFFERENT: u8 = 1;
+/// Exit code when lint finds a problem.
+const EXIT_WARNINGS: u8 = 1;
+/// Exit code for invalid arguments and unreadable files.
+const EXIT_USAGE: u8 = 2;
+
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  the program halted (jumped to itself), the session ended, or the traces
+     compared by trace-diff are the same
+  1  the program failed at runtime or trapped on an unknown opcode, the
+     traces differ, or lint found problems
+  2  invalid arguments or unreadable files";
+
+#[derive(Parser)]
+#[command(name = "secrus8", version, about = "A CHIP-8 interpreter", after_help = EXIT_CODES_HELP)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a ROM
+    Run {
+        rom: PathBuf,
+        #[command(flatten)]
+        machine: MachineArgs,
+        #[command(flatten)]
+        frontend: Box<FrontendArgs>,
+    },
+    /// Run a ROM in the interactive debugger
+    Debug {
+        #[arg(required_unless_present = "crash_dump")]
+        rom: Option<PathBuf>,
+        /// Inspect the machine saved in a crash dump instead of loading a ROM
+        #[arg(long, conflicts_with = "rom")]
+        crash_dump: Option<PathBuf>,
+        /// Wait for a GDB remote protocol client on this TCP port instead of
+        /// reading commands from the terminal
+        #[arg(long, value_name = "PORT")]
+        gdb: Option<u16>,
+        /// Symbol file naming addresses [default: the ROM's name with .sym, if
+        /// it exists]
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<PathBuf>,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// Serve the Debug Adapter Protocol on stdin and stdout, for editors
+    Dap {
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// Print the instructions of a ROM
+    Disasm {
+        rom: PathBuf,
+        /// Address the ROM is loaded at
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        load_address: u16,
+        /// Decode every word instead of following the code from the entry point
+        #[arg(long)]
+        linear: bool,
+        /// Write the control-flow graph in Graphviz DOT to this file
+        #[arg(long, value_name = "FILE")]
+        dot: Option<PathBuf>,
+        /// Symbol file naming addresses [default: the ROM's name with .sym, if
+        /// it exists]
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<PathBuf>,
+    },
+    /// Decompile a ROM into Octo source
+    Decompile {
+        rom: PathBuf,
+        /// Output source file, instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Address the ROM is loaded at
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        load_address: u16,
+    },
+    /// Assemble source, or Octo source ending in .8o, into a ROM
+    Asm {
+        source: PathBuf,
+        /// Output ROM file
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Also write the labels and source lines to a symbol file
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<PathBuf>,
+        /// Address the ROM will be loaded at
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        load_address: u16,
+    },
+    /// Print information about a ROM and guess its platform
+    Info {
+        rom: PathBuf,
+        /// Address the ROM is loaded at
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        load_address: u16,
+    },
+    /// Check a ROM for common bugs without running it
+    Lint {
+        rom: PathBuf,
+        /// Address the ROM is loaded at
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        load_address: u16,
+        /// Bytes of RAM
+        #[arg(long, default_value = "0x1000", value_parser = parse_ram_size)]
+        ram_size: usize,
+        /// Warnings to leave out, e.g. "quirk,unreachable-code"
+        #[arg(long, value_name = "KIND", value_delimiter = ',')]
+        allow: Vec<LintKind>,
+    },
+    /// Find where two traces written with --trace start to differ
+    TraceDiff {
+        first: PathBuf,
+        second: PathBuf,
+        /// Number of matching records to show before the difference
+        #[arg(long, default_value_t = 5)]
+        context: usize,
+    },
+}
+
+/// Options shared by everything that executes a ROM.
+#[derive(Args)]
+struct MachineArgs {
+    /// Platform whose behaviour to emulate: vip, chip8, schip or xochip
+    /// [default: from the ROM database, or guessed from the opcodes used]
+    #[arg(long)]
+    platform: Option<Platform>,
+    /// Quirks to turn on (or off with a leading '-') on top of the platform,
+    /// e.g. "shift,-vblank"
+    #[arg(long)]
+    quirks: Option<String>,
+    /// Instructions per second [default: from the ROM database, or 700]
+    #[arg(long)]
+    ips: Option<u32>,
+    /// What 0NNN machine code calls do: ignore, error, or dispatch to the
+    /// routines registered by programs embedding the interpreter
+    #[arg(long, default_value = "error")]
+    sys_calls: SysCallPolicy,
+    /// What words that aren't instructions do: strict (fail), lenient (skip
+    /// them) or trap (skip one and stop). Skipped words are logged to stderr
+    #[arg(long, default_value = "strict")]
+    unknown_opcodes: UnknownOpcodePolicy,
+    /// Seed for the random number generator, for reproducible runs
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Address the ROM is loaded at and execution starts from
+    /// [default: from the ROM database, or the program start of the memory
+    /// layout]
+    #[arg(long, value_parser = parse_address)]
+    load_address: Option<u16>,
+    /// Memory layout: standard, eti660 (programs at 0x600) or xochip (64 KiB
+    /// of RAM)
+    #[arg(long, default_value = "standard")]
+    memory: MemoryLayout,
+    /// Address of the small font, on top of the memory layout
+    #[arg(long, value_parser = parse_address)]
+    font_address: Option<u16>,
+    /// Address of the big font, on top of the memory layout
+    #[arg(long, value_parser = parse_address)]
+    big_font_address: Option<u16>,
+    /// Bytes of RAM, on top of the memory layout
+    #[arg(long, value_parser = parse_ram_size)]
+    ram_size: Option<usize>,
+    /// Small font: vip, dream6800, eti660, schip, octo, or a file with the 80
+    /// bytes of the glyphs 0-F [default: schip]
+    #[arg(long, value_name = "NAME|FILE")]
+    font: Option<String>,
+    /// Big font: schip, octo, or a file with 10 bytes per glyph [default: schip]
+    #[arg(long, value_name = "NAME|FILE")]
+    big_font: Option<String>,
+    /// Ignore the ROM database
+    #[arg(long)]
+    no_database: bool,
+}
+
+/// Options for the interactive terminal front end.
+#[derive(Args)]
+struct FrontendArgs {
+    /// Write a trace of the executed instructions to a file
+    #[arg(long)]
+    trace: Option<PathBuf>,
+    /// Trace file format
+    #[arg(long, value_enum, default_value_t = TraceFormat::Text, requires = "trace")]
+    trace_format: TraceFormat,
+    /// Only trace instructions in an address range, e.g. 0x200..0x300
+    #[arg(long, value_parser = parse_address_range, requires = "trace")]
+    trace_range: Option<RangeInclusive<u16>>,
+    /// Count executed instructions by address, kind and subroutine, and
+    /// write a report to a file ('-' for stderr) when the program ends
+    #[arg(long, value_name = "FILE")]
+    profile: Option<PathBuf>,
+    /// Record which bytes of the ROM are executed, used as data or never
+    /// touched, and write a report to a file when the program ends
+    #[arg(long, value_name = "FILE")]
+    coverage: Option<PathBuf>,
+    /// Coverage report format
+    #[arg(long, value_enum, default_value_t = CoverageFormat::Annotated, requires = "coverage")]
+    coverage_format: CoverageFormat,
+    /// Symbol file naming addresses in the trace and profile [default: the
+    /// ROM's name with .sym, if it exists]
+    #[arg(long, value_name = "FILE")]
+    symbols: Option<PathBuf>,
+    /// Where to save the machine state when the program fails [default:
+    /// secrus8-crash.json, or secrus8-crash-2.json and so on if it exists]
+    #[arg(long, value_name = "FILE")]
+    crash_dump: Option<PathBuf>,
+    /// How to draw the screen
+    #[arg(long, value_enum, default_value_t = RendererArg::Blocks)]
+    renderer: RendererArg,
+    /// Draw only the screen, without the panels for the registers, stack,
+    /// disassembly and memory or their shortcuts
+    #[arg(long)]
+    plain: bool,
+    /// Size of a CHIP-8 pixel in terminal characters
+    #[arg(long, default_value_t = 1)]
+    scale: usize,
+    /// Key map file applied over the default and configured key maps
+    #[arg(long)]
+    keymap: Option<PathBuf>,
+    /// Extra key binding, e.g. "k=5"
+    #[arg(long = "key", value_name = "KEY=HEX")]
+    keys: Vec<String>,
+    /// Script keypad input from a timeline file
+    #[arg(long)]
+    timeline: Option<PathBuf>,
+    /// Run as fast as possible without drawing, then print the final screen
+    #[arg(long)]
+    headless: bool,
+    /// Number of frames to run with --headless
+    #[arg(long, default_value_t = 600)]
+    frames: u64,
+    /// Record the sound to a WAV file
+    #[arg(long, conflicts_with = "pcm")]
+    wav: Option<PathBuf>,
+    /// Write raw S16_LE mono samples to stdout, drawing the screen on stderr
+    #[arg(long)]
+    pcm: bool,
+    /// Tone frequency in Hz
+    #[arg(long, default_value_t = audio::DEFAULT_PITCH, value_parser = parse_pitch)]
+    pitch: f32,
+    /// Tone volume between 0 and 1
+    #[arg(long, default_value_t = audio::DEFAULT_VOLUME)]
+    volume: f32,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TraceFormat {
+    /// One line per instruction
+    Text,
+    /// Compact records for long runs
+    Binary,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CoverageFormat {
+    /// Disassembly with the coverage of each line
+    Annotated,
+    /// lcov tracefile with addresses as line numbers
+    Lcov,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RendererArg {
+    Blocks,
+    HalfBlocks,
+    Ascii,
+    /// Don't draw the screen at all
+    None,
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    match parse_number(text) {
+        Some(address) if address <= 0xFFFF => Ok(address as u16),
+        _ => Err(format!("'{}' is not an address between 0 and 0xFFFF", text)),
+    }
+}
+
+fn parse_address_range(text: &str) -> Result<RangeInclusive<u16>, String> {
+    parse_range(text).ok_or_else(|| format!("'{}' is not an address range like 0x200..0x300", text))
+}
+
+fn parse_ram_size(text: &str) -> Result<usize, String> {
+    match parse_number(text) {
+        Some(size) if size as usize <= MAX_RAM_SIZE => Ok(size as usize),
+        _ => Err(format!(
+            "'{}' is not a RAM size up to {:#x}",
+            text, MAX_RAM_SIZE
+        )),
+    }
+}
+
+fn parse_pitch(text: &str) -> Result<f32, String> {
+    match text.parse::<f32>() {
+        Ok(pitch) if pitch.is_finite() && pitch > 0.0 => Ok(pitch),
+        _ => Err(format!("'{}' is not a positive frequency in Hz", text)),
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse_from(legacy_args());
+
+    let result = match cli.command {
+        Command::Run {
+            rom,
+            machine,
+            frontend,
+        } => run(&rom, &machine, &frontend),
+        Command::Debug {
+            rom,
+            crash_dump,
+            gdb,
+            symbols,
+            machine,
+        } => debug(
+            rom.as_deref(),
+            crash_dump.as_deref(),
+            gdb,
+            symbols.as_deref(),
+            &machine,
+        ),
+        Command::Dap { machine } => dap(machine),
+        Command::Disasm {
+            rom,
+            load_address,
+            linear,
+            dot,
+            symbols,
+        } => disasm(
+            &rom,
+            load_address,
+            linear,
+            dot.as_deref(),
+            symbols.as_deref(),
+        ),
+        Command::Decompile {
+            rom,
+            output,
+            load_address,
+        } => decompile(&rom, output.as_deref(), load_address),
+        Command::Asm {
+            source,
+            output,
+            symbols,
+            load_address,
+        } => asm(&source, &output, symbols.as_deref(), load_address),
+        Command::Info { rom, load_address } => info(&rom, load_address),
+        Command::Lint {
+            rom,
+            load_address,
+            ram_size,
+            allow,
+        } => lint(&rom, load_address, ram_size, &allow),
+        Command::TraceDiff {
+            first,
+            second,
+            context,
+        } => trace_diff(&first, &second, context),
+    };
+
+    match result {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("secrus8: {}", e);
+            ExitCode::from(EXIT_USAGE)
+        }
+    }
+}
+
+/// Accept the old `secrus8 <rom>` form as `secrus8 run <rom>`, unless the
+/// argument names a subcommand.
+fn legacy_args() -> Vec<String> {
+    let mut args: Vec<String> = std::env::args().collect();
+    if let Some(first) = args.get(1)
+        && !first.starts_with('-')
+        && !Cli::command()
+            .get_subcommands()
+            .any(|command| command.get_name() == first)
+        && Path::new(first).is_file()
+    {
+        args.insert(1, "run".to_string());
     }
+    args
+}
 
-    let filename = &args[1];
+/// The ROM database, warning about the entries left out.
+fn rom_database() -> io::Result<RomDatabase> {
+    let database = RomDatabase::with_user_overrides()?;
+    for warning in database.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+    Ok(database)
+}
 
-    // Open the file in read-only mode
-    let mut file = File::open(filename)?;
+/// Set up an interpreter for `rom`, taking settings from the command line
+/// first, then from the ROM database, then guessing from the ROM itself.
+fn build_interpreter(
+    rom: &Path,
+    machine: &MachineArgs,
+) -> Result<(Interpreter, Option<RomInfo>), Box<dyn Error>> {
+    interpreter_for(fs::read(rom)?, machine, machine.load_address)
+}
 
-    // Read the file contents into a buffer
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
+/// Like [`build_interpreter`] for ROM contents, loaded at `load_address` if
+/// given.
+fn interpreter_for(
+    bytes: Vec<u8>,
+    machine: &MachineArgs,
+    load_address: Option<u16>,
+) -> Result<(Interpreter, Option<RomInfo>), Box<dyn Error>> {
+    let rom_info = if machine.no_database {
+        None
+    } else {
+        rom_database()?.get(&bytes).cloned()
+    };
+    let known = rom_info.as_ref();
 
-    // You can now use `buffer` as your binary data
-    println!("Read {} bytes from file '{}'", buffer.len(), filename);
+    let mut layout = machine.memory;
+    layout.program_start = load_address
+        .or_else(|| known.and_then(|info| info.start_address))
+        .unwrap_or(layout.program_start);
+    if let Some(address) = machine.font_address {
+        layout.font_address = address;
+    }
+    if let Some(address) = machine.big_font_address {
+        layout.big_font_address = address;
+    }
+    if let Some(size) = machine.ram_size {
+        layout.ram_size = size;
+    }
+
+    let mut quirks = match (machine.platform, known.and_then(|info| info.quirks)) {
+        (Some(platform), _) => platform.quirks(),
+        (None, Some(quirks)) => quirks,
+        (None, None) => guess_platform(&bytes, layout.program_start)
+            .platform
+            .quirks(),
+    };
+    if let Some(list) = &machine.quirks {
+        quirks.apply(list)?;
+    }
 
-    // println!("Read bytes:");
-    // for (i, byte) in buffer.iter().take(buffer.len()).enumerate() {
-    //     if i % 8 == 0 {
-    //         println!();
-    //     }
-    //     print!("{:02X} ", byte);
-    // }
-    // println!();
+    let ips = machine
+        .ips
+        .or_else(|| {
+            known
+                .and_then(|info| info.tickrate)
+                .map(|t| t * FRAMES_PER_SECOND)
+        })
+        .unwrap_or(DEFAULT_IPS);
+
+    let mut fonts = Fonts::default();
+    if let Some(spec) = &machine.font {
+        fonts.set_small(font_data(spec, small_font)?)?;
+    }
+    if let Some(spec) = &machine.big_font {
+        fonts.set_big(font_data(spec, big_font)?)?;
+    }
 
     let mut core = Interpreter::new();
-    core.load_rom(buffer);
-    core.run();
+    core.set_memory(layout, &fonts)?;
+    core.set_quirks(quirks);
+    core.set_instructions_per_second(ips);
+    core.set_sys_call_policy(machine.sys_calls);
+    core.set_unknown_opcode_policy(machine.unknown_opcodes);
+    if machine.unknown_opcodes != UnknownOpcodePolicy::Strict {
+        core.set_event_handler(Box::new(|event| match event {
+            Event::UnknownOpcode { address, opcode } => {
+                eprintln!("Skipped unknown opcode {:04X} at {:#05x}", opcode, address)
+            }
+        }));
+    }
+    if let Some(seed) = machine.seed {
+        core.set_seed(seed);
+    }
+    core.load_rom(bytes)?;
+    Ok((core, rom_info))
+}
+
+/// A built-in font called `spec`, or the contents of the file `spec`.
+fn font_data(
+    spec: &str,
+    builtin: fn(&str) -> Option<&'static [u8]>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    match builtin(spec) {
+        Some(data) => Ok(data.to_vec()),
+        None if Path::new(spec).is_file() => Ok(fs::read(spec)?),
+        None => Err(secrus8::Error::UnknownFont(spec.to_string()).into()),
+    }
+}
+
+fn run(rom: &Path, machine: &MachineArgs, frontend: &FrontendArgs) -> Result<u8, Box<dyn Error>> {
+    let (mut core, rom_info) = build_interpreter(rom, machine)?;
+    let symbols = load_symbols(Some(rom), frontend.symbols.as_deref())?;
 
-    Ok(())
+    let renderer = match frontend.renderer {
+        RendererArg::Blocks => Some(Renderer::Blocks),
+        RendererArg::HalfBlocks => Some(Renderer::HalfBlocks),
+        RendererArg::Ascii => Some(Renderer::Ascii),
+        RendererArg::None => None,
+    };
+    // The full-screen view draws the screen itself along with its panels
+    let tui = renderer.is_some()
+        && !(frontend.headless || frontend.pcm || frontend.plain)
+        && io::stdin().is_terminal()
+        && io::stdout().is_terminal();
+    let display = if renderer.is_none() || frontend.headless || tui {
+        CLIDisplay::headless()
+    } else if frontend.pcm {
+        // stdout carries the samples, so draw the screen on stderr
+        CLIDisplay::with_output(Box::new(io::stderr()))
+    } else {
+        CLIDisplay::new()
+    };
+    core.set_display(
+        display
+            .with_renderer(renderer.unwrap_or_default())
+            .with_scale(frontend.scale),
+    );
+
+    let wave = SquareWave::default()
+        .with_pitch(frontend.pitch)
+        .with_volume(frontend.volume);
+    let sink: Option<Box<dyn AudioSink>> = if frontend.pcm {
+        Some(Box::new(RawPcmSink::stdout()))
+    } else if let Some(path) = &frontend.wav {
+        let file = BufWriter::new(File::create(path)?);
+        Some(Box::new(WavWriter::new(file, wave.sample_rate())?))
+    } else {
+        None
+    };
+    if let Some(sink) = sink {
+        core.set_audio_output(AudioOutput::new(wave, sink, FRAMES_PER_SECOND));
+    }
+
+    if let Some(path) = &frontend.trace {
+        let file = BufWriter::new(File::create(path)?);
+        let sink: Box<dyn TraceSink> = match frontend.trace_format {
+            TraceFormat::Text => Box::new(TextTrace::new(file)?.with_symbols(symbols.clone())),
+            TraceFormat::Binary => Box::new(BinaryTrace::new(file)?),
+        };
+        core.set_trace(sink);
+        if let Some(range) = &frontend.trace_range {
+            core.set_trace_range(range.clone());
+        }
+    }
+
+    if frontend.profile.is_some() {
+        core.set_profiler(Profiler::new());
+    }
+    if frontend.coverage.is_some() {
+        core.set_coverage(Coverage::new(core.state().ram.len()));
+    }
+
+    // Keys for the full-screen view to press, as it reads the terminal itself
+    let mut tui_keymap = None;
+    if let Some(path) = &frontend.timeline {
+        core.set_input(Box::new(Timeline::load(path)?));
+    } else if !frontend.headless && io::stdin().is_terminal() {
+        let mut keymap = KeyMap::default();
+        if let Some(info) = &rom_info {
+            keymap.merge(&info.keymap);
+        }
+        keymap.merge_config_files(rom)?;
+        if let Some(path) = &frontend.keymap {
+            keymap.merge(&KeyMap::load(path)?);
+        }
+        for binding in &frontend.keys {
+            let (key, chip8_key) = KeyMap::parse_binding(binding)?;
+            keymap.bind(key, chip8_key);
+        }
+        if tui {
+            tui_keymap = Some(keymap);
+        } else {
+            core.set_input(Box::new(TerminalInput::new(keymap)));
+        }
+    }
+
+    let result = if frontend.headless {
+        let result = core.run_headless(frontend.frames);
+        // Print the final screen so scripts can check it
+        print!("{}", core.display().render());
+        result
+    } else if tui {
+        let mut debugger = Debugger::new(core);
+        // Nothing steps backwards here, so don't pay for recording undo
+        debugger.interpreter_mut().set_undo_limit(0);
+        debugger.set_symbols(symbols.clone());
+        let mut view = Tui::new();
+        if let Some(keymap) = tui_keymap {
+            view = view.with_keymap(keymap);
+        }
+        let result = view.run(&mut debugger);
+        // Restore the terminal before printing anything
+        drop(view);
+        core = debugger.into_interpreter();
+        result
+    } else {
+        core.run()
+    };
+
+    if let (Some(path), Some(profiler)) = (&frontend.profile, core.profiler())
+        && let Err(e) = write_profile(path, profiler, &symbols)
+    {
+        eprintln!("Profile output error: {}", e);
+    }
+    if let (Some(path), Some(coverage)) = (&frontend.coverage, core.coverage())
+        && let Err(e) = write_coverage(path, frontend.coverage_format, rom, &core, coverage)
+    {
+        eprintln!("Coverage output error: {}", e);
+    }
+
+    match result {
+        Ok(StepResult::Trap(address)) => {
+            eprintln!("\nTrapped on unknown opcode at {:#05x}", address);
+            Ok(EXIT_RUNTIME_ERROR)
+        }
+        Ok(_) => Ok(EXIT_HALT),
+        Err(e) => {
+            let dump = CrashDump::capture(&core, &e);
+            eprint!("\n{}", dump);
+            let path = frontend
+                .crash_dump
+                .clone()
+                .unwrap_or_else(free_crash_dump_path);
+            match dump.save(&path) {
+                Ok(()) => eprintln!(
+                    "Crash dump written to {}, open it with 'secrus8 debug --crash-dump'",
+                    path.display()
+                ),
+                Err(e) => eprintln!("Could not write crash dump: {}", e),
+            }
+            Ok(EXIT_RUNTIME_ERROR)
+        }
+    }
+}
+
+/// The first default crash dump name not taken yet, so earlier dumps are kept.
+fn free_crash_dump_path() -> PathBuf {
+    let mut path = PathBuf::from("secrus8-crash.json");
+    let mut number = 1;
+    while path.exists() {
+        number += 1;
+        path = PathBuf::from(format!("secrus8-crash-{}.json", number));
+    }
+    path
+}
+
+/// The symbols in `path`, or else in the `.sym` file next to `rom`, if any.
+fn load_symbols(rom: Option<&Path>, path: Option<&Path>) -> io::Result<Symbols> {
+    match (path, rom.map(|rom| rom.with_extension("sym"))) {
+        (Some(path), _) => Symbols::load(path),
+        (None, Some(default)) if default.is_file() => Symbols::load(default),
+        (None, _) => Ok(Symbols::new()),
+    }
+}
+
+fn write_profile(path: &Path, profiler: &Profiler, symbols: &Symbols) -> io::Result<()> {
+    if path == Path::new("-") {
+        profiler.report(&mut io::stderr(), DEFAULT_REPORT_ROWS, symbols)
+    } else {
+        let mut out = BufWriter::new(File::create(path)?);
+        profiler.report(&mut out, DEFAULT_REPORT_ROWS, symbols)?;
+        out.flush()
+    }
+}
+
+/// Write the coverage of the ROM file `rom`, as loaded in `core`.
+fn write_coverage(
+    path: &Path,
+    format: CoverageFormat,
+    rom: &Path,
+    core: &Interpreter,
+    coverage: &Coverage,
+) -> io::Result<()> {
+    let bytes = fs::read(rom)?;
+    let base = core.memory_layout().program_start;
+    let mut out = BufWriter::new(File::create(path)?);
+    match format {
+        CoverageFormat::Annotated => coverage.write_annotated(&bytes, base, &mut out)?,
+        CoverageFormat::Lcov => {
+            coverage.write_lcov(&rom.display().to_string(), &bytes, base, &mut out)?
+        }
+    }
+    out.flush()
+}
+
+fn debug(
+    rom: Option<&Path>,
+    crash_dump: Option<&Path>,
+    gdb: Option<u16>,
+    symbols: Option<&Path>,
+    machine: &MachineArgs,
+) -> Result<u8, Box<dyn Error>> {
+    let mut core = match (rom, crash_dump) {
+        (_, Some(path)) => {
+            let dump = CrashDump::load(path)?;
+            let mut core = Interpreter::new();
+            dump.restore(&mut core)?;
+            eprint!("{}", dump);
+            core
+        }
+        (Some(rom), None) => build_interpreter(rom, machine)?.0,
+        (None, None) => unreachable!("clap requires a ROM or a crash dump"),
+    };
+    core.set_display(CLIDisplay::headless());
+
+    let mut debugger = Debugger::new(core);
+    debugger.set_symbols(load_symbols(rom, symbols)?);
+    if let Some(port) = gdb {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("Waiting for GDB on port {}", port);
+        let (stream, peer) = listener.accept()?;
+        eprintln!("GDB connected from {}", peer);
+        GdbStub::new(debugger).serve(stream)?;
+        return Ok(EXIT_HALT);
+    }
+    debugger.repl(io::stdin().lock(), &mut io::stdout())?;
+    Ok(EXIT_HALT)
+}
+
+fn dap(machine: MachineArgs) -> Result<u8, Box<dyn Error>> {
+    let launcher: Launcher = Box::new(move |rom, load_address| {
+        let (mut core, _) = interpreter_for(
+            rom.to_vec(),
+            &machine,
+            load_address.or(machine.load_address),
+        )?;
+        core.set_display(CLIDisplay::headless());
+        Ok(core)
+    });
+    let mut server = DapServer::new(io::stdout(), launcher);
+    server.serve(io::BufReader::new(io::stdin()))?;
+    Ok(EXIT_HALT)
+}
+
+/// Read a ROM for static analysis, checking that it fits in `ram_size`
+/// bytes when loaded at `load_address`.
+fn read_rom(rom: &Path, load_address: u16, ram_size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let bytes = fs::read(rom)?;
+    if load_address as usize + bytes.len() > ram_size {
+        return Err(secrus8::Error::RomTooLarge(bytes.len(), load_address).into());
+    }
+    Ok(bytes)
+}
+
+fn disasm(
+    rom: &Path,
+    load_address: u16,
+    linear: bool,
+    dot: Option<&Path>,
+    symbols: Option<&Path>,
+) -> Result<u8, Box<dyn Error>> {
+    let bytes = read_rom(rom, load_address, MAX_RAM_SIZE)?;
+    let symbols = load_symbols(Some(rom), symbols)?;
+    let flow = ControlFlow::analyze(&bytes, load_address);
+    if let Some(path) = dot {
+        let mut out = BufWriter::new(File::create(path)?);
+        flow.write_dot(&mut out)?;
+        out.flush()?;
+    }
+    let lines = if linear {
+        disassemble(&bytes, load_address)
+    } else {
+        for address in flow.computed_jumps() {
+            eprintln!(
+                "Computed jump at {:#05x}: the code it reaches is listed as data",
+                address
+            );
+        }
+        disassemble_flow(&bytes, load_address, &flow)
+    };
+    for line in listing(&lines, &symbols) {
+        println!("{}", line);
+    }
+    Ok(EXIT_HALT)
+}
+
+fn decompile(rom: &Path, output: Option<&Path>, load_address: u16) -> Result<u8, Box<dyn Error>> {
+    let bytes = read_rom(rom, load_address, MAX_RAM_SIZE)?;
+    let source = decompiler::decompile(&bytes, load_address);
+    // The source is only useful if it compiles back to the same ROM
+    match octo::compile(&source, load_address) {
+        Ok(assembly) if assembly.rom == bytes => {}
+        Ok(_) => eprintln!("Warning: the source doesn't compile back to the identical ROM"),
+        Err(e) => eprintln!("Warning: the source doesn't compile: {}", e),
+    }
+    match output {
+        Some(path) => fs::write(path, source)?,
+        None => print!("{}", source),
+    }
+    Ok(EXIT_HALT)
+}
+
+fn asm(
+    source: &Path,
+    output: &Path,
+    symbols: Option<&Path>,
+    load_address: u16,
+) -> Result<u8, Box<dyn Error>> {
+    let text = fs::read_to_string(source)?;
+    let assembly = if source.extension().is_some_and(|e| e == "8o") {
+        octo::compile(&text, load_address)?
+    } else {
+        assemble_program(&text, load_address)?
+    };
+    fs::write(output, &assembly.rom)?;
+    eprintln!("Wrote {} bytes to {}", assembly.rom.len(), output.display());
+    if let Some(path) = symbols {
+        let mut out = BufWriter::new(File::create(path)?);
+        Symbols::from_assembly(&assembly, &source.display().to_string()).write(&mut out)?;
+        out.flush()?;
+    }
+    Ok(EXIT_HALT)
+}
+
+fn lint(
+    rom: &Path,
+    load_address: u16,
+    ram_size: usize,
+    allow: &[LintKind],
+) -> Result<u8, Box<dyn Error>> {
+    let warnings: Vec<_> = secrus8::lint::lint(
+        &read_rom(rom, load_address, ram_size)?,
+        load_address,
+        ram_size,
+    )
+    .into_iter()
+    .filter(|w| !allow.contains(&w.kind))
+    .collect();
+    for warning in &warnings {
+        println!("{}", warning);
+    }
+    if warnings.is_empty() {
+        return Ok(EXIT_HALT);
+    }
+    eprintln!(
+        "{} warning{}",
+        warnings.len(),
+        if warnings.len() == 1 { "" } else { "s" }
+    );
+    Ok(EXIT_WARNINGS)
+}
+
+fn info(rom: &Path, load_address: u16) -> Result<u8, Box<dyn Error>> {
+    let bytes = read_rom(rom, load_address, MAX_RAM_SIZE)?;
+    let report = analyze(&bytes, load_address);
+
+    println!("File:     {}", rom.display());
+    if let Some(info) = rom_database()?.get(&bytes) {
+        println!("Title:    {}", info.title);
+    }
+    println!("Size:     {} bytes", report.size);
+    println!("SHA-1:    {}", report.sha1);
+    println!("Platform: {} (guessed)", report.platform.platform);
+    for evidence in &report.platform.evidence {
+        println!("          {}", evidence);
+    }
+
+    println!("\nOpcodes:");
+    for (pattern, count) in &report.families {
+        println!("  {}  {}", pattern, count);
+    }
+
+    if !report.invalid.is_empty() {
+        println!("\nInvalid words (may be data):");
+        for (address, word) in &report.invalid {
+            println!("  {:#05x}: {:04X}", address, word);
+        }
+    }
+    Ok(EXIT_HALT)
+}
+
+fn trace_diff(first: &Path, second: &Path, context: usize) -> Result<u8, Box<dyn Error>> {
+    let a = trace::parse(&fs::read(first)?)?;
+    let b = trace::parse(&fs::read(second)?)?;
+
+    let Some(divergence) = trace::diff(&a, &b) else {
+        println!("Traces match ({} records)", a.len());
+        return Ok(EXIT_HALT);
+    };
+
+    let index = divergence.index;
+    println!("First difference at record {}:", index);
+    for record in &a[index.saturating_sub(context)..index] {
+        println!("  {}", record);
+    }
+    if let Some(record) = a.get(index) {
+        println!("- {}", record);
+    }
+    if let Some(record) = b.get(index) {
+        println!("+ {}", record);
+    }
+    for difference in &divergence.differences {
+        println!("  {}", difference);
+    }
+    Ok(EXIT_DIFFERENT)
 }
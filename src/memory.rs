@@ -0,0 +1,164 @@
+//! Where programs and fonts live in RAM, and how much RAM there is.
+
+use crate::Error;
+use crate::consts::{INITIAL_PC, TOTAL_RAM_SIZE};
+use crate::font::Fonts;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Largest RAM addressable with a 16-bit index register.
+pub const MAX_RAM_SIZE: usize = 0x10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLayout {
+    /// Address ROMs are loaded at and execution starts from
+    pub program_start: u16,
+    /// Address of the 4x5 hex digits used by FX29
+    pub font_address: u16,
+    /// Address of the 8x10 digits of SUPER-CHIP
+    pub big_font_address: u16,
+    pub ram_size: usize,
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        Self::PRESETS[0].1
+    }
+}
+
+impl MemoryLayout {
+    /// Named layouts, selectable with `FromStr`.
+    pub const PRESETS: [(&str, MemoryLayout); 3] = [
+        (
+            "standard",
+            MemoryLayout {
+                program_start: INITIAL_PC,
+                font_address: 0x50,
+                big_font_address: 0xA0,
+                ram_size: TOTAL_RAM_SIZE as usize,
+            },
+        ),
+        // The ETI-660 keeps its interpreter below 0x600
+        (
+            "eti660",
+            MemoryLayout {
+                program_start: 0x600,
+                font_address: 0x50,
+                big_font_address: 0xA0,
+                ram_size: TOTAL_RAM_SIZE as usize,
+            },
+        ),
+        (
+            "xochip",
+            MemoryLayout {
+                program_start: INITIAL_PC,
+                font_address: 0x50,
+                big_font_address: 0xA0,
+                ram_size: MAX_RAM_SIZE,
+            },
+        ),
+    ];
+
+    /// Check that RAM is addressable and that the program start and both
+    /// `fonts` fit in it.
+    pub fn validate(&self, fonts: &Fonts) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::InvalidMemoryLayout(message));
+        if self.ram_size > MAX_RAM_SIZE {
+            return invalid(format!(
+                "RAM size {:#x} is larger than {:#x}",
+                self.ram_size, MAX_RAM_SIZE
+            ));
+        }
+        if self.program_start as usize >= self.ram_size {
+            return invalid(format!(
+                "program start {:#05x} is outside RAM",
+                self.program_start
+            ));
+        }
+        for (name, address, size) in [
+            ("font", self.font_address, fonts.small().len()),
+            ("big font", self.big_font_address, fonts.big().len()),
+        ] {
+            if address as usize + size > self.ram_size {
+                return invalid(format!("{} at {:#05x} does not fit in RAM", name, address));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MemoryLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "program {:#05x}, font {:#05x}, big font {:#05x}, {} bytes of RAM",
+            self.program_start, self.font_address, self.big_font_address, self.ram_size
+        )
+    }
+}
+
+impl FromStr for MemoryLayout {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::PRESETS
+            .into_iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, layout)| layout)
+            .ok_or_else(|| Error::UnknownMemoryLayout(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_are_selected_by_name() {
+        let layout: MemoryLayout = "eti660".parse().unwrap();
+        assert_eq!(layout.program_start, 0x600);
+        assert_eq!(
+            "xochip".parse::<MemoryLayout>().unwrap().ram_size,
+            MAX_RAM_SIZE
+        );
+        assert_eq!(
+            "c64".parse::<MemoryLayout>(),
+            Err(Error::UnknownMemoryLayout("c64".to_string()))
+        );
+        for (_, layout) in MemoryLayout::PRESETS {
+            assert_eq!(layout.validate(&Fonts::default()), Ok(()));
+        }
+    }
+
+    #[test]
+    fn validate_rejects_what_does_not_fit() {
+        let fonts = Fonts::default();
+        let standard = MemoryLayout::default();
+        let invalid = [
+            MemoryLayout {
+                ram_size: MAX_RAM_SIZE + 1,
+                ..standard
+            },
+            MemoryLayout {
+                program_start: 0x1000,
+                ..standard
+            },
+            MemoryLayout {
+                font_address: 0xFFF,
+                ..standard
+            },
+            MemoryLayout {
+                big_font_address: 0xFA0,
+                ..standard
+            },
+        ];
+        for layout in invalid {
+            assert!(
+                matches!(layout.validate(&fonts), Err(Error::InvalidMemoryLayout(_))),
+                "{}",
+                layout
+            );
+        }
+    }
+}
@@ -0,0 +1,661 @@
+//! Compiler for the subset of Octo, the language of the Octo CHIP-8 IDE,
+//! written by the decompiler.
+//!
+//! ```text
+//! # comments start with a hash
+//! : main
+//!   i := smile
+//!   loop
+//!     sprite v0 v1 3
+//!     v0 += 1
+//!     if v0 == 60 then v0 := 0
+//!   again
+//!
+//! : smile
+//!   0b01000010
+//!   0b00000000
+//!   0b00111100
+//! ```
+//!
+//! Every CHIP-8 instruction has its Octo form. Naming a label calls it, and
+//! `if ... then`, `if ... begin ... else ... end`, `loop ... while ...
+//! again` and `:const` work as in Octo. Numbers on their own are emitted as
+//! bytes. SUPER-CHIP and XO-CHIP statements, macros and `:calc` aren't
+//! supported.
+
+use crate::Error;
+use crate::assembler::{Assembly, parse_number};
+
+use std::collections::HashMap;
+
+/// Compile `source` into a ROM to be loaded at `base`.
+///
+/// As in Octo, a program that doesn't start with `: main` gets a jump to
+/// `main` at `base`.
+pub fn compile(source: &str, base: u16) -> Result<Assembly, Error> {
+    let tokens = tokenize(source);
+    let mut compiler = Compiler {
+        tokens,
+        position: 0,
+        base,
+        rom: Vec::new(),
+        lines: Vec::new(),
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        fixups: Vec::new(),
+        flow: Vec::new(),
+    };
+
+    let starts_with_main = matches!(
+        compiler.tokens.as_slice(),
+        [(colon, _), (name, _), ..] if colon == ":" && name == "main"
+    );
+    let defines_main = compiler.tokens.windows(2).any(|pair| {
+        let [(colon, _), (name, _)] = pair else {
+            return false;
+        };
+        colon == ":" && name == "main"
+    });
+    if defines_main && !starts_with_main {
+        compiler.fixups.push(Fixup {
+            offset: 0,
+            name: "main".to_string(),
+            line: 1,
+        });
+        compiler.rom.extend([0x10, 0x00]);
+    }
+
+    while compiler.position < compiler.tokens.len() {
+        let start = compiler.rom.len();
+        let line = compiler.tokens[compiler.position].1;
+        compiler.statement()?;
+        if compiler.rom.len() > start {
+            compiler.lines.push((base.wrapping_add(start as u16), line));
+        }
+    }
+    compiler.finish()
+}
+
+/// Whitespace separated words with their line numbers, without comments.
+fn tokenize(source: &str) -> Vec<(String, usize)> {
+    let mut tokens = Vec::new();
+    for (number, line) in source.lines().enumerate() {
+        let text = line.split('#').next().unwrap_or("");
+        tokens.extend(
+            text.split_whitespace()
+                .map(|token| (token.to_string(), number + 1)),
+        );
+    }
+    tokens
+}
+
+/// A 12-bit address to fill in once the label `name` is defined.
+struct Fixup {
+    offset: usize,
+    name: String,
+    line: usize,
+}
+
+/// An open `if ... begin` or `loop`.
+enum Flow {
+    /// Offset of the jump to patch at `else` or `end`, and whether `else`
+    /// was seen
+    If {
+        jump: usize,
+        has_else: bool,
+        line: usize,
+    },
+    /// Start address, and the jumps of its `while`s
+    Loop {
+        start: u16,
+        exits: Vec<usize>,
+        line: usize,
+    },
+}
+
+struct Compiler {
+    tokens: Vec<(String, usize)>,
+    position: usize,
+    base: u16,
+    rom: Vec<u8>,
+    lines: Vec<(u16, usize)>,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, u32>,
+    fixups: Vec<Fixup>,
+    flow: Vec<Flow>,
+}
+
+impl Compiler {
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.position.saturating_sub(1))
+            .map_or(0, |&(_, line)| line)
+    }
+
+    fn error(&self, message: String) -> Error {
+        Error::Assembly(self.line(), message)
+    }
+
+    fn next(&mut self) -> Result<String, Error> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .map(|(token, _)| token.clone())
+            .ok_or_else(|| self.error("Unexpected end of source".to_string()))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens
+            .get(self.position)
+            .map(|(token, _)| token.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), Error> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(self.error(format!("Expected '{}', found '{}'", expected, token)));
+        }
+        Ok(())
+    }
+
+    fn here(&self) -> u16 {
+        self.base.wrapping_add(self.rom.len() as u16)
+    }
+
+    fn emit(&mut self, opcode: u16) {
+        self.rom.extend(opcode.to_be_bytes());
+    }
+
+    fn statement(&mut self) -> Result<(), Error> {
+        let token = self.next()?;
+        match token.as_str() {
+            ":" => {
+                let name = self.name()?;
+                let here = self.here();
+                if self.labels.insert(name.clone(), here).is_some() {
+                    return Err(self.error(format!("Duplicate label '{}'", name)));
+                }
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.number()?;
+                self.constants.insert(name, value);
+            }
+            ":call" => {
+                let address = self.address()?;
+                self.emit(0x2000 | address);
+            }
+            "clear" => self.emit(0x00E0),
+            "return" | ";" => self.emit(0x00EE),
+            "jump" => {
+                let address = self.address()?;
+                self.emit(0x1000 | address);
+            }
+            "jump0" => {
+                let address = self.address()?;
+                self.emit(0xB000 | address);
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.number()?;
+                if n > 0xF {
+                    return Err(self.error(format!("Sprite height {} out of range", n)));
+                }
+                self.emit(0xD000 | xy(x, y) | n as u16);
+            }
+            "bcd" => self.emit_x(0xF033)?,
+            "save" => self.emit_x(0xF055)?,
+            "load" => self.emit_x(0xF065)?,
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                self.emit_x(if token == "delay" { 0xF015 } else { 0xF018 })?;
+            }
+            "i" => self.index()?,
+            "if" => self.conditional()?,
+            "else" => {
+                let jump = self.rom.len();
+                let here = self.here();
+                match self.flow.last_mut() {
+                    Some(Flow::If {
+                        jump: pending,
+                        has_else,
+                        ..
+                    }) if !*has_else => {
+                        let target = here.wrapping_add(2);
+                        patch(&mut self.rom, *pending, target);
+                        *pending = jump;
+                        *has_else = true;
+                    }
+                    _ => return Err(self.error("'else' without 'if ... begin'".to_string())),
+                }
+                self.emit(0x1000);
+            }
+            "end" => match self.flow.pop() {
+                Some(Flow::If { jump, .. }) => {
+                    let here = self.here();
+                    patch(&mut self.rom, jump, here);
+                }
+                _ => return Err(self.error("'end' without 'if ... begin'".to_string())),
+            },
+            "loop" => {
+                let start = self.here();
+                let line = self.line();
+                self.flow.push(Flow::Loop {
+                    start,
+                    exits: Vec::new(),
+                    line,
+                });
+            }
+            "again" => match self.flow.pop() {
+                Some(Flow::Loop { start, exits, .. }) => {
+                    self.emit(0x1000 | start);
+                    let here = self.here();
+                    for exit in exits {
+                        patch(&mut self.rom, exit, here);
+                    }
+                }
+                _ => return Err(self.error("'again' without 'loop'".to_string())),
+            },
+            "while" => {
+                let (skip_if_true, _) = self.condition()?;
+                self.emit(skip_if_true);
+                let exit = self.rom.len();
+                self.emit(0x1000);
+                match self
+                    .flow
+                    .iter_mut()
+                    .rev()
+                    .find(|f| matches!(f, Flow::Loop { .. }))
+                {
+                    Some(Flow::Loop { exits, .. }) => exits.push(exit),
+                    _ => return Err(self.error("'while' outside a loop".to_string())),
+                }
+            }
+            _ if register_index(&token).is_some() => {
+                self.position -= 1;
+                self.assignment()?;
+            }
+            _ => {
+                if let Some(byte) = self.value_of(&token) {
+                    let byte = to_byte(byte)
+                        .ok_or_else(|| self.error(format!("Byte '{}' out of range", token)))?;
+                    self.rom.push(byte);
+                } else if is_name(&token) {
+                    self.reference(token, 0x2000)?;
+                } else {
+                    return Err(self.error(format!("Unknown statement '{}'", token)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, Error> {
+        let name = self.next()?;
+        if !is_name(&name) {
+            return Err(self.error(format!("Invalid name '{}'", name)));
+        }
+        Ok(name)
+    }
+
+    fn register(&mut self) -> Result<u16, Error> {
+        let token = self.next()?;
+        register_index(&token)
+            .ok_or_else(|| self.error(format!("Expected a register, found '{}'", token)))
+    }
+
+    fn emit_x(&mut self, opcode: u16) -> Result<(), Error> {
+        let x = self.register()?;
+        self.emit(opcode | x << 8);
+        Ok(())
+    }
+
+    /// A number or constant.
+    fn value_of(&self, token: &str) -> Option<i64> {
+        if let Some(&value) = self.constants.get(token) {
+            return Some(value as i64);
+        }
+        match token.strip_prefix('-') {
+            Some(positive) => parse_number(positive).map(|n| -(n as i64)),
+            None => parse_number(token).map(|n| n as i64),
+        }
+    }
+
+    fn number(&mut self) -> Result<u32, Error> {
+        let token = self.next()?;
+        match self.value_of(&token) {
+            Some(value) if value >= 0 => Ok(value as u32),
+            _ => Err(self.error(format!("Expected a number, found '{}'", token))),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u16, Error> {
+        let token = self.next()?;
+        self.value_of(&token)
+            .and_then(to_byte)
+            .map(u16::from)
+            .ok_or_else(|| self.error(format!("Expected a byte, found '{}'", token)))
+    }
+
+    /// A 12-bit address: a number, a constant, or a label, which may be
+    /// defined later.
+    fn address(&mut self) -> Result<u16, Error> {
+        let token = self.next()?;
+        if let Some(value) = self.value_of(&token) {
+            return match value {
+                0..=0xFFF => Ok(value as u16),
+                _ => Err(self.error(format!("Address '{}' out of range", token))),
+            };
+        }
+        if !is_name(&token) {
+            return Err(self.error(format!("Expected an address, found '{}'", token)));
+        }
+        if let Some(&address) = self.labels.get(&token) {
+            return label_address(&token, address, self.line());
+        }
+        self.fixups.push(Fixup {
+            offset: self.rom.len(),
+            name: token,
+            line: self.line(),
+        });
+        Ok(0)
+    }
+
+    /// Emit `opcode` with the address of the label `name`.
+    fn reference(&mut self, name: String, opcode: u16) -> Result<(), Error> {
+        match self.labels.get(&name) {
+            Some(&address) => self.emit(opcode | label_address(&name, address, self.line())?),
+            None => {
+                self.fixups.push(Fixup {
+                    offset: self.rom.len(),
+                    name,
+                    line: self.line(),
+                });
+                self.emit(opcode);
+            }
+        }
+        Ok(())
+    }
+
+    /// `i := NNN`, `i := hex vX` and `i += vX`.
+    fn index(&mut self) -> Result<(), Error> {
+        let operator = self.next()?;
+        match operator.as_str() {
+            ":=" if self.peek() == Some("hex") => {
+                self.position += 1;
+                self.emit_x(0xF029)
+            }
+            ":=" => {
+                let address = self.address()?;
+                self.emit(0xA000 | address);
+                Ok(())
+            }
+            "+=" => self.emit_x(0xF01E),
+            _ => Err(self.error(format!("Unknown operator 'i {}'", operator))),
+        }
+    }
+
+    /// `vX <operator> <operand>`.
+    fn assignment(&mut self) -> Result<(), Error> {
+        let x = self.register()?;
+        let operator = self.next()?;
+        let operand = self.peek().and_then(register_index);
+
+        let opcode = match (operator.as_str(), operand) {
+            (":=", Some(y)) => 0x8000 | xy(x, y),
+            (":=", None) => match self.peek() {
+                Some("random") => {
+                    self.position += 1;
+                    let mask = self.byte()?;
+                    return self.emitted(0xC000 | x << 8 | mask);
+                }
+                Some("delay") => 0xF007 | x << 8,
+                Some("key") => 0xF00A | x << 8,
+                _ => {
+                    let value = self.byte()?;
+                    return self.emitted(0x6000 | x << 8 | value);
+                }
+            },
+            ("+=", None) => {
+                let value = self.byte()?;
+                return self.emitted(0x7000 | x << 8 | value);
+            }
+            ("-=", None) => {
+                let value = self.byte()?;
+                return self.emitted(0x7000 | x << 8 | (0x100 - value) & 0xFF);
+            }
+            ("+=", Some(y)) => 0x8004 | xy(x, y),
+            ("-=", Some(y)) => 0x8005 | xy(x, y),
+            ("=-", Some(y)) => 0x8007 | xy(x, y),
+            ("|=", Some(y)) => 0x8001 | xy(x, y),
+            ("&=", Some(y)) => 0x8002 | xy(x, y),
+            ("^=", Some(y)) => 0x8003 | xy(x, y),
+            (">>=", Some(y)) => 0x8006 | xy(x, y),
+            ("<<=", Some(y)) => 0x800E | xy(x, y),
+            _ => {
+                let operand = self.peek().unwrap_or("").to_string();
+                return Err(self.error(format!(
+                    "Unknown operation 'v{:x} {} {}'",
+                    x, operator, operand
+                )));
+            }
+        };
+        self.position += 1;
+        self.emitted(opcode)
+    }
+
+    fn emitted(&mut self, opcode: u16) -> Result<(), Error> {
+        self.emit(opcode);
+        Ok(())
+    }
+
+    /// `if <condition> then` or `if <condition> begin`.
+    fn conditional(&mut self) -> Result<(), Error> {
+        let (skip_if_true, skip_if_false) = self.condition()?;
+        match self.next()?.as_str() {
+            "then" => self.emit(skip_if_false),
+            "begin" => {
+                self.emit(skip_if_true);
+                let jump = self.rom.len();
+                let line = self.line();
+                self.emit(0x1000);
+                self.flow.push(Flow::If {
+                    jump,
+                    has_else: false,
+                    line,
+                });
+            }
+            other => {
+                return Err(self.error(format!("Expected 'then' or 'begin', found '{}'", other)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The skips taken when the condition holds and when it doesn't.
+    fn condition(&mut self) -> Result<(u16, u16), Error> {
+        let x = self.register()?;
+        let operator = self.next()?;
+        let (equal, not_equal) = match operator.as_str() {
+            "key" => return Ok((0xE09E | x << 8, 0xE0A1 | x << 8)),
+            "-key" => return Ok((0xE0A1 | x << 8, 0xE09E | x << 8)),
+            "==" | "!=" => match self.peek().and_then(register_index) {
+                Some(y) => {
+                    self.position += 1;
+                    (0x5000 | xy(x, y), 0x9000 | xy(x, y))
+                }
+                None => {
+                    let value = self.byte()?;
+                    (0x3000 | x << 8 | value, 0x4000 | x << 8 | value)
+                }
+            },
+            _ => return Err(self.error(format!("Unknown comparison '{}'", operator))),
+        };
+        Ok(if operator == "==" {
+            (equal, not_equal)
+        } else {
+            (not_equal, equal)
+        })
+    }
+
+    fn finish(mut self) -> Result<Assembly, Error> {
+        if let Some(open) = self.flow.last() {
+            let (line, what) = match open {
+                Flow::If { line, .. } => (*line, "'if ... begin' without 'end'"),
+                Flow::Loop { line, .. } => (*line, "'loop' without 'again'"),
+            };
+            return Err(Error::Assembly(line, what.to_string()));
+        }
+        for fixup in &self.fixups {
+            let address = self.labels.get(&fixup.name).ok_or_else(|| {
+                Error::Assembly(fixup.line, format!("Unknown name '{}'", fixup.name))
+            })?;
+            let address = label_address(&fixup.name, *address, fixup.line)?;
+            patch(&mut self.rom, fixup.offset, address);
+        }
+        Ok(Assembly {
+            rom: self.rom,
+            lines: self.lines,
+            labels: self.labels,
+        })
+    }
+}
+
+/// The address of the label `name`, if it fits in an instruction.
+fn label_address(name: &str, address: u16, line: usize) -> Result<u16, Error> {
+    if address > 0xFFF {
+        return Err(Error::Assembly(
+            line,
+            format!("Label '{}' at {:#x} is out of range", name, address),
+        ));
+    }
+    Ok(address)
+}
+
+/// Fill in the 12-bit `address` of the instruction at `offset`.
+fn patch(rom: &mut [u8], offset: usize, address: u16) {
+    rom[offset] = rom[offset] & 0xF0 | (address >> 8 & 0xF) as u8;
+    rom[offset + 1] = address as u8;
+}
+
+fn xy(x: u16, y: u16) -> u16 {
+    x << 8 | y << 4
+}
+
+/// Index of `v0`-`vf`.
+fn register_index(token: &str) -> Option<u16> {
+    let digit = token
+        .strip_prefix('v')
+        .or_else(|| token.strip_prefix('V'))?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u16::from_str_radix(digit, 16).ok()
+}
+
+/// A byte from -128 to 255, negative numbers in two's complement.
+fn to_byte(value: i64) -> Option<u8> {
+    match value {
+        -128..=-1 => Some((value + 256) as u8),
+        0..=255 => Some(value as u8),
+        _ => None,
+    }
+}
+
+fn is_name(token: &str) -> bool {
+    let mut chars = token.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        && register_index(token).is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(source: &str) -> Vec<u8> {
+        compile(source, 0x200).unwrap().rom
+    }
+
+    fn error(source: &str, base: u16) -> Error {
+        compile(source, base).unwrap_err()
+    }
+
+    #[test]
+    fn compiles_statements_and_structures() {
+        let source = "
+            :const speed 2
+            : main
+              i := smile
+              loop
+                sprite v0 v1 3
+                v0 += speed
+                if v0 == 60 then v0 := 0
+              again
+            : smile
+              0b01000010 0 -1
+        ";
+        assert_eq!(
+            rom(source),
+            [
+                0x12, 0x02, // main isn't first
+                0xA2, 0x0E, // i := smile
+                0xD0, 0x13, // sprite v0 v1 3
+                0x70, 0x02, // v0 += speed
+                0x40, 0x3C, // skip unless v0 == 60
+                0x60, 0x00, // v0 := 0
+                0x12, 0x04, // again
+                0x42, 0x00, 0xFF,
+            ]
+        );
+        let assembly = compile(source, 0x200).unwrap();
+        assert_eq!(assembly.labels["smile"], 0x20E);
+    }
+
+    #[test]
+    fn main_later_in_the_source_is_jumped_to() {
+        assert_eq!(
+            rom(": draw return\n: main draw"),
+            [0x12, 0x04, 0x00, 0xEE, 0x22, 0x02]
+        );
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        assert_eq!(
+            error(": main\n  jump nowhere", 0x200),
+            Error::Assembly(2, "Unknown name 'nowhere'".to_string())
+        );
+        assert_eq!(
+            error(": main\n  loop\n  v0 := 1", 0x200),
+            Error::Assembly(2, "'loop' without 'again'".to_string())
+        );
+        assert_eq!(
+            error(": main\n  jump 0x1000", 0x200),
+            Error::Assembly(2, "Address '0x1000' out of range".to_string())
+        );
+    }
+
+    #[test]
+    fn labels_past_0xfff_are_out_of_range() {
+        // Referenced after the label, and before it through a fixup
+        assert_eq!(
+            error(": main\n  jump main", 0x1000),
+            Error::Assembly(2, "Label 'main' at 0x1000 is out of range".to_string())
+        );
+        assert_eq!(
+            error(": main\n  i := far\n  0 0\n: far", 0xFFC),
+            Error::Assembly(2, "Label 'far' at 0x1000 is out of range".to_string())
+        );
+        assert_eq!(
+            error(": main\n  far\n  0 0\n: far", 0xFFC),
+            Error::Assembly(2, "Label 'far' at 0x1000 is out of range".to_string())
+        );
+        assert!(compile(": main\n  jump far\n: far", 0xFFC).is_ok());
+    }
+}
@@ -1,6 +1,11 @@
 use crate::Error;
 
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Instruction {
+    /// 0NNN - call the machine code routine at NNN
+    SysCall(u16),
     /// 00E0 - clear screen
     ClearScreen,
     /// 00EE - return from subroutine
@@ -31,12 +36,12 @@ pub enum Instruction {
     RegistersSumWithOverflow(usize, usize),
     /// 8XY5 - VX = VX - VY (with VF as overflow control)
     SubtractRegisterFromRegisterValue(usize, usize),
-    /// 8XY6 - VX >>= 1, LSB stored in VF
-    ShiftRegisterBitsRight(usize),
+    /// 8XY6 - VX >>= 1, LSB stored in VF (VX = VY >> 1 with the shift quirk off)
+    ShiftRegisterBitsRight(usize, usize),
     /// 8XY7 - VX = VY - VX (with VF as overflow control)
     SubtractRegisterValueFromRegister(usize, usize),
-    /// 8XYE - VX <<= 1 (with VF as overflow control)
-    ShiftRegisterBitsLeft(usize),
+    /// 8XYE - VX <<= 1 (with VF as overflow control, VX = VY << 1 with the shift quirk off)
+    ShiftRegisterBitsLeft(usize, usize),
     /// 9XY0 - skip next if VX does not equal VY
     SkipIfRegistersNotEqual(usize, usize),
     /// ANNN - set I to NNN
@@ -47,12 +52,14 @@ pub enum Instruction {
     SetRegisterToRandAndValue(usize, u8),
     /// DXYN - draw a sprite
     DrawSprite(usize, usize, u8),
-    /// EX9E - skip next if key == VX
+    /// EX9E - skip next if the key in VX is pressed
     SkipIfKeyEqualsRegister(usize),
-    /// EXA1 - skip next if key != VX
+    /// EXA1 - skip next if the key in VX is not pressed
     SkipIfKeyNotEqualsRegister(usize),
     /// FX07 - set VX to delay timer value
     SetRegisterToDelayTimerValue(usize),
+    /// FX0A - wait for a key press and release, store the key in VX
+    WaitForKeyPress(usize),
     /// FX15 - set delay timer to VX
     SetDelayTimerToRegisterValue(usize),
     /// FX18 - set sound timer to VX
@@ -79,28 +86,32 @@ impl Instruction {
         match (n1, n2, n3, n4) {
             (0, 0, 0xE, 0) => Ok(Instruction::ClearScreen),
             (0, 0, 0xE, 0xE) => Ok(Instruction::ReturnFromSubroutine),
-            (1, n2, n3, n4) => {
+            // SUPER-CHIP and XO-CHIP scrolling, exit and resolution changes,
+            // not machine code calls
+            (0, 0, 0xC | 0xD, _) | (0, 0, 0xF, 0xB..=0xF) => Err(Error::UnknownOpcode(opcode)),
+            (0, _, _, _) => Ok(Instruction::SysCall(opcode & 0x0FFF)),
+            (1, _, _, _) => {
                 let address = opcode & 0x0FFF;
                 Ok(Instruction::Jump(address))
             }
-            (2, n2, n3, n4) => {
+            (2, _, _, _) => {
                 let address = opcode & 0x0FFF;
                 Ok(Instruction::Call(address))
             }
-            (3, n2, n3, n4) => {
+            (3, n2, _, _) => {
                 let byte_value = (opcode & 0x00FF) as u8;
                 Ok(Instruction::SkipIfEqualByte(n2, byte_value))
             }
-            (4, n2, n3, n4) => {
+            (4, n2, _, _) => {
                 let byte_value = (opcode & 0x00FF) as u8;
                 Ok(Instruction::SkipIfNotEqualByte(n2, byte_value))
             }
             (5, n2, n3, 0) => Ok(Instruction::SkipIfRegistersEqual(n2, n3)),
-            (6, n2, n3, n4) => {
+            (6, n2, _, _) => {
                 let byte_value = (opcode & 0x00FF) as u8;
                 Ok(Instruction::SetRegisterToValue(n2, byte_value))
             }
-            (7, n2, n3, n4) => {
+            (7, n2, _, _) => {
                 let byte_value = (opcode & 0x00FF) as u8;
                 Ok(Instruction::AddToRegister(n2, byte_value))
             }
@@ -110,19 +121,19 @@ impl Instruction {
             (8, n2, n3, 3) => Ok(Instruction::RegistersBitwiseXor(n2, n3)),
             (8, n2, n3, 4) => Ok(Instruction::RegistersSumWithOverflow(n2, n3)),
             (8, n2, n3, 5) => Ok(Instruction::SubtractRegisterFromRegisterValue(n2, n3)),
-            (8, n2, n3, 6) => Ok(Instruction::ShiftRegisterBitsRight(n2)),
+            (8, n2, n3, 6) => Ok(Instruction::ShiftRegisterBitsRight(n2, n3)),
             (8, n2, n3, 7) => Ok(Instruction::SubtractRegisterValueFromRegister(n2, n3)),
-            (8, n2, n3, 0xE) => Ok(Instruction::ShiftRegisterBitsLeft(n2)),
+            (8, n2, n3, 0xE) => Ok(Instruction::ShiftRegisterBitsLeft(n2, n3)),
             (9, n2, n3, 0) => Ok(Instruction::SkipIfRegistersNotEqual(n2, n3)),
-            (0xA, n2, n3, n4) => {
+            (0xA, _, _, _) => {
                 let address = opcode & 0x0FFF;
                 Ok(Instruction::SetIndexRegisterToValue(address))
             }
-            (0xB, n2, n3, n4) => {
+            (0xB, _, _, _) => {
                 let address = opcode & 0x0FFF;
                 Ok(Instruction::JumpByValue(address))
             }
-            (0xC, n2, n3, n4) => {
+            (0xC, n2, _, _) => {
                 let byte_value = (opcode & 0x00FF) as u8;
                 Ok(Instruction::SetRegisterToRandAndValue(n2, byte_value))
             }
@@ -130,6 +141,7 @@ impl Instruction {
             (0xE, n2, 9, 0xE) => Ok(Instruction::SkipIfKeyEqualsRegister(n2)),
             (0xE, n2, 0xA, 1) => Ok(Instruction::SkipIfKeyNotEqualsRegister(n2)),
             (0xF, n2, 0, 7) => Ok(Instruction::SetRegisterToDelayTimerValue(n2)),
+            (0xF, n2, 0, 0xA) => Ok(Instruction::WaitForKeyPress(n2)),
             (0xF, n2, 1, 5) => Ok(Instruction::SetDelayTimerToRegisterValue(n2)),
             (0xF, n2, 1, 8) => Ok(Instruction::SetSoundTimerToRegisterValue(n2)),
             (0xF, n2, 2, 9) => Ok(Instruction::SetIndexRegisterToSpriteForRegister(n2)),
@@ -140,4 +152,183 @@ impl Instruction {
             (_, _, _, _) => Err(Error::UnknownOpcode(opcode)),
         }
     }
+
+    /// Encode back into the 16-bit opcode, the inverse of [`Instruction::from_opcode`].
+    pub fn to_opcode(&self) -> u16 {
+        let xy =
+            |code: u16, x: usize, y: usize, n: u16| code | (x as u16) << 8 | (y as u16) << 4 | n;
+        let xnn = |code: u16, x: usize, nn: u8| code | (x as u16) << 8 | nn as u16;
+
+        match *self {
+            Instruction::SysCall(address) => address,
+            Instruction::ClearScreen => 0x00E0,
+            Instruction::ReturnFromSubroutine => 0x00EE,
+            Instruction::Jump(address) => 0x1000 | address,
+            Instruction::Call(address) => 0x2000 | address,
+            Instruction::SkipIfEqualByte(x, nn) => xnn(0x3000, x, nn),
+            Instruction::SkipIfNotEqualByte(x, nn) => xnn(0x4000, x, nn),
+            Instruction::SkipIfRegistersEqual(x, y) => xy(0x5000, x, y, 0),
+            Instruction::SetRegisterToValue(x, nn) => xnn(0x6000, x, nn),
+            Instruction::AddToRegister(x, nn) => xnn(0x7000, x, nn),
+            Instruction::SetRegisterToRegisterValue(x, y) => xy(0x8000, x, y, 0),
+            Instruction::RegistersBitwiseOr(x, y) => xy(0x8000, x, y, 1),
+            Instruction::RegistersBitwiseAnd(x, y) => xy(0x8000, x, y, 2),
+            Instruction::RegistersBitwiseXor(x, y) => xy(0x8000, x, y, 3),
+            Instruction::RegistersSumWithOverflow(x, y) => xy(0x8000, x, y, 4),
+            Instruction::SubtractRegisterFromRegisterValue(x, y) => xy(0x8000, x, y, 5),
+            Instruction::ShiftRegisterBitsRight(x, y) => xy(0x8000, x, y, 6),
+            Instruction::SubtractRegisterValueFromRegister(x, y) => xy(0x8000, x, y, 7),
+            Instruction::ShiftRegisterBitsLeft(x, y) => xy(0x8000, x, y, 0xE),
+            Instruction::SkipIfRegistersNotEqual(x, y) => xy(0x9000, x, y, 0),
+            Instruction::SetIndexRegisterToValue(address) => 0xA000 | address,
+            Instruction::JumpByValue(address) => 0xB000 | address,
+            Instruction::SetRegisterToRandAndValue(x, nn) => xnn(0xC000, x, nn),
+            Instruction::DrawSprite(x, y, n) => xy(0xD000, x, y, n as u16),
+            Instruction::SkipIfKeyEqualsRegister(x) => xnn(0xE000, x, 0x9E),
+            Instruction::SkipIfKeyNotEqualsRegister(x) => xnn(0xE000, x, 0xA1),
+            Instruction::SetRegisterToDelayTimerValue(x) => xnn(0xF000, x, 0x07),
+            Instruction::WaitForKeyPress(x) => xnn(0xF000, x, 0x0A),
+            Instruction::SetDelayTimerToRegisterValue(x) => xnn(0xF000, x, 0x15),
+            Instruction::SetSoundTimerToRegisterValue(x) => xnn(0xF000, x, 0x18),
+            Instruction::SetIndexRegisterToSpriteForRegister(x) => xnn(0xF000, x, 0x29),
+            Instruction::StoreBinaryCodedDecimalAtIndexRegisterValue(x) => xnn(0xF000, x, 0x33),
+            Instruction::AddRegisterToIndexRegister(x) => xnn(0xF000, x, 0x1E),
+            Instruction::DumpRegistersToMemoryAtIndexRegister(x) => xnn(0xF000, x, 0x55),
+            Instruction::LoadMemoryToRegistersAtIndexRegister(x) => xnn(0xF000, x, 0x65),
+        }
+    }
+
+    /// Opcode pattern of the instruction, e.g. `8XY4`.
+    pub fn pattern(&self) -> &'static str {
+        match self {
+            Instruction::SysCall(_) => "0NNN",
+            Instruction::ClearScreen => "00E0",
+            Instruction::ReturnFromSubroutine => "00EE",
+            Instruction::Jump(_) => "1NNN",
+            Instruction::Call(_) => "2NNN",
+            Instruction::SkipIfEqualByte(..) => "3XNN",
+            Instruction::SkipIfNotEqualByte(..) => "4XNN",
+            Instruction::SkipIfRegistersEqual(..) => "5XY0",
+            Instruction::SetRegisterToValue(..) => "6XNN",
+            Instruction::AddToRegister(..) => "7XNN",
+            Instruction::SetRegisterToRegisterValue(..) => "8XY0",
+            Instruction::RegistersBitwiseOr(..) => "8XY1",
+            Instruction::RegistersBitwiseAnd(..) => "8XY2",
+            Instruction::RegistersBitwiseXor(..) => "8XY3",
+            Instruction::RegistersSumWithOverflow(..) => "8XY4",
+            Instruction::SubtractRegisterFromRegisterValue(..) => "8XY5",
+            Instruction::ShiftRegisterBitsRight(..) => "8XY6",
+            Instruction::SubtractRegisterValueFromRegister(..) => "8XY7",
+            Instruction::ShiftRegisterBitsLeft(..) => "8XYE",
+            Instruction::SkipIfRegistersNotEqual(..) => "9XY0",
+            Instruction::SetIndexRegisterToValue(_) => "ANNN",
+            Instruction::JumpByValue(_) => "BNNN",
+            Instruction::SetRegisterToRandAndValue(..) => "CXNN",
+            Instruction::DrawSprite(..) => "DXYN",
+            Instruction::SkipIfKeyEqualsRegister(_) => "EX9E",
+            Instruction::SkipIfKeyNotEqualsRegister(_) => "EXA1",
+            Instruction::SetRegisterToDelayTimerValue(_) => "FX07",
+            Instruction::WaitForKeyPress(_) => "FX0A",
+            Instruction::SetDelayTimerToRegisterValue(_) => "FX15",
+            Instruction::SetSoundTimerToRegisterValue(_) => "FX18",
+            Instruction::SetIndexRegisterToSpriteForRegister(_) => "FX29",
+            Instruction::StoreBinaryCodedDecimalAtIndexRegisterValue(_) => "FX33",
+            Instruction::AddRegisterToIndexRegister(_) => "FX1E",
+            Instruction::DumpRegistersToMemoryAtIndexRegister(_) => "FX55",
+            Instruction::LoadMemoryToRegistersAtIndexRegister(_) => "FX65",
+        }
+    }
+}
+
+/// Assembly mnemonics in the common Cowgod syntax, e.g. `LD V1, 0x2A`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::SysCall(address) => write!(f, "SYS 0x{:03X}", address),
+            Instruction::ClearScreen => write!(f, "CLS"),
+            Instruction::ReturnFromSubroutine => write!(f, "RET"),
+            Instruction::Jump(address) => write!(f, "JP 0x{:03X}", address),
+            Instruction::Call(address) => write!(f, "CALL 0x{:03X}", address),
+            Instruction::SkipIfEqualByte(x, nn) => write!(f, "SE V{:X}, 0x{:02X}", x, nn),
+            Instruction::SkipIfNotEqualByte(x, nn) => write!(f, "SNE V{:X}, 0x{:02X}", x, nn),
+            Instruction::SkipIfRegistersEqual(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::SetRegisterToValue(x, nn) => write!(f, "LD V{:X}, 0x{:02X}", x, nn),
+            Instruction::AddToRegister(x, nn) => write!(f, "ADD V{:X}, 0x{:02X}", x, nn),
+            Instruction::SetRegisterToRegisterValue(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::RegistersBitwiseOr(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::RegistersBitwiseAnd(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::RegistersBitwiseXor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::RegistersSumWithOverflow(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::SubtractRegisterFromRegisterValue(x, y) => {
+                write!(f, "SUB V{:X}, V{:X}", x, y)
+            }
+            Instruction::ShiftRegisterBitsRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::SubtractRegisterValueFromRegister(x, y) => {
+                write!(f, "SUBN V{:X}, V{:X}", x, y)
+            }
+            Instruction::ShiftRegisterBitsLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipIfRegistersNotEqual(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::SetIndexRegisterToValue(address) => write!(f, "LD I, 0x{:03X}", address),
+            Instruction::JumpByValue(address) => write!(f, "JP V0, 0x{:03X}", address),
+            Instruction::SetRegisterToRandAndValue(x, nn) => {
+                write!(f, "RND V{:X}, 0x{:02X}", x, nn)
+            }
+            Instruction::DrawSprite(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipIfKeyEqualsRegister(x) => write!(f, "SKP V{:X}", x),
+            Instruction::SkipIfKeyNotEqualsRegister(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::SetRegisterToDelayTimerValue(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::WaitForKeyPress(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::SetDelayTimerToRegisterValue(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::SetSoundTimerToRegisterValue(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::SetIndexRegisterToSpriteForRegister(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::StoreBinaryCodedDecimalAtIndexRegisterValue(x) => {
+                write!(f, "LD B, V{:X}", x)
+            }
+            Instruction::AddRegisterToIndexRegister(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::DumpRegistersToMemoryAtIndexRegister(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::LoadMemoryToRegistersAtIndexRegister(x) => write!(f, "LD V{:X}, [I]", x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_decoded_opcode_encodes_back() {
+        for opcode in 0..=u16::MAX {
+            if let Ok(instruction) = Instruction::from_opcode(opcode) {
+                assert_eq!(instruction.to_opcode(), opcode, "{}", instruction);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_opcodes_are_errors() {
+        for opcode in [
+            0x00C4, 0x00D2, 0x00FB, 0x00FF, 0x5001, 0x800F, 0x9008, 0xE000, 0xF0FF,
+        ] {
+            assert_eq!(
+                Instruction::from_opcode(opcode),
+                Err(Error::UnknownOpcode(opcode))
+            );
+        }
+        assert_eq!(
+            Instruction::from_opcode(0x00FA),
+            Ok(Instruction::SysCall(0x0FA))
+        );
+    }
+
+    #[test]
+    fn mnemonics() {
+        let text = |opcode| Instruction::from_opcode(opcode).unwrap().to_string();
+        assert_eq!(text(0x00E0), "CLS");
+        assert_eq!(text(0x1ABC), "JP 0xABC");
+        assert_eq!(text(0x6A2F), "LD VA, 0x2F");
+        assert_eq!(text(0x812E), "SHL V1, V2");
+        assert_eq!(text(0xB300), "JP V0, 0x300");
+        assert_eq!(text(0xD125), "DRW V1, V2, 5");
+        assert_eq!(text(0xF355), "LD [I], V3");
+    }
 }
@@ -0,0 +1,297 @@
+//! Execution profiler: where the instructions of a run were spent.
+//!
+//! Every executed instruction is counted by address and by kind, and
+//! charged to the subroutine running it. Subroutines are tracked through
+//! 2NNN calls and 00EE returns, giving a call tree whose nodes have a self
+//! count (instructions in the routine itself) and a total count (including
+//! the routines it called).
+
+use crate::parser::Instruction;
+use crate::symbols::Symbols;
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Rows of the flat reports.
+pub const DEFAULT_REPORT_ROWS: usize = 20;
+
+/// A routine called from a particular place in the call tree.
+#[derive(Debug, Clone)]
+struct Node {
+    /// Entry address, or None for the code running before any call
+    address: Option<u16>,
+    parent: Option<usize>,
+    children: HashMap<u16, usize>,
+    calls: u64,
+    self_count: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    /// Executions and last opcode seen at each address
+    by_address: HashMap<u16, (u64, u16)>,
+    /// Executions of each kind of instruction, by opcode pattern
+    by_pattern: HashMap<&'static str, u64>,
+    nodes: Vec<Node>,
+    /// Node of the routine running now
+    current: usize,
+    total: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            by_address: HashMap::new(),
+            by_pattern: HashMap::new(),
+            nodes: vec![Node {
+                address: None,
+                parent: None,
+                children: HashMap::new(),
+                calls: 1,
+                self_count: 0,
+            }],
+            current: 0,
+            total: 0,
+        }
+    }
+
+    /// Count the instruction `opcode` executed at `address`.
+    pub fn record(&mut self, address: u16, opcode: u16) {
+        self.total += 1;
+        let entry = self.by_address.entry(address).or_default();
+        entry.0 += 1;
+        entry.1 = opcode;
+        self.nodes[self.current].self_count += 1;
+
+        let Ok(instruction) = Instruction::from_opcode(opcode) else {
+            return;
+        };
+        *self.by_pattern.entry(instruction.pattern()).or_default() += 1;
+        match instruction {
+            Instruction::Call(target) => self.enter(target),
+            Instruction::ReturnFromSubroutine => {
+                if let Some(parent) = self.nodes[self.current].parent {
+                    self.current = parent;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn enter(&mut self, target: u16) {
+        let index = match self.nodes[self.current].children.get(&target) {
+            Some(&index) => index,
+            None => {
+                let index = self.nodes.len();
+                self.nodes.push(Node {
+                    address: Some(target),
+                    parent: Some(self.current),
+                    children: HashMap::new(),
+                    calls: 0,
+                    self_count: 0,
+                });
+                self.nodes[self.current].children.insert(target, index);
+                index
+            }
+        };
+        self.nodes[index].calls += 1;
+        self.current = index;
+    }
+
+    /// Instructions counted so far.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Executions of the instruction at `address`.
+    pub fn count_at(&self, address: u16) -> u64 {
+        self.by_address.get(&address).map_or(0, |&(count, _)| count)
+    }
+
+    /// Addresses with their execution counts, most executed first.
+    pub fn hot_spots(&self) -> Vec<(u16, u64)> {
+        let mut spots: Vec<(u16, u64)> = self
+            .by_address
+            .iter()
+            .map(|(&address, &(count, _))| (address, count))
+            .collect();
+        spots.sort_by_key(|&(address, count)| (std::cmp::Reverse(count), address));
+        spots
+    }
+
+    /// Print the `rows` most executed addresses and instruction kinds, then
+    /// the call tree, naming addresses with `symbols`.
+    pub fn report(&self, out: &mut impl Write, rows: usize, symbols: &Symbols) -> io::Result<()> {
+        writeln!(out, "Profile of {} instructions", self.total)?;
+
+        writeln!(out, "\nHot spots:")?;
+        writeln!(out, "  address      count       %  instruction")?;
+        for (address, count) in self.hot_spots().into_iter().take(rows) {
+            let opcode = self.by_address[&address].1;
+            let mut text = Instruction::from_opcode(opcode)
+                .map_or_else(|_| format!("{:04X}", opcode), |i| i.to_string());
+            if let Some(description) = symbols.describe(address) {
+                text = format!("{:<20}  <{}>", text, description);
+            }
+            writeln!(
+                out,
+                "  {:<7} {:>10} {:>6.2}%  {}",
+                format!("{:#05x}", address),
+                count,
+                self.percent(count),
+                text
+            )?;
+        }
+
+        writeln!(out, "\nInstructions:")?;
+        writeln!(out, "  pattern      count       %")?;
+        let mut patterns: Vec<(&str, u64)> =
+            self.by_pattern.iter().map(|(&p, &c)| (p, c)).collect();
+        patterns.sort_by_key(|&(pattern, count)| (std::cmp::Reverse(count), pattern));
+        for (pattern, count) in patterns.into_iter().take(rows) {
+            writeln!(
+                out,
+                "  {:<7} {:>10} {:>6.2}%",
+                pattern,
+                count,
+                self.percent(count)
+            )?;
+        }
+
+        writeln!(out, "\nCall tree:")?;
+        writeln!(out, "       total       %        self    calls  routine")?;
+        let totals = self.totals();
+        self.report_node(out, 0, 0, &totals, symbols)
+    }
+
+    fn report_node(
+        &self,
+        out: &mut impl Write,
+        index: usize,
+        depth: usize,
+        totals: &[u64],
+        symbols: &Symbols,
+    ) -> io::Result<()> {
+        let node = &self.nodes[index];
+        let name = match node.address {
+            Some(address) => symbols.format(address),
+            None => "<main>".to_string(),
+        };
+        writeln!(
+            out,
+            "  {:>10} {:>6.2}% {:>10} {:>8}  {:indent$}{}",
+            totals[index],
+            self.percent(totals[index]),
+            node.self_count,
+            node.calls,
+            "",
+            name,
+            indent = depth * 2
+        )?;
+        let mut children: Vec<usize> = node.children.values().copied().collect();
+        children
+            .sort_by_key(|&child| (std::cmp::Reverse(totals[child]), self.nodes[child].address));
+        for child in children {
+            self.report_node(out, child, depth + 1, totals, symbols)?;
+        }
+        Ok(())
+    }
+
+    /// Instructions spent in each node including its callees. Children are
+    /// always created after their parents, so one backwards pass suffices.
+    fn totals(&self) -> Vec<u64> {
+        let mut totals: Vec<u64> = self.nodes.iter().map(|n| n.self_count).collect();
+        for index in (1..self.nodes.len()).rev() {
+            if let Some(parent) = self.nodes[index].parent {
+                totals[parent] += totals[index];
+            }
+        }
+        totals
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / self.total as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Main calls 0x300 twice, which calls 0x400 each time.
+    fn profile() -> Profiler {
+        let mut profiler = Profiler::new();
+        for call in [0x200, 0x202] {
+            for (address, opcode) in [
+                (call, 0x2300),
+                (0x300, 0x7001),
+                (0x302, 0x2400),
+                (0x400, 0x00EE),
+                (0x304, 0x00EE),
+            ] {
+                profiler.record(address, opcode);
+            }
+        }
+        profiler
+    }
+
+    #[test]
+    fn counts_executions_by_address() {
+        let profiler = profile();
+        assert_eq!(profiler.total(), 10);
+        assert_eq!(profiler.count_at(0x300), 2);
+        assert_eq!(profiler.count_at(0x200), 1);
+        assert_eq!(profiler.count_at(0x206), 0);
+        assert_eq!(
+            profiler.hot_spots(),
+            [
+                (0x300, 2),
+                (0x302, 2),
+                (0x304, 2),
+                (0x400, 2),
+                (0x200, 1),
+                (0x202, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn report_lists_hot_spots_kinds_and_the_call_tree() {
+        let mut symbols = Symbols::new();
+        symbols.add_label(0x300, "update");
+        let mut out = Vec::new();
+        profile().report(&mut out, 2, &symbols).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.starts_with("Profile of 10 instructions\n"));
+        assert!(report.contains(
+            "  0x300            2  20.00%  ADD V0, 0x01          <update>\n  \
+             0x302            2  20.00%  CALL 0x400            <update+2>\n\n"
+        ));
+        assert!(report.contains("  00EE             4  40.00%\n  2NNN             4  40.00%\n\n"));
+        assert!(report.ends_with(
+            "          10 100.00%          2        1  <main>\n           \
+             8  80.00%          6        2    0x300 <update>\n           \
+             2  20.00%          2        2      0x400 <update+256>\n"
+        ));
+    }
+
+    #[test]
+    fn returns_without_a_call_stay_in_main() {
+        let mut profiler = Profiler::new();
+        profiler.record(0x200, 0x00EE);
+        profiler.record(0x202, 0xF000);
+        assert_eq!(profiler.total(), 2);
+        assert_eq!(profiler.totals(), [2]);
+        assert_eq!(profiler.by_pattern.values().sum::<u64>(), 1);
+    }
+}
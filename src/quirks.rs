@@ -0,0 +1,223 @@
+//! Behaviour differences between CHIP-8 implementations.
+//!
+//! Quirk names follow the CHIP-8 community database so profiles can be
+//! exchanged with other emulators.
+
+use crate::Error;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Platform presets, each selecting a set of quirks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Platform {
+    /// Original COSMAC VIP interpreter
+    Vip,
+    /// The default: 8XY6/8XYE shift VX in place and FX55/FX65 leave I
+    /// unchanged, as most modern CHIP-8 programs expect
+    Chip8,
+    /// SUPER-CHIP 1.1
+    SuperChip,
+    /// XO-CHIP, as implemented by Octo
+    XoChip,
+}
+
+impl Platform {
+    pub const ALL: [Platform; 4] = [
+        Platform::Vip,
+        Platform::Chip8,
+        Platform::SuperChip,
+        Platform::XoChip,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Vip => "vip",
+            Platform::Chip8 => "chip8",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+        }
+    }
+
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::Vip => Quirks {
+                shift: false,
+                memory_increment_by_x: false,
+                memory_leave_i_unchanged: false,
+                wrap: false,
+                jump: false,
+                vblank: true,
+                logic: true,
+            },
+            Platform::Chip8 => Quirks {
+                shift: true,
+                memory_increment_by_x: false,
+                memory_leave_i_unchanged: true,
+                wrap: false,
+                jump: false,
+                vblank: false,
+                logic: false,
+            },
+            Platform::SuperChip => Quirks {
+                shift: true,
+                memory_increment_by_x: false,
+                memory_leave_i_unchanged: true,
+                wrap: false,
+                jump: true,
+                vblank: false,
+                logic: false,
+            },
+            Platform::XoChip => Quirks {
+                shift: false,
+                memory_increment_by_x: false,
+                memory_leave_i_unchanged: false,
+                wrap: true,
+                jump: false,
+                vblank: false,
+                logic: false,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Platform {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Platform::ALL
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| Error::UnknownPlatform(s.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY6/8XYE shift VX in place instead of shifting VY into VX
+    pub shift: bool,
+    /// FX55/FX65 advance I by X instead of X + 1
+    pub memory_increment_by_x: bool,
+    /// FX55/FX65 leave I unchanged
+    pub memory_leave_i_unchanged: bool,
+    /// Sprites wrap around the screen edges instead of being clipped
+    pub wrap: bool,
+    /// BNNN jumps to VX + NNN, X being the high nibble of NNN
+    pub jump: bool,
+    /// DXYN waits for the next frame, allowing one sprite per frame
+    pub vblank: bool,
+    /// 8XY1/8XY2/8XY3 reset VF to 0
+    pub logic: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Platform::Chip8.quirks()
+    }
+}
+
+impl Quirks {
+    /// All quirks off.
+    pub const NONE: Quirks = Quirks {
+        shift: false,
+        memory_increment_by_x: false,
+        memory_leave_i_unchanged: false,
+        wrap: false,
+        jump: false,
+        vblank: false,
+        logic: false,
+    };
+
+    pub const NAMES: [&str; 7] = [
+        "shift",
+        "memoryIncrementByX",
+        "memoryLeaveIUnchanged",
+        "wrap",
+        "jump",
+        "vblank",
+        "logic",
+    ];
+
+    fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "shift" => Some(&mut self.shift),
+            "memoryIncrementByX" => Some(&mut self.memory_increment_by_x),
+            "memoryLeaveIUnchanged" => Some(&mut self.memory_leave_i_unchanged),
+            "wrap" => Some(&mut self.wrap),
+            "jump" => Some(&mut self.jump),
+            "vblank" => Some(&mut self.vblank),
+            "logic" => Some(&mut self.logic),
+            _ => None,
+        }
+    }
+
+    /// Turn the quirk called `name` on or off.
+    pub fn set(&mut self, name: &str, enabled: bool) -> Result<(), Error> {
+        let flag = self
+            .flag_mut(name)
+            .ok_or_else(|| Error::UnknownQuirk(name.to_string()))?;
+        *flag = enabled;
+        Ok(())
+    }
+
+    /// Apply a comma separated list of quirk names, each optionally prefixed
+    /// with `-` to turn it off, e.g. `shift,jump,-vblank`.
+    pub fn apply(&mut self, list: &str) -> Result<(), Error> {
+        for item in list.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            match item.strip_prefix('-') {
+                Some(name) => self.set(name, false)?,
+                None => self.set(item, true)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of the quirks that are turned on.
+    pub fn enabled(&self) -> Vec<&'static str> {
+        let mut quirks = *self;
+        Self::NAMES
+            .into_iter()
+            .filter(|name| quirks.flag_mut(name).is_some_and(|flag| *flag))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_keeps_the_original_shift_and_memory_behaviour() {
+        let quirks = Quirks::default();
+        assert_eq!(quirks, Platform::Chip8.quirks());
+        assert_eq!(quirks.enabled(), ["shift", "memoryLeaveIUnchanged"]);
+    }
+
+    #[test]
+    fn platforms_parse_by_name() {
+        for platform in Platform::ALL {
+            assert_eq!(platform.name().parse(), Ok(platform));
+        }
+        assert_eq!(
+            "chip9".parse::<Platform>(),
+            Err(Error::UnknownPlatform("chip9".to_string()))
+        );
+    }
+
+    #[test]
+    fn apply_turns_quirks_on_and_off() {
+        let mut quirks = Platform::Vip.quirks();
+        quirks.apply("shift, -vblank,,jump").unwrap();
+        assert_eq!(quirks.enabled(), ["shift", "jump", "logic"]);
+        assert_eq!(
+            quirks.apply("wrap,bogus"),
+            Err(Error::UnknownQuirk("bogus".to_string()))
+        );
+    }
+}
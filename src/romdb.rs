@@ -0,0 +1,348 @@
+//! Per-ROM settings keyed by the SHA-1 of the ROM bytes.
+//!
+//! Databases use the `programs.json` schema of the CHIP-8 community database,
+//! so its file can be dropped in as the user database. A small database is
+//! bundled with the interpreter; entries in the user database
+//! (`~/.config/secrus8/programs.json`) take precedence.
+
+use crate::consts::FRAMES_PER_SECOND;
+use crate::hex;
+use crate::input::KeyMap;
+use crate::quirks::{Platform, Quirks};
+use crate::{Error, user_config_dir};
+
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const BUNDLED: &str = include_str!("../data/programs.json");
+
+/// Keyboard keys bound to the game actions listed in the database.
+const ACTION_KEYS: [(&str, char); 6] = [
+    ("up", 'w'),
+    ("down", 's'),
+    ("left", 'a'),
+    ("right", 'd'),
+    ("a", ' '),
+    ("b", 'b'),
+];
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    roms: HashMap<String, RomEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RomEntry {
+    #[serde(default)]
+    platforms: Vec<String>,
+    tickrate: Option<u32>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, HashMap<String, bool>>,
+    start_address: Option<u16>,
+}
+
+/// Settings for one ROM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    pub platform: Option<Platform>,
+    pub quirks: Option<Quirks>,
+    /// Instructions per frame
+    pub tickrate: Option<u32>,
+    pub start_address: Option<u16>,
+    /// Bindings for the game's controls, to merge over the default key map
+    pub keymap: KeyMap,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RomDatabase {
+    roms: HashMap<String, RomInfo>,
+    /// Why entries were left out, e.g. for naming an unknown quirk
+    warnings: Vec<String>,
+}
+
+impl RomDatabase {
+    pub fn bundled() -> Self {
+        Self::parse(BUNDLED).expect("bundled ROM database is valid")
+    }
+
+    /// The bundled database with the user database on top, if there is one.
+    pub fn with_user_overrides() -> io::Result<Self> {
+        let mut database = Self::bundled();
+        if let Some(path) = Self::user_database_path()
+            && path.exists()
+        {
+            database.merge(Self::load(&path)?);
+        }
+        Ok(database)
+    }
+
+    pub fn user_database_path() -> Option<PathBuf> {
+        user_config_dir().map(|dir| dir.join("programs.json"))
+    }
+
+    /// Parse a database. Entries with invalid settings are left out and
+    /// listed in [`RomDatabase::warnings`], so one bad entry doesn't stop
+    /// the others from being used.
+    pub fn parse(json: &str) -> Result<Self, Error> {
+        let programs: Vec<Program> =
+            serde_json::from_str(json).map_err(|e| Error::InvalidDatabase(e.to_string()))?;
+
+        let mut database = RomDatabase::default();
+        for program in programs {
+            for (hash, entry) in program.roms {
+                match rom_info(&program.title, entry) {
+                    Ok(info) => {
+                        database.roms.insert(hash.to_ascii_lowercase(), info);
+                    }
+                    Err(e) => database.warnings.push(format!(
+                        "Skipped ROM database entry '{}' ({}): {}",
+                        program.title, hash, e
+                    )),
+                }
+            }
+        }
+        Ok(database)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Add the entries of `other`, replacing entries for the same ROM.
+    pub fn merge(&mut self, other: RomDatabase) {
+        self.roms.extend(other.roms);
+        self.warnings.extend(other.warnings);
+    }
+
+    pub fn get(&self, rom: &[u8]) -> Option<&RomInfo> {
+        self.roms.get(&sha1_hex(rom))
+    }
+
+    pub fn len(&self) -> usize {
+        self.roms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roms.is_empty()
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+}
+
+fn rom_info(title: &str, entry: RomEntry) -> Result<RomInfo, Error> {
+    // Use the first platform we can emulate, with its profile's quirks and
+    // the entry's overrides
+    let platform = entry.platforms.iter().find_map(|id| {
+        let platform = platform_from_id(id)?;
+        Some((id, platform, profile_quirks(id)?))
+    });
+
+    let quirks = match platform {
+        Some((id, _, mut quirks)) => {
+            for (name, &enabled) in entry.quirky_platforms.get(id).into_iter().flatten() {
+                quirks.set(name, enabled)?;
+            }
+            Some(quirks)
+        }
+        None => None,
+    };
+
+    let mut keymap = KeyMap::empty();
+    for (action, key) in ACTION_KEYS {
+        if let Some(&chip8_key) = entry.keys.get(action) {
+            keymap.bind(key, chip8_key);
+        }
+    }
+
+    if let Some(tickrate) = entry.tickrate
+        && tickrate.checked_mul(FRAMES_PER_SECOND).is_none()
+    {
+        return Err(Error::InvalidDatabase(format!(
+            "tickrate {} is too high",
+            tickrate
+        )));
+    }
+
+    Ok(RomInfo {
+        title: title.to_string(),
+        platform: platform.map(|(_, platform, _)| platform),
+        quirks,
+        tickrate: entry.tickrate,
+        start_address: entry.start_address,
+        keymap,
+    })
+}
+
+/// Our closest platform to a database platform id.
+pub fn platform_from_id(id: &str) -> Option<Platform> {
+    match id {
+        "originalChip8" | "hybridVIP" => Some(Platform::Vip),
+        "modernChip8" | "chip48" | "chip8x" => Some(Platform::Chip8),
+        "superchip1" | "superchip" | "megachip8" => Some(Platform::SuperChip),
+        "xochip" => Some(Platform::XoChip),
+        _ => None,
+    }
+}
+
+/// The quirks of a database platform id, as listed in the community
+/// database's `platforms.json`. These differ from our presets, e.g.
+/// `modernChip8` has every quirk off while the `chip8` preset keeps the
+/// original shift and load/store behaviour.
+pub fn profile_quirks(id: &str) -> Option<Quirks> {
+    let quirks = match id {
+        "originalChip8" | "hybridVIP" | "chip8x" => Quirks {
+            vblank: true,
+            logic: true,
+            ..Quirks::NONE
+        },
+        "modernChip8" => Quirks::NONE,
+        "chip48" => Quirks {
+            shift: true,
+            memory_increment_by_x: true,
+            jump: true,
+            ..Quirks::NONE
+        },
+        "superchip1" | "superchip" | "megachip8" => Quirks {
+            shift: true,
+            memory_leave_i_unchanged: true,
+            jump: true,
+            ..Quirks::NONE
+        },
+        "xochip" => Quirks {
+            wrap: true,
+            ..Quirks::NONE
+        },
+        _ => return None,
+    };
+    Some(quirks)
+}
+
+/// Lowercase hex SHA-1 of `rom`, the database key.
+pub fn sha1_hex(rom: &[u8]) -> String {
+    hex::encode(&Sha1::digest(rom))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: &[u8] = b"\x12\x00";
+
+    fn database(quirks: &str) -> String {
+        format!(
+            r#"[{{
+                "title": "Pong",
+                "roms": {{
+                    "{}": {{
+                        "platforms": ["megachip8", "superchip"],
+                        "tickrate": 30,
+                        "startAddress": 768,
+                        "keys": {{ "up": 1, "a": 10 }},
+                        "quirkyPlatforms": {{ "megachip8": {{ {} }} }}
+                    }},
+                    "0000": {{ "platforms": ["xochip"] }}
+                }}
+            }}]"#,
+            sha1_hex(ROM).to_ascii_uppercase(),
+            quirks
+        )
+    }
+
+    #[test]
+    fn entries_are_found_by_hash() {
+        let database = RomDatabase::parse(&database(r#""wrap": true"#)).unwrap();
+        assert_eq!(database.len(), 2);
+        assert!(database.warnings().is_empty());
+
+        let info = database.get(ROM).unwrap();
+        assert_eq!(info.title, "Pong");
+        assert_eq!(info.platform, Some(Platform::SuperChip));
+        let mut quirks = Platform::SuperChip.quirks();
+        quirks.wrap = true;
+        assert_eq!(info.quirks, Some(quirks));
+        assert_eq!(info.tickrate, Some(30));
+        assert_eq!(info.start_address, Some(0x300));
+        assert_eq!(info.keymap.get('w'), Some(0x1));
+        assert_eq!(info.keymap.get(' '), Some(0xA));
+        assert_eq!(info.keymap.get('s'), None);
+        assert!(database.get(b"other").is_none());
+    }
+
+    #[test]
+    fn entries_with_unknown_quirks_are_skipped() {
+        let database = RomDatabase::parse(&database(r#""warp": true"#)).unwrap();
+        assert_eq!(database.len(), 1);
+        assert!(database.get(ROM).is_none());
+        assert_eq!(database.warnings().len(), 1);
+        assert!(database.warnings()[0].contains("Unknown quirk 'warp'"));
+    }
+
+    #[test]
+    fn quirks_follow_the_database_profiles() {
+        let json = r#"[{
+            "title": "Modern",
+            "roms": {
+                "01": { "platforms": ["modernChip8"] },
+                "02": { "platforms": ["chip48"] },
+                "03": { "platforms": ["chip8x"], "quirkyPlatforms": { "chip8x": { "wrap": true } } }
+            }
+        }]"#;
+        let database = RomDatabase::parse(json).unwrap();
+        let quirks = |hash: &str| database.roms[hash].quirks.unwrap();
+        assert_eq!(quirks("01"), Quirks::NONE);
+        assert!(quirks("01").enabled().is_empty());
+        assert_eq!(database.roms["01"].platform, Some(Platform::Chip8));
+        assert_eq!(
+            quirks("02").enabled(),
+            ["shift", "memoryIncrementByX", "jump"]
+        );
+        assert_eq!(quirks("03").enabled(), ["wrap", "vblank", "logic"]);
+    }
+
+    #[test]
+    fn entries_with_huge_tickrates_are_skipped() {
+        let json = r#"[{ "title": "Fast", "roms": { "01": { "tickrate": 4294967295 } } }]"#;
+        let database = RomDatabase::parse(json).unwrap();
+        assert!(database.is_empty());
+        assert!(database.warnings()[0].contains("tickrate 4294967295 is too high"));
+    }
+
+    #[test]
+    fn merged_entries_take_precedence() {
+        let mut database = RomDatabase::parse(&database("")).unwrap();
+        let user = r#"[{ "title": "Mine", "roms": { "0000": {} } }]"#;
+        database.merge(RomDatabase::parse(user).unwrap());
+        assert_eq!(database.len(), 2);
+        assert_eq!(database.roms["0000"].title, "Mine");
+        assert_eq!(database.roms["0000"].platform, None);
+    }
+
+    #[test]
+    fn bundled_database_is_valid() {
+        assert!(!RomDatabase::bundled().is_empty());
+        assert!(RomDatabase::bundled().warnings().is_empty());
+        assert!(matches!(
+            RomDatabase::parse("{"),
+            Err(Error::InvalidDatabase(_))
+        ));
+    }
+
+    #[test]
+    fn hashes_are_lowercase_hex() {
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+}
@@ -0,0 +1,174 @@
+//! Static facts about a ROM and a guess of the platform it targets.
+
+use crate::flow::ControlFlow;
+use crate::parser::Instruction;
+use crate::quirks::Platform;
+use crate::romdb::sha1_hex;
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomReport {
+    pub size: usize,
+    pub sha1: String,
+    /// Number of words decoding to each opcode pattern, e.g. `DXYN`
+    pub families: BTreeMap<&'static str, usize>,
+    /// Address and value of the words that are not valid instructions
+    pub invalid: Vec<(u16, u16)>,
+    pub platform: PlatformGuess,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlatformGuess {
+    pub platform: Platform,
+    /// Why the platform was picked, e.g. `00FF (high resolution) at 0x2A4`
+    pub evidence: Vec<String>,
+}
+
+/// Decode every word of `rom`, as loaded at `base`.
+///
+/// Data is decoded too, so the report is an over-approximation of the code.
+pub fn analyze(rom: &[u8], base: u16) -> RomReport {
+    let mut families = BTreeMap::new();
+    let mut invalid = Vec::new();
+
+    for (address, word) in words(rom, base) {
+        match Instruction::from_opcode(word) {
+            Ok(instruction) => *families.entry(instruction.pattern()).or_insert(0) += 1,
+            Err(_) => invalid.push((address, word)),
+        }
+    }
+
+    RomReport {
+        size: rom.len(),
+        sha1: sha1_hex(rom),
+        families,
+        invalid,
+        platform: guess_platform(rom, base),
+    }
+}
+
+/// Guess the platform from opcodes only some platforms have: XO-CHIP
+/// extensions win over SUPER-CHIP ones, which win over COSMAC VIP machine
+/// code calls.
+///
+/// Only words reachable from `base` are considered, so sprite data that
+/// happens to look like an extension opcode doesn't count.
+pub fn guess_platform(rom: &[u8], base: u16) -> PlatformGuess {
+    let mut xochip = Vec::new();
+    let mut schip = Vec::new();
+    let mut vip = Vec::new();
+
+    for (address, word) in ControlFlow::analyze(rom, base).instructions() {
+        let evidence = |name: &str| format!("{:04X} ({}) at {:#05x}", word, name, address);
+
+        if let Some(name) = xochip_opcode(word) {
+            xochip.push(evidence(name));
+        } else if let Some(name) = schip_opcode(word) {
+            schip.push(evidence(name));
+        } else if is_machine_code_call(word) {
+            vip.push(evidence("machine code call"));
+        }
+    }
+
+    let (platform, evidence) = if !xochip.is_empty() {
+        (Platform::XoChip, xochip)
+    } else if !schip.is_empty() {
+        (Platform::SuperChip, schip)
+    } else if !vip.is_empty() {
+        (Platform::Vip, vip)
+    } else {
+        (Platform::Chip8, Vec::new())
+    };
+    PlatformGuess { platform, evidence }
+}
+
+fn is_machine_code_call(word: u16) -> bool {
+    word != 0 && matches!(Instruction::from_opcode(word), Ok(Instruction::SysCall(_)))
+}
+
+pub(crate) fn xochip_opcode(word: u16) -> Option<&'static str> {
+    match word {
+        0xF000 => Some("long index load"),
+        0xF002 => Some("audio pattern"),
+        _ if word & 0xFFF0 == 0x00D0 => Some("scroll up"),
+        _ if word & 0xF00F == 0x5002 => Some("register range save"),
+        _ if word & 0xF00F == 0x5003 => Some("register range load"),
+        _ if word & 0xF0FF == 0xF001 => Some("plane select"),
+        _ if word & 0xF0FF == 0xF03A => Some("pitch"),
+        _ => None,
+    }
+}
+
+pub(crate) fn schip_opcode(word: u16) -> Option<&'static str> {
+    match word {
+        0x00FB => Some("scroll right"),
+        0x00FC => Some("scroll left"),
+        0x00FD => Some("exit"),
+        0x00FE => Some("low resolution"),
+        0x00FF => Some("high resolution"),
+        _ if word & 0xFFF0 == 0x00C0 => Some("scroll down"),
+        _ if word & 0xF0FF == 0xF030 => Some("large font"),
+        _ if word & 0xF0FF == 0xF075 => Some("flag save"),
+        _ if word & 0xF0FF == 0xF085 => Some("flag load"),
+        _ => None,
+    }
+}
+
+fn words(rom: &[u8], base: u16) -> impl Iterator<Item = (u16, u16)> + '_ {
+    // Words past the end of the 64 KiB address space can't be loaded
+    rom.chunks_exact(2)
+        .enumerate()
+        .map_while(move |(i, bytes)| {
+            let address = u16::try_from(i * 2)
+                .ok()
+                .and_then(|o| base.checked_add(o))?;
+            Some((address, (bytes[0] as u16) << 8 | bytes[1] as u16))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_opcode_families_and_invalid_words() {
+        // CLS; LD V0, 1; LD V1, 2; DW 0x5001; JP 0x208; trailing byte
+        let rom = [
+            0x00, 0xE0, 0x60, 0x01, 0x61, 0x02, 0x50, 0x01, 0x12, 0x08, 0xFF,
+        ];
+        let report = analyze(&rom, 0x200);
+        assert_eq!(report.size, 11);
+        assert_eq!(report.families["00E0"], 1);
+        assert_eq!(report.families["6XNN"], 2);
+        assert_eq!(report.families["1NNN"], 1);
+        assert_eq!(report.invalid, [(0x206, 0x5001)]);
+        assert_eq!(report.platform.platform, Platform::Chip8);
+    }
+
+    #[test]
+    fn guesses_the_platform_from_reachable_code() {
+        let guess = |rom: &[u8]| guess_platform(rom, 0x200);
+
+        // SYS 0x300; hires; F002; JP 0x206
+        let rom = [0x03, 0x00, 0x00, 0xFF, 0xF0, 0x02, 0x12, 0x06];
+        let xochip = guess(&rom);
+        assert_eq!(xochip.platform, Platform::XoChip);
+        assert_eq!(xochip.evidence, ["F002 (audio pattern) at 0x204"]);
+        assert_eq!(guess(&rom[..4]).platform, Platform::SuperChip);
+        assert_eq!(
+            guess(&rom[..2]).evidence,
+            ["0300 (machine code call) at 0x200"]
+        );
+
+        // JP 0x204; F002 as data; JP 0x204
+        let rom = [0x12, 0x04, 0xF0, 0x02, 0x12, 0x04];
+        assert_eq!(guess(&rom).platform, Platform::Chip8);
+    }
+
+    #[test]
+    fn words_past_the_address_space_are_dropped() {
+        let rom = [0x00, 0xE0, 0x00, 0xE0, 0x00, 0xE0];
+        assert_eq!(analyze(&rom, 0xFFFC).families["00E0"], 2);
+    }
+}
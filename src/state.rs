@@ -1,33 +1,45 @@
-use crate::consts::{FONT_DATA, INITIAL_PC, TOTAL_RAM_SIZE};
+use crate::font::Fonts;
+use crate::input::{KEY_COUNT, Keypad};
+use crate::memory::MemoryLayout;
 
 pub struct State {
-    pub ram: [u8; TOTAL_RAM_SIZE as usize],
+    pub ram: Vec<u8>,
+    pub layout: MemoryLayout,
     pub stack: Vec<u16>,
     pub pc: u16,
     pub registers: [u8; 16],
     pub index_register: u16,
     pub delay_timer: u8,
     pub sound_timer: u8,
+    pub keypad: Keypad,
 }
 
 impl Default for State {
     fn default() -> Self {
-        Self::new()
+        Self::new(MemoryLayout::default(), &Fonts::default())
     }
 }
 
 impl State {
-    pub fn new() -> Self {
-        let mut ram = [0; TOTAL_RAM_SIZE as usize];
-        ram[0x50..=0x9F].copy_from_slice(&FONT_DATA);
+    /// Fresh machine with `fonts` installed where `layout` says.
+    ///
+    /// Panics if the fonts don't fit, see [`MemoryLayout::validate`].
+    pub fn new(layout: MemoryLayout, fonts: &Fonts) -> Self {
+        let mut ram = vec![0; layout.ram_size];
+        let font = layout.font_address as usize;
+        ram[font..font + fonts.small().len()].copy_from_slice(fonts.small());
+        let big_font = layout.big_font_address as usize;
+        ram[big_font..big_font + fonts.big().len()].copy_from_slice(fonts.big());
         State {
             ram,
+            layout,
             stack: Vec::new(),
-            pc: INITIAL_PC,
+            pc: layout.program_start,
             registers: [0; 16],
             index_register: 0,
             delay_timer: 0,
             sound_timer: 0,
+            keypad: [false; KEY_COUNT],
         }
     }
 }
@@ -0,0 +1,259 @@
+//! Symbol files: names and source lines for addresses, so listings and
+//! reports can say `main_loop+4` and `game.8o:12` instead of `0x208`.
+//!
+//! A symbol file has one entry per line, a label or a source line:
+//!
+//! ```text
+//! # address name, or address file:line
+//! 0x200 main
+//! 0x204 main_loop
+//! 0x204 game.8o:12
+//! ```
+//!
+//! `asm --symbols` writes one for the labels and statements of a program.
+
+use crate::Error;
+use crate::assembler::{Assembly, parse_number};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+    /// The first label given for each address
+    labels: BTreeMap<u16, String>,
+    addresses: HashMap<String, u16>,
+    files: Vec<String>,
+    /// File index and line of the statement at each address
+    lines: BTreeMap<u16, (usize, usize)>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The labels and statement lines of `assembly`, compiled from `file`.
+    pub fn from_assembly(assembly: &Assembly, file: &str) -> Self {
+        let mut symbols = Symbols::new();
+        let mut labels: Vec<(&String, &u16)> = assembly.labels.iter().collect();
+        labels.sort_by_key(|&(name, &address)| (address, name));
+        for (name, &address) in labels {
+            symbols.add_label(address, name);
+        }
+        for &(address, line) in &assembly.lines {
+            symbols.add_line(address, file, line);
+        }
+        symbols
+    }
+
+    /// Parse a symbol file.
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut symbols = Symbols::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || Error::InvalidSymbols(number + 1, line.to_string());
+
+            let (address, rest) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let address = parse_number(address)
+                .filter(|&a| a <= u16::MAX as u32)
+                .ok_or_else(invalid)? as u16;
+            let rest = rest.trim();
+            match rest.rsplit_once(':') {
+                Some((file, line)) if !file.is_empty() => {
+                    let line = line.parse().map_err(|_| invalid())?;
+                    symbols.add_line(address, file, line);
+                }
+                Some(_) => return Err(invalid()),
+                None if rest.contains(char::is_whitespace) => return Err(invalid()),
+                None => symbols.add_label(address, rest),
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Load a symbol file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Write the symbols in the format [`Symbols::parse`] reads.
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "# address name, or address file:line")?;
+        let mut labels: Vec<(&String, &u16)> = self.addresses.iter().collect();
+        labels.sort_by_key(|&(name, &address)| (address, name));
+        for (name, address) in labels {
+            writeln!(out, "{:#05x} {}", address, name)?;
+        }
+        for (address, &(file, line)) in &self.lines {
+            writeln!(out, "{:#05x} {}:{}", address, self.files[file], line)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.lines.is_empty()
+    }
+
+    pub fn add_label(&mut self, address: u16, name: &str) {
+        self.labels
+            .entry(address)
+            .or_insert_with(|| name.to_string());
+        self.addresses.insert(name.to_string(), address);
+    }
+
+    pub fn add_line(&mut self, address: u16, file: &str, line: usize) {
+        let index = match self.files.iter().position(|f| f == file) {
+            Some(index) => index,
+            None => {
+                self.files.push(file.to_string());
+                self.files.len() - 1
+            }
+        };
+        self.lines.insert(address, (index, line));
+    }
+
+    /// The label at exactly `address`.
+    pub fn label_at(&self, address: u16) -> Option<&str> {
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    /// `address` relative to the closest label at or before it, e.g.
+    /// `main_loop+4`.
+    pub fn name(&self, address: u16) -> Option<String> {
+        let (&start, label) = self.labels.range(..=address).next_back()?;
+        Some(match address - start {
+            0 => label.clone(),
+            offset => format!("{}+{}", label, offset),
+        })
+    }
+
+    /// The source line of the statement at `address`, e.g. `game.8o:12`.
+    pub fn source_line(&self, address: u16) -> Option<String> {
+        let &(file, line) = self.lines.get(&address)?;
+        Some(format!("{}:{}", self.files[file], line))
+    }
+
+    /// The name and source line of `address`, as far as they're known, e.g.
+    /// `main_loop+4, game.8o:12`.
+    pub fn describe(&self, address: u16) -> Option<String> {
+        match (self.name(address), self.source_line(address)) {
+            (Some(name), Some(line)) => Some(format!("{}, {}", name, line)),
+            (name, line) => name.or(line),
+        }
+    }
+
+    /// `address` followed by its description in angle brackets, if any,
+    /// e.g. `0x208 <main_loop+4, game.8o:12>`.
+    pub fn format(&self, address: u16) -> String {
+        match self.describe(address) {
+            Some(description) => format!("{:#05x} <{}>", address, description),
+            None => format!("{:#05x}", address),
+        }
+    }
+
+    /// Read an address written as a number, a label, or a label plus an
+    /// offset such as `main_loop+4`.
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        if let Some(number) = parse_number(text) {
+            return u16::try_from(number).ok();
+        }
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => (name, u16::try_from(parse_number(offset)?).ok()?),
+            None => (text, 0),
+        };
+        self.address_of(name)?.checked_add(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_program;
+
+    const FILE: &str = "\
+# address name, or address file:line
+0x200 main
+0x204 main_loop   # the game loop
+0x204 game.8o:12
+0x20a C:/games/game.8o:14
+";
+
+    #[test]
+    fn parses_labels_and_source_lines() {
+        let symbols = Symbols::parse(FILE).unwrap();
+        assert_eq!(symbols.label_at(0x204), Some("main_loop"));
+        assert_eq!(symbols.address_of("main"), Some(0x200));
+        assert_eq!(symbols.source_line(0x204).as_deref(), Some("game.8o:12"));
+        assert_eq!(
+            symbols.source_line(0x20A).as_deref(),
+            Some("C:/games/game.8o:14")
+        );
+        assert!(Symbols::parse("# nothing\n\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_lines_with_their_number() {
+        for line in [
+            "0x200",
+            "main 0x200",
+            "0x10000 main",
+            "0x200 main loop",
+            "0x200 game.8o:x",
+            "0x200 :12",
+        ] {
+            let text = format!("0x200 main\n{}\n", line);
+            assert_eq!(
+                Symbols::parse(&text),
+                Err(Error::InvalidSymbols(2, line.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn written_symbols_parse_back() {
+        let assembly = assemble_program("start: CLS\nloop:\n JP loop\n", 0x200).unwrap();
+        let symbols = Symbols::from_assembly(&assembly, "game.s");
+        assert_eq!(symbols.address_of("loop"), Some(0x202));
+        assert_eq!(symbols.source_line(0x202).as_deref(), Some("game.s:3"));
+
+        let mut out = Vec::new();
+        symbols.write(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(
+            text,
+            "# address name, or address file:line\n\
+             0x200 start\n0x202 loop\n0x200 game.s:1\n0x202 game.s:3\n"
+        );
+        assert_eq!(Symbols::parse(&text).unwrap(), symbols);
+    }
+
+    #[test]
+    fn names_addresses_relative_to_labels() {
+        let symbols = Symbols::parse(FILE).unwrap();
+        assert_eq!(symbols.name(0x1FE), None);
+        assert_eq!(symbols.name(0x206).as_deref(), Some("main_loop+2"));
+        assert_eq!(
+            symbols.describe(0x204).as_deref(),
+            Some("main_loop, game.8o:12")
+        );
+        assert_eq!(symbols.format(0x202), "0x202 <main+2>");
+        assert_eq!(Symbols::new().format(0x202), "0x202");
+
+        assert_eq!(symbols.resolve("main_loop+0x4"), Some(0x208));
+        assert_eq!(symbols.resolve("0x300"), Some(0x300));
+        assert_eq!(symbols.resolve("main+0x10000"), None);
+        assert_eq!(symbols.resolve("nowhere"), None);
+    }
+}
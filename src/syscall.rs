@@ -0,0 +1,75 @@
+//! Handling of 0NNN, which calls CDP1802 machine code on the COSMAC VIP.
+//!
+//! Hybrid ROMs ship machine code routines alongside their CHIP-8 code. They
+//! can't be run as is, but a routine can be reimplemented in Rust and
+//! registered for its address. No routines are built in: dispatching is for
+//! programs embedding the interpreter.
+
+use crate::state::State;
+use crate::{Error, Result};
+
+use std::fmt;
+use std::str::FromStr;
+
+/// A routine standing in for the machine code at one address.
+pub type SysCallHandler = Box<dyn FnMut(&mut State) -> Result<()>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SysCallPolicy {
+    /// Treat 0NNN as a no-op, which is what most modern interpreters do
+    Ignore,
+    /// Fail with `Error::UnsupportedSysCall`
+    #[default]
+    Error,
+    /// Run the handler registered for the address, failing when there is none
+    Dispatch,
+}
+
+impl SysCallPolicy {
+    pub const ALL: [SysCallPolicy; 3] = [
+        SysCallPolicy::Ignore,
+        SysCallPolicy::Error,
+        SysCallPolicy::Dispatch,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SysCallPolicy::Ignore => "ignore",
+            SysCallPolicy::Error => "error",
+            SysCallPolicy::Dispatch => "dispatch",
+        }
+    }
+}
+
+impl fmt::Display for SysCallPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for SysCallPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        SysCallPolicy::ALL
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| Error::UnknownSysCallPolicy(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies_parse_from_their_names() {
+        for policy in SysCallPolicy::ALL {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+        assert_eq!(
+            "skip".parse::<SysCallPolicy>(),
+            Err(Error::UnknownSysCallPolicy("skip".to_string()))
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Scripted keypad input for running ROMs without a human at the keyboard.
+//!
+//! A timeline file lists key events by frame number, one per line:
+//!
+//! ```text
+//! # start the game, then pick the second menu entry
+//! frame 120 press 5
+//! frame 130 release 5
+//! frame 200 press 8
+//! frame 205 release 8
+//! ```
+//!
+//! The same timeline can be built in Rust:
+//!
+//! ```
+//! use secrus8::display::CLIDisplay;
+//! use secrus8::interpreter::Interpreter;
+//! use secrus8::timeline::Timeline;
+//!
+//! let mut core = Interpreter::new();
+//! core.set_display(CLIDisplay::headless());
+//! core.set_input(Box::new(Timeline::new().tap(120, 0x5, 10).tap(200, 0x8, 5)));
+//! // LD V0, 0; JP 0x200
+//! core.load_rom(vec![0x60, 0x00, 0x12, 0x00]).unwrap();
+//! core.run_headless(125).unwrap();
+//! assert!(core.state().keypad[0x5]);
+//! core.run_headless(10).unwrap();
+//! assert!(!core.state().keypad[0x5]);
+//! ```
+
+use crate::Error;
+use crate::input::{InputBackend, KEY_COUNT, Keypad};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyEvent {
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+/// Key events applied at given frame numbers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timeline {
+    events: Vec<KeyEvent>,
+    next: usize,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Press `key` at the start of `frame`.
+    pub fn press(self, frame: u64, key: u8) -> Self {
+        self.event(frame, key, true)
+    }
+
+    /// Release `key` at the start of `frame`.
+    pub fn release(self, frame: u64, key: u8) -> Self {
+        self.event(frame, key, false)
+    }
+
+    /// Press `key` at `frame` and release it `frames` frames later.
+    pub fn tap(self, frame: u64, key: u8, frames: u64) -> Self {
+        self.press(frame, key).release(frame + frames, key)
+    }
+
+    fn event(mut self, frame: u64, key: u8, pressed: bool) -> Self {
+        // Keep events ordered by frame, and in insertion order within a frame
+        let index = self.events.partition_point(|e| e.frame <= frame);
+        self.events.insert(
+            index,
+            KeyEvent {
+                frame,
+                key: key & 0xF,
+                pressed,
+            },
+        );
+        self
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    /// Parse a timeline file, see the module documentation for the format.
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut timeline = Timeline::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || Error::InvalidTimeline(number + 1, line.to_string());
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let [keyword, frame, action, key] = words[..] else {
+                return Err(invalid());
+            };
+            if keyword != "frame" {
+                return Err(invalid());
+            }
+            let frame: u64 = frame.parse().map_err(|_| invalid())?;
+            let key = key.strip_prefix("0x").unwrap_or(key);
+            let key = u8::from_str_radix(key, 16).map_err(|_| invalid())?;
+            if key as usize >= KEY_COUNT {
+                return Err(invalid());
+            }
+            timeline = match action {
+                "press" => timeline.press(frame, key),
+                "release" => timeline.release(frame, key),
+                _ => return Err(invalid()),
+            };
+        }
+
+        Ok(timeline)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Whether every event has been applied.
+    pub fn is_finished(&self) -> bool {
+        self.next == self.events.len()
+    }
+}
+
+impl InputBackend for Timeline {
+    fn update(&mut self, frame: u64, keypad: &mut Keypad) {
+        while let Some(event) = self.events.get(self.next) {
+            if event.frame > frame {
+                break;
+            }
+            keypad[event.key as usize] = event.pressed;
+            self.next += 1;
+        }
+    }
+
+    fn position(&self) -> Option<usize> {
+        Some(self.next)
+    }
+
+    fn rewind(&mut self, position: usize) {
+        self.next = position.min(self.events.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_events_in_frame_order() {
+        let text = "# comment\nframe 130 release 5\n\nframe 120 press 0x5 # start\n";
+        let timeline = Timeline::parse(text).unwrap();
+        assert_eq!(timeline, Timeline::new().tap(120, 0x5, 10));
+    }
+
+    #[test]
+    fn rejects_malformed_lines_with_their_number() {
+        for line in [
+            "frame 1 press",
+            "frames 1 press 5",
+            "frame x press 5",
+            "frame 1 hold 5",
+            "frame 1 press 10",
+        ] {
+            let text = format!("frame 0 press 1\n{}\n", line);
+            assert_eq!(
+                Timeline::parse(&text),
+                Err(Error::InvalidTimeline(2, line.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn applies_events_up_to_the_frame() {
+        let mut timeline = Timeline::new().press(2, 0x1).tap(3, 0x2, 2);
+        let mut keypad = [false; KEY_COUNT];
+        timeline.update(1, &mut keypad);
+        assert_eq!(keypad, [false; KEY_COUNT]);
+
+        // Frames can be skipped, e.g. when resuming a run
+        timeline.update(3, &mut keypad);
+        assert!(keypad[0x1] && keypad[0x2]);
+        assert!(!timeline.is_finished());
+
+        timeline.update(5, &mut keypad);
+        assert!(keypad[0x1] && !keypad[0x2]);
+        assert!(timeline.is_finished());
+    }
+
+    #[test]
+    fn rewinding_replays_the_events() {
+        let mut timeline = Timeline::new().tap(1, 0x3, 1);
+        let mut keypad = [false; KEY_COUNT];
+        let start = timeline.position();
+        timeline.update(2, &mut keypad);
+        assert!(timeline.is_finished());
+
+        timeline.rewind(start.unwrap());
+        keypad = [false; KEY_COUNT];
+        timeline.update(1, &mut keypad);
+        assert!(keypad[0x3]);
+        assert_eq!(timeline.position(), Some(1));
+    }
+}
@@ -0,0 +1,503 @@
+//! Per-instruction execution traces, for comparing runs with each other or
+//! with other emulators.
+//!
+//! Each record holds the machine state after one instruction. The text format
+//! has one line per instruction:
+//!
+//! ```text
+//! # cycle pc opcode v0 .. vf i sp dt st [address=value ..] ; instruction
+//! 4 0208 D01F 0C 08 00 00 00 00 00 00 00 00 00 00 00 00 00 00 022A 00 00 00 ; DRW V0, V1, 15
+//! ```
+//!
+//! Numbers are hex except the decimal cycle. The RAM writes made by the
+//! instruction come before the `;`, e.g. `0300=01`. With symbols, the label
+//! and source line of the instruction follow it, e.g. `<main_loop+4>`.
+//! The binary format holds the same fields in little-endian order after an
+//! 8 byte header.
+
+use crate::Error;
+use crate::parser::Instruction;
+use crate::state::State;
+use crate::symbols::Symbols;
+
+use std::fmt;
+use std::io::{self, Write};
+
+/// Start of a binary trace.
+pub const BINARY_MAGIC: &[u8; 8] = b"S8TRACE1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Number of instructions executed before this one
+    pub cycle: u64,
+    /// Address of the instruction
+    pub pc: u16,
+    pub opcode: u16,
+    pub registers: [u8; 16],
+    pub index_register: u16,
+    /// Stack depth
+    pub sp: u8,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    /// Address and value of each RAM write, in order
+    pub writes: Vec<(u16, u8)>,
+}
+
+impl TraceRecord {
+    /// Record for the instruction at `pc` that left the machine in `state`.
+    pub fn new(cycle: u64, pc: u16, opcode: u16, state: &State, writes: &[(u16, u8)]) -> Self {
+        TraceRecord {
+            cycle,
+            pc,
+            opcode,
+            registers: state.registers,
+            index_register: state.index_register,
+            sp: state.stack.len() as u8,
+            delay_timer: state.delay_timer,
+            sound_timer: state.sound_timer,
+            writes: writes.to_vec(),
+        }
+    }
+
+    /// The instruction in assembler syntax, `???` if it doesn't decode.
+    pub fn mnemonic(&self) -> String {
+        Instruction::from_opcode(self.opcode)
+            .map(|i| i.to_string())
+            .unwrap_or_else(|_| "???".to_string())
+    }
+}
+
+/// The record as a line of the text format, without the newline.
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:04X} {:04X}", self.cycle, self.pc, self.opcode)?;
+        for v in self.registers {
+            write!(f, " {:02X}", v)?;
+        }
+        write!(
+            f,
+            " {:04X} {:02X} {:02X} {:02X}",
+            self.index_register, self.sp, self.delay_timer, self.sound_timer
+        )?;
+        for (address, value) in &self.writes {
+            write!(f, " {:04X}={:02X}", address, value)?;
+        }
+        write!(f, " ; {}", self.mnemonic())
+    }
+}
+
+/// Destination for the records of a trace.
+pub trait TraceSink {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()>;
+
+    /// Called once when the interpreter stops.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes the text format.
+pub struct TextTrace<W: Write> {
+    out: W,
+    symbols: Symbols,
+}
+
+impl<W: Write> TextTrace<W> {
+    /// Start a trace, writing the header line.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        writeln!(
+            out,
+            "# cycle pc opcode v0 .. vf i sp dt st [address=value ..] ; instruction"
+        )?;
+        Ok(TextTrace {
+            out,
+            symbols: Symbols::new(),
+        })
+    }
+
+    /// Follow each instruction with its label and source line.
+    pub fn with_symbols(mut self, symbols: Symbols) -> Self {
+        self.symbols = symbols;
+        self
+    }
+}
+
+impl<W: Write> TraceSink for TextTrace<W> {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        match self.symbols.describe(record.pc) {
+            Some(description) => writeln!(self.out, "{} <{}>", record, description),
+            None => writeln!(self.out, "{}", record),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Writes the binary format: 34 bytes per record plus 3 per RAM write.
+pub struct BinaryTrace<W: Write> {
+    out: W,
+}
+
+impl<W: Write> BinaryTrace<W> {
+    /// Start a trace, writing the header.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(BINARY_MAGIC)?;
+        Ok(BinaryTrace { out })
+    }
+}
+
+impl<W: Write> TraceSink for BinaryTrace<W> {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(34 + record.writes.len() * 3);
+        bytes.extend(record.cycle.to_le_bytes());
+        bytes.extend(record.pc.to_le_bytes());
+        bytes.extend(record.opcode.to_le_bytes());
+        bytes.extend(record.registers);
+        bytes.extend(record.index_register.to_le_bytes());
+        bytes.extend([
+            record.sp,
+            record.delay_timer,
+            record.sound_timer,
+            record.writes.len() as u8,
+        ]);
+        for (address, value) in &record.writes {
+            bytes.extend(address.to_le_bytes());
+            bytes.push(*value);
+        }
+        self.out.write_all(&bytes)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Read a trace in either format.
+pub fn parse(bytes: &[u8]) -> Result<Vec<TraceRecord>, Error> {
+    match bytes.strip_prefix(BINARY_MAGIC) {
+        Some(records) => parse_binary(records),
+        None => {
+            let text = std::str::from_utf8(bytes)
+                .map_err(|_| Error::InvalidTrace("not a trace file".to_string()))?;
+            parse_text(text)
+        }
+    }
+}
+
+fn parse_text(text: &str) -> Result<Vec<TraceRecord>, Error> {
+    let mut records = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || Error::InvalidTrace(format!("line {}: '{}'", i + 1, line));
+        let fields = line.split(" ; ").next().unwrap_or(line);
+        let words: Vec<&str> = fields.split_whitespace().collect();
+        if words.len() < 23 {
+            return Err(invalid());
+        }
+        let hex16 = |w: &str| u16::from_str_radix(w, 16).map_err(|_| invalid());
+        let hex8 = |w: &str| u8::from_str_radix(w, 16).map_err(|_| invalid());
+
+        let mut registers = [0; 16];
+        for (register, word) in registers.iter_mut().zip(&words[3..19]) {
+            *register = hex8(word)?;
+        }
+        let writes = words[23..]
+            .iter()
+            .map(|w| {
+                let (address, value) = w.split_once('=').ok_or_else(invalid)?;
+                Ok((hex16(address)?, hex8(value)?))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        records.push(TraceRecord {
+            cycle: words[0].parse().map_err(|_| invalid())?,
+            pc: hex16(words[1])?,
+            opcode: hex16(words[2])?,
+            registers,
+            index_register: hex16(words[19])?,
+            sp: hex8(words[20])?,
+            delay_timer: hex8(words[21])?,
+            sound_timer: hex8(words[22])?,
+            writes,
+        });
+    }
+    Ok(records)
+}
+
+fn parse_binary(mut bytes: &[u8]) -> Result<Vec<TraceRecord>, Error> {
+    let mut records = Vec::new();
+    while !bytes.is_empty() {
+        let truncated =
+            || Error::InvalidTrace(format!("record {} is truncated", records.len() + 1));
+        let header = bytes.get(..34).ok_or_else(truncated)?;
+        let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+        let write_count = header[33] as usize;
+        let writes = bytes
+            .get(34..34 + write_count * 3)
+            .ok_or_else(truncated)?
+            .chunks(3)
+            .map(|w| (u16::from_le_bytes([w[0], w[1]]), w[2]))
+            .collect();
+
+        records.push(TraceRecord {
+            cycle: u64::from_le_bytes(header[..8].try_into().unwrap()),
+            pc: u16_at(8),
+            opcode: u16_at(10),
+            registers: header[12..28].try_into().unwrap(),
+            index_register: u16_at(28),
+            sp: header[30],
+            delay_timer: header[31],
+            sound_timer: header[32],
+            writes,
+        });
+        bytes = &bytes[34 + write_count * 3..];
+    }
+    Ok(records)
+}
+
+/// Where two traces stop agreeing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Index of the first record that differs
+    pub index: usize,
+    /// What differs, e.g. `V3: 05 != 07`, or which trace ended first
+    pub differences: Vec<String>,
+}
+
+/// Find the first record where `a` and `b` differ.
+pub fn diff(a: &[TraceRecord], b: &[TraceRecord]) -> Option<Divergence> {
+    for index in 0..a.len().max(b.len()) {
+        let differences = match (a.get(index), b.get(index)) {
+            (Some(a), Some(b)) => differences(a, b),
+            (Some(_), None) => vec![format!("second trace ends after {} records", index)],
+            (None, _) => vec![format!("first trace ends after {} records", index)],
+        };
+        if !differences.is_empty() {
+            return Some(Divergence { index, differences });
+        }
+    }
+    None
+}
+
+fn differences(a: &TraceRecord, b: &TraceRecord) -> Vec<String> {
+    let mut found = Vec::new();
+    let mut compare = |name: String, a: String, b: String| {
+        if a != b {
+            found.push(format!("{}: {} != {}", name, a, b));
+        }
+    };
+    compare("cycle".into(), a.cycle.to_string(), b.cycle.to_string());
+    compare(
+        "PC".into(),
+        format!("{:04X}", a.pc),
+        format!("{:04X}", b.pc),
+    );
+    compare(
+        "opcode".into(),
+        format!("{:04X}", a.opcode),
+        format!("{:04X}", b.opcode),
+    );
+    for (i, (va, vb)) in a.registers.iter().zip(b.registers).enumerate() {
+        compare(
+            format!("V{:X}", i),
+            format!("{:02X}", va),
+            format!("{:02X}", vb),
+        );
+    }
+    compare(
+        "I".into(),
+        format!("{:04X}", a.index_register),
+        format!("{:04X}", b.index_register),
+    );
+    compare("SP".into(), a.sp.to_string(), b.sp.to_string());
+    compare(
+        "DT".into(),
+        format!("{:02X}", a.delay_timer),
+        format!("{:02X}", b.delay_timer),
+    );
+    compare(
+        "ST".into(),
+        format!("{:02X}", a.sound_timer),
+        format!("{:02X}", b.sound_timer),
+    );
+
+    // Compare writes by address, so a missing write shows as `--`
+    let written = |writes: &[(u16, u8)], address: u16| {
+        writes
+            .iter()
+            .rev()
+            .find(|(a, _)| *a == address)
+            .map_or("--".to_string(), |(_, v)| format!("{:02X}", v))
+    };
+    let mut addresses: Vec<u16> = a.writes.iter().chain(&b.writes).map(|(a, _)| *a).collect();
+    addresses.sort_unstable();
+    addresses.dedup();
+    for address in addresses {
+        compare(
+            format!("write {:04X}", address),
+            written(&a.writes, address),
+            written(&b.writes, address),
+        );
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::parse_range;
+    use crate::consts::FRAMES_PER_SECOND;
+    use crate::display::CLIDisplay;
+    use crate::interpreter::Interpreter;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Output the test can read while the interpreter owns the sink.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// LD V0, 0x2A; LD I, 0x300; LD B, V0; CALL 0x20A; JP 0x208; RET
+    const ROM: [u8; 12] = [
+        0x60, 0x2A, 0xA3, 0x00, 0xF0, 0x33, 0x22, 0x0A, 0x12, 0x08, 0x00, 0xEE,
+    ];
+
+    fn traced(sink: Box<dyn TraceSink>, steps: usize) -> Interpreter {
+        let mut core = Interpreter::new();
+        core.set_display(CLIDisplay::headless());
+        core.set_instructions_per_second(FRAMES_PER_SECOND);
+        core.load_rom(ROM.to_vec()).unwrap();
+        core.set_trace(sink);
+        for _ in 0..steps {
+            core.step().unwrap();
+        }
+        core.finish_outputs();
+        core
+    }
+
+    #[test]
+    fn text_records_hold_the_state_after_each_instruction() {
+        let out = Shared::default();
+        traced(Box::new(TextTrace::new(out.clone()).unwrap()), 4);
+        let text = String::from_utf8(out.0.take()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("# cycle pc opcode"));
+        assert_eq!(
+            lines[3],
+            "2 0204 F033 2A 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 0300 00 00 00 \
+             0300=00 0301=04 0302=02 ; LD B, V0"
+        );
+        assert!(
+            lines[4].starts_with("3 0206 220A 2A")
+                && lines[4].ends_with(" 0300 01 00 00 ; CALL 0x20A")
+        );
+    }
+
+    #[test]
+    fn both_formats_parse_back_to_the_same_records() {
+        let text = Shared::default();
+        traced(Box::new(TextTrace::new(text.clone()).unwrap()), 6);
+        let binary = Shared::default();
+        traced(Box::new(BinaryTrace::new(binary.clone()).unwrap()), 6);
+
+        let from_text = parse(&text.0.take()).unwrap();
+        let from_binary = parse(&binary.0.take()).unwrap();
+        assert_eq!(from_text.len(), 6);
+        assert_eq!(from_text, from_binary);
+        assert_eq!(from_text[2].writes, [(0x300, 0), (0x301, 4), (0x302, 2)]);
+        assert_eq!(from_text[5].pc, 0x208);
+    }
+
+    #[test]
+    fn the_range_limits_the_traced_addresses() {
+        let out = Shared::default();
+        let mut core = Interpreter::new();
+        core.set_display(CLIDisplay::headless());
+        core.load_rom(ROM.to_vec()).unwrap();
+        core.set_trace(Box::new(TextTrace::new(out.clone()).unwrap()));
+        core.set_trace_range(parse_range("0x206..0x20C").unwrap());
+        for _ in 0..6 {
+            core.step().unwrap();
+        }
+        let records = parse(&out.0.take()).unwrap();
+        let pcs: Vec<u16> = records.iter().map(|r| r.pc).collect();
+        assert_eq!(pcs, [0x206, 0x20A, 0x208]);
+
+        assert_eq!(parse_range("0x300..0x300"), None);
+        assert_eq!(parse_range("0..0x10001"), None);
+        assert_eq!(parse_range("0x0..0x10000"), Some(0..=0xFFFF));
+    }
+
+    #[test]
+    fn malformed_traces_are_errors() {
+        assert!(matches!(
+            parse(b"0 0200 00E0 00"),
+            Err(Error::InvalidTrace(_))
+        ));
+        let mut binary = BINARY_MAGIC.to_vec();
+        binary.extend([0; 33]);
+        assert_eq!(
+            parse(&binary),
+            Err(Error::InvalidTrace("record 1 is truncated".to_string()))
+        );
+    }
+
+    fn record(cycle: u64) -> TraceRecord {
+        TraceRecord::new(
+            cycle,
+            0x200 + cycle as u16 * 2,
+            0x6000,
+            &State::default(),
+            &[],
+        )
+    }
+
+    #[test]
+    fn diff_reports_the_first_differing_fields() {
+        let a: Vec<TraceRecord> = (0..4).map(record).collect();
+        assert_eq!(diff(&a, &a), None);
+
+        let mut b = a.clone();
+        b[2].registers[3] = 7;
+        b[2].index_register = 0x300;
+        b[2].writes = vec![(0x300, 1)];
+        b[3].pc = 0;
+        assert_eq!(
+            diff(&a, &b),
+            Some(Divergence {
+                index: 2,
+                differences: vec![
+                    "V3: 00 != 07".to_string(),
+                    "I: 0000 != 0300".to_string(),
+                    "write 0300: -- != 01".to_string(),
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn diff_reports_which_trace_ends_first() {
+        let a: Vec<TraceRecord> = (0..4).map(record).collect();
+        let ended = |a: &[TraceRecord], b: &[TraceRecord]| diff(a, b).unwrap().differences;
+        assert_eq!(ended(&a, &a[..3]), ["second trace ends after 3 records"]);
+        assert_eq!(ended(&a[..1], &a), ["first trace ends after 1 records"]);
+        assert_eq!(diff(&[], &[]), None);
+    }
+}
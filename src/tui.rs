@@ -0,0 +1,593 @@
+//! Full-screen terminal front end: the screen next to the registers and the
+//! stack, with the disassembly around PC and a view of RAM below, redrawn
+//! every frame.
+//!
+//! ```text
+//! space  pause or resume        +  -   double or halve the speed
+//! n      step one instruction   j  k   move the disassembly cursor
+//! b      toggle a breakpoint    [  ]   scroll the memory view
+//! i      show memory at I       esc    quit
+//! ```
+//!
+//! Keys bound to the keypad take precedence over these.
+
+use crate::Result;
+use crate::consts::FRAMES_PER_SECOND;
+use crate::debugger::{CONTINUE_LIMIT, Debugger, StopReason};
+use crate::disassembler::disassemble;
+use crate::input::{HeldKeys, KeyMap, RawMode, Utf8Decoder, read_stdin};
+use crate::interpreter::StepResult;
+
+use std::io::{self, Write};
+use std::sync::mpsc::Receiver;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Instructions shown in the disassembly panel.
+const DISASSEMBLY_ROWS: usize = 12;
+
+/// Width of the disassembly panel, so the memory view lines up next to it.
+const DISASSEMBLY_WIDTH: usize = 44;
+
+/// Rows of the memory view, of [`MEMORY_ROW_BYTES`] each.
+const MEMORY_ROWS: usize = 11;
+
+const MEMORY_ROW_BYTES: usize = 8;
+
+/// Slowest and fastest speeds `-` and `+` go to.
+const MIN_IPS: u32 = FRAMES_PER_SECOND;
+const MAX_IPS: u32 = 1_000_000;
+
+const HELP: &str =
+    "space pause  n step  +/- speed  j/k cursor  b breakpoint  [/] memory  i memory at I  esc quit";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Quit,
+    Pause,
+    Step,
+    Faster,
+    Slower,
+    Breakpoint,
+    CursorUp,
+    CursorDown,
+    MemoryUp,
+    MemoryDown,
+    MemoryAtI,
+}
+
+impl Action {
+    fn for_key(key: char) -> Option<Self> {
+        Some(match key {
+            '\x1B' => Action::Quit,
+            ' ' => Action::Pause,
+            'n' => Action::Step,
+            '+' | '=' => Action::Faster,
+            '-' => Action::Slower,
+            'b' => Action::Breakpoint,
+            'k' => Action::CursorUp,
+            'j' => Action::CursorDown,
+            '[' => Action::MemoryUp,
+            ']' => Action::MemoryDown,
+            'i' => Action::MemoryAtI,
+            _ => return None,
+        })
+    }
+}
+
+pub struct Tui {
+    keymap: Option<KeyMap>,
+    keys: Receiver<u8>,
+    decoder: Utf8Decoder,
+    held: HeldKeys,
+    out: Box<dyn Write>,
+    paused: bool,
+    /// Why the program stopped for good, once it halted or trapped
+    stopped: Option<StepResult>,
+    /// Address of the disassembly cursor, following PC while `None`
+    cursor: Option<u16>,
+    /// Start of the memory view, following I while `None`
+    memory: Option<u16>,
+    message: String,
+    _mode: RawMode,
+}
+
+impl Default for Tui {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tui {
+    /// Switch the terminal to a blank full-screen view and start reading
+    /// keys. The terminal is restored when the `Tui` is dropped.
+    pub fn new() -> Self {
+        let mode = RawMode::enter();
+        let mut out: Box<dyn Write> = Box::new(io::stdout());
+        // Alternate screen, hidden cursor
+        let _ = write!(out, "\x1B[?1049h\x1B[?25l\x1B[2J");
+        Tui {
+            keymap: None,
+            keys: read_stdin(),
+            decoder: Utf8Decoder::default(),
+            held: HeldKeys::default(),
+            out,
+            paused: false,
+            stopped: None,
+            cursor: None,
+            memory: None,
+            message: String::new(),
+            _mode: mode,
+        }
+    }
+
+    /// Press the keypad keys of `keymap` when their keys are typed, instead
+    /// of leaving the keypad to the interpreter's input.
+    pub fn with_keymap(mut self, keymap: KeyMap) -> Self {
+        self.keymap = Some(keymap);
+        self
+    }
+
+    /// Run in real time until the user quits or the program fails. After
+    /// the program halts or traps, the view stays up until the user quits,
+    /// and the halt or trap is returned.
+    pub fn run(&mut self, debugger: &mut Debugger) -> Result<StepResult> {
+        let frame_duration = Duration::from_secs_f32(1.0 / FRAMES_PER_SECOND as f32);
+        let result = loop {
+            let frame_start = Instant::now();
+
+            match self.handle_keys(debugger) {
+                Ok(Some(Action::Quit)) => break Ok(self.stopped.unwrap_or(StepResult::Halt)),
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            }
+            if !self.paused && self.stopped.is_none() {
+                if self.keymap.is_some() {
+                    self.held
+                        .update(&mut debugger.interpreter_mut().state_mut().keypad);
+                }
+                let frame = debugger.interpreter().frame();
+                match debugger.resume_until(CONTINUE_LIMIT, |i| i.frame() != frame) {
+                    Ok(reason) => self.stop(reason, debugger),
+                    Err(e) => break Err(e),
+                }
+            }
+            if let Err(e) = self.draw(debugger) {
+                self.message = format!("Drawing failed: {}", e);
+            }
+
+            if let Some(sleep_time) = frame_duration.checked_sub(frame_start.elapsed()) {
+                sleep(sleep_time);
+            }
+        };
+
+        debugger.interpreter_mut().finish_outputs();
+        result
+    }
+
+    /// Apply the keys typed since the last frame, returning `Quit` if the
+    /// user asked to.
+    fn handle_keys(&mut self, debugger: &mut Debugger) -> Result<Option<Action>> {
+        while let Ok(byte) = self.keys.try_recv() {
+            if byte == 0x1B && self.skip_escape_sequence() {
+                continue;
+            }
+            let Some(c) = self.decoder.push(byte) else {
+                continue;
+            };
+            if let Some(key) = self.keymap.as_ref().and_then(|k| k.get(c)) {
+                self.held.press(key);
+                continue;
+            }
+            match Action::for_key(c) {
+                Some(Action::Quit) => return Ok(Some(Action::Quit)),
+                Some(action) => self.apply(action, debugger)?,
+                None => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Drop the rest of an escape sequence such as an arrow key, so only a
+    /// lone escape quits.
+    fn skip_escape_sequence(&mut self) -> bool {
+        match self.keys.try_recv() {
+            Ok(b'[' | b'O') => {
+                while let Ok(byte) = self.keys.try_recv() {
+                    if (0x40..=0x7E).contains(&byte) {
+                        break;
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn apply(&mut self, action: Action, debugger: &mut Debugger) -> Result<()> {
+        let pc = debugger.interpreter().state().pc;
+        match action {
+            Action::Quit => {}
+            Action::Pause if self.stopped.is_none() => {
+                self.paused = !self.paused;
+                self.cursor = None;
+                self.message.clear();
+            }
+            Action::Step if self.stopped.is_none() => {
+                self.paused = true;
+                self.cursor = None;
+                self.message.clear();
+                let reason = debugger.step()?;
+                self.stop(reason, debugger);
+            }
+            Action::Pause | Action::Step => {}
+            Action::Faster | Action::Slower => {
+                let interpreter = debugger.interpreter_mut();
+                let ips = interpreter.instructions_per_second();
+                let ips = if action == Action::Faster {
+                    ips.saturating_mul(2).min(MAX_IPS)
+                } else {
+                    (ips / 2).max(MIN_IPS)
+                };
+                interpreter.set_instructions_per_second(ips);
+            }
+            Action::Breakpoint => {
+                let address = self.cursor.unwrap_or(pc);
+                let name = debugger.symbols().format(address);
+                self.message = if debugger.remove_breakpoint(address) {
+                    format!("Deleted breakpoint at {}", name)
+                } else {
+                    debugger.add_breakpoint(address);
+                    format!("Breakpoint at {}", name)
+                };
+            }
+            Action::CursorUp | Action::CursorDown => {
+                let cursor = self.cursor.unwrap_or(pc);
+                self.cursor = Some(if action == Action::CursorUp {
+                    cursor.saturating_sub(2)
+                } else {
+                    cursor.saturating_add(2)
+                });
+            }
+            Action::MemoryUp | Action::MemoryDown => {
+                let page = (MEMORY_ROWS * MEMORY_ROW_BYTES) as u16;
+                let start = self.memory_start(debugger);
+                self.memory = Some(if action == Action::MemoryUp {
+                    start.saturating_sub(page)
+                } else {
+                    start.saturating_add(page)
+                });
+            }
+            Action::MemoryAtI => self.memory = None,
+        }
+        Ok(())
+    }
+
+    /// Pause or stop on anything but the end of a frame or step.
+    fn stop(&mut self, reason: StopReason, debugger: &Debugger) {
+        let symbols = debugger.symbols();
+        match reason {
+            StopReason::Step => return,
+            StopReason::Halt => {
+                self.stopped = Some(StepResult::Halt);
+                self.message = "Program halted".to_string();
+            }
+            StopReason::Trap(address) => {
+                self.stopped = Some(StepResult::Trap(address));
+                self.message = format!("Skipped unknown opcode at {}", symbols.format(address));
+            }
+            StopReason::Breakpoint(address) => {
+                self.message = format!("Breakpoint at {}", symbols.format(address));
+            }
+            StopReason::Limit => {
+                self.message = format!("Stopped after {} instructions", CONTINUE_LIMIT);
+            }
+            // Only set from the line debugger
+            StopReason::Watchpoint(_)
+            | StopReason::Condition(_)
+            | StopReason::Changed { .. }
+            | StopReason::HistoryStart => {}
+        }
+        self.paused = true;
+        self.cursor = None;
+    }
+
+    fn memory_start(&self, debugger: &Debugger) -> u16 {
+        let start = self
+            .memory
+            .unwrap_or(debugger.interpreter().state().index_register);
+        start & !(MEMORY_ROW_BYTES as u16 - 1)
+    }
+
+    fn draw(&mut self, debugger: &Debugger) -> io::Result<()> {
+        let screen: Vec<String> = debugger
+            .interpreter()
+            .display()
+            .render()
+            .lines()
+            .map(String::from)
+            .collect();
+        let screen_width = screen.first().map_or(0, |row| row.chars().count());
+
+        let mut frame = String::from("\x1B[H");
+        let rows = side_by_side(&screen, screen_width, &self.registers(debugger))
+            .into_iter()
+            .chain([String::new()])
+            .chain(side_by_side(
+                &self.disassembly(debugger),
+                DISASSEMBLY_WIDTH,
+                &self.memory_view(debugger),
+            ))
+            .chain([String::new(), self.status(debugger), HELP.to_string()]);
+        for row in rows {
+            frame.push_str(&row);
+            // Clear what's left of a longer row from the previous frame
+            frame.push_str("\x1B[K\n");
+        }
+        frame.push_str("\x1B[J");
+        self.out.write_all(frame.as_bytes())?;
+        self.out.flush()
+    }
+
+    fn registers(&self, debugger: &Debugger) -> Vec<String> {
+        let state = debugger.interpreter().state();
+        let symbols = debugger.symbols();
+        let mut rows = vec![
+            format!("PC {}", symbols.format(state.pc)),
+            format!("I  {}", symbols.format(state.index_register)),
+            format!("DT {:02X}  ST {:02X}", state.delay_timer, state.sound_timer),
+            String::new(),
+        ];
+        for (row, values) in state.registers.chunks(4).enumerate() {
+            let cells: Vec<String> = values
+                .iter()
+                .enumerate()
+                .map(|(i, v)| format!("V{:X} {:02X}", row * 4 + i, v))
+                .collect();
+            rows.push(cells.join("  "));
+        }
+        rows.push(String::new());
+        rows.push(format!("Stack ({})", state.stack.len()));
+        // Innermost call first
+        for &address in state.stack.iter().rev() {
+            rows.push(format!("  {}", symbols.format(address)));
+        }
+        rows
+    }
+
+    fn disassembly(&self, debugger: &Debugger) -> Vec<String> {
+        let state = debugger.interpreter().state();
+        let symbols = debugger.symbols();
+        let pc = state.pc;
+        let center = self.cursor.unwrap_or(pc) as usize;
+        let start = center.saturating_sub(DISASSEMBLY_ROWS / 2 * 2);
+        let end = (start + DISASSEMBLY_ROWS * 2).min(state.ram.len());
+
+        let mut rows = vec!["Disassembly".to_string()];
+        for line in disassemble(&state.ram[start.min(end)..end], start as u16) {
+            let breakpoint = debugger.breakpoints().any(|b| b == line.address);
+            let marker = match (line.address == pc, self.cursor == Some(line.address)) {
+                (true, _) => "=>",
+                (false, true) => "> ",
+                (false, false) => "  ",
+            };
+            let mut row = format!(
+                "{}{}{:#05x}  {}",
+                if breakpoint { '*' } else { ' ' },
+                marker,
+                line.address,
+                line.source()
+            );
+            if let Some(label) = symbols.label_at(line.address) {
+                row = format!("{:<28}<{}>", row, label);
+            }
+            rows.push(row);
+        }
+        rows
+    }
+
+    fn memory_view(&self, debugger: &Debugger) -> Vec<String> {
+        let state = debugger.interpreter().state();
+        let start = self.memory_start(debugger) as usize;
+        let title = match self.memory {
+            Some(_) => "Memory".to_string(),
+            None => format!("Memory at I ({:#05x})", state.index_register),
+        };
+        let mut rows = vec![title];
+        for row in 0..MEMORY_ROWS {
+            let address = start + row * MEMORY_ROW_BYTES;
+            let Some(bytes) = state
+                .ram
+                .get(address..(address + MEMORY_ROW_BYTES).min(state.ram.len()))
+                .filter(|bytes| !bytes.is_empty())
+            else {
+                break;
+            };
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            rows.push(format!("{:#05x}  {}", address, hex.join(" ")));
+        }
+        rows
+    }
+
+    fn status(&self, debugger: &Debugger) -> String {
+        let interpreter = debugger.interpreter();
+        let state = match (self.stopped, self.paused) {
+            (Some(_), _) => "Stopped",
+            (None, true) => "Paused",
+            (None, false) => "Running",
+        };
+        format!(
+            "{}  {} ips  frame {}  cycle {}  {}",
+            state,
+            interpreter.instructions_per_second(),
+            interpreter.frame(),
+            interpreter.cycle(),
+            self.message
+        )
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        // Show the cursor and go back to the normal screen
+        let _ = write!(self.out, "\x1B[?25h\x1B[?1049l");
+        let _ = self.out.flush();
+    }
+}
+
+/// Rows of `left`, padded to `width` characters, followed by the rows of
+/// `right`.
+fn side_by_side(left: &[String], width: usize, right: &[String]) -> Vec<String> {
+    (0..left.len().max(right.len()))
+        .map(|i| {
+            let left = left.get(i).map_or("", String::as_str);
+            match right.get(i) {
+                Some(right) => format!("{:<width$}  {}", left, right, width = width),
+                None => left.to_string(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::tests::debugger;
+
+    use std::sync::mpsc::{self, Sender};
+
+    const PROGRAM: &str = "
+        LD I, 0x300
+    loop:
+        ADD V0, 1
+        JP loop
+    ";
+
+    /// A view of nothing, fed keys through the returned sender.
+    fn tui() -> (Tui, Sender<u8>) {
+        let (sender, keys) = mpsc::channel();
+        let tui = Tui {
+            keymap: None,
+            keys,
+            decoder: Utf8Decoder::default(),
+            held: HeldKeys::default(),
+            out: Box::new(io::sink()),
+            paused: false,
+            stopped: None,
+            cursor: None,
+            memory: None,
+            message: String::new(),
+            _mode: RawMode::unchanged(),
+        };
+        (tui, sender)
+    }
+
+    fn type_keys(
+        tui: &mut Tui,
+        sender: &Sender<u8>,
+        keys: &str,
+        debugger: &mut Debugger,
+    ) -> Option<Action> {
+        for byte in keys.bytes() {
+            sender.send(byte).unwrap();
+        }
+        tui.handle_keys(debugger).unwrap()
+    }
+
+    #[test]
+    fn keys_map_to_actions() {
+        assert_eq!(Action::for_key('\x1B'), Some(Action::Quit));
+        assert_eq!(Action::for_key('='), Action::for_key('+'));
+        assert_eq!(Action::for_key('j'), Some(Action::CursorDown));
+        assert_eq!(Action::for_key('x'), None);
+    }
+
+    #[test]
+    fn typed_keys_step_and_set_breakpoints() {
+        let mut debugger = debugger(PROGRAM);
+        let (mut tui, sender) = tui();
+
+        assert_eq!(type_keys(&mut tui, &sender, "nn", &mut debugger), None);
+        assert!(tui.paused);
+        assert_eq!(debugger.interpreter().state().pc, 0x204);
+
+        type_keys(&mut tui, &sender, "kb", &mut debugger);
+        assert_eq!(tui.cursor, Some(0x202));
+        assert!(debugger.breakpoints().eq([0x202]));
+        assert_eq!(tui.message, "Breakpoint at 0x202");
+        type_keys(&mut tui, &sender, "b", &mut debugger);
+        assert_eq!(debugger.breakpoints().count(), 0);
+        assert_eq!(tui.message, "Deleted breakpoint at 0x202");
+
+        let ips = debugger.interpreter().instructions_per_second();
+        type_keys(&mut tui, &sender, "+", &mut debugger);
+        assert_eq!(debugger.interpreter().instructions_per_second(), ips * 2);
+        for _ in 0..40 {
+            type_keys(&mut tui, &sender, "-", &mut debugger);
+        }
+        assert_eq!(debugger.interpreter().instructions_per_second(), MIN_IPS);
+    }
+
+    #[test]
+    fn only_a_lone_escape_quits() {
+        let mut debugger = debugger(PROGRAM);
+        let (mut tui, sender) = tui();
+        // Up arrow, then a lone escape
+        assert_eq!(type_keys(&mut tui, &sender, "\x1B[A", &mut debugger), None);
+        assert_eq!(tui.cursor, None);
+        assert_eq!(
+            type_keys(&mut tui, &sender, "\x1B", &mut debugger),
+            Some(Action::Quit)
+        );
+    }
+
+    #[test]
+    fn keypad_keys_take_precedence() {
+        let mut debugger = debugger(PROGRAM);
+        let (tui, sender) = tui();
+        let mut keymap = KeyMap::empty();
+        keymap.bind('b', 0xB);
+        keymap.bind('é', 0x5);
+        let mut tui = tui.with_keymap(keymap);
+        type_keys(&mut tui, &sender, "bé", &mut debugger);
+        assert_eq!(debugger.breakpoints().count(), 0);
+        let mut keypad = [false; 16];
+        tui.held.update(&mut keypad);
+        assert!(keypad[0xB] && keypad[0x5]);
+    }
+
+    #[test]
+    fn panels_show_the_machine() {
+        let mut debugger = debugger(PROGRAM);
+        let (mut tui, _sender) = tui();
+        debugger.step().unwrap();
+        debugger.add_breakpoint(0x202);
+
+        let registers = tui.registers(&debugger);
+        assert_eq!(registers[0], "PC 0x202");
+        assert_eq!(registers[1], "I  0x300");
+        assert_eq!(registers[4], "V0 00  V1 00  V2 00  V3 00");
+        assert_eq!(registers.last().unwrap(), "Stack (0)");
+
+        let disassembly = tui.disassembly(&debugger);
+        assert!(disassembly.contains(&"*=>0x202  ADD V0, 0x01".to_string()));
+        assert!(disassembly.contains(&"   0x204  JP 0x202".to_string()));
+
+        let memory = tui.memory_view(&debugger);
+        assert_eq!(memory[0], "Memory at I (0x300)");
+        assert_eq!(memory[1], "0x300  00 00 00 00 00 00 00 00");
+        assert_eq!(memory.len(), MEMORY_ROWS + 1);
+        tui.memory = Some(0xFF8);
+        assert_eq!(tui.memory_view(&debugger).len(), 2);
+
+        assert!(tui.status(&debugger).starts_with("Running  "));
+    }
+
+    #[test]
+    fn panels_are_laid_out_side_by_side() {
+        let left = ["ab".to_string(), "c".to_string()];
+        let right = ["1".to_string()];
+        assert_eq!(side_by_side(&left, 3, &right), ["ab   1", "c"]);
+        assert_eq!(side_by_side(&right, 2, &left), ["1   ab", "    c"]);
+    }
+}